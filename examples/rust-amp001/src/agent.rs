@@ -0,0 +1,1356 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::OsRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{
+    build_authcrypt_signed, build_plain_signed, build_proc_result, make_message_id, now_ms,
+    read_frame, receive_and_verify, validate_ack_semantics, write_frame, AckBody, AckSource,
+    AgentKeys, AmpError, DemoAgents, DidResolver, ErrorBody, HelloBody, MessageMeta,
+    ProcFailBody, ProcOkBody, ReceivedMessage, Recipients, TextMessageBody, TYPE_ACK, TYPE_HELLO,
+    TYPE_MESSAGE, TYPE_PING, TYPE_PONG,
+};
+use crate::keepalive::{
+    build_ping, build_pong, spawn_keepalive, ConnectionHealth, Keepalive, KeepaliveConfig,
+};
+use crate::mailbox::Mailbox;
+
+pub const DEFAULT_AGENT_TTL_MS: u64 = 60_000;
+
+/// Result of a message handler; `Err` becomes `PROC_FAIL` when processing results are
+/// reported.
+pub type HandlerResult = Result<(), ErrorBody>;
+
+type Handler = Arc<dyn Fn(&AgentHandle, &ReceivedMessage) -> HandlerResult + Send + Sync>;
+type Connector = Arc<dyn Fn() -> io::Result<Connection> + Send + Sync>;
+type EventCallback = Arc<dyn Fn(&AgentEvent) + Send + Sync>;
+
+/// One framed transport connection: the read half, the write half, and an optional hook
+/// that unblocks a pending read on shutdown.
+pub struct Connection {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    pub closer: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl Connection {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            closer: None,
+        }
+    }
+
+    /// AMPS over TCP (RFC 002 §3).
+    pub fn tcp(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let control = stream.try_clone()?;
+        Ok(Self {
+            reader: Box::new(reader),
+            writer: Box::new(stream),
+            closer: Some(Box::new(move || {
+                let _ = control.shutdown(Shutdown::Both);
+            })),
+        })
+    }
+}
+
+/// What a [`Handshake`] learned about the connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeOutcome {
+    /// Version the peer selected, when it negotiates one.
+    pub version: Option<String>,
+    /// DID at the other end of the connection (the relay); keepalive `PING`s go there.
+    pub peer_did: Option<String>,
+}
+
+/// Work done on every fresh connection before messages flow, e.g. HELLO registration or
+/// a relay session proof.
+pub trait Handshake: Send + Sync {
+    fn open(
+        &self,
+        keys: &AgentKeys,
+        resolver: &mut DidResolver,
+        connection: &mut Connection,
+    ) -> Result<HandshakeOutcome, AmpError>;
+}
+
+/// Registration `HELLO` addressed to a relay that forwards by `to` and never answers.
+#[derive(Debug, Clone)]
+pub struct HelloHandshake {
+    pub relay_did: String,
+    pub versions: Vec<String>,
+}
+
+impl Handshake for HelloHandshake {
+    fn open(
+        &self,
+        keys: &AgentKeys,
+        _resolver: &mut DidResolver,
+        connection: &mut Connection,
+    ) -> Result<HandshakeOutcome, AmpError> {
+        let ts = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, OsRng.next_u64()),
+            typ: TYPE_HELLO,
+            ts_ms: ts,
+            ttl_ms: DEFAULT_AGENT_TTL_MS,
+            from: String::new(),
+            to: Recipients::One(self.relay_did.clone()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = HelloBody {
+            versions: self.versions.clone(),
+        };
+        let wire = build_plain_signed(keys, meta, &body)?;
+        write_frame(&mut connection.writer, &wire)
+            .map_err(|e| AmpError::endpoint_unreachable(format!("HELLO write failed: {e}")))?;
+        Ok(HandshakeOutcome {
+            version: None,
+            peer_did: Some(self.relay_did.clone()),
+        })
+    }
+}
+
+/// Delay before each reconnect attempt: `initial`, multiplied by `factor` after every
+/// failed attempt, capped at `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub factor: u32,
+}
+
+impl Backoff {
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            initial: delay,
+            max: delay,
+            factor: 1,
+        }
+    }
+
+    /// Doubling delays from `initial` up to `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            factor: 2,
+        }
+    }
+
+    /// Delay before reconnect attempt `attempt` (1-based) of a run of failures.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..attempt {
+            if delay >= self.max {
+                break;
+            }
+            delay = delay.saturating_mul(self.factor);
+        }
+        delay.min(self.max)
+    }
+}
+
+/// Application message kept until every recipient (or a relay) acknowledged it, so it can
+/// be written again after a reconnect.
+struct PendingSend {
+    id: [u8; 16],
+    to: Vec<String>,
+    wire: Vec<u8>,
+    expires_at: u64,
+}
+
+/// Lifecycle notifications for logging and UI; handlers never see these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentEvent {
+    Connected { version: Option<String> },
+    HandshakeFailed(AmpError),
+    Disconnected { reason: String },
+    Rejected { frame_len: usize, error: AmpError },
+    HandlerFailed { typ: u8, from: String, error: ErrorBody },
+    /// Too many keepalive `PING`s went unanswered; the connection is being closed.
+    KeepaliveLost(ConnectionHealth),
+    /// Unacknowledged messages written again on a fresh connection.
+    Replayed { count: usize },
+    /// A message id seen before (e.g. a replay after a lost `ACK`); it was acknowledged
+    /// again but not handed to a handler.
+    Duplicate { typ: u8, from: String, id: [u8; 16] },
+    Stopped,
+}
+
+struct Shared {
+    keys: AgentKeys,
+    resolver: Mutex<DidResolver>,
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+    closer: Mutex<Option<Box<dyn Fn() + Send + Sync>>>,
+    version: Mutex<Option<String>>,
+    keepalive: Mutex<Option<Arc<Mutex<Keepalive>>>>,
+    /// `Some` when unacknowledged sends are replayed after a reconnect.
+    pending: Option<Mutex<Vec<PendingSend>>>,
+    mailbox: Option<Mutex<Mailbox>>,
+    connected: Mutex<bool>,
+    connected_changed: Condvar,
+    stopping: AtomicBool,
+}
+
+/// Cloneable way to send through the agent's current connection, from handlers or from
+/// application code.
+#[derive(Clone)]
+pub struct AgentHandle {
+    shared: Arc<Shared>,
+}
+
+impl AgentHandle {
+    pub fn did(&self) -> &str {
+        &self.shared.keys.did
+    }
+
+    pub fn keys(&self) -> &AgentKeys {
+        &self.shared.keys
+    }
+
+    /// Version selected by the last handshake, when the peer negotiates one.
+    pub fn version(&self) -> Option<String> {
+        self.shared.version.lock().expect("agent version poisoned").clone()
+    }
+
+    /// Keepalive RTT and missed-`PONG` figures for the current connection.
+    pub fn health(&self) -> Option<ConnectionHealth> {
+        let keepalive = self.shared.keepalive.lock().expect("agent keepalive poisoned").clone()?;
+        let health = keepalive.lock().expect("keepalive poisoned").health().clone();
+        Some(health)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.shared.connected.lock().expect("agent state poisoned")
+    }
+
+    /// Blocks until a handshake has completed, at most `timeout`.
+    pub fn wait_connected(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut connected = self.shared.connected.lock().expect("agent state poisoned");
+        while !*connected {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            connected = self
+                .shared
+                .connected_changed
+                .wait_timeout(connected, remaining)
+                .expect("agent state poisoned")
+                .0;
+        }
+        true
+    }
+
+    pub fn with_resolver<R>(&self, f: impl FnOnce(&mut DidResolver) -> R) -> R {
+        f(&mut self.shared.resolver.lock().expect("agent resolver poisoned"))
+    }
+
+    /// Signed, authcrypt-encrypted message to one recipient.
+    pub fn send<T: Serialize>(&self, to: &str, typ: u8, body: &T, ttl_ms: u64) -> Result<[u8; 16], AmpError> {
+        let (id, wire) = self.seal(to, typ, body, ttl_ms)?;
+        self.submit(id, vec![to.to_string()], typ, wire, ttl_ms)?;
+        Ok(id)
+    }
+
+    /// Builds what [`AgentHandle::send`] would write without writing it, for callers that
+    /// track the message (e.g. in an outbox) before it goes out.
+    pub fn seal<T: Serialize>(
+        &self,
+        to: &str,
+        typ: u8,
+        body: &T,
+        ttl_ms: u64,
+    ) -> Result<([u8; 16], Vec<u8>), AmpError> {
+        let meta = self.meta(typ, Recipients::One(to.to_string()), ttl_ms, None, None);
+        let id = meta.id;
+        let resolver = self.shared.resolver.lock().expect("agent resolver poisoned");
+        let wire = build_authcrypt_signed(&self.shared.keys, to, meta, body, &resolver)?;
+        Ok((id, wire))
+    }
+
+    /// Signed plaintext message, possibly to several recipients.
+    pub fn send_plain<T: Serialize>(
+        &self,
+        to: Recipients,
+        typ: u8,
+        body: &T,
+        ttl_ms: u64,
+    ) -> Result<[u8; 16], AmpError> {
+        let recipients = to.as_vec();
+        let meta = self.meta(typ, to, ttl_ms, None, None);
+        let id = meta.id;
+        let wire = build_plain_signed(&self.shared.keys, meta, body)?;
+        self.submit(id, recipients, typ, wire, ttl_ms)?;
+        Ok(id)
+    }
+
+    /// `MESSAGE {msg}`, encrypted to `to`.
+    pub fn send_text(&self, to: &str, text: &str, ttl_ms: u64) -> Result<[u8; 16], AmpError> {
+        let body = TextMessageBody {
+            msg: text.to_string(),
+        };
+        self.send(to, TYPE_MESSAGE, &body, ttl_ms)
+    }
+
+    /// Signed reply to `original`'s sender in the same thread, with `reply_to` set.
+    pub fn reply<T: Serialize>(
+        &self,
+        original: &ReceivedMessage,
+        typ: u8,
+        body: &T,
+    ) -> Result<[u8; 16], AmpError> {
+        let meta = self.meta(
+            typ,
+            Recipients::One(original.meta.from.clone()),
+            DEFAULT_AGENT_TTL_MS,
+            Some(original.meta.id),
+            original.meta.thread_id.clone(),
+        );
+        let id = meta.id;
+        let wire = build_plain_signed(&self.shared.keys, meta, body)?;
+        self.send_wire(&wire)?;
+        Ok(id)
+    }
+
+    /// Writes already-built AMP bytes to the current connection.
+    pub fn send_wire(&self, wire: &[u8]) -> Result<(), AmpError> {
+        let mut writer = self.shared.writer.lock().expect("agent writer poisoned");
+        let Some(writer) = writer.as_mut() else {
+            return Err(AmpError::endpoint_unreachable("agent is not connected"));
+        };
+        write_frame(writer, wire).map_err(|e| AmpError::endpoint_unreachable(format!("write failed: {e}")))
+    }
+
+    /// Ids of sent application messages still waiting for an `ACK`, oldest first. Empty
+    /// unless replay is enabled.
+    pub fn pending(&self) -> Vec<[u8; 16]> {
+        let Some(pending) = &self.shared.pending else {
+            return Vec::new();
+        };
+        pending.lock().expect("agent outbox poisoned").iter().map(|p| p.id).collect()
+    }
+
+    /// Runs `f` on the agent's mailbox, when it has one.
+    pub fn with_mailbox<R>(&self, f: impl FnOnce(&mut Mailbox) -> R) -> Option<R> {
+        let mailbox = self.shared.mailbox.as_ref()?;
+        Some(f(&mut mailbox.lock().expect("agent mailbox poisoned")))
+    }
+
+    /// Stops the agent: closes the connection and ends the run loop without reconnecting.
+    pub fn shutdown(&self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        self.close();
+    }
+
+    /// Drops the current connection; a reconnecting agent connects again.
+    fn close(&self) {
+        if let Some(close) = self.shared.closer.lock().expect("agent closer poisoned").as_ref() {
+            close();
+        }
+    }
+
+    /// Writes an outgoing message. With replay enabled an application message is kept until
+    /// acknowledged and a failed write only defers it to the next connection.
+    fn submit(&self, id: [u8; 16], to: Vec<String>, typ: u8, wire: Vec<u8>, ttl_ms: u64) -> Result<(), AmpError> {
+        let Some(pending) = self.shared.pending.as_ref().filter(|_| typ >= TYPE_MESSAGE) else {
+            return self.send_wire(&wire);
+        };
+        let now = now_ms();
+        let expires_at = now.saturating_add(ttl_ms);
+        self.with_mailbox(|mailbox| mailbox.queue_outbound(id, to.clone(), wire.clone(), (now, expires_at)))
+            .transpose()?;
+        pending.lock().expect("agent outbox poisoned").push(PendingSend {
+            id,
+            to,
+            wire: wire.clone(),
+            expires_at,
+        });
+        match self.send_wire(&wire) {
+            Err(err) if self.shared.stopping.load(Ordering::SeqCst) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Settles a pending send from a verified `ACK`: the `ack_target` (or the recipient
+    /// that sent it) is done, and a relay `ACK` for a single recipient settles it all.
+    fn acknowledged(&self, ack: &ReceivedMessage) {
+        let (Some(pending), Some(id)) = (&self.shared.pending, ack.meta.reply_to) else {
+            return;
+        };
+        let Ok(body) = ack.decode_body::<AckBody>() else {
+            return;
+        };
+        let mut pending = pending.lock().expect("agent outbox poisoned");
+        let Some(index) = pending.iter().position(|p| p.id == id) else {
+            return;
+        };
+        let entry = &mut pending[index];
+        let valid = self.with_resolver(|resolver| validate_ack_semantics(ack, &entry.to, resolver));
+        if valid.is_err() {
+            return;
+        }
+        let settled: Vec<String> = match (body.ack_target, body.ack_source) {
+            (Some(target), _) => vec![target],
+            (None, AckSource::Relay) => entry.to.clone(),
+            (None, AckSource::Recipient) => vec![ack.meta.from.clone()],
+        };
+        entry.to.retain(|did| !settled.contains(did));
+        let _ = self.with_mailbox(|mailbox| mailbox.outbound_acked(&id, &settled, now_ms()));
+        if entry.to.is_empty() {
+            pending.remove(index);
+        }
+    }
+
+    /// Writes every unexpired pending send again; returns how many went out.
+    fn replay(&self) -> usize {
+        let Some(pending) = &self.shared.pending else {
+            return 0;
+        };
+        let wires: Vec<Vec<u8>> = {
+            let mut pending = pending.lock().expect("agent outbox poisoned");
+            let now = now_ms();
+            pending.retain(|p| p.expires_at > now);
+            pending.iter().map(|p| p.wire.clone()).collect()
+        };
+        wires.iter().take_while(|wire| self.send_wire(wire).is_ok()).count()
+    }
+
+    fn meta(
+        &self,
+        typ: u8,
+        to: Recipients,
+        ttl_ms: u64,
+        reply_to: Option<[u8; 16]>,
+        thread_id: Option<Vec<u8>>,
+    ) -> MessageMeta {
+        let ts = now_ms();
+        MessageMeta {
+            v: 1,
+            id: make_message_id(ts, OsRng.next_u64()),
+            typ,
+            ts_ms: ts,
+            ttl_ms,
+            from: String::new(),
+            to,
+            reply_to,
+            thread_id,
+        }
+    }
+
+    fn set_connection(&self, writer: Option<Box<dyn Write + Send>>, closer: Option<Box<dyn Fn() + Send + Sync>>, version: Option<String>) {
+        let connected = writer.is_some();
+        *self.shared.writer.lock().expect("agent writer poisoned") = writer;
+        *self.shared.closer.lock().expect("agent closer poisoned") = closer;
+        *self.shared.version.lock().expect("agent version poisoned") = version;
+        *self.shared.connected.lock().expect("agent state poisoned") = connected;
+        self.shared.connected_changed.notify_all();
+    }
+}
+
+/// Builds an [`Agent`]: keys, resolver, transport, handshake and typed handlers.
+pub struct AgentBuilder {
+    keys: AgentKeys,
+    resolver: DidResolver,
+    connector: Option<Connector>,
+    handshake: Option<Arc<dyn Handshake>>,
+    handlers: HashMap<u8, Handler>,
+    fallback: Option<Handler>,
+    events: Option<EventCallback>,
+    auto_ack: bool,
+    report_processing: bool,
+    reconnect: Option<Backoff>,
+    max_reconnects: Option<u32>,
+    keepalive: Option<KeepaliveConfig>,
+    replay_unacked: bool,
+    mailbox: Option<Mailbox>,
+}
+
+impl AgentBuilder {
+    pub fn new(keys: AgentKeys) -> Self {
+        Self {
+            keys,
+            resolver: DidResolver::default(),
+            connector: None,
+            handshake: None,
+            handlers: HashMap::new(),
+            fallback: None,
+            events: None,
+            auto_ack: true,
+            report_processing: false,
+            reconnect: None,
+            max_reconnects: None,
+            keepalive: None,
+            replay_unacked: false,
+            mailbox: None,
+        }
+    }
+
+    pub fn resolver(mut self, resolver: DidResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn connect_tcp(self, addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        self.connect_with(move || Connection::tcp(&addr))
+    }
+
+    /// Any framed transport: called for the first connection and for every reconnect.
+    pub fn connect_with(mut self, connect: impl Fn() -> io::Result<Connection> + Send + Sync + 'static) -> Self {
+        self.connector = Some(Arc::new(connect));
+        self
+    }
+
+    pub fn handshake(mut self, handshake: impl Handshake + 'static) -> Self {
+        self.handshake = Some(Arc::new(handshake));
+        self
+    }
+
+    /// Handles verified messages of `typ` with their body decoded as `T`. A body that does
+    /// not decode fails the handler with `4001`.
+    pub fn on<T, F>(mut self, typ: u8, handler: F) -> Self
+    where
+        T: DeserializeOwned,
+        F: Fn(&AgentHandle, &ReceivedMessage, T) -> HandlerResult + Send + Sync + 'static,
+    {
+        let handler: Handler = Arc::new(move |agent, message| {
+            let body: T = message
+                .decode_body()
+                .map_err(|err| ErrorBody::new(4001, err.detail))?;
+            handler(agent, message, body)
+        });
+        self.handlers.insert(typ, handler);
+        self
+    }
+
+    /// `MESSAGE` handler with the text body decoded.
+    pub fn on_message<F>(self, handler: F) -> Self
+    where
+        F: Fn(&AgentHandle, &ReceivedMessage, TextMessageBody) -> HandlerResult + Send + Sync + 'static,
+    {
+        self.on(TYPE_MESSAGE, handler)
+    }
+
+    /// Handles every verified message without a typed handler.
+    pub fn on_unhandled<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&AgentHandle, &ReceivedMessage) -> HandlerResult + Send + Sync + 'static,
+    {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn on_event(mut self, callback: impl Fn(&AgentEvent) + Send + Sync + 'static) -> Self {
+        self.events = Some(Arc::new(callback));
+        self
+    }
+
+    /// Recipient `ACK` for every handled message of an application type (`typ >= 0x10`).
+    /// On by default.
+    pub fn auto_ack(mut self, enabled: bool) -> Self {
+        self.auto_ack = enabled;
+        self
+    }
+
+    /// `PROC_OK`/`PROC_FAIL` from each application handler's result. Off by default.
+    pub fn report_processing(mut self, enabled: bool) -> Self {
+        self.report_processing = enabled;
+        self
+    }
+
+    /// Reconnects after `delay` when the connection drops, up to `max_attempts` in a row
+    /// (`None` = forever).
+    pub fn reconnect(self, delay: Duration, max_attempts: Option<u32>) -> Self {
+        self.reconnect_backoff(Backoff::fixed(delay), max_attempts)
+    }
+
+    /// Like [`AgentBuilder::reconnect`], waiting `backoff.delay(n)` before the `n`th
+    /// attempt in a row. A connection that completed its handshake resets the count.
+    pub fn reconnect_backoff(mut self, backoff: Backoff, max_attempts: Option<u32>) -> Self {
+        self.reconnect = Some(backoff);
+        self.max_reconnects = max_attempts;
+        self
+    }
+
+    /// Keeps each sent application message until it is acknowledged and writes it again
+    /// after every reconnect handshake, until its `ttl_ms` runs out. Sends while
+    /// disconnected are queued instead of failing. Off by default.
+    pub fn replay_unacked(mut self, enabled: bool) -> Self {
+        self.replay_unacked = enabled;
+        self
+    }
+
+    /// Persists every verified inbound message (except `PING`/`PONG`) before it is
+    /// acknowledged or handled, records the `ACK`s sent for them, and skips ids already
+    /// stored, also across restarts. With [`AgentBuilder::replay_unacked`] the pending
+    /// sends live in the mailbox outbox too and are replayed after a restart.
+    pub fn mailbox(mut self, mailbox: Mailbox) -> Self {
+        self.mailbox = Some(mailbox);
+        self
+    }
+
+    /// `PING`s the handshake's peer while the connection is idle and closes it after
+    /// `max_missed` unanswered ones, which triggers a reconnect when enabled. Incoming
+    /// `PING`s are always answered.
+    pub fn keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<Agent, AmpError> {
+        let connector = self
+            .connector
+            .ok_or_else(|| AmpError::invalid_message("agent needs a transport (connect_tcp/connect_with)"))?;
+        let pending = self.replay_unacked.then(|| {
+            let stored = self.mailbox.as_ref().map(|mailbox| mailbox.pending_outbound(now_ms()));
+            let pending = stored.into_iter().flatten().map(|entry| PendingSend {
+                id: entry.id,
+                to: entry.unacked(),
+                wire: entry.wire.clone(),
+                expires_at: entry.expires_at,
+            });
+            Mutex::new(pending.collect())
+        });
+        let shared = Arc::new(Shared {
+            keys: self.keys,
+            resolver: Mutex::new(self.resolver),
+            writer: Mutex::new(None),
+            closer: Mutex::new(None),
+            version: Mutex::new(None),
+            keepalive: Mutex::new(None),
+            pending,
+            mailbox: self.mailbox.map(Mutex::new),
+            connected: Mutex::new(false),
+            connected_changed: Condvar::new(),
+            stopping: AtomicBool::new(false),
+        });
+        Ok(Agent {
+            handle: AgentHandle { shared },
+            connector,
+            handshake: self.handshake,
+            handlers: self.handlers,
+            fallback: self.fallback,
+            events: self.events,
+            auto_ack: self.auto_ack,
+            report_processing: self.report_processing,
+            reconnect: self.reconnect,
+            max_reconnects: self.max_reconnects,
+            keepalive: self.keepalive,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/// Connection lifecycle plus the receive/verify/ACK loop; applications supply handlers.
+pub struct Agent {
+    handle: AgentHandle,
+    connector: Connector,
+    handshake: Option<Arc<dyn Handshake>>,
+    handlers: HashMap<u8, Handler>,
+    fallback: Option<Handler>,
+    events: Option<EventCallback>,
+    auto_ack: bool,
+    report_processing: bool,
+    reconnect: Option<Backoff>,
+    max_reconnects: Option<u32>,
+    keepalive: Option<KeepaliveConfig>,
+    /// Ids of received messages until their TTL runs out, to drop redeliveries.
+    seen: Mutex<HashMap<(String, [u8; 16]), u64>>,
+}
+
+impl Agent {
+    pub fn builder(keys: AgentKeys) -> AgentBuilder {
+        AgentBuilder::new(keys)
+    }
+
+    pub fn handle(&self) -> AgentHandle {
+        self.handle.clone()
+    }
+
+    /// Runs the agent on its own thread.
+    pub fn spawn(self) -> (AgentHandle, JoinHandle<Result<(), AmpError>>) {
+        let handle = self.handle();
+        (handle, thread::spawn(move || self.run()))
+    }
+
+    /// Connects, runs the handshake and serves frames until shutdown. When reconnecting
+    /// is enabled a dropped connection is retried; otherwise the first disconnect ends the
+    /// run with its error.
+    pub fn run(self) -> Result<(), AmpError> {
+        let mut failures = 0_u32;
+        let result = loop {
+            if self.stopping() {
+                break Ok(());
+            }
+            let error = match self.session() {
+                Ok(served) => {
+                    if served {
+                        failures = 0;
+                    }
+                    AmpError::endpoint_unreachable("connection closed")
+                }
+                Err(err) => err,
+            };
+            if self.stopping() {
+                break Ok(());
+            }
+            let Some(backoff) = self.reconnect else {
+                break Err(error);
+            };
+            failures += 1;
+            if self.max_reconnects.is_some_and(|max| failures > max) {
+                break Err(error);
+            }
+            thread::sleep(backoff.delay(failures));
+        };
+        self.emit(&AgentEvent::Stopped);
+        result
+    }
+
+    /// One connection: connect, handshake, serve. `Ok(true)` once the handshake succeeded
+    /// and the connection later closed.
+    fn session(&self) -> Result<bool, AmpError> {
+        let mut connection =
+            (self.connector)().map_err(|e| AmpError::endpoint_unreachable(format!("connect failed: {e}")))?;
+        let outcome = match &self.handshake {
+            Some(handshake) => {
+                // The handshake blocks on the network; sends and dispatch keep the live
+                // resolver meanwhile, and whatever the handshake learned is merged back.
+                let before = self.handle.shared.resolver.lock().expect("agent resolver poisoned").clone();
+                let mut resolver = before.clone();
+                let outcome = handshake.open(&self.handle.shared.keys, &mut resolver, &mut connection);
+                self.handle
+                    .shared
+                    .resolver
+                    .lock()
+                    .expect("agent resolver poisoned")
+                    .merge_changes(&before, &resolver);
+                outcome
+            }
+            None => Ok(HandshakeOutcome::default()),
+        };
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                self.emit(&AgentEvent::HandshakeFailed(err.clone()));
+                return Err(err);
+            }
+        };
+
+        let Connection {
+            mut reader,
+            writer,
+            closer,
+        } = connection;
+        self.handle.set_connection(Some(writer), closer, outcome.version.clone());
+        let keepalive_stop = Arc::new(AtomicBool::new(false));
+        if let (Some(config), Some(peer)) = (self.keepalive, outcome.peer_did) {
+            self.start_keepalive(config, peer, Arc::clone(&keepalive_stop));
+        }
+        self.emit(&AgentEvent::Connected {
+            version: outcome.version,
+        });
+        if self.stopping() {
+            self.handle.shutdown();
+        }
+        let replayed = self.handle.replay();
+        if replayed > 0 {
+            self.emit(&AgentEvent::Replayed { count: replayed });
+        }
+
+        let reason = loop {
+            let frame = match read_frame(&mut reader) {
+                Ok(frame) => frame,
+                Err(err) => break err.to_string(),
+            };
+            self.dispatch(&frame);
+        };
+        keepalive_stop.store(true, Ordering::SeqCst);
+        *self.handle.shared.keepalive.lock().expect("agent keepalive poisoned") = None;
+        self.handle.set_connection(None, None, None);
+        self.emit(&AgentEvent::Disconnected { reason });
+        Ok(true)
+    }
+
+    fn start_keepalive(&self, config: KeepaliveConfig, peer: String, stop: Arc<AtomicBool>) {
+        let keepalive = Arc::new(Mutex::new(Keepalive::new(config, now_ms())));
+        *self.handle.shared.keepalive.lock().expect("agent keepalive poisoned") = Some(Arc::clone(&keepalive));
+
+        let pinger = self.handle.clone();
+        let closer = self.handle.clone();
+        let events = self.events.clone();
+        spawn_keepalive(
+            keepalive,
+            stop,
+            move |now| {
+                let (id, wire) = build_ping(pinger.keys(), &peer, config.pong_timeout_ms, now)?;
+                pinger.send_wire(&wire)?;
+                Ok(id)
+            },
+            move |health| {
+                if let Some(events) = events {
+                    events(&AgentEvent::KeepaliveLost(health.clone()));
+                }
+                closer.close();
+            },
+        );
+    }
+
+    fn dispatch(&self, frame: &[u8]) {
+        let shared = &self.handle.shared;
+        let keepalive = shared.keepalive.lock().expect("agent keepalive poisoned").clone();
+        if let Some(keepalive) = &keepalive {
+            keepalive.lock().expect("keepalive poisoned").observe_inbound(now_ms());
+        }
+        let verified = {
+            let resolver = shared.resolver.lock().expect("agent resolver poisoned");
+            receive_and_verify(&shared.keys, frame, &resolver, now_ms())
+        };
+        let message = match verified {
+            Ok(message) => message,
+            Err(error) => {
+                self.emit(&AgentEvent::Rejected {
+                    frame_len: frame.len(),
+                    error,
+                });
+                return;
+            }
+        };
+
+        let typ = message.meta.typ;
+        match (typ, message.meta.reply_to) {
+            (TYPE_PING, _) => {
+                if let Ok(pong) = build_pong(&shared.keys, &message.meta, now_ms()) {
+                    let _ = self.handle.send_wire(&pong);
+                }
+            }
+            (TYPE_PONG, Some(ping_id)) => {
+                if let Some(keepalive) = &keepalive {
+                    keepalive.lock().expect("keepalive poisoned").pong_received(&ping_id, now_ms());
+                }
+            }
+            (TYPE_ACK, Some(_)) => self.handle.acknowledged(&message),
+            _ => {}
+        }
+        let application = typ >= TYPE_MESSAGE;
+        let stored = match self.store(&message) {
+            Ok(stored) => stored,
+            Err(error) => {
+                // Not ACKed either: the sender's replay gets another chance to store it.
+                self.emit(&AgentEvent::Rejected {
+                    frame_len: frame.len(),
+                    error,
+                });
+                return;
+            }
+        };
+        let first = stored && self.first_sighting(&message.meta);
+        // ACKed whether or not anything handles it, so the relay stops redelivering.
+        if application && self.auto_ack {
+            self.acknowledge(&message);
+        }
+        if !first {
+            self.emit(&AgentEvent::Duplicate {
+                typ,
+                from: message.meta.from.clone(),
+                id: message.meta.id,
+            });
+            return;
+        }
+        let Some(handler) = self.handlers.get(&typ).or(self.fallback.as_ref()) else {
+            return;
+        };
+
+        let result = handler(&self.handle, &message);
+        if application && self.report_processing {
+            let outcome = match &result {
+                Ok(()) => Ok(ProcOkBody::default()),
+                Err(error) => Err(ProcFailBody::from_error(error)),
+            };
+            if let Ok(wire) = build_proc_result(&shared.keys, &message.meta, &outcome, now_ms()) {
+                let _ = self.handle.send_wire(&wire);
+            }
+        }
+        if let Err(error) = result {
+            self.emit(&AgentEvent::HandlerFailed {
+                typ,
+                from: message.meta.from.clone(),
+                error,
+            });
+        }
+    }
+
+    /// Puts a verified message in the mailbox; `false` when it is already there.
+    fn store(&self, message: &ReceivedMessage) -> Result<bool, AmpError> {
+        if matches!(message.meta.typ, TYPE_PING | TYPE_PONG) {
+            return Ok(true);
+        }
+        let stored = self
+            .handle
+            .with_mailbox(|mailbox| mailbox.store_received(message, now_ms()))
+            .transpose()?;
+        Ok(stored.unwrap_or(true))
+    }
+
+    /// Recipient `ACK`, recorded in the mailbox once it is written.
+    fn acknowledge(&self, message: &ReceivedMessage) {
+        if self.send_recipient_ack(message).is_ok() {
+            let _ = self.handle.with_mailbox(|mailbox| mailbox.mark_acked(&message.meta.from, &message.meta.id, now_ms()));
+        }
+    }
+
+    /// Records `(from, id)` (§16.2) until the message expires; `false` when it was already seen.
+    fn first_sighting(&self, meta: &MessageMeta) -> bool {
+        let now = now_ms();
+        let mut seen = self.seen.lock().expect("agent seen ids poisoned");
+        seen.retain(|_, expires_at| *expires_at > now);
+        let expires_at = meta.ts_ms.saturating_add(meta.ttl_ms).max(now);
+        seen.insert((meta.from.clone(), meta.id), expires_at).is_none()
+    }
+
+    fn send_recipient_ack(&self, message: &ReceivedMessage) -> Result<[u8; 16], AmpError> {
+        let body = AckBody {
+            ack_source: AckSource::Recipient,
+            received_at: now_ms(),
+            ack_target: (message.meta.to.len() > 1).then(|| self.handle.did().to_string()),
+        };
+        self.handle.reply(message, TYPE_ACK, &body)
+    }
+
+    fn stopping(&self) -> bool {
+        self.handle.shared.stopping.load(Ordering::SeqCst)
+    }
+
+    fn emit(&self, event: &AgentEvent) {
+        if let Some(events) = &self.events {
+            events(event);
+        }
+    }
+}
+
+/// Splits `"<first> <rest>"` for CLI commands such as `/send bob hello there`.
+pub fn split_first(input: &str) -> Option<(&str, &str)> {
+    let mut parts = input.splitn(2, ' ');
+    let first = parts.next()?.trim();
+    let rest = parts.next()?.trim();
+    if first.is_empty() || rest.is_empty() {
+        return None;
+    }
+    Some((first, rest))
+}
+
+/// Maps a demo alias or DID to a DID the resolver can encrypt to.
+pub fn resolve_target_did(
+    demo: &DemoAgents,
+    resolver: &DidResolver,
+    target_token: &str,
+) -> Result<String, String> {
+    let token = target_token.trim();
+    let mapped = demo.did_for_alias(token);
+
+    if mapped.starts_with("did:") {
+        if resolver.key_agreement_for(&mapped).is_some() {
+            return Ok(mapped);
+        }
+        return Err(format!(
+            "target DID not available in local resolver: {mapped} (use alice/bob or a known DID)",
+        ));
+    }
+
+    Err(format!(
+        "invalid target '{token}': use alice, bob, relay, or a full DID",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{demo_agents, peek_routing, TYPE_PROC_FAIL, TYPE_PROC_OK};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn text_from(sender: &AgentKeys, to: &str, text: &str, resolver: &DidResolver) -> Vec<u8> {
+        let ts = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, OsRng.next_u64()),
+            typ: TYPE_MESSAGE,
+            ts_ms: ts,
+            ttl_ms: DEFAULT_AGENT_TTL_MS,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = TextMessageBody {
+            msg: text.to_string(),
+        };
+        build_authcrypt_signed(sender, to, meta, &body, resolver).expect("message")
+    }
+
+    #[test]
+    fn agent_handles_acks_reports_and_reconnects() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+
+        let (seen_tx, seen) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let agent = Agent::builder(demo.alice.clone())
+            .resolver(resolver.clone())
+            .connect_tcp(addr)
+            .handshake(HelloHandshake {
+                relay_did: demo.relay.did.clone(),
+                versions: vec!["1.0.0".to_string()],
+            })
+            .on_message(move |_, _, body| {
+                seen_tx.send(body.msg.clone()).expect("seen");
+                if body.msg == "fail" {
+                    return Err(ErrorBody::new(4001, "cannot do that"));
+                }
+                Ok(())
+            })
+            .on_event(move |event| {
+                let _ = events_tx.send(event.clone());
+            })
+            .report_processing(true)
+            .reconnect(Duration::from_millis(50), Some(3))
+            .build()
+            .expect("agent");
+        let (handle, run) = agent.spawn();
+
+        // Stand-in relay: expects the HELLO, delivers two messages, reads the replies,
+        // then drops the connection to force a reconnect.
+        let bob = demo.bob.clone();
+        let alice_did = demo.alice.did.clone();
+        let relay_resolver = resolver.clone();
+        let relay = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let hello = peek_routing(&read_frame(&mut stream).expect("hello")).expect("routing");
+            assert_eq!((hello.typ, hello.from.as_str()), (TYPE_HELLO, alice_did.as_str()));
+            let mut replies = Vec::new();
+            for text in ["ok", "fail"] {
+                let wire = text_from(&bob, &alice_did, text, &relay_resolver);
+                write_frame(&mut stream, &wire).expect("deliver");
+                for _ in 0..2 {
+                    let frame = read_frame(&mut stream).expect("reply");
+                    replies.push(receive_and_verify(&bob, &frame, &relay_resolver, now_ms()).expect("verify"));
+                }
+            }
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().expect("reconnect");
+            let hello = peek_routing(&read_frame(&mut stream).expect("hello again")).expect("routing");
+            assert_eq!(hello.typ, TYPE_HELLO);
+            (replies, stream)
+        });
+
+        let (replies, _reconnected) = relay.join().expect("relay");
+        assert_eq!(seen.recv().expect("first"), "ok");
+        assert_eq!(seen.recv().expect("second"), "fail");
+        let kinds: Vec<u8> = replies.iter().map(|r| r.meta.typ).collect();
+        assert_eq!(kinds, vec![TYPE_ACK, TYPE_PROC_OK, TYPE_ACK, TYPE_PROC_FAIL]);
+        let failure: ProcFailBody = replies[3].decode_body().expect("proc fail body");
+        assert_eq!(failure.reason().expect("reason").code, 4001);
+
+        assert!(handle.wait_connected(Duration::from_secs(5)));
+        let connects = std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(5)).ok())
+            .filter(|e| matches!(e, AgentEvent::Connected { .. }))
+            .take(2)
+            .count();
+        assert_eq!(connects, 2);
+        handle.shutdown();
+        run.join().expect("run thread").expect("clean stop");
+    }
+
+    #[test]
+    fn agent_without_reconnect_reports_connect_failure() {
+        let demo = demo_agents();
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+            listener.local_addr().expect("addr").to_string()
+        };
+        let agent = Agent::builder(demo.alice.clone())
+            .resolver(demo.resolver())
+            .connect_tcp(addr)
+            .build()
+            .expect("agent");
+        let handle = agent.handle();
+        let err = agent.run().expect_err("nothing is listening");
+        assert_eq!(err.code, 2002);
+        assert!(handle.send_text(&demo.bob.did, "hi", 1_000).is_err(), "not connected");
+        assert!(Agent::builder(demo.alice).build().is_err(), "transport is required");
+    }
+
+    #[test]
+    fn agent_keepalive_measures_rtt_and_reconnects_a_silent_peer() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+
+        let (events_tx, events) = mpsc::channel();
+        let agent = Agent::builder(demo.alice.clone())
+            .resolver(resolver.clone())
+            .connect_tcp(addr)
+            .handshake(HelloHandshake {
+                relay_did: demo.relay.did.clone(),
+                versions: vec!["1.0.0".to_string()],
+            })
+            .keepalive(KeepaliveConfig {
+                interval_ms: 100,
+                pong_timeout_ms: 100,
+                max_missed: 2,
+            })
+            .on_event(move |event| {
+                let _ = events_tx.send(event.clone());
+            })
+            .reconnect(Duration::from_millis(20), Some(3))
+            .build()
+            .expect("agent");
+        let (handle, run) = agent.spawn();
+
+        // Stand-in relay: answers the first PING, then goes silent but keeps the socket
+        // open, so only the keepalive can notice.
+        let relay_keys = demo.relay.clone();
+        let relay = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            read_frame(&mut stream).expect("hello");
+            let frame = read_frame(&mut stream).expect("ping");
+            let ping = receive_and_verify(&relay_keys, &frame, &resolver, now_ms()).expect("verify ping");
+            assert_eq!(ping.meta.typ, TYPE_PING);
+            let pong = build_pong(&relay_keys, &ping.meta, now_ms()).expect("pong");
+            write_frame(&mut stream, &pong).expect("pong");
+
+            let (mut again, _) = listener.accept().expect("reconnect after keepalive loss");
+            assert_eq!(peek_routing(&read_frame(&mut again).expect("hello")).expect("routing").typ, TYPE_HELLO);
+            (stream, again)
+        });
+
+        let lost = std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(5)).ok())
+            .find_map(|event| match event {
+                AgentEvent::KeepaliveLost(health) => Some(health),
+                _ => None,
+            })
+            .expect("keepalive loss");
+        assert_eq!(lost.pongs_received, 1);
+        assert!(lost.last_rtt_ms.is_some());
+        assert_eq!(lost.missed_pongs, 2);
+
+        let _sockets = relay.join().expect("relay");
+        assert!(handle.wait_connected(Duration::from_secs(5)));
+        handle.shutdown();
+        run.join().expect("run thread").expect("clean stop");
+    }
+
+    #[test]
+    fn agent_replays_unacked_sends_and_drops_duplicates() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+
+        let (seen_tx, seen) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let agent = Agent::builder(demo.alice.clone())
+            .resolver(resolver.clone())
+            .connect_tcp(addr)
+            .handshake(HelloHandshake {
+                relay_did: demo.relay.did.clone(),
+                versions: vec!["1.0.0".to_string()],
+            })
+            .on_message(move |_, _, body| {
+                seen_tx.send(body.msg).expect("seen");
+                Ok(())
+            })
+            .on_event(move |event| {
+                let _ = events_tx.send(event.clone());
+            })
+            .replay_unacked(true)
+            .reconnect_backoff(Backoff::exponential(Duration::from_millis(20), Duration::from_millis(200)), Some(5))
+            .build()
+            .expect("agent");
+        let (handle, run) = agent.spawn();
+
+        // First connection: the message goes out but the connection drops before any ACK.
+        let (mut stream, _) = listener.accept().expect("accept");
+        read_frame(&mut stream).expect("hello");
+        assert!(handle.wait_connected(Duration::from_secs(5)));
+        let id = handle.send_text(&demo.bob.did, "exactly once", DEFAULT_AGENT_TTL_MS).expect("send");
+        let first = receive_and_verify(&demo.bob, &read_frame(&mut stream).expect("message"), &resolver, now_ms())
+            .expect("verify");
+        assert_eq!(first.meta.id, id);
+        assert_eq!(handle.pending(), vec![id]);
+        drop(stream);
+
+        // After the reconnect handshake the same message is written again; bob's ACK
+        // settles it.
+        let (mut stream, _) = listener.accept().expect("reconnect");
+        read_frame(&mut stream).expect("hello again");
+        let replayed = peek_routing(&read_frame(&mut stream).expect("replay")).expect("routing");
+        assert_eq!(replayed.id, id);
+        let ack = build_plain_signed(
+            &demo.bob,
+            MessageMeta {
+                v: 1,
+                id: make_message_id(now_ms(), OsRng.next_u64()),
+                typ: TYPE_ACK,
+                ts_ms: now_ms(),
+                ttl_ms: DEFAULT_AGENT_TTL_MS,
+                from: String::new(),
+                to: Recipients::One(demo.alice.did.clone()),
+                reply_to: Some(id),
+                thread_id: None,
+            },
+            &AckBody {
+                ack_source: AckSource::Recipient,
+                received_at: now_ms(),
+                ack_target: None,
+            },
+        )
+        .expect("ack");
+        write_frame(&mut stream, &ack).expect("ack");
+
+        // A redelivered message is acknowledged again but handled once.
+        let wire = text_from(&demo.bob, &demo.alice.did, "redelivered", &resolver);
+        for _ in 0..2 {
+            write_frame(&mut stream, &wire).expect("deliver");
+            let reply = peek_routing(&read_frame(&mut stream).expect("ack")).expect("routing");
+            assert_eq!(reply.typ, TYPE_ACK);
+        }
+        assert_eq!(seen.recv_timeout(Duration::from_secs(5)).expect("handled"), "redelivered");
+        assert!(seen.recv_timeout(Duration::from_millis(200)).is_err(), "duplicate not handled");
+
+        // Ids are only unique per sender (§16.2), and a type nothing handles is still ACKed.
+        let reused = peek_routing(&wire).expect("routing").id;
+        let meta = |typ| MessageMeta {
+            v: 1,
+            id: reused,
+            typ,
+            ts_ms: now_ms(),
+            ttl_ms: DEFAULT_AGENT_TTL_MS,
+            from: String::new(),
+            to: Recipients::One(demo.alice.did.clone()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = TextMessageBody {
+            msg: "same id, other sender".to_string(),
+        };
+        let other = build_authcrypt_signed(&demo.relay, &demo.alice.did, meta(TYPE_MESSAGE), &body, &resolver)
+            .expect("message");
+        let unhandled = build_plain_signed(&demo.relay, meta(0x20), &body).expect("unhandled");
+        for frame in [other, unhandled] {
+            write_frame(&mut stream, &frame).expect("deliver");
+            let reply = peek_routing(&read_frame(&mut stream).expect("ack")).expect("routing");
+            assert_eq!(reply.typ, TYPE_ACK);
+        }
+        assert_eq!(seen.recv_timeout(Duration::from_secs(5)).expect("handled"), "same id, other sender");
+        assert!(handle.pending().is_empty(), "ACK settled the replayed send");
+
+        let events: Vec<AgentEvent> = events.try_iter().collect();
+        assert!(events.contains(&AgentEvent::Replayed { count: 1 }));
+        assert!(events.iter().any(|e| matches!(e, AgentEvent::Duplicate { typ: TYPE_MESSAGE, .. })));
+        handle.shutdown();
+        run.join().expect("run thread").expect("clean stop");
+    }
+
+    /// Learns a key, then blocks until released, like a handshake with a slow relay.
+    struct SlowHandshake {
+        learned: AgentKeys,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Handshake for SlowHandshake {
+        fn open(
+            &self,
+            _keys: &AgentKeys,
+            resolver: &mut DidResolver,
+            _connection: &mut Connection,
+        ) -> Result<HandshakeOutcome, AmpError> {
+            resolver.add_agent(&self.learned);
+            let _ = self.release.lock().expect("release poisoned").recv();
+            Ok(HandshakeOutcome::default())
+        }
+    }
+
+    #[test]
+    fn agent_seals_while_a_handshake_is_in_flight() {
+        let demo = demo_agents();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+        let carol = AgentKeys::from_sign_seed("did:web:example.com:agent:carol", [3; 32]);
+        let dave = AgentKeys::from_sign_seed("did:web:example.com:agent:dave", [4; 32]);
+        let (release, released) = mpsc::channel();
+        let agent = Agent::builder(demo.alice.clone())
+            .resolver(demo.resolver())
+            .connect_tcp(addr)
+            .handshake(SlowHandshake {
+                learned: carol.clone(),
+                release: Mutex::new(released),
+            })
+            .build()
+            .expect("agent");
+        let (handle, run) = agent.spawn();
+        let (_stream, _) = listener.accept().expect("accept");
+
+        // The handshake is parked on the connection; the resolver is still usable.
+        let (sealed_tx, sealed) = mpsc::channel();
+        let sealer = handle.clone();
+        let bob = demo.bob.did.clone();
+        thread::spawn(move || {
+            let body = TextMessageBody { msg: "queued".to_string() };
+            sealed_tx
+                .send(sealer.seal(&bob, TYPE_MESSAGE, &body, DEFAULT_AGENT_TTL_MS).is_ok())
+                .expect("sealed");
+        });
+        assert!(sealed.recv_timeout(Duration::from_secs(2)).expect("seal does not wait for the handshake"));
+        handle.with_resolver(|resolver| resolver.add_agent(&dave));
+
+        release.send(()).expect("release");
+        assert!(handle.wait_connected(Duration::from_secs(5)));
+        let known = handle.with_resolver(|resolver| {
+            [&carol, &dave].map(|keys| resolver.signing_key_for(&keys.did).is_some())
+        });
+        assert_eq!(known, [true, true], "learned and concurrently added keys are both kept");
+        handle.shutdown();
+        run.join().expect("run thread").expect("clean stop");
+    }
+
+    #[test]
+    fn backoff_grows_to_its_cap() {
+        let backoff = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<u128> = (1..=6).map(|n| backoff.delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1_000, 1_000]);
+        assert_eq!(Backoff::fixed(Duration::from_millis(50)).delay(9), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn cli_helpers_split_and_resolve_targets() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        assert_eq!(split_first("bob hello there"), Some(("bob", "hello there")));
+        assert_eq!(split_first("bob"), None);
+        assert_eq!(resolve_target_did(&demo, &resolver, "bob"), Ok(demo.bob.did.clone()));
+        assert!(resolve_target_did(&demo, &resolver, "did:web:example.com:agent:carol").is_err());
+        assert!(resolve_target_did(&demo, &resolver, "carol").is_err());
+    }
+}
@@ -1,55 +1,124 @@
 use std::io;
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
 
 use amp001_example::{
-    build_authcrypt_signed, build_plain_signed, demo_agents, make_message_id, now_ms, read_frame,
-    receive_and_verify, validate_ack_semantics, write_frame, AckBody, AckSource, AgentKeys, DidResolver,
-    HelloBody, MessageMeta, Recipients, TextMessageBody, TYPE_ACK, TYPE_HELLO, TYPE_MESSAGE,
+    demo_agents, hex_encode, resolve_target_did, split_first, validate_ack_semantics, AckBody,
+    Agent, AgentEvent, AgentHandle, Backoff, HelloBody, HelloHandshake, InboxQuery,
+    KeepaliveConfig, Mailbox, TextMessageBody, DEFAULT_AGENT_TTL_MS, TYPE_ACK, TYPE_HELLO,
 };
 
+const RECONNECT_INITIAL: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        return Err("usage: cargo run --bin amp-client -- <alice|bob> [server_addr]".into());
+        return Err("usage: cargo run --bin amp-client -- <alice|bob> [server_addr] [--mailbox <dir>]".into());
     }
 
     let name = args[1].as_str();
-    let server_addr = args
-        .get(2)
-        .cloned()
-        .unwrap_or_else(|| "127.0.0.1:7001".to_string());
+    let mut cursor = 2;
+    let mut server_addr = "127.0.0.1:7001".to_string();
+    if args.get(cursor).is_some_and(|arg| !arg.starts_with("--")) {
+        server_addr = args[cursor].clone();
+        cursor += 1;
+    }
+    let mailbox = match args.get(cursor).map(String::as_str) {
+        Some("--mailbox") => {
+            let dir = args.get(cursor + 1).ok_or("--mailbox requires <dir>")?;
+            Some(Mailbox::open(dir)?)
+        }
+        Some(flag) => return Err(format!("unknown flag: {}", flag).into()),
+        None => None,
+    };
 
     let demo = demo_agents();
     let me = demo
         .by_name(name)
         .ok_or("client name must be one of: alice, bob")?;
     let resolver = demo.resolver();
+    let did = me.did.clone();
 
-    let stream = TcpStream::connect(&server_addr)?;
-    stream.set_nodelay(true)?;
-
+    let mut builder = Agent::builder(me.clone());
+    if let Some(mailbox) = mailbox {
+        println!("[client:{}] mailbox at {}", name, mailbox.dir().display());
+        builder = builder.mailbox(mailbox);
+    }
+    let agent = builder
+        .resolver(resolver.clone())
+        .connect_tcp(server_addr.clone())
+        .handshake(HelloHandshake {
+            relay_did: demo.relay.did.clone(),
+            versions: vec!["0.30.0".to_string(), "1.0.0".to_string()],
+        })
+        .keepalive(KeepaliveConfig::default())
+        .reconnect_backoff(Backoff::exponential(RECONNECT_INITIAL, RECONNECT_MAX), None)
+        .replay_unacked(true)
+        .on_message(|agent, message, body| {
+            println!("\n[recv:{}] from {}: {}", agent.did(), message.meta.from, body.msg);
+            Ok(())
+        })
+        .on(TYPE_ACK, |agent, message, ack: AckBody| {
+            let expected_sender = vec![message.meta.from.clone()];
+            let checked = agent.with_resolver(|r| validate_ack_semantics(message, &expected_sender, r));
+            if let Err(err) = checked {
+                eprintln!("[recv:{}] ACK semantic check failed: {}", agent.did(), err);
+                return Ok(());
+            }
+            let reply_to = message
+                .meta
+                .reply_to
+                .map(|id| hex_encode(&id))
+                .unwrap_or_else(|| "none".to_string());
+            println!(
+                "[recv:{}] ACK from {} source={:?} reply_to={}",
+                agent.did(), message.meta.from, ack.ack_source, reply_to
+            );
+            Ok(())
+        })
+        .on(TYPE_HELLO, |agent, message, hello: HelloBody| {
+            println!(
+                "[recv:{}] HELLO from {} {:?}",
+                agent.did(), message.meta.from, hello.versions
+            );
+            Ok(())
+        })
+        .on_unhandled(|agent, message| {
+            println!(
+                "\n[recv:{}] typ=0x{:02x} from={}",
+                agent.did(), message.meta.typ, message.meta.from
+            );
+            Ok(())
+        })
+        .on_event(move |event| match event {
+            AgentEvent::Connected { .. } => {
+                println!("[client:{}] connected, registration HELLO sent", did)
+            }
+            AgentEvent::Rejected { error, .. } => {
+                eprintln!("[recv:{}] rejected frame: {}", did, error)
+            }
+            AgentEvent::HandlerFailed { typ, error, .. } => {
+                eprintln!("[recv:{}] typ=0x{:02x} handling failed: {}", did, typ, error.message)
+            }
+            AgentEvent::KeepaliveLost(health) => eprintln!(
+                "[client:{}] relay stopped answering PING (missed={}); closing",
+                did, health.missed_pongs
+            ),
+            AgentEvent::Replayed { count } => {
+                println!("[client:{}] re-sent {} unacknowledged message(s)", did, count)
+            }
+            AgentEvent::Disconnected { reason } => {
+                eprintln!("[client:{}] connection lost: {}; reconnecting", did, reason)
+            }
+            _ => {}
+        })
+        .build()?;
+    let (handle, _run) = agent.spawn();
+    if !handle.wait_connected(Duration::from_secs(5)) {
+        return Err(format!("could not connect to {server_addr}").into());
+    }
     println!("[client:{}] connected to {}", name, server_addr);
 
-    let reader = stream.try_clone()?;
-    let writer = Arc::new(Mutex::new(stream));
-    let counter = Arc::new(AtomicU64::new(1));
-
-    send_hello_registration(&me, &demo.relay.did, &writer, &counter)?;
-
-    let recv_writer = Arc::clone(&writer);
-    let recv_counter = Arc::clone(&counter);
-    let recv_resolver = resolver.clone();
-    let recv_me = me.clone();
-
-    thread::spawn(move || {
-        if let Err(err) = receiver_loop(reader, recv_writer, recv_counter, recv_me, recv_resolver) {
-            eprintln!("[client] receiver loop stopped: {err}");
-        }
-    });
-
     let default_target = if me.did.contains(":alice") {
         demo.bob.did.clone()
     } else {
@@ -58,6 +127,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("commands:");
     println!("  /send <alice|bob|did> <text>");
+    println!("  /inbox [alice|bob|did]");
     println!("  /quit");
     println!("default: type plain text to send to {}", default_target);
 
@@ -79,267 +149,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             break;
         }
 
-        if let Some(rest) = input.strip_prefix("/send ") {
-            if let Some((target_token, text)) = split_first(rest) {
-                let target_did = match resolve_target_did(&demo, &resolver, target_token) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[client:{}] {}", name, err);
-                        continue;
-                    }
-                };
-                if let Err(err) =
-                    send_text_message(&me, &resolver, &writer, &counter, &target_did, text)
-                {
-                    eprintln!("[client:{}] send failed: {}", name, err);
-                }
-            } else {
-                eprintln!("usage: /send <alice|bob|did> <text>");
-            }
+        if let Some(rest) = input.strip_prefix("/inbox") {
+            let from = Some(rest.trim()).filter(|v| !v.is_empty()).map(|v| demo.did_for_alias(v));
+            print_inbox(&handle, InboxQuery { from, ..InboxQuery::default() });
             continue;
         }
 
-        if let Err(err) =
-            send_text_message(&me, &resolver, &writer, &counter, &default_target, input)
-        {
-            eprintln!("[client:{}] send failed: {}", name, err);
-        }
-    }
-
-    Ok(())
-}
-
-fn receiver_loop(
-    mut reader: TcpStream,
-    writer: Arc<Mutex<TcpStream>>,
-    counter: Arc<AtomicU64>,
-    me: AgentKeys,
-    resolver: DidResolver,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let frame = read_frame(&mut reader)?;
-        let message = match receive_and_verify(&me, &frame, &resolver, now_ms()) {
-            Ok(msg) => msg,
-            Err(err) => {
-                eprintln!("[recv:{}] rejected frame: {}", me.did, err);
+        let (target_did, text) = if let Some(rest) = input.strip_prefix("/send ") {
+            let Some((target_token, text)) = split_first(rest) else {
+                eprintln!("usage: /send <alice|bob|did> <text>");
                 continue;
-            }
-        };
-
-        match message.meta.typ {
-            TYPE_MESSAGE => {
-                let body: TextMessageBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode message body failed: {}", me.did, err);
-                        continue;
-                    }
-                };
-                println!("\n[recv:{}] from {}: {}", me.did, message.meta.from, body.msg);
-
-                if let Err(err) = send_ack(
-                    &me,
-                    &writer,
-                    &counter,
-                    &message.meta.from,
-                    message.meta.id,
-                ) {
-                    eprintln!("[recv:{}] send ACK failed: {}", me.did, err);
+            };
+            match resolve_target_did(&demo, &resolver, target_token) {
+                Ok(v) => (v, text),
+                Err(err) => {
+                    eprintln!("[client:{}] {}", name, err);
+                    continue;
                 }
             }
-            TYPE_ACK => {
-                let ack: AckBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode ACK failed: {}", me.did, err);
-                        continue;
-                    }
-                };
+        } else {
+            (default_target.clone(), input)
+        };
 
-                let expected_sender = vec![message.meta.from.clone()];
-                if let Err(err) = validate_ack_semantics(&message, &expected_sender, &resolver) {
-                    eprintln!("[recv:{}] ACK semantic check failed: {}", me.did, err);
-                } else {
-                    let reply_to = message
-                        .meta
-                        .reply_to
-                        .map(|id| hex16(&id))
-                        .unwrap_or_else(|| "none".to_string());
-                    println!(
-                        "[recv:{}] ACK from {} source={:?} reply_to={}",
-                        me.did, message.meta.from, ack.ack_source, reply_to
-                    );
-                }
-            }
-            TYPE_HELLO => {
-                let hello: HelloBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode HELLO failed: {}", me.did, err);
-                        continue;
-                    }
-                };
-                println!(
-                    "[recv:{}] HELLO from {} {:?}",
-                    me.did, message.meta.from, hello.versions
-                );
-            }
-            other => {
-                println!("\n[recv:{}] typ=0x{:02x} from={}", me.did, other, message.meta.from);
-            }
+        match handle.send_text(&target_did, text, DEFAULT_AGENT_TTL_MS) {
+            Ok(_) => println!("[client:{}] sent encrypted MESSAGE to {}", me.did, target_did),
+            Err(err) => eprintln!("[client:{}] send failed: {}", name, err),
         }
     }
-}
-
-fn send_hello_registration(
-    me: &AgentKeys,
-    relay_did: &str,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-    let body = HelloBody {
-        versions: vec!["0.30.0".to_string(), "1.0.0".to_string()],
-    };
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_HELLO,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(relay_did.to_string()),
-        reply_to: None,
-        thread_id: None,
-    };
-
-    let wire = build_plain_signed(me, meta, &body)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
-    println!("[client:{}] registration HELLO sent", me.did);
 
+    handle.shutdown();
     Ok(())
 }
 
-fn send_text_message(
-    me: &AgentKeys,
-    resolver: &DidResolver,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-    target_did: &str,
-    text: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if resolver.key_agreement_for(target_did).is_none() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!(
-                "unknown recipient DID or missing keyAgreement key: {}",
-                target_did
-            ),
-        )
-        .into());
-    }
-
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_MESSAGE,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(target_did.to_string()),
-        reply_to: None,
-        thread_id: None,
-    };
-
-    let body = TextMessageBody {
-        msg: text.to_string(),
-    };
-
-    let wire = build_authcrypt_signed(me, target_did, meta, &body, resolver)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
-
-    println!("[client:{}] sent encrypted MESSAGE to {}", me.did, target_did);
-    Ok(())
-}
-
-fn send_ack(
-    me: &AgentKeys,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-    target_did: &str,
-    reply_to: [u8; 16],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-    let body = AckBody {
-        ack_source: AckSource::Recipient,
-        received_at: ts,
-        ack_target: None,
-    };
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_ACK,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(target_did.to_string()),
-        reply_to: Some(reply_to),
-        thread_id: None,
-    };
-
-    let wire = build_plain_signed(me, meta, &body)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
-    Ok(())
-}
-
-fn split_first(input: &str) -> Option<(&str, &str)> {
-    let mut parts = input.splitn(2, ' ');
-    let first = parts.next()?.trim();
-    let rest = parts.next()?.trim();
-    if first.is_empty() || rest.is_empty() {
-        return None;
-    }
-    Some((first, rest))
-}
-
-fn resolve_target_did(
-    demo: &amp001_example::DemoAgents,
-    resolver: &DidResolver,
-    target_token: &str,
-) -> Result<String, String> {
-    let token = target_token.trim();
-    let mapped = demo.did_for_alias(token);
-
-    if mapped.starts_with("did:") {
-        if resolver.key_agreement_for(&mapped).is_some() {
-            return Ok(mapped);
+fn print_inbox(handle: &AgentHandle, query: InboxQuery) {
+    let printed = handle.with_mailbox(|mailbox| {
+        for entry in mailbox.query(&query) {
+            let meta = &entry.message.meta;
+            let text = entry
+                .message
+                .decode_body::<TextMessageBody>()
+                .map(|body| body.msg)
+                .unwrap_or_else(|_| format!("typ=0x{:02x}", meta.typ));
+            let acked = if entry.acked_at.is_some() { "acked" } else { "unacked" };
+            println!("  [{}] {} {}: {}", meta.ts_ms, acked, meta.from, text);
         }
-        return Err(format!(
-            "target DID not available in local resolver: {} (use alice/bob or a known DID)",
-            mapped
-        ));
-    }
-
-    Err(format!(
-        "invalid target '{}': use alice, bob, relay, or a full DID",
-        token
-    ))
-}
-
-fn hex16(id: &[u8; 16]) -> String {
-    let mut out = String::with_capacity(32);
-    for b in id {
-        out.push_str(&format!("{:02x}", b));
+    });
+    if printed.is_none() {
+        eprintln!("no mailbox; start the client with --mailbox <dir>");
     }
-    out
 }
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+
+use crate::{AgentKeys, AmpError, DidResolver};
+
+pub const COSE_ALG_EDDSA: i64 = -8;
+pub const COSE_ALG_ES256: i64 = -7;
+
+const COSE_HEADER_ALG: i64 = 1;
+const COSE_HEADER_KID: i64 = 4;
+
+type CoseSign1Wire = (ByteBuf, BTreeMap<i64, Value>, ByteBuf, ByteBuf);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoseSign1 {
+    pub alg: i64,
+    pub kid: String,
+    pub payload: Vec<u8>,
+}
+
+impl CoseSign1 {
+    /// DID part of `kid` (`did:web:example.com:relay:b#k1` -> `did:web:example.com:relay:b`).
+    pub fn kid_did(&self) -> &str {
+        self.kid.split('#').next().unwrap_or_default()
+    }
+}
+
+/// Signs `payload` as an untagged COSE_Sign1 with EdDSA (`alg = -8`).
+pub fn cose_sign1_sign(signer: &AgentKeys, kid: &str, payload: &[u8]) -> Result<Vec<u8>, AmpError> {
+    if kid.is_empty() {
+        return Err(AmpError::invalid_message("COSE kid is required"));
+    }
+
+    let mut protected_map = BTreeMap::new();
+    protected_map.insert(COSE_HEADER_ALG, Value::Integer(COSE_ALG_EDDSA.into()));
+    protected_map.insert(COSE_HEADER_KID, Value::Bytes(kid.as_bytes().to_vec()));
+    let protected = serde_cbor::to_vec(&protected_map)
+        .map_err(|e| AmpError::invalid_message(format!("COSE protected header encode failed: {e}")))?;
+
+    let to_be_signed = sig_structure(&protected, payload)?;
+    let sig = signer.signing_key.sign(&to_be_signed).to_bytes().to_vec();
+
+    let wire: CoseSign1Wire = (
+        ByteBuf::from(protected),
+        BTreeMap::new(),
+        ByteBuf::from(payload.to_vec()),
+        ByteBuf::from(sig),
+    );
+    serde_cbor::to_vec(&wire)
+        .map_err(|e| AmpError::invalid_message(format!("COSE_Sign1 encode failed: {e}")))
+}
+
+/// Decodes a COSE_Sign1 without checking its signature.
+pub fn cose_sign1_peek(bytes: &[u8]) -> Result<CoseSign1, AmpError> {
+    decode(bytes).map(|(signed, _, _)| signed)
+}
+
+/// Decodes a COSE_Sign1 and verifies it against the `assertionMethod` key that `kid`
+/// resolves to. Only EdDSA is implemented; any other `alg` is rejected with `3001`.
+pub fn cose_sign1_verify(bytes: &[u8], resolver: &DidResolver) -> Result<CoseSign1, AmpError> {
+    let (signed, protected, sig) = decode(bytes)?;
+    if signed.alg != COSE_ALG_EDDSA {
+        return Err(AmpError::unauthorized(format!(
+            "unsupported COSE alg {}",
+            signed.alg
+        )));
+    }
+
+    let verifying_key = resolver
+        .assertion_method_for(&signed.kid)
+        .ok_or_else(|| AmpError::unauthorized(format!("kid {} is not an assertionMethod", signed.kid)))?;
+    if sig.len() != 64 {
+        return Err(AmpError::unauthorized("ed25519 signature must be 64 bytes"));
+    }
+    let signature = Signature::from_slice(&sig)
+        .map_err(|e| AmpError::unauthorized(format!("invalid COSE signature bytes: {e}")))?;
+
+    let to_be_signed = sig_structure(&protected, &signed.payload)?;
+    verifying_key
+        .verify(&to_be_signed, &signature)
+        .map_err(|_| AmpError::unauthorized("COSE signature verification failed"))?;
+    Ok(signed)
+}
+
+fn decode(bytes: &[u8]) -> Result<(CoseSign1, Vec<u8>, Vec<u8>), AmpError> {
+    let (protected, _unprotected, payload, sig): CoseSign1Wire = serde_cbor::from_slice(bytes)
+        .map_err(|e| AmpError::invalid_message(format!("invalid COSE_Sign1: {e}")))?;
+    let headers: BTreeMap<i64, Value> = serde_cbor::from_slice(protected.as_ref())
+        .map_err(|e| AmpError::invalid_message(format!("invalid COSE protected header: {e}")))?;
+
+    let alg = match headers.get(&COSE_HEADER_ALG) {
+        Some(Value::Integer(alg)) => i64::try_from(*alg)
+            .map_err(|_| AmpError::invalid_message("COSE alg out of range"))?,
+        _ => return Err(AmpError::unauthorized("COSE protected header alg is required")),
+    };
+    let kid = match headers.get(&COSE_HEADER_KID) {
+        Some(Value::Bytes(kid)) => String::from_utf8(kid.clone())
+            .map_err(|_| AmpError::invalid_message("COSE kid must be UTF-8"))?,
+        _ => return Err(AmpError::unauthorized("COSE protected header kid is required")),
+    };
+
+    Ok((
+        CoseSign1 {
+            alg,
+            kid,
+            payload: payload.into_vec(),
+        },
+        protected.into_vec(),
+        sig.into_vec(),
+    ))
+}
+
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>, AmpError> {
+    let structure = (
+        "Signature1",
+        ByteBuf::from(protected.to_vec()),
+        ByteBuf::new(),
+        ByteBuf::from(payload.to_vec()),
+    );
+    serde_cbor::to_vec(&structure)
+        .map_err(|e| AmpError::invalid_message(format!("COSE Sig_structure encode failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (AgentKeys, AgentKeys, DidResolver) {
+        let relay = AgentKeys::from_sign_seed("did:web:example.com:relay:b", [21_u8; 32]);
+        let other = AgentKeys::from_sign_seed("did:web:example.com:relay:c", [22_u8; 32]);
+        let mut resolver = DidResolver::default();
+        resolver.add_agent(&relay);
+        resolver.add_agent(&other);
+        (relay, other, resolver)
+    }
+
+    #[test]
+    fn cose_sign1_roundtrip() {
+        let (relay, _, resolver) = setup();
+        let kid = relay.assertion_kid();
+        let bytes = cose_sign1_sign(&relay, &kid, b"payload").expect("sign");
+
+        let verified = cose_sign1_verify(&bytes, &resolver).expect("verify");
+        assert_eq!(verified.alg, COSE_ALG_EDDSA);
+        assert_eq!(verified.kid, kid);
+        assert_eq!(verified.kid_did(), relay.did);
+        assert_eq!(verified.payload, b"payload");
+    }
+
+    #[test]
+    fn cose_sign1_rejects_forgery() {
+        let (relay, other, resolver) = setup();
+
+        let mut tampered = cose_sign1_sign(&relay, &relay.assertion_kid(), b"payload").expect("sign");
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        let err = cose_sign1_verify(&tampered, &resolver).expect_err("flipped signature bit");
+        assert_eq!(err.code, 3001);
+
+        let impersonated = cose_sign1_sign(&other, &relay.assertion_kid(), b"payload").expect("sign");
+        let err = cose_sign1_verify(&impersonated, &resolver).expect_err("wrong key for kid");
+        assert_eq!(err.code, 3001);
+
+        let unknown = cose_sign1_sign(&relay, "did:web:example.com:relay:x#k1", b"payload").expect("sign");
+        let err = cose_sign1_verify(&unknown, &resolver).expect_err("unresolvable kid");
+        assert_eq!(err.code, 3001);
+
+        let err = cose_sign1_verify(b"\x01\x02", &resolver).expect_err("not COSE");
+        assert_eq!(err.code, 1001);
+    }
+}
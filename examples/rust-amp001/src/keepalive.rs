@@ -0,0 +1,303 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::OsRng;
+
+use crate::{
+    build_plain_signed, make_message_id, now_ms, AgentKeys, AmpError, MessageMeta, Recipients,
+    TYPE_PING, TYPE_PONG,
+};
+
+pub const DEFAULT_PING_INTERVAL_MS: u64 = 15_000;
+/// RFC 001 §16.4 suggested PING/PONG timeout.
+pub const DEFAULT_PONG_TIMEOUT_MS: u64 = 5_000;
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// Idle time after the last inbound frame (or PING) before the next `PING`.
+    pub interval_ms: u64,
+    pub pong_timeout_ms: u64,
+    /// Consecutive unanswered `PING`s after which the connection is considered dead.
+    pub max_missed: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: DEFAULT_PING_INTERVAL_MS,
+            pong_timeout_ms: DEFAULT_PONG_TIMEOUT_MS,
+            max_missed: DEFAULT_MAX_MISSED_PONGS,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// How often a driver should call [`Keepalive::tick`] to honour both timers.
+    pub fn tick_interval(&self) -> Duration {
+        let finest = self.interval_ms.min(self.pong_timeout_ms) / 5;
+        Duration::from_millis(finest.clamp(10, 1_000))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveAction {
+    Wait,
+    /// Send a `PING` now and report its id with [`Keepalive::ping_sent`].
+    Ping,
+    /// `max_missed` `PING`s in a row went unanswered; close the connection.
+    Dead,
+}
+
+/// Connection health as measured by the keepalive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionHealth {
+    pub last_rtt_ms: Option<u64>,
+    /// Exponentially smoothed RTT (7/8 old + 1/8 new), as TCP does.
+    pub smoothed_rtt_ms: Option<u64>,
+    pub pings_sent: u64,
+    pub pongs_received: u64,
+    /// Unanswered `PING`s since the last sign of life.
+    pub missed_pongs: u32,
+    pub last_seen_at: u64,
+}
+
+/// Keepalive state for one connection, driven by the caller's clock: [`Keepalive::tick`]
+/// says when to `PING` and when to give up, inbound traffic and `PONG`s reset it.
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    config: KeepaliveConfig,
+    outstanding: Option<([u8; 16], u64)>,
+    last_ping_at: u64,
+    health: ConnectionHealth,
+}
+
+impl Keepalive {
+    pub fn new(config: KeepaliveConfig, now_ms: u64) -> Self {
+        Self {
+            config,
+            outstanding: None,
+            last_ping_at: 0,
+            health: ConnectionHealth {
+                last_seen_at: now_ms,
+                ..ConnectionHealth::default()
+            },
+        }
+    }
+
+    pub fn config(&self) -> &KeepaliveConfig {
+        &self.config
+    }
+
+    pub fn health(&self) -> &ConnectionHealth {
+        &self.health
+    }
+
+    /// Any inbound frame proves the peer is alive and postpones the next `PING`.
+    pub fn observe_inbound(&mut self, now_ms: u64) {
+        self.health.last_seen_at = self.health.last_seen_at.max(now_ms);
+        self.health.missed_pongs = 0;
+    }
+
+    pub fn tick(&mut self, now_ms: u64) -> KeepaliveAction {
+        if let Some((_, sent_at)) = self.outstanding {
+            if now_ms < sent_at.saturating_add(self.config.pong_timeout_ms) {
+                return KeepaliveAction::Wait;
+            }
+            self.outstanding = None;
+            if self.health.last_seen_at < sent_at {
+                self.health.missed_pongs += 1;
+            }
+        }
+        if self.health.missed_pongs >= self.config.max_missed {
+            return KeepaliveAction::Dead;
+        }
+        let idle_since = self.health.last_seen_at.max(self.last_ping_at);
+        if now_ms >= idle_since.saturating_add(self.config.interval_ms) || self.health.missed_pongs > 0 {
+            return KeepaliveAction::Ping;
+        }
+        KeepaliveAction::Wait
+    }
+
+    pub fn ping_sent(&mut self, ping_id: [u8; 16], now_ms: u64) {
+        self.outstanding = Some((ping_id, now_ms));
+        self.last_ping_at = now_ms;
+        self.health.pings_sent += 1;
+    }
+
+    /// Records a `PONG` answering `reply_to`; returns the RTT when it answers the
+    /// outstanding `PING`.
+    pub fn pong_received(&mut self, reply_to: &[u8; 16], now_ms: u64) -> Option<u64> {
+        self.observe_inbound(now_ms);
+        let (ping_id, sent_at) = self.outstanding?;
+        if ping_id != *reply_to {
+            return None;
+        }
+        self.outstanding = None;
+        let rtt = now_ms.saturating_sub(sent_at);
+        self.health.pongs_received += 1;
+        self.health.last_rtt_ms = Some(rtt);
+        self.health.smoothed_rtt_ms = Some(match self.health.smoothed_rtt_ms {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        Some(rtt)
+    }
+}
+
+/// Signed `PING` (body CBOR null) to `to`, valid for one PONG timeout.
+pub fn build_ping(
+    keys: &AgentKeys,
+    to: &str,
+    ttl_ms: u64,
+    now_ms: u64,
+) -> Result<([u8; 16], Vec<u8>), AmpError> {
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ: TYPE_PING,
+        ts_ms: now_ms,
+        ttl_ms,
+        from: String::new(),
+        to: Recipients::One(to.to_string()),
+        reply_to: None,
+        thread_id: None,
+    };
+    let id = meta.id;
+    Ok((id, build_plain_signed(keys, meta, &())?))
+}
+
+/// Signed `PONG` answering `ping`, with `reply_to` set to the `PING` id.
+pub fn build_pong(keys: &AgentKeys, ping: &MessageMeta, now_ms: u64) -> Result<Vec<u8>, AmpError> {
+    if ping.typ != TYPE_PING {
+        return Err(AmpError::invalid_message("PONG answers a PING only"));
+    }
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ: TYPE_PONG,
+        ts_ms: now_ms,
+        ttl_ms: ping.ttl_ms,
+        from: String::new(),
+        to: Recipients::One(ping.from.clone()),
+        reply_to: Some(ping.id),
+        thread_id: None,
+    };
+    build_plain_signed(keys, meta, &())
+}
+
+/// Drives `keepalive` on its own thread until `stop` is set: `ping` sends one `PING` and
+/// returns its id, `dead` runs once when too many `PONG`s were missed. A failed `PING`
+/// write also ends the thread; the connection's reader will see the same failure.
+pub fn spawn_keepalive<P, D>(
+    keepalive: Arc<Mutex<Keepalive>>,
+    stop: Arc<AtomicBool>,
+    mut ping: P,
+    dead: D,
+) -> JoinHandle<()>
+where
+    P: FnMut(u64) -> Result<[u8; 16], AmpError> + Send + 'static,
+    D: FnOnce(&ConnectionHealth) + Send + 'static,
+{
+    let tick = keepalive.lock().expect("keepalive poisoned").config().tick_interval();
+    thread::spawn(move || loop {
+        thread::sleep(tick);
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        let now = now_ms();
+        let action = keepalive.lock().expect("keepalive poisoned").tick(now);
+        match action {
+            KeepaliveAction::Wait => {}
+            KeepaliveAction::Ping => match ping(now) {
+                Ok(id) => keepalive.lock().expect("keepalive poisoned").ping_sent(id, now),
+                Err(_) => return,
+            },
+            KeepaliveAction::Dead => {
+                let health = keepalive.lock().expect("keepalive poisoned").health().clone();
+                dead(&health);
+                return;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{demo_agents, receive_and_verify};
+
+    const T0: u64 = 1_707_055_200_000;
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig {
+            interval_ms: 1_000,
+            pong_timeout_ms: 300,
+            max_missed: 2,
+        }
+    }
+
+    #[test]
+    fn keepalive_pings_when_idle_and_measures_rtt() {
+        let mut keepalive = Keepalive::new(config(), T0);
+        assert_eq!(keepalive.tick(T0 + 999), KeepaliveAction::Wait);
+        keepalive.observe_inbound(T0 + 500);
+        assert_eq!(keepalive.tick(T0 + 1_000), KeepaliveAction::Wait, "traffic postpones the PING");
+        assert_eq!(keepalive.tick(T0 + 1_500), KeepaliveAction::Ping);
+
+        keepalive.ping_sent([1; 16], T0 + 1_500);
+        assert_eq!(keepalive.tick(T0 + 1_700), KeepaliveAction::Wait, "PONG still due");
+        assert_eq!(keepalive.pong_received(&[9; 16], T0 + 1_540), None, "answers another PING");
+        assert_eq!(keepalive.pong_received(&[1; 16], T0 + 1_580), Some(80));
+        keepalive.ping_sent([2; 16], T0 + 2_580);
+        assert_eq!(keepalive.pong_received(&[2; 16], T0 + 2_740), Some(160));
+
+        let health = keepalive.health();
+        assert_eq!((health.pings_sent, health.pongs_received), (2, 2));
+        assert_eq!(health.last_rtt_ms, Some(160));
+        assert_eq!(health.smoothed_rtt_ms, Some(90));
+        assert_eq!(health.missed_pongs, 0);
+    }
+
+    #[test]
+    fn keepalive_declares_dead_after_missed_pongs() {
+        let mut keepalive = Keepalive::new(config(), T0);
+        assert_eq!(keepalive.tick(T0 + 1_000), KeepaliveAction::Ping);
+        keepalive.ping_sent([1; 16], T0 + 1_000);
+        assert_eq!(keepalive.tick(T0 + 1_300), KeepaliveAction::Ping, "missed PONGs retry at once");
+        assert_eq!(keepalive.health().missed_pongs, 1);
+
+        keepalive.ping_sent([2; 16], T0 + 1_300);
+        keepalive.observe_inbound(T0 + 1_400);
+        assert_eq!(keepalive.tick(T0 + 1_600), KeepaliveAction::Wait, "any frame counts as life");
+        assert_eq!(keepalive.health().missed_pongs, 0);
+
+        assert_eq!(keepalive.tick(T0 + 2_400), KeepaliveAction::Ping);
+        keepalive.ping_sent([3; 16], T0 + 2_400);
+        assert_eq!(keepalive.tick(T0 + 2_700), KeepaliveAction::Ping);
+        keepalive.ping_sent([4; 16], T0 + 2_700);
+        assert_eq!(keepalive.tick(T0 + 3_000), KeepaliveAction::Dead);
+        assert_eq!(keepalive.health().missed_pongs, 2);
+    }
+
+    #[test]
+    fn ping_and_pong_are_signed_null_bodies_correlated_by_reply_to() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let now = now_ms();
+        let (id, ping) = build_ping(&demo.alice, &demo.relay.did, 5_000, now).expect("ping");
+        let received = receive_and_verify(&demo.relay, &ping, &resolver, now).expect("verify ping");
+        assert_eq!((received.meta.typ, received.meta.id), (TYPE_PING, id));
+
+        let pong = build_pong(&demo.relay, &received.meta, now).expect("pong");
+        let answered = receive_and_verify(&demo.alice, &pong, &resolver, now).expect("verify pong");
+        assert_eq!(answered.meta.typ, TYPE_PONG);
+        assert_eq!(answered.meta.reply_to, Some(id));
+        assert_eq!(answered.meta.from, demo.relay.did);
+
+        assert_eq!(build_pong(&demo.alice, &answered.meta, now).expect_err("PONG to PONG").code, 1001);
+    }
+}
@@ -3,6 +3,7 @@ use std::fmt;
 use std::io::{self, Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crypto_box::aead::rand_core::RngCore;
 use crypto_box::aead::{Aead, AeadCore, OsRng};
 use crypto_box::{PublicKey as X25519PublicKey, SalsaBox, SecretKey as X25519SecretKey};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
@@ -11,18 +12,51 @@ use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use serde_cbor::Value;
 
+mod agent;
+mod cose;
+mod keepalive;
+mod mailbox;
+mod relay;
+mod rpc;
+mod semver;
+
+pub use agent::{
+    resolve_target_did, split_first, Agent, AgentBuilder, AgentEvent, AgentHandle, Backoff,
+    Connection, Handshake, HandlerResult, HandshakeOutcome, HelloHandshake, DEFAULT_AGENT_TTL_MS,
+};
+
+pub use cose::{
+    cose_sign1_peek, cose_sign1_sign, cose_sign1_verify, CoseSign1, COSE_ALG_EDDSA,
+    COSE_ALG_ES256,
+};
+pub use keepalive::{
+    build_ping, build_pong, spawn_keepalive, ConnectionHealth, Keepalive, KeepaliveAction,
+    KeepaliveConfig, DEFAULT_MAX_MISSED_PONGS, DEFAULT_PING_INTERVAL_MS, DEFAULT_PONG_TIMEOUT_MS,
+};
+pub use mailbox::{InboxEntry, InboxKey, InboxQuery, Mailbox, MailboxError, OutboxEntry};
+pub use relay::{run_relay_server, RelayEvent, RelayServerConfig};
+pub use rpc::{
+    RequestBody, ResponseBody, RpcCall, RpcClient, RpcError, RpcRouter, TYPE_REQUEST,
+    TYPE_RESPONSE,
+};
+pub use semver::{Comparator, Identifier, Op, SemverError, Version, VersionRange};
+
 pub const MAX_CLOCK_SKEW_MS: u64 = 30_000;
 pub const MAX_ID_TIMESTAMP_DELTA_MS: u64 = 1_000;
 
 pub const TYPE_PING: u8 = 0x01;
 pub const TYPE_PONG: u8 = 0x02;
 pub const TYPE_ACK: u8 = 0x03;
+pub const TYPE_PROC_OK: u8 = 0x04;
+pub const TYPE_PROC_FAIL: u8 = 0x05;
+pub const TYPE_ERROR: u8 = 0x0F;
 pub const TYPE_MESSAGE: u8 = 0x10;
 pub const TYPE_HELLO: u8 = 0x70;
 pub const TYPE_HELLO_ACK: u8 = 0x71;
 pub const TYPE_HELLO_REJECT: u8 = 0x72;
 pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
 pub const TRANSPORT_WRAPPER_VERSION_V1: u64 = 1;
+pub const DEFAULT_ASSERTION_KEY_FRAGMENT: &str = "k1";
 
 pub fn now_ms() -> u64 {
     SystemTime::now()
@@ -71,6 +105,14 @@ impl AmpError {
         }
     }
 
+    pub fn endpoint_unreachable(detail: impl Into<String>) -> Self {
+        Self {
+            code: 2002,
+            name: "ENDPOINT_UNREACHABLE",
+            detail: detail.into(),
+        }
+    }
+
     pub fn unauthorized(detail: impl Into<String>) -> Self {
         Self {
             code: 3001,
@@ -78,6 +120,82 @@ impl AmpError {
             detail: detail.into(),
         }
     }
+
+    pub fn delegation_invalid(detail: impl Into<String>) -> Self {
+        Self {
+            code: 3004,
+            name: "DELEGATION_INVALID",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn bad_request(detail: impl Into<String>) -> Self {
+        Self {
+            code: 4001,
+            name: "BAD_REQUEST",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn capability_not_found(detail: impl Into<String>) -> Self {
+        Self {
+            code: 4002,
+            name: "CAPABILITY_NOT_FOUND",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn version_mismatch(detail: impl Into<String>) -> Self {
+        Self {
+            code: 4003,
+            name: "VERSION_MISMATCH",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn schema_violation(detail: impl Into<String>) -> Self {
+        Self {
+            code: 4004,
+            name: "SCHEMA_VIOLATION",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn internal_error(detail: impl Into<String>) -> Self {
+        Self {
+            code: 5001,
+            name: "INTERNAL_ERROR",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn unavailable(detail: impl Into<String>) -> Self {
+        Self {
+            code: 5002,
+            name: "UNAVAILABLE",
+            detail: detail.into(),
+        }
+    }
+
+    pub fn timeout(detail: impl Into<String>) -> Self {
+        Self {
+            code: 5003,
+            name: "TIMEOUT",
+            detail: detail.into(),
+        }
+    }
+}
+
+impl From<MailboxError> for AmpError {
+    fn from(err: MailboxError) -> Self {
+        AmpError::internal_error(err.to_string())
+    }
+}
+
+impl From<SemverError> for AmpError {
+    fn from(err: SemverError) -> Self {
+        AmpError::invalid_message(err.to_string())
+    }
 }
 
 impl fmt::Display for AmpError {
@@ -120,12 +238,18 @@ impl AgentKeys {
     pub fn from_sign_seed(did: impl Into<String>, sign_seed: [u8; 32]) -> Self {
         Self::from_seeds(did, sign_seed, sign_seed)
     }
+
+    /// Verification method id under which the signing key is published as `assertionMethod`.
+    pub fn assertion_kid(&self) -> String {
+        format!("{}#{}", self.did, DEFAULT_ASSERTION_KEY_FRAGMENT)
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct DidResolver {
     signing: HashMap<String, VerifyingKey>,
     key_agreement: HashMap<String, X25519PublicKey>,
+    assertion_methods: HashMap<String, VerifyingKey>,
     trusted_relays: HashSet<String>,
 }
 
@@ -135,6 +259,24 @@ impl DidResolver {
             .insert(agent.did.clone(), agent.signing_public_key);
         self.key_agreement
             .insert(agent.did.clone(), agent.key_agreement_public.clone());
+        self.assertion_methods
+            .insert(agent.assertion_kid(), agent.signing_public_key);
+    }
+
+    /// Publishes a DID known only by its raw Ed25519 public key: the key verifies the DID's
+    /// signatures and is its default `assertionMethod`.
+    pub fn add_signing_key(&mut self, did: impl Into<String>, public_key: &[u8; 32]) -> Result<(), AmpError> {
+        let key = VerifyingKey::from_bytes(public_key)
+            .map_err(|e| AmpError::invalid_message(format!("invalid Ed25519 public key: {e}")))?;
+        let did = did.into();
+        self.assertion_methods
+            .insert(format!("{did}#{DEFAULT_ASSERTION_KEY_FRAGMENT}"), key);
+        self.signing.insert(did, key);
+        Ok(())
+    }
+
+    pub fn add_assertion_method(&mut self, kid: impl Into<String>, key: VerifyingKey) {
+        self.assertion_methods.insert(kid.into(), key);
     }
 
     pub fn add_trusted_relay(&mut self, relay_did: impl Into<String>) {
@@ -149,9 +291,35 @@ impl DidResolver {
         self.key_agreement.get(did).cloned()
     }
 
+    pub fn assertion_method_for(&self, kid: &str) -> Option<VerifyingKey> {
+        self.assertion_methods.get(kid).cloned()
+    }
+
     pub fn is_trusted_relay(&self, did: &str) -> bool {
         self.trusted_relays.contains(did)
     }
+
+    /// Copies in what `after` learned relative to its snapshot `before`, leaving entries
+    /// added here in the meantime alone.
+    pub fn merge_changes(&mut self, before: &DidResolver, after: &DidResolver) {
+        fn changed<K, V>(before: &HashMap<K, V>, after: &HashMap<K, V>) -> Vec<(K, V)>
+        where
+            K: Clone + Eq + std::hash::Hash,
+            V: Clone + PartialEq,
+        {
+            after
+                .iter()
+                .filter(|(k, v)| before.get(*k) != Some(*v))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }
+        self.signing.extend(changed(&before.signing, &after.signing));
+        self.key_agreement.extend(changed(&before.key_agreement, &after.key_agreement));
+        self.assertion_methods
+            .extend(changed(&before.assertion_methods, &after.assertion_methods));
+        self.trusted_relays
+            .extend(after.trusted_relays.difference(&before.trusted_relays).cloned());
+    }
 }
 
 pub const DEMO_ALICE_DID: &str = "did:web:example.com:agent:alice";
@@ -229,6 +397,10 @@ impl Recipients {
             Recipients::Many(vs) => vs.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -254,6 +426,8 @@ impl MessageMeta {
 pub struct RoutingEnvelope {
     pub id: [u8; 16],
     pub typ: u8,
+    pub ts_ms: u64,
+    pub ttl_ms: u64,
     pub from: String,
     pub to: Vec<String>,
     pub reply_to: Option<[u8; 16]>,
@@ -341,6 +515,97 @@ pub struct HelloBody {
     pub versions: Vec<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HelloAckBody {
+    pub selected: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub code: u16,
+    pub category: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<bool>,
+}
+
+impl ErrorBody {
+    /// ERROR body for `code` with the §15.2 category of its range and the §15.3 retry hint.
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            category: error_category(code).to_string(),
+            message: message.into(),
+            retry: Some(matches!(code, 2001..=2003 | 3005 | 5001..=5004)),
+        }
+    }
+}
+
+impl From<AmpError> for ErrorBody {
+    fn from(err: AmpError) -> Self {
+        Self::new(err.code, err.detail)
+    }
+}
+
+/// `proc-ok-body`: the recipient finished handling the message it replies to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProcOkBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ProcOkBody {
+    pub fn with_details<T: Serialize>(details: &T) -> Result<Self, AmpError> {
+        let value = serde_cbor::value::to_value(details)
+            .map_err(|e| AmpError::invalid_message(format!("PROC_OK details encode failed: {e}")))?;
+        Ok(Self {
+            details: (value != Value::Null).then_some(value),
+        })
+    }
+}
+
+/// `proc-fail-body`: the recipient tried and failed. `error` is `any` on the wire; when it is
+/// an `error-body` map it carries an §15.3 reason code.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProcFailBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+impl ProcFailBody {
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
+        Self::from_error(&ErrorBody::new(code, message))
+    }
+
+    pub fn from_error(error: &ErrorBody) -> Self {
+        Self {
+            error: serde_cbor::value::to_value(error).ok(),
+        }
+    }
+
+    /// The failure reason when `error` has the `error-body` shape.
+    pub fn reason(&self) -> Option<ErrorBody> {
+        match &self.error {
+            Some(value @ Value::Map(_)) => serde_cbor::value::from_value(value.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), AmpError> {
+        match self.reason() {
+            Some(error) => validate_error_reason(&error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A validated processing result (§16.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcOutcome {
+    Ok(ProcOkBody),
+    Fail(ProcFailBody),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TextMessageBody {
     pub msg: String,
@@ -372,6 +637,13 @@ pub struct RelayForward {
     pub transfer_mode: TransferMode,
 }
 
+/// RFC 002 §6.6 `relay-commit-report` wrapper.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RelayCommitReport {
+    pub commit_v: u64,
+    pub commit_receipt: ByteBuf,
+}
+
 pub fn make_message_id(ts_ms: u64, random_tail: u64) -> [u8; 16] {
     let mut id = [0_u8; 16];
     id[..8].copy_from_slice(&ts_ms.to_be_bytes());
@@ -393,9 +665,10 @@ pub fn build_plain_signed<T: Serialize>(
     meta.from = sender.did.clone();
     validate_meta(&meta, meta.ts_ms)?;
 
-    let body_bytes = to_cbor_deterministic(body)?;
-    let body_value: Value = serde_cbor::from_slice(&body_bytes)
+    let body_value: Value = serde_cbor::from_slice(&to_cbor_deterministic(body)?)
         .map_err(|e| AmpError::invalid_message(format!("body -> value failed: {e}")))?;
+    // Sign the canonical map order the receiver re-encodes, not the struct field order.
+    let body_bytes = to_cbor_deterministic(&body_value)?;
     let sig_input = sig_input_bytes(&meta, &body_bytes)?;
     let sig = sender.signing_key.sign(&sig_input).to_bytes().to_vec();
 
@@ -557,15 +830,139 @@ pub fn validate_ack_semantics(
     Ok(())
 }
 
+/// §16.1 checks for a `PROC_OK`/`PROC_FAIL` answering `original_id`: it must reply to that
+/// message, come from one of its recipients, and any failure must carry a well-formed
+/// reason code.
+pub fn validate_proc_semantics(
+    proc: &ReceivedMessage,
+    original_id: &[u8; 16],
+    original_to: &[String],
+) -> Result<ProcOutcome, AmpError> {
+    if !matches!(proc.meta.typ, TYPE_PROC_OK | TYPE_PROC_FAIL) {
+        return Err(AmpError::invalid_message("message typ is not PROC_OK/PROC_FAIL"));
+    }
+    if proc.meta.reply_to.as_ref() != Some(original_id) {
+        return Err(AmpError::invalid_message(
+            "PROC_OK/PROC_FAIL reply_to must be the processed message id",
+        ));
+    }
+    if !original_to.iter().any(|did| did == &proc.meta.from) {
+        return Err(AmpError::invalid_message(
+            "PROC_OK/PROC_FAIL requires from in original to",
+        ));
+    }
+
+    if proc.meta.typ == TYPE_PROC_OK {
+        return Ok(ProcOutcome::Ok(proc.decode_body()?));
+    }
+    let body: ProcFailBody = proc.decode_body()?;
+    body.validate()?;
+    Ok(ProcOutcome::Fail(body))
+}
+
+/// `PROC_OK` (`Ok`) or `PROC_FAIL` (`Err`) from `recipient`, replying to `original` in its
+/// thread with the original TTL.
+pub fn build_proc_result(
+    recipient: &AgentKeys,
+    original: &MessageMeta,
+    outcome: &Result<ProcOkBody, ProcFailBody>,
+    now_ms: u64,
+) -> Result<Vec<u8>, AmpError> {
+    let typ = if outcome.is_ok() { TYPE_PROC_OK } else { TYPE_PROC_FAIL };
+    let meta = MessageMeta {
+        v: original.v,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ,
+        ts_ms: now_ms,
+        ttl_ms: original.ttl_ms,
+        from: String::new(),
+        to: Recipients::One(original.from.clone()),
+        reply_to: Some(original.id),
+        thread_id: original.thread_id.clone(),
+    };
+    match outcome {
+        Ok(body) => build_plain_signed(recipient, meta, body),
+        Err(body) => {
+            body.validate()?;
+            build_plain_signed(recipient, meta, body)
+        }
+    }
+}
+
+/// Runs `handler` on a received message and builds the matching processing result:
+/// `Ok(details)` becomes `PROC_OK`, `Err(error)` becomes `PROC_FAIL` carrying that reason.
+pub fn process_and_respond<T, F>(
+    recipient: &AgentKeys,
+    message: &ReceivedMessage,
+    now_ms: u64,
+    handler: F,
+) -> Result<Vec<u8>, AmpError>
+where
+    T: Serialize,
+    F: FnOnce(&ReceivedMessage) -> Result<T, ErrorBody>,
+{
+    let outcome = match handler(message) {
+        Ok(details) => Ok(ProcOkBody::with_details(&details)?),
+        Err(error) => Err(ProcFailBody::from_error(&error)),
+    };
+    build_proc_result(recipient, &message.meta, &outcome, now_ms)
+}
+
+fn validate_error_reason(error: &ErrorBody) -> Result<(), AmpError> {
+    if !(1000..=5999).contains(&error.code) {
+        return Err(AmpError::invalid_message(format!(
+            "failure reason code {} is outside 1xxx-5xxx",
+            error.code
+        )));
+    }
+    if error.category != error_category(error.code) {
+        return Err(AmpError::invalid_message(format!(
+            "failure category {} does not match code {}",
+            error.category, error.code
+        )));
+    }
+    if error.message.is_empty() {
+        return Err(AmpError::invalid_message("failure message must not be empty"));
+    }
+    Ok(())
+}
+
+pub fn error_category(code: u16) -> &'static str {
+    match code / 1000 {
+        1 => "protocol",
+        2 => "routing",
+        3 => "security",
+        4 => "client",
+        _ => "server",
+    }
+}
+
+/// `HELLO` version selection (RFC 001 §13.1): the first local version, in preference
+/// order, that the peer offers. A release accepts any peer release in
+/// `>=major.0.0 <major+1.0.0`; a pre-release only the same pre-release. Unparseable
+/// entries are ignored.
 pub fn select_compatible_version(local_supported: &[String], peer_versions: &[String]) -> Option<String> {
-    let peer_majors: HashSet<u64> = peer_versions
+    let peer: Vec<Version> = peer_versions
         .iter()
-        .filter_map(|v| major_of_semver(v))
+        .filter_map(|v| Version::parse_loose(v).ok())
         .collect();
+    let accepts = |local: &Version| {
+        if local.is_prerelease() {
+            return Some(VersionRange::exact(local.clone()));
+        }
+        let range = match local.major.checked_add(1) {
+            Some(next) => format!(">={}.0.0 <{next}.0.0", local.major),
+            None => format!(">={}.0.0", local.major),
+        };
+        VersionRange::parse(&range).ok()
+    };
 
     local_supported
         .iter()
-        .find(|v| major_of_semver(v).is_some_and(|m| peer_majors.contains(&m)))
+        .find(|raw| {
+            let range = Version::parse_loose(raw).ok().and_then(|local| accepts(&local));
+            range.is_some_and(|range| peer.iter().any(|p| range.matches(p)))
+        })
         .cloned()
 }
 
@@ -587,6 +984,16 @@ pub fn hex_encode(bytes: &[u8]) -> String {
     out
 }
 
+pub fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
     if payload.len() > MAX_FRAME_SIZE {
         return Err(io::Error::new(
@@ -626,6 +1033,8 @@ pub fn peek_routing(wire_bytes: &[u8]) -> Result<RoutingEnvelope, AmpError> {
             Ok(RoutingEnvelope {
                 id: meta.id,
                 typ: meta.typ,
+                ts_ms: meta.ts_ms,
+                ttl_ms: meta.ttl_ms,
                 from: meta.from,
                 to: meta.to.as_vec(),
                 reply_to: meta.reply_to,
@@ -636,6 +1045,8 @@ pub fn peek_routing(wire_bytes: &[u8]) -> Result<RoutingEnvelope, AmpError> {
             Ok(RoutingEnvelope {
                 id: meta.id,
                 typ: meta.typ,
+                ts_ms: meta.ts_ms,
+                ttl_ms: meta.ttl_ms,
                 from: meta.from,
                 to: meta.to.as_vec(),
                 reply_to: meta.reply_to,
@@ -710,6 +1121,25 @@ pub fn decode_relay_forward(bytes: &[u8]) -> Result<RelayForward, AmpError> {
     Ok(wrapper)
 }
 
+pub fn decode_relay_commit_report(bytes: &[u8]) -> Result<RelayCommitReport, AmpError> {
+    let report: RelayCommitReport = serde_cbor::from_slice(bytes)
+        .map_err(|e| AmpError::invalid_message(format!("invalid relay-commit wrapper: {e}")))?;
+
+    if report.commit_v != TRANSPORT_WRAPPER_VERSION_V1 {
+        return Err(AmpError::unsupported_version(format!(
+            "unsupported relay-commit commit_v={}, expected {}",
+            report.commit_v, TRANSPORT_WRAPPER_VERSION_V1
+        )));
+    }
+    if report.commit_receipt.is_empty() {
+        return Err(AmpError::invalid_message(
+            "relay-commit commit_receipt must not be empty",
+        ));
+    }
+
+    Ok(report)
+}
+
 fn parse_wire(bytes: &[u8]) -> Result<InboundWire, AmpError> {
     if let Ok(wire) = serde_cbor::from_slice::<WirePlainMessage>(bytes) {
         return Ok(InboundWire::Plain(wire));
@@ -794,15 +1224,20 @@ fn validate_meta(meta: &MessageMeta, now_ms: u64) -> Result<(), AmpError> {
     if meta.from.is_empty() {
         return Err(AmpError::invalid_message("from is required"));
     }
-    if meta.to.len() == 0 {
+    if meta.to.is_empty() {
         return Err(AmpError::invalid_message("to must not be empty"));
     }
+    if matches!(meta.typ, TYPE_PROC_OK | TYPE_PROC_FAIL) && meta.reply_to.is_none() {
+        return Err(AmpError::invalid_message("PROC_OK/PROC_FAIL requires reply_to"));
+    }
     if meta.ts_ms > now_ms.saturating_add(MAX_CLOCK_SKEW_MS) {
         return Err(AmpError::invalid_timestamp(
             "message ts is too far in the future",
         ));
     }
-    if now_ms > meta.ts_ms.saturating_add(meta.ttl_ms) {
+    // TTL=0 means "deliver immediately" (§8.3); it stays valid within the clock skew window.
+    let validity_ms = if meta.ttl_ms == 0 { MAX_CLOCK_SKEW_MS } else { meta.ttl_ms };
+    if now_ms > meta.ts_ms.saturating_add(validity_ms) {
         return Err(AmpError::invalid_timestamp("message ttl expired"));
     }
 
@@ -882,6 +1317,14 @@ fn sig_input_bytes(meta: &MessageMeta, body_bytes: &[u8]) -> Result<Vec<u8>, Amp
     to_cbor_deterministic(&sig_input)
 }
 
+/// Deterministic CBOR of `value`: encoding through [`Value`] orders every map key
+/// canonically (shorter keys first, then bytewise), so equal objects give equal bytes.
+pub fn to_cbor_canonical<T: Serialize>(value: &T) -> Result<Vec<u8>, AmpError> {
+    let value = serde_cbor::value::to_value(value)
+        .map_err(|e| AmpError::invalid_message(format!("cbor encode failed: {e}")))?;
+    to_cbor_deterministic(&value)
+}
+
 fn to_cbor_deterministic<T: Serialize>(value: &T) -> Result<Vec<u8>, AmpError> {
     serde_cbor::to_vec(value)
         .map_err(|e| AmpError::invalid_message(format!("cbor encode failed: {e}")))
@@ -948,6 +1391,44 @@ mod tests {
         let received = receive_and_verify(&bob, &wire, &resolver, ts + 10).expect("receive hello");
         let parsed: HelloBody = received.decode_body().expect("decode hello body");
         assert_eq!(parsed.versions[0], "0.30.0");
+
+        // A sender published only by its public key verifies the same way.
+        let mut public_only = DidResolver::default();
+        public_only
+            .add_signing_key(alice.did.clone(), alice.signing_public_key.as_bytes())
+            .expect("public key");
+        assert_eq!(public_only.assertion_method_for(&alice.assertion_kid()), Some(alice.signing_public_key));
+        receive_and_verify(&bob, &wire, &public_only, ts + 10).expect("verify with public key");
+    }
+
+    #[test]
+    fn plain_signature_covers_canonical_body_order() {
+        let (alice, bob, resolver) = setup();
+        let ts = 1_707_055_201_500_u64;
+
+        // Struct field order (code, category, message, retry) is not the canonical map order.
+        let body = ErrorBody::new(2003, "TTL=0 requires immediate delivery");
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, 3),
+            typ: TYPE_ERROR,
+            ts_ms: ts,
+            ttl_ms: 0,
+            from: String::new(),
+            to: Recipients::One(bob.did.clone()),
+            reply_to: Some(make_message_id(ts, 2)),
+            thread_id: None,
+        };
+
+        let wire = build_plain_signed(&alice, meta, &body).expect("build error");
+        let received = receive_and_verify(&bob, &wire, &resolver, ts + 10).expect("receive error");
+        let parsed: ErrorBody = received.decode_body().expect("decode error body");
+        assert_eq!(parsed, body);
+        assert_eq!((parsed.category.as_str(), parsed.retry), ("routing", Some(true)));
+
+        let err = receive_and_verify(&bob, &wire, &resolver, ts + MAX_CLOCK_SKEW_MS + 1)
+            .expect_err("ttl=0 is only valid within the skew window");
+        assert_eq!(err.code, 1003);
     }
 
     #[test]
@@ -1038,7 +1519,7 @@ mod tests {
         let ack_wire = build_plain_signed(&bob, ack_meta, &ack).expect("build ack");
         let ack_received = receive_and_verify(&alice, &ack_wire, &resolver, ts + 10).expect("receive ack");
 
-        validate_ack_semantics(&ack_received, &[bob.did.clone()], &resolver).expect("ack semantics");
+        validate_ack_semantics(&ack_received, std::slice::from_ref(&bob.did), &resolver).expect("ack semantics");
     }
 
     #[test]
@@ -1167,5 +1648,129 @@ mod tests {
         let unsupported_bytes = serde_cbor::to_vec(&unsupported).expect("encode unsupported");
         let err = decode_relay_forward(&unsupported_bytes).expect_err("fwd_v must be rejected");
         assert_eq!(err.code, 1004);
+
+        let report = RelayCommitReport {
+            commit_v: TRANSPORT_WRAPPER_VERSION_V1,
+            commit_receipt: ByteBuf::from(vec![0x84]),
+        };
+        let report_bytes = serde_cbor::to_vec(&report).expect("encode relay-commit");
+        assert_eq!(decode_relay_commit_report(&report_bytes).expect("decode relay-commit"), report);
+        let empty = RelayCommitReport {
+            commit_receipt: ByteBuf::new(),
+            ..report
+        };
+        let empty_bytes = serde_cbor::to_vec(&empty).expect("encode empty");
+        let err = decode_relay_commit_report(&empty_bytes).expect_err("receipt required");
+        assert_eq!(err.code, 1001);
+    }
+
+    #[test]
+    fn proc_results_reply_to_the_processed_message() {
+        let (alice, bob, resolver) = setup();
+        let ts = 1_707_055_206_000_u64;
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, 6),
+            typ: TYPE_MESSAGE,
+            ts_ms: ts,
+            ttl_ms: 86_400_000,
+            from: String::new(),
+            to: Recipients::One(bob.did.clone()),
+            reply_to: None,
+            thread_id: Some(b"thread-1".to_vec()),
+        };
+        let body = TextMessageBody {
+            msg: "do the thing".to_string(),
+        };
+        let wire = build_plain_signed(&alice, meta, &body).expect("build");
+        let request = receive_and_verify(&bob, &wire, &resolver, ts + 10).expect("receive");
+        let original_to = vec![bob.did.clone()];
+
+        let ok = process_and_respond(&bob, &request, ts + 20, |m| {
+            m.decode_body::<TextMessageBody>().map(|b| b.msg.len()).map_err(ErrorBody::from)
+        })
+        .expect("PROC_OK");
+        let ok = receive_and_verify(&alice, &ok, &resolver, ts + 30).expect("receive PROC_OK");
+        assert_eq!(ok.meta.typ, TYPE_PROC_OK);
+        assert_eq!(ok.meta.thread_id, request.meta.thread_id);
+        assert_eq!(
+            validate_proc_semantics(&ok, &request.meta.id, &original_to).expect("valid"),
+            ProcOutcome::Ok(ProcOkBody {
+                details: Some(Value::Integer(12))
+            })
+        );
+
+        let fail = process_and_respond(&bob, &request, ts + 20, |_| -> Result<(), ErrorBody> {
+            Err(ErrorBody::new(5003, "processing timeout"))
+        })
+        .expect("PROC_FAIL");
+        let fail = receive_and_verify(&alice, &fail, &resolver, ts + 30).expect("receive PROC_FAIL");
+        assert_eq!(fail.meta.typ, TYPE_PROC_FAIL);
+        let ProcOutcome::Fail(body) =
+            validate_proc_semantics(&fail, &request.meta.id, &original_to).expect("valid")
+        else {
+            panic!("expected PROC_FAIL");
+        };
+        let error = body.reason().expect("reason");
+        assert_eq!((error.code, error.category.as_str(), error.retry), (5003, "server", Some(true)));
+
+        let err = validate_proc_semantics(&fail, &make_message_id(ts, 99), &original_to)
+            .expect_err("reply_to must match");
+        assert_eq!(err.code, 1001);
+        let err = validate_proc_semantics(&fail, &request.meta.id, std::slice::from_ref(&alice.did))
+            .expect_err("recipient only");
+        assert_eq!(err.code, 1001);
+    }
+
+    #[test]
+    fn proc_results_require_reply_to_and_valid_reasons() {
+        let (alice, bob, resolver) = setup();
+        let ts = 1_707_055_207_000_u64;
+        let mut meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, 7),
+            typ: TYPE_PROC_OK,
+            ts_ms: ts,
+            ttl_ms: 86_400_000,
+            from: String::new(),
+            to: Recipients::One(alice.did.clone()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let err = build_plain_signed(&bob, meta.clone(), &ProcOkBody::default())
+            .expect_err("PROC_OK without reply_to");
+        assert_eq!(err.code, 1001);
+
+        // A bad reason code cannot be built...
+        let original = MessageMeta {
+            typ: TYPE_MESSAGE,
+            from: alice.did.clone(),
+            to: Recipients::One(bob.did.clone()),
+            ..meta.clone()
+        };
+        let bogus = ProcFailBody::new(42, "not a reason code");
+        let err = build_proc_result(&bob, &original, &Err(bogus.clone()), ts + 10)
+            .expect_err("reason code out of range");
+        assert_eq!(err.code, 1001);
+
+        // ...and is refused when a peer signs one anyway.
+        meta.typ = TYPE_PROC_FAIL;
+        meta.reply_to = Some(original.id);
+        let wire = build_plain_signed(&bob, meta, &bogus).expect("raw PROC_FAIL");
+        let received = receive_and_verify(&alice, &wire, &resolver, ts + 10).expect("receive");
+        let err = validate_proc_semantics(&received, &original.id, std::slice::from_ref(&bob.did))
+            .expect_err("invalid reason");
+        assert_eq!(err.code, 1001);
+
+        // `error` is `any`: a value that is not an error-body carries no reason code to check.
+        let opaque = ProcFailBody {
+            error: Some(Value::Text("disk full".into())),
+        };
+        let wire = build_proc_result(&bob, &original, &Err(opaque.clone()), ts + 20).expect("opaque PROC_FAIL");
+        let received = receive_and_verify(&alice, &wire, &resolver, ts + 20).expect("receive");
+        let outcome = validate_proc_semantics(&received, &original.id, std::slice::from_ref(&bob.did))
+            .expect("any error value");
+        assert_eq!(outcome, ProcOutcome::Fail(opaque.clone()));
+        assert_eq!(opaque.reason(), None);
     }
 }
@@ -0,0 +1,616 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{verify_signature, AmpError, DidResolver, MessageMeta, ReceivedMessage, Recipients};
+
+const INBOX_FILE: &str = "inbox.cbor";
+const OUTBOX_FILE: &str = "outbox.cbor";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailboxError {
+    /// Reading or writing a mailbox file failed.
+    Io(String),
+    /// A mailbox file does not decode as a mailbox.
+    Corrupt(String),
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailboxError::Io(detail) => write!(f, "mailbox I/O failed: {detail}"),
+            MailboxError::Corrupt(detail) => write!(f, "mailbox file is corrupt: {detail}"),
+        }
+    }
+}
+
+impl std::error::Error for MailboxError {}
+
+/// Inbox identity of a message: its sender and id (RFC 001 §16.2).
+pub type InboxKey = (String, [u8; 16]);
+
+/// A verified inbound message as stored: headers, signature and the (decrypted) body
+/// bytes the signature covers, so it can be re-verified later.
+#[derive(Debug, Clone)]
+pub struct InboxEntry {
+    pub message: ReceivedMessage,
+    pub received_at: u64,
+    /// When this agent sent its recipient `ACK`.
+    pub acked_at: Option<u64>,
+}
+
+impl InboxEntry {
+    fn is(&self, from: &str, id: &[u8; 16]) -> bool {
+        self.message.meta.from == from && self.message.meta.id == *id
+    }
+}
+
+/// An outbound wire message kept until every recipient has acknowledged it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub id: [u8; 16],
+    pub to: Vec<String>,
+    pub wire: Vec<u8>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Recipients whose delivery was acknowledged (by them or by a relay).
+    pub acked: Vec<String>,
+    /// Set once every recipient in `to` is acknowledged.
+    pub acked_at: Option<u64>,
+}
+
+impl OutboxEntry {
+    /// Recipients still waiting for an `ACK`.
+    pub fn unacked(&self) -> Vec<String> {
+        self.to.iter().filter(|did| !self.acked.contains(did)).cloned().collect()
+    }
+}
+
+/// Inbox filter; unset fields match everything. Times compare against the message `ts`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InboxQuery {
+    pub thread_id: Option<Vec<u8>>,
+    pub from: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+}
+
+impl InboxQuery {
+    pub fn matches(&self, meta: &MessageMeta) -> bool {
+        self.thread_id.as_ref().is_none_or(|t| meta.thread_id.as_ref() == Some(t))
+            && self.from.as_ref().is_none_or(|from| *from == meta.from)
+            && self.since_ms.is_none_or(|since| meta.ts_ms >= since)
+            && self.until_ms.is_none_or(|until| meta.ts_ms <= until)
+    }
+}
+
+/// Local mailbox kept in a directory: an inbox of verified messages and an outbox of
+/// pending wire messages, each a CBOR file rewritten (via rename) on every change.
+#[derive(Debug)]
+pub struct Mailbox {
+    dir: PathBuf,
+    inbox: Vec<InboxEntry>,
+    outbox: Vec<OutboxEntry>,
+}
+
+impl Mailbox {
+    /// Opens the mailbox in `dir`, creating the directory when it does not exist yet.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, MailboxError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| MailboxError::Io(format!("{}: {e}", dir.display())))?;
+        let inbox: Vec<InboxRecord> = load(&dir.join(INBOX_FILE))?;
+        let outbox: Vec<OutboxRecord> = load(&dir.join(OUTBOX_FILE))?;
+        Ok(Self {
+            inbox: inbox.into_iter().map(InboxRecord::into_entry).collect::<Result<_, _>>()?,
+            outbox: outbox.into_iter().map(OutboxRecord::into_entry).collect::<Result<_, _>>()?,
+            dir,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores a verified message; `false` when its `(from, id)` is already in the inbox.
+    /// Ids are only unique per sender (RFC 001 §16.2).
+    pub fn store_received(&mut self, message: &ReceivedMessage, received_at: u64) -> Result<bool, MailboxError> {
+        if self.received(&message.meta.from, &message.meta.id).is_some() {
+            return Ok(false);
+        }
+        self.inbox.push(InboxEntry {
+            message: message.clone(),
+            received_at,
+            acked_at: None,
+        });
+        self.save_inbox()?;
+        Ok(true)
+    }
+
+    pub fn received(&self, from: &str, id: &[u8; 16]) -> Option<&InboxEntry> {
+        self.inbox.iter().find(|entry| entry.is(from, id))
+    }
+
+    /// Records that this agent acknowledged inbound message `id` from `from`.
+    pub fn mark_acked(&mut self, from: &str, id: &[u8; 16], at: u64) -> Result<bool, MailboxError> {
+        let Some(entry) = self.inbox.iter_mut().find(|entry| entry.is(from, id)) else {
+            return Ok(false);
+        };
+        if entry.acked_at.is_none() {
+            entry.acked_at = Some(at);
+            self.save_inbox()?;
+        }
+        Ok(true)
+    }
+
+    /// Inbox entries matching `query`, oldest message first.
+    pub fn query(&self, query: &InboxQuery) -> Vec<&InboxEntry> {
+        let mut entries: Vec<&InboxEntry> = self
+            .inbox
+            .iter()
+            .filter(|entry| query.matches(&entry.message.meta))
+            .collect();
+        entries.sort_by_key(|entry| (entry.message.meta.ts_ms, entry.received_at));
+        entries
+    }
+
+    pub fn thread(&self, thread_id: &[u8]) -> Vec<&InboxEntry> {
+        self.query(&InboxQuery {
+            thread_id: Some(thread_id.to_vec()),
+            ..InboxQuery::default()
+        })
+    }
+
+    pub fn from_sender(&self, did: &str) -> Vec<&InboxEntry> {
+        self.query(&InboxQuery {
+            from: Some(did.to_string()),
+            ..InboxQuery::default()
+        })
+    }
+
+    /// Checks a stored message's signature again against the sender keys `resolver`
+    /// knows now. Expiry is not re-checked: the message was valid when it arrived.
+    pub fn reverify(&self, from: &str, id: &[u8; 16], resolver: &DidResolver) -> Result<(), AmpError> {
+        let entry = self
+            .received(from, id)
+            .ok_or_else(|| AmpError::invalid_message("message is not in the mailbox"))?;
+        let message = &entry.message;
+        verify_signature(&message.meta, &message.sig, &message.body_bytes, resolver)
+    }
+
+    /// [`Mailbox::reverify`] for every stored message, in inbox order.
+    pub fn reverify_all(&self, resolver: &DidResolver) -> Vec<(InboxKey, Result<(), AmpError>)> {
+        self.inbox
+            .iter()
+            .map(|entry| {
+                let meta = &entry.message.meta;
+                ((meta.from.clone(), meta.id), self.reverify(&meta.from, &meta.id, resolver))
+            })
+            .collect()
+    }
+
+    /// Keeps outbound `wire` until each of `to` is acknowledged or `expires_at` passes.
+    pub fn queue_outbound(
+        &mut self,
+        id: [u8; 16],
+        to: Vec<String>,
+        wire: Vec<u8>,
+        (created_at, expires_at): (u64, u64),
+    ) -> Result<(), MailboxError> {
+        if self.outbound(&id).is_some() {
+            return Ok(());
+        }
+        self.outbox.push(OutboxEntry {
+            id,
+            to,
+            wire,
+            created_at,
+            expires_at,
+            acked: Vec::new(),
+            acked_at: None,
+        });
+        self.save_outbox()
+    }
+
+    pub fn outbound(&self, id: &[u8; 16]) -> Option<&OutboxEntry> {
+        self.outbox.iter().find(|entry| entry.id == *id)
+    }
+
+    /// Records `ACK`s for `recipients` of outbound `id`; returns `true` once the message is
+    /// acknowledged by all of them.
+    pub fn outbound_acked(&mut self, id: &[u8; 16], recipients: &[String], at: u64) -> Result<bool, MailboxError> {
+        let Some(entry) = self.outbox.iter_mut().find(|entry| entry.id == *id) else {
+            return Ok(false);
+        };
+        for did in recipients {
+            if entry.to.contains(did) && !entry.acked.contains(did) {
+                entry.acked.push(did.clone());
+            }
+        }
+        if entry.acked_at.is_none() && entry.unacked().is_empty() {
+            entry.acked_at = Some(at);
+        }
+        let done = entry.acked_at.is_some();
+        self.save_outbox()?;
+        Ok(done)
+    }
+
+    /// Outbound messages still to (re)send at `now_ms`: not fully acknowledged, not
+    /// expired, oldest first.
+    pub fn pending_outbound(&self, now_ms: u64) -> Vec<&OutboxEntry> {
+        self.outbox
+            .iter()
+            .filter(|entry| entry.acked_at.is_none() && entry.expires_at > now_ms)
+            .collect()
+    }
+
+    /// Drops acknowledged and expired outbound messages; returns how many were removed.
+    pub fn prune_outbox(&mut self, now_ms: u64) -> Result<usize, MailboxError> {
+        let before = self.outbox.len();
+        self.outbox
+            .retain(|entry| entry.acked_at.is_none() && entry.expires_at > now_ms);
+        let removed = before - self.outbox.len();
+        if removed > 0 {
+            self.save_outbox()?;
+        }
+        Ok(removed)
+    }
+
+    fn save_inbox(&self) -> Result<(), MailboxError> {
+        let records: Vec<InboxRecord> = self.inbox.iter().map(InboxRecord::from_entry).collect();
+        save(&self.dir.join(INBOX_FILE), &records)
+    }
+
+    fn save_outbox(&self) -> Result<(), MailboxError> {
+        let records: Vec<OutboxRecord> = self.outbox.iter().map(OutboxRecord::from_entry).collect();
+        save(&self.dir.join(OUTBOX_FILE), &records)
+    }
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, MailboxError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let bytes = fs::read(path).map_err(|e| MailboxError::Io(format!("{}: {e}", path.display())))?;
+    serde_cbor::from_slice(&bytes).map_err(|e| MailboxError::Corrupt(format!("{}: {e}", path.display())))
+}
+
+/// Writes next to `path` and renames over it, so a crash leaves the old or the new file.
+fn save<T: Serialize>(path: &Path, records: &[T]) -> Result<(), MailboxError> {
+    let bytes = serde_cbor::to_vec(&records).map_err(|e| MailboxError::Corrupt(format!("encode failed: {e}")))?;
+    let tmp = path.with_extension("cbor.tmp");
+    fs::write(&tmp, bytes)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|e| MailboxError::Io(format!("{}: {e}", path.display())))
+}
+
+fn to_id(field: &str, bytes: &[u8]) -> Result<[u8; 16], MailboxError> {
+    bytes
+        .try_into()
+        .map_err(|_| MailboxError::Corrupt(format!("{field} must be 16 bytes")))
+}
+
+#[derive(Serialize, Deserialize)]
+struct InboxRecord {
+    v: u64,
+    id: ByteBuf,
+    typ: u8,
+    ts: u64,
+    ttl: u64,
+    from: String,
+    to: Recipients,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_id: Option<ByteBuf>,
+    sig: ByteBuf,
+    body: ByteBuf,
+    received_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acked_at: Option<u64>,
+}
+
+impl InboxRecord {
+    fn from_entry(entry: &InboxEntry) -> Self {
+        let meta = &entry.message.meta;
+        Self {
+            v: meta.v,
+            id: ByteBuf::from(meta.id.to_vec()),
+            typ: meta.typ,
+            ts: meta.ts_ms,
+            ttl: meta.ttl_ms,
+            from: meta.from.clone(),
+            to: meta.to.clone(),
+            reply_to: meta.reply_to.map(|v| ByteBuf::from(v.to_vec())),
+            thread_id: meta.thread_id.clone().map(ByteBuf::from),
+            sig: ByteBuf::from(entry.message.sig.clone()),
+            body: ByteBuf::from(entry.message.body_bytes.clone()),
+            received_at: entry.received_at,
+            acked_at: entry.acked_at,
+        }
+    }
+
+    fn into_entry(self) -> Result<InboxEntry, MailboxError> {
+        let meta = MessageMeta {
+            v: self.v,
+            id: to_id("id", &self.id)?,
+            typ: self.typ,
+            ts_ms: self.ts,
+            ttl_ms: self.ttl,
+            from: self.from,
+            to: self.to,
+            reply_to: self.reply_to.map(|v| to_id("reply_to", &v)).transpose()?,
+            thread_id: self.thread_id.map(ByteBuf::into_vec),
+        };
+        Ok(InboxEntry {
+            message: ReceivedMessage {
+                meta,
+                sig: self.sig.into_vec(),
+                body_bytes: self.body.into_vec(),
+            },
+            received_at: self.received_at,
+            acked_at: self.acked_at,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxRecord {
+    id: ByteBuf,
+    to: Vec<String>,
+    wire: ByteBuf,
+    created_at: u64,
+    expires_at: u64,
+    acked: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acked_at: Option<u64>,
+}
+
+impl OutboxRecord {
+    fn from_entry(entry: &OutboxEntry) -> Self {
+        Self {
+            id: ByteBuf::from(entry.id.to_vec()),
+            to: entry.to.clone(),
+            wire: ByteBuf::from(entry.wire.clone()),
+            created_at: entry.created_at,
+            expires_at: entry.expires_at,
+            acked: entry.acked.clone(),
+            acked_at: entry.acked_at,
+        }
+    }
+
+    fn into_entry(self) -> Result<OutboxEntry, MailboxError> {
+        Ok(OutboxEntry {
+            id: to_id("id", &self.id)?,
+            to: self.to,
+            wire: self.wire.into_vec(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            acked: self.acked,
+            acked_at: self.acked_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::{
+        build_plain_signed, demo_agents, make_message_id, now_ms, peek_routing, read_frame,
+        receive_and_verify, write_frame, Agent, AgentKeys, HelloHandshake, TextMessageBody, DEFAULT_AGENT_TTL_MS,
+        TYPE_ACK, TYPE_MESSAGE,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("amp-mailbox-{name}-{}-{}", std::process::id(), now_ms()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn text(sender: &AgentKeys, to: &str, msg: &str, ts: u64, thread_id: Option<&[u8]>) -> Vec<u8> {
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, ts ^ msg.len() as u64),
+            typ: TYPE_MESSAGE,
+            ts_ms: ts,
+            ttl_ms: DEFAULT_AGENT_TTL_MS,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: thread_id.map(<[u8]>::to_vec),
+        };
+        let body = TextMessageBody { msg: msg.to_string() };
+        build_plain_signed(sender, meta, &body).expect("message")
+    }
+
+    #[test]
+    fn mailbox_keeps_inbox_outbox_and_acks_across_reopen() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let dir = temp_dir("reopen");
+        let now = now_ms();
+
+        let mut mailbox = Mailbox::open(&dir).expect("open");
+        let mut ids = Vec::new();
+        for (sender, msg, ts, thread) in [
+            (&demo.alice, "first", now - 3_000, Some(&b"t1"[..])),
+            (&demo.bob, "second", now - 2_000, None),
+            (&demo.alice, "third", now - 1_000, Some(&b"t1"[..])),
+        ] {
+            let wire = text(sender, &demo.relay.did, msg, ts, thread);
+            let message = receive_and_verify(&demo.relay, &wire, &resolver, now).expect("verify");
+            assert!(mailbox.store_received(&message, now).expect("store"));
+            assert!(!mailbox.store_received(&message, now).expect("store again"), "same id");
+            ids.push(message.meta.id);
+        }
+        assert!(mailbox.mark_acked(&demo.bob.did, &ids[1], now).expect("ack"));
+        assert!(!mailbox.mark_acked(&demo.alice.did, &ids[1], now).expect("other sender"));
+        mailbox
+            .queue_outbound([7; 16], vec![demo.alice.did.clone(), demo.bob.did.clone()], b"wire".to_vec(), (now, now + 60_000))
+            .expect("queue");
+        assert!(!mailbox.outbound_acked(&[7; 16], std::slice::from_ref(&demo.alice.did), now).expect("one ack"));
+        drop(mailbox);
+
+        let mut mailbox = Mailbox::open(&dir).expect("reopen");
+        let thread: Vec<[u8; 16]> = mailbox.thread(b"t1").iter().map(|e| e.message.meta.id).collect();
+        assert_eq!(thread, vec![ids[0], ids[2]]);
+        let from_bob = mailbox.from_sender(&demo.bob.did);
+        assert_eq!(from_bob.len(), 1);
+        assert_eq!(from_bob[0].acked_at, Some(now));
+        assert_eq!(from_bob[0].message.decode_body::<TextMessageBody>().expect("body").msg, "second");
+        let recent = mailbox.query(&InboxQuery {
+            since_ms: Some(now - 2_000),
+            ..InboxQuery::default()
+        });
+        assert_eq!(recent.len(), 2);
+
+        let pending = mailbox.pending_outbound(now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].unacked(), vec![demo.bob.did.clone()]);
+        assert!(mailbox.outbound_acked(&[7; 16], std::slice::from_ref(&demo.bob.did), now + 1).expect("last ack"));
+        assert!(mailbox.pending_outbound(now).is_empty());
+        assert_eq!(mailbox.outbound(&[7; 16]).expect("kept").acked_at, Some(now + 1));
+        assert_eq!(mailbox.prune_outbox(now).expect("prune"), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mailbox_reverifies_stored_messages_for_audit() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let dir = temp_dir("audit");
+        let now = now_ms();
+        let mut mailbox = Mailbox::open(&dir).expect("open");
+
+        let wire = text(&demo.alice, &demo.bob.did, "signed", now, None);
+        let genuine = receive_and_verify(&demo.bob, &wire, &resolver, now).expect("verify");
+        let mut tampered = genuine.clone();
+        tampered.meta.id = [9; 16];
+        tampered.body_bytes = serde_cbor::to_vec(&TextMessageBody { msg: "forged".to_string() }).expect("cbor");
+        mailbox.store_received(&genuine, now).expect("store");
+        mailbox.store_received(&tampered, now).expect("store");
+
+        let results = Mailbox::open(&dir).expect("reopen").reverify_all(&resolver);
+        assert_eq!(results[0], ((demo.alice.did.clone(), genuine.meta.id), Ok(())));
+        assert_eq!(results[1].1.as_ref().expect_err("tampered").code, 1002);
+        let unknown = mailbox.reverify(&demo.alice.did, &genuine.meta.id, &DidResolver::default());
+        assert_eq!(unknown.expect_err("sender key unknown").code, 3001);
+
+        fs::write(dir.join(INBOX_FILE), b"not cbor").expect("corrupt");
+        assert!(matches!(Mailbox::open(&dir), Err(MailboxError::Corrupt(_))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn agent_with_mailbox_remembers_messages_across_restarts() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let dir = temp_dir("agent");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+        let wire = text(&demo.alice, &demo.bob.did, "persisted", now_ms(), Some(b"t9"));
+
+        // Each run: bob connects, gets the same frame, ACKs it and stops.
+        for run in 0..2 {
+            let (seen_tx, seen) = mpsc::channel();
+            let agent = Agent::builder(demo.bob.clone())
+                .resolver(resolver.clone())
+                .connect_tcp(addr.clone())
+                .handshake(HelloHandshake {
+                    relay_did: demo.relay.did.clone(),
+                    versions: vec!["1.0.0".to_string()],
+                })
+                .mailbox(Mailbox::open(&dir).expect("open"))
+                .on_message(move |_, _, body| {
+                    seen_tx.send(body.msg).expect("seen");
+                    Ok(())
+                })
+                .build()
+                .expect("agent");
+            let (handle, agent_run) = agent.spawn();
+
+            let (mut stream, _) = listener.accept().expect("accept");
+            read_frame(&mut stream).expect("hello");
+            write_frame(&mut stream, &wire).expect("deliver");
+            let ack = receive_and_verify(&demo.alice, &read_frame(&mut stream).expect("ack"), &resolver, now_ms())
+                .expect("verify ack");
+            assert_eq!(ack.meta.typ, TYPE_ACK, "run {run} acknowledges");
+
+            let handled = seen.recv_timeout(Duration::from_millis(300));
+            if run == 0 {
+                assert_eq!(handled.expect("handled"), "persisted");
+            } else {
+                assert!(handled.is_err(), "already in the mailbox from the previous run");
+            }
+            let stored = handle
+                .with_mailbox(|mailbox| mailbox.thread(b"t9").iter().map(|e| e.acked_at.is_some()).collect::<Vec<_>>())
+                .expect("mailbox");
+            assert_eq!(stored, vec![true]);
+            handle.shutdown();
+            agent_run.join().expect("agent thread").expect("clean stop");
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn agent_with_mailbox_handles_the_same_id_from_another_sender() {
+        let demo = demo_agents();
+        let resolver = demo.resolver();
+        let dir = temp_dir("same-id");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+
+        let (seen_tx, seen) = mpsc::channel();
+        let agent = Agent::builder(demo.bob.clone())
+            .resolver(resolver.clone())
+            .connect_tcp(addr)
+            .handshake(HelloHandshake {
+                relay_did: demo.relay.did.clone(),
+                versions: vec!["1.0.0".to_string()],
+            })
+            .mailbox(Mailbox::open(&dir).expect("open"))
+            .on_message(move |_, _, body| {
+                seen_tx.send(body.msg).expect("seen");
+                Ok(())
+            })
+            .build()
+            .expect("agent");
+        let (handle, agent_run) = agent.spawn();
+        let (mut stream, _) = listener.accept().expect("accept");
+        read_frame(&mut stream).expect("hello");
+
+        // Same timestamp and message length, so both senders pick the same id (§16.2).
+        let ts = now_ms();
+        let from_alice = text(&demo.alice, &demo.bob.did, "hello", ts, None);
+        let from_relay = text(&demo.relay, &demo.bob.did, "hallo", ts, None);
+        let id = peek_routing(&from_alice).expect("routing").id;
+        assert_eq!(peek_routing(&from_relay).expect("routing").id, id);
+        for wire in [&from_alice, &from_relay, &from_alice] {
+            write_frame(&mut stream, wire).expect("deliver");
+            let ack = peek_routing(&read_frame(&mut stream).expect("ack")).expect("routing");
+            assert_eq!(ack.typ, TYPE_ACK);
+        }
+        assert_eq!(seen.recv_timeout(Duration::from_secs(5)).expect("alice"), "hello");
+        assert_eq!(seen.recv_timeout(Duration::from_secs(5)).expect("relay"), "hallo");
+        assert!(seen.recv_timeout(Duration::from_millis(200)).is_err(), "alice's redelivery is a duplicate");
+
+        let stored = handle
+            .with_mailbox(|mailbox| {
+                [&demo.alice.did, &demo.relay.did]
+                    .map(|from| mailbox.received(from, &id).is_some_and(|e| e.acked_at.is_some()))
+            })
+            .expect("mailbox");
+        assert_eq!(stored, [true, true]);
+        handle.shutdown();
+        agent_run.join().expect("agent thread").expect("clean stop");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
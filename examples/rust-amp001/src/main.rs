@@ -103,7 +103,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let ack_wire = build_plain_signed(&bob, ack_meta, &ack_body)?;
     let ack_rx = receive_and_verify(&alice, &ack_wire, &resolver, base_ts + 90)?;
-    validate_ack_semantics(&ack_rx, &[bob.did.clone()], &resolver)?;
+    validate_ack_semantics(&ack_rx, std::slice::from_ref(&bob.did), &resolver)?;
 
     println!("HELLO versions from Alice: {:?}", hello_decoded.versions);
     println!("Selected version: {selected}");
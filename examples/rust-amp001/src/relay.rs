@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::{
+    build_ping, build_pong, now_ms, peek_routing, read_frame, receive_and_verify, spawn_keepalive,
+    write_frame, AgentKeys, AmpError, ConnectionHealth, DidResolver, Keepalive, KeepaliveConfig,
+    RoutingEnvelope, TYPE_PING, TYPE_PONG,
+};
+
+/// Command line of the example relay binaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayServerConfig {
+    pub addr: String,
+    pub keepalive: KeepaliveConfig,
+}
+
+impl RelayServerConfig {
+    /// Parses `[addr] [--ping-interval <ms>] [--pong-timeout <ms>] [--max-missed-pongs <n>]`;
+    /// `program` names the binary in the usage message.
+    pub fn from_args(
+        program: &str,
+        default_addr: &str,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, String> {
+        let usage = format!(
+            "usage: {program} [addr] [--ping-interval <ms>] [--pong-timeout <ms>] \
+             [--max-missed-pongs <n>]"
+        );
+        let mut args = args.into_iter().peekable();
+        let addr = match args.peek() {
+            Some(first) if !first.starts_with("--") => args.next().unwrap_or_default(),
+            _ => default_addr.to_string(),
+        };
+        let mut keepalive = KeepaliveConfig::default();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{flag} requires a value; {usage}"))?;
+            let invalid = |e: std::num::ParseIntError| format!("{flag} {value}: {e}");
+            match flag.as_str() {
+                "--ping-interval" => keepalive.interval_ms = value.parse().map_err(invalid)?,
+                "--pong-timeout" => keepalive.pong_timeout_ms = value.parse().map_err(invalid)?,
+                "--max-missed-pongs" => keepalive.max_missed = value.parse().map_err(invalid)?,
+                other => return Err(format!("unknown option {other}; {usage}")),
+            }
+        }
+        Ok(Self { addr, keepalive })
+    }
+}
+
+/// What the relay did with a connection or frame; [`run_relay_server`] reports these
+/// instead of logging, and `Display` gives the one-line log form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayEvent {
+    Accepted { peer: String },
+    AcceptFailed { reason: String },
+    /// The connection could not be set up, or its read loop ended.
+    Disconnected { reason: String },
+    /// A frame without a readable routing envelope, or a keepalive frame that does not verify.
+    Dropped { reason: String },
+    /// A registered connection sent a frame from another DID; the frame was dropped.
+    SenderSwitched { registered: String, from: String },
+    Registered { did: String },
+    Unregistered { did: String },
+    Forwarded { typ: u8, from: String, to: String },
+    ForwardFailed { to: String, reason: String },
+    /// Kept for an offline recipient until its TTL runs out.
+    Stored { typ: u8, from: String, to: String, backlog: usize },
+    /// Not deliverable: addressed to the relay itself, or already expired.
+    Undeliverable { typ: u8, from: String, to: String },
+    Flushed { did: String, count: usize },
+    FlushFailed { did: String, reason: String },
+    KeepaliveLost { did: String, health: ConnectionHealth },
+    PongFailed { to: String, reason: String },
+    Pong { from: String, rtt_ms: u64 },
+}
+
+impl RelayEvent {
+    /// Events worth reporting on stderr.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::AcceptFailed { .. }
+                | Self::Disconnected { .. }
+                | Self::Dropped { .. }
+                | Self::SenderSwitched { .. }
+                | Self::ForwardFailed { .. }
+                | Self::FlushFailed { .. }
+                | Self::PongFailed { .. }
+        )
+    }
+}
+
+impl fmt::Display for RelayEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accepted { peer } => write!(f, "accepted {peer}"),
+            Self::AcceptFailed { reason } => write!(f, "accept failed: {reason}"),
+            Self::Disconnected { reason } => write!(f, "connection ended: {reason}"),
+            Self::Dropped { reason } => write!(f, "drop frame: {reason}"),
+            Self::SenderSwitched { registered, from } => write!(
+                f,
+                "sender DID switched on same connection: {registered} -> {from} (drop)"
+            ),
+            Self::Registered { did } => write!(f, "registered {did}"),
+            Self::Unregistered { did } => write!(f, "unregistered {did}"),
+            Self::Forwarded { typ, from, to } => write!(f, "forwarded typ=0x{typ:02x} from={from} to={to}"),
+            Self::ForwardFailed { to, reason } => write!(f, "forward to {to} failed: {reason}"),
+            Self::Stored { typ, from, to, backlog } => write!(
+                f,
+                "stored typ=0x{typ:02x} from={from} for offline {to} (backlog={backlog})"
+            ),
+            Self::Undeliverable { typ, from, to } => write!(
+                f,
+                "no online recipient for typ=0x{typ:02x} from={from} to={to} (dropped)"
+            ),
+            Self::Flushed { did, count } => write!(f, "flushed {count} stored message(s) to {did}"),
+            Self::FlushFailed { did, reason } => write!(f, "backlog flush to {did} failed: {reason}"),
+            Self::KeepaliveLost { did, health } => write!(
+                f,
+                "keepalive lost {did} missed={} last_rtt_ms={:?}; closing",
+                health.missed_pongs, health.last_rtt_ms
+            ),
+            Self::PongFailed { to, reason } => write!(f, "PONG to {to} failed: {reason}"),
+            Self::Pong { from, rtt_ms } => write!(f, "PONG from {from} rtt_ms={rtt_ms}"),
+        }
+    }
+}
+
+type RelayEvents = Arc<dyn Fn(&RelayEvent) + Send + Sync>;
+
+struct RelayState {
+    keys: AgentKeys,
+    resolver: DidResolver,
+    keepalive: KeepaliveConfig,
+    writers: HashMap<String, Arc<Mutex<TcpStream>>>,
+    /// Frames for recipients that were offline, with their expiry, flushed when the
+    /// recipient registers again.
+    backlog: HashMap<String, Vec<(u64, Vec<u8>)>>,
+    events: RelayEvents,
+}
+
+impl RelayState {
+    fn emit(&self, event: RelayEvent) {
+        (self.events)(&event);
+    }
+}
+
+/// Runs the example relay on `listener`: the first frame on a connection registers its
+/// sender, frames are forwarded to registered recipients or stored until they come back,
+/// and idle agents are `PING`ed as `keys`. Everything it does is reported to `on_event`.
+/// Returns only if the listener fails.
+pub fn run_relay_server(
+    listener: TcpListener,
+    keys: AgentKeys,
+    resolver: DidResolver,
+    keepalive: KeepaliveConfig,
+    on_event: impl Fn(&RelayEvent) + Send + Sync + 'static,
+) {
+    let events: RelayEvents = Arc::new(on_event);
+    let state = Arc::new(Mutex::new(RelayState {
+        keys,
+        resolver,
+        keepalive,
+        writers: HashMap::new(),
+        backlog: HashMap::new(),
+        events: Arc::clone(&events),
+    }));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let peer = stream
+                    .peer_addr()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                events(&RelayEvent::Accepted { peer });
+
+                let state = Arc::clone(&state);
+                let events = Arc::clone(&events);
+                thread::spawn(move || {
+                    if let Err(err) = handle_connection(stream, state) {
+                        events(&RelayEvent::Disconnected {
+                            reason: err.to_string(),
+                        });
+                    }
+                });
+            }
+            Err(err) => events(&RelayEvent::AcceptFailed {
+                reason: err.to_string(),
+            }),
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<RelayState>>) -> io::Result<()> {
+    let mut reader = stream.try_clone()?;
+    let writer = Arc::new(Mutex::new(stream));
+    let (config, events) = {
+        let guard = state.lock().expect("relay state poisoned");
+        (guard.keepalive, Arc::clone(&guard.events))
+    };
+    let keepalive = Arc::new(Mutex::new(Keepalive::new(config, now_ms())));
+    let keepalive_stop = Arc::new(AtomicBool::new(false));
+
+    let mut registered_did: Option<String> = None;
+
+    loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(frame) => frame,
+            Err(err) => {
+                events(&RelayEvent::Disconnected {
+                    reason: err.to_string(),
+                });
+                break;
+            }
+        };
+        keepalive.lock().expect("keepalive poisoned").observe_inbound(now_ms());
+
+        let routing = match peek_routing(&frame) {
+            Ok(v) => v,
+            Err(err) => {
+                events(&RelayEvent::Dropped {
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        };
+
+        match &registered_did {
+            Some(current) if current != &routing.from => {
+                events(&RelayEvent::SenderSwitched {
+                    registered: current.clone(),
+                    from: routing.from.clone(),
+                });
+                continue;
+            }
+            None => {
+                registered_did = Some(routing.from.clone());
+                let mut guard = state.lock().expect("relay state poisoned");
+                guard
+                    .writers
+                    .insert(routing.from.clone(), Arc::clone(&writer));
+                guard.emit(RelayEvent::Registered {
+                    did: routing.from.clone(),
+                });
+                start_keepalive(&guard, &routing.from, &writer, &keepalive, &keepalive_stop);
+                flush_backlog(&mut guard, &routing.from, &writer);
+            }
+            _ => {}
+        }
+
+        if matches!(routing.typ, TYPE_PING | TYPE_PONG) && is_for_relay(&state, &routing) {
+            answer_keepalive(&frame, &state, &writer, &keepalive);
+            continue;
+        }
+
+        let recipients: Vec<(String, Option<Arc<Mutex<TcpStream>>>)> = {
+            let guard = state.lock().expect("relay state poisoned");
+            routing
+                .to
+                .iter()
+                .map(|did| (did.clone(), guard.writers.get(did).map(Arc::clone)))
+                .collect()
+        };
+
+        for (recipient, socket) in recipients {
+            let Some(socket) = socket else {
+                store(&state, &recipient, &routing, &frame);
+                continue;
+            };
+            let mut guard = socket.lock().expect("recipient socket poisoned");
+            if let Err(err) = write_frame(&mut *guard, &frame) {
+                drop(guard);
+                events(&RelayEvent::ForwardFailed {
+                    to: recipient.clone(),
+                    reason: err.to_string(),
+                });
+                store(&state, &recipient, &routing, &frame);
+            } else {
+                events(&RelayEvent::Forwarded {
+                    typ: routing.typ,
+                    from: routing.from.clone(),
+                    to: recipient,
+                });
+            }
+        }
+    }
+
+    keepalive_stop.store(true, Ordering::SeqCst);
+    if let Some(did) = registered_did {
+        let mut guard = state.lock().expect("relay state poisoned");
+        if guard
+            .writers
+            .get(&did)
+            .is_some_and(|current| Arc::ptr_eq(current, &writer))
+        {
+            guard.writers.remove(&did);
+        }
+        guard.emit(RelayEvent::Unregistered { did });
+    }
+
+    Ok(())
+}
+
+/// Keeps `frame` for an offline `recipient` until its TTL runs out.
+fn store(state: &Mutex<RelayState>, recipient: &str, routing: &RoutingEnvelope, frame: &[u8]) {
+    let expires_at = routing.ts_ms.saturating_add(routing.ttl_ms);
+    let mut guard = state.lock().expect("relay state poisoned");
+    if *recipient == guard.keys.did || expires_at <= now_ms() {
+        guard.emit(RelayEvent::Undeliverable {
+            typ: routing.typ,
+            from: routing.from.clone(),
+            to: recipient.to_string(),
+        });
+        return;
+    }
+    let queue = guard.backlog.entry(recipient.to_string()).or_default();
+    queue.push((expires_at, frame.to_vec()));
+    let backlog = queue.len();
+    guard.emit(RelayEvent::Stored {
+        typ: routing.typ,
+        from: routing.from.clone(),
+        to: recipient.to_string(),
+        backlog,
+    });
+}
+
+/// Delivers what `did` missed while disconnected, oldest first. Runs under the state lock
+/// so nothing newer overtakes the backlog; whatever cannot be written stays stored.
+fn flush_backlog(relay: &mut RelayState, did: &str, writer: &Arc<Mutex<TcpStream>>) {
+    let Some(missed) = relay.backlog.remove(did) else {
+        return;
+    };
+    let now = now_ms();
+    let mut pending = missed.into_iter().filter(|(expires_at, _)| *expires_at > now);
+    let mut delivered = 0;
+    let mut guard = writer.lock().expect("writer poisoned");
+    for (expires_at, frame) in pending.by_ref() {
+        if let Err(err) = write_frame(&mut *guard, &frame) {
+            let mut rest = vec![(expires_at, frame)];
+            rest.extend(pending);
+            relay.backlog.insert(did.to_string(), rest);
+            relay.emit(RelayEvent::FlushFailed {
+                did: did.to_string(),
+                reason: err.to_string(),
+            });
+            return;
+        }
+        delivered += 1;
+    }
+    if delivered > 0 {
+        relay.emit(RelayEvent::Flushed {
+            did: did.to_string(),
+            count: delivered,
+        });
+    }
+}
+
+fn is_for_relay(state: &Mutex<RelayState>, routing: &RoutingEnvelope) -> bool {
+    let guard = state.lock().expect("relay state poisoned");
+    routing.to.iter().any(|did| *did == guard.keys.did)
+}
+
+/// `PING`s the registered agent while its connection is idle; too many missed `PONG`s
+/// shut the socket down, which ends the read loop and drops the registration.
+fn start_keepalive(
+    relay: &RelayState,
+    did: &str,
+    writer: &Arc<Mutex<TcpStream>>,
+    keepalive: &Arc<Mutex<Keepalive>>,
+    stop: &Arc<AtomicBool>,
+) {
+    let keys = relay.keys.clone();
+    let events = Arc::clone(&relay.events);
+    let did = did.to_string();
+    let ping_writer = Arc::clone(writer);
+    let dead_writer = Arc::clone(writer);
+    let pong_timeout_ms = keepalive.lock().expect("keepalive poisoned").config().pong_timeout_ms;
+    let dead_did = did.clone();
+    spawn_keepalive(
+        Arc::clone(keepalive),
+        Arc::clone(stop),
+        move |now| {
+            let (id, ping) = build_ping(&keys, &did, pong_timeout_ms, now)?;
+            let mut guard = ping_writer.lock().expect("writer poisoned");
+            write_frame(&mut *guard, &ping).map_err(|e| {
+                AmpError::endpoint_unreachable(format!("PING write failed: {e}"))
+            })?;
+            Ok(id)
+        },
+        move |health| {
+            events(&RelayEvent::KeepaliveLost {
+                did: dead_did.clone(),
+                health: health.clone(),
+            });
+            let guard = dead_writer.lock().expect("writer poisoned");
+            let _ = guard.shutdown(Shutdown::Both);
+        },
+    );
+}
+
+/// `PING`/`PONG` addressed to the relay itself: verified, then answered or matched
+/// against the outstanding keepalive `PING`.
+fn answer_keepalive(
+    frame: &[u8],
+    state: &Mutex<RelayState>,
+    writer: &Arc<Mutex<TcpStream>>,
+    keepalive: &Mutex<Keepalive>,
+) {
+    let now = now_ms();
+    let (message, pong, events) = {
+        let guard = state.lock().expect("relay state poisoned");
+        let message = match receive_and_verify(&guard.keys, frame, &guard.resolver, now) {
+            Ok(message) => message,
+            Err(err) => {
+                guard.emit(RelayEvent::Dropped {
+                    reason: format!("keepalive frame: {err}"),
+                });
+                return;
+            }
+        };
+        let pong = (message.meta.typ == TYPE_PING).then(|| build_pong(&guard.keys, &message.meta, now));
+        (message, pong, Arc::clone(&guard.events))
+    };
+
+    let pong_failed = |reason: String| {
+        events(&RelayEvent::PongFailed {
+            to: message.meta.from.clone(),
+            reason,
+        })
+    };
+    match pong {
+        Some(Ok(pong)) => {
+            let mut guard = writer.lock().expect("writer poisoned");
+            if let Err(err) = write_frame(&mut *guard, &pong) {
+                pong_failed(err.to_string());
+            }
+        }
+        Some(Err(err)) => pong_failed(err.to_string()),
+        None => {
+            let Some(ping_id) = message.meta.reply_to else {
+                return;
+            };
+            let rtt = keepalive.lock().expect("keepalive poisoned").pong_received(&ping_id, now);
+            if let Some(rtt_ms) = rtt {
+                events(&RelayEvent::Pong {
+                    from: message.meta.from.clone(),
+                    rtt_ms,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::{
+        build_plain_signed, demo_agents, make_message_id, MessageMeta, Recipients, TextMessageBody,
+        TYPE_MESSAGE,
+    };
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn relay_server_args_default_addr_and_keepalive() {
+        let config = RelayServerConfig::from_args("amp-server", "127.0.0.1:7001", args(&[])).expect("config");
+        assert_eq!(config.addr, "127.0.0.1:7001");
+        assert_eq!(config.keepalive, KeepaliveConfig::default());
+
+        let config = RelayServerConfig::from_args(
+            "amp-server",
+            "127.0.0.1:7001",
+            args(&["0.0.0.0:9000", "--ping-interval", "100", "--max-missed-pongs", "2"]),
+        )
+        .expect("config");
+        assert_eq!(config.addr, "0.0.0.0:9000");
+        assert_eq!(config.keepalive.interval_ms, 100);
+        assert_eq!(config.keepalive.pong_timeout_ms, KeepaliveConfig::default().pong_timeout_ms);
+        assert_eq!(config.keepalive.max_missed, 2);
+    }
+
+    #[test]
+    fn relay_server_args_reject_bad_flags() {
+        let err = RelayServerConfig::from_args("amp002-server", "x", args(&["--pong-timeout"])).unwrap_err();
+        assert!(err.contains("requires a value") && err.contains("amp002-server"), "{err}");
+        let err = RelayServerConfig::from_args("amp-server", "x", args(&["--ping-interval", "soon"])).unwrap_err();
+        assert!(err.starts_with("--ping-interval soon"), "{err}");
+        let err = RelayServerConfig::from_args("amp-server", "x", args(&["--verbose", "1"])).unwrap_err();
+        assert!(err.contains("unknown option --verbose"), "{err}");
+    }
+
+    fn text(from: &AgentKeys, to: &str, counter: u64) -> Vec<u8> {
+        let ts_ms = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts_ms, counter),
+            typ: TYPE_MESSAGE,
+            ts_ms,
+            ttl_ms: 60_000,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: None,
+        };
+        build_plain_signed(from, meta, &TextMessageBody { msg: "hi".to_string() }).expect("message")
+    }
+
+    #[test]
+    fn relay_server_reports_what_it_does_through_events() {
+        let demo = demo_agents();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let (events_tx, events) = mpsc::channel();
+        let (keys, resolver) = (demo.relay.clone(), demo.resolver());
+        thread::spawn(move || {
+            run_relay_server(listener, keys, resolver, KeepaliveConfig::default(), move |event| {
+                let _ = events_tx.send(event.clone());
+            })
+        });
+        let next = |wanted: fn(&RelayEvent) -> bool| loop {
+            let event = events.recv_timeout(Duration::from_secs(5)).expect("event");
+            if wanted(&event) {
+                return event;
+            }
+        };
+
+        let for_bob = text(&demo.alice, &demo.bob.did, 1);
+        let mut alice = TcpStream::connect(addr).expect("alice");
+        write_frame(&mut alice, &for_bob).expect("send");
+        let stored = next(|e| matches!(e, RelayEvent::Stored { .. }));
+        assert_eq!(
+            stored,
+            RelayEvent::Stored {
+                typ: TYPE_MESSAGE,
+                from: demo.alice.did.clone(),
+                to: demo.bob.did.clone(),
+                backlog: 1
+            }
+        );
+        assert!(!stored.is_failure());
+
+        let mut bob = TcpStream::connect(addr).expect("bob");
+        write_frame(&mut bob, &text(&demo.bob, &demo.alice.did, 2)).expect("register");
+        assert_eq!(read_frame(&mut bob).expect("backlog"), for_bob);
+        let flushed = next(|e| matches!(e, RelayEvent::Flushed { .. }));
+        assert_eq!(flushed.to_string(), format!("flushed 1 stored message(s) to {}", demo.bob.did));
+
+        write_frame(&mut alice, &[0xff]).expect("garbage");
+        assert!(next(|e| matches!(e, RelayEvent::Dropped { .. })).is_failure());
+    }
+}
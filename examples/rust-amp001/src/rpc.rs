@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::OsRng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+use crate::{
+    build_plain_signed, make_message_id, now_ms, read_frame, receive_and_verify, write_frame,
+    AgentKeys, AmpError, DidResolver, ErrorBody, MessageMeta, ReceivedMessage, Recipients,
+    TYPE_ERROR,
+};
+
+pub const TYPE_REQUEST: u8 = 0x11;
+pub const TYPE_RESPONSE: u8 = 0x12;
+
+/// `REQUEST` body: a method name and its CBOR parameters.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RequestBody {
+    pub method: String,
+    pub params: Value,
+}
+
+/// `RESPONSE` body: the method result. Failures are answered with `ERROR` instead.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ResponseBody {
+    pub result: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// The request could not be built, sent, or its reply could not be read.
+    Local(AmpError),
+    /// The callee answered with `ERROR`.
+    Remote(ErrorBody),
+    /// No reply before the deadline taken from the request `ttl_ms`.
+    Timeout,
+    Cancelled,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Local(err) => write!(f, "{err}"),
+            RpcError::Remote(err) => write!(f, "remote ERROR {}: {}", err.code, err.message),
+            RpcError::Timeout => write!(f, "RPC deadline exceeded"),
+            RpcError::Cancelled => write!(f, "RPC call cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<AmpError> for RpcError {
+    fn from(err: AmpError) -> Self {
+        RpcError::Local(err)
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<[u8; 16], (String, Sender<ReceivedMessage>)>>>;
+
+/// Caller side of REQUEST/RESPONSE over any framed transport. Requests go out through the
+/// writer; whatever loop reads the transport hands replies to [`RpcClient::handle_message`]
+/// (or runs [`RpcClient::pump`]), which wakes the call whose request id is the reply's
+/// `reply_to`.
+pub struct RpcClient<W: Write> {
+    agent: AgentKeys,
+    resolver: DidResolver,
+    writer: Mutex<W>,
+    pending: Pending,
+}
+
+/// An outstanding call; wait for its reply or cancel it.
+pub struct RpcCall {
+    pub id: [u8; 16],
+    deadline: Instant,
+    replies: Receiver<ReceivedMessage>,
+    pending: Pending,
+}
+
+impl<W: Write> RpcClient<W> {
+    pub fn new(agent: AgentKeys, resolver: DidResolver, writer: W) -> Self {
+        Self {
+            agent,
+            resolver,
+            writer: Mutex::new(writer),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sends `method(params)` to `to` and blocks for the reply, at most `ttl_ms`.
+    pub fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        to: &str,
+        method: &str,
+        params: &P,
+        ttl_ms: u64,
+    ) -> Result<R, RpcError> {
+        self.start(to, method, params, ttl_ms)?.wait()
+    }
+
+    /// Sends a `REQUEST` and returns the call handle. The call is registered before the
+    /// frame is written so a fast reply cannot be missed.
+    pub fn start<P: Serialize>(
+        &self,
+        to: &str,
+        method: &str,
+        params: &P,
+        ttl_ms: u64,
+    ) -> Result<RpcCall, RpcError> {
+        if ttl_ms == 0 {
+            return Err(AmpError::invalid_message("REQUEST needs ttl_ms > 0 to bound the call").into());
+        }
+        let params = serde_cbor::value::to_value(params)
+            .map_err(|e| AmpError::invalid_message(format!("REQUEST params encode failed: {e}")))?;
+        let ts = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, OsRng.next_u64()),
+            typ: TYPE_REQUEST,
+            ts_ms: ts,
+            ttl_ms,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let id = meta.id;
+        let body = RequestBody {
+            method: method.to_string(),
+            params,
+        };
+        let wire = build_plain_signed(&self.agent, meta, &body)?;
+
+        let (tx, replies) = mpsc::channel();
+        self.pending
+            .lock()
+            .expect("rpc pending poisoned")
+            .insert(id, (to.to_string(), tx));
+        let call = RpcCall {
+            id,
+            deadline: Instant::now() + Duration::from_millis(ttl_ms),
+            replies,
+            pending: Arc::clone(&self.pending),
+        };
+
+        let written = {
+            let mut writer = self.writer.lock().expect("rpc writer poisoned");
+            write_frame(&mut *writer, &wire)
+        };
+        if let Err(err) = written {
+            call.forget();
+            return Err(AmpError::invalid_message(format!("REQUEST write failed: {err}")).into());
+        }
+        Ok(call)
+    }
+
+    /// Routes a verified reply to its call. Returns `false` for anything that is not a
+    /// `RESPONSE`/`ERROR` from the callee of an outstanding request.
+    pub fn handle_message(&self, message: &ReceivedMessage) -> bool {
+        if !matches!(message.meta.typ, TYPE_RESPONSE | TYPE_ERROR) {
+            return false;
+        }
+        let Some(reply_to) = message.meta.reply_to else {
+            return false;
+        };
+        let mut pending = self.pending.lock().expect("rpc pending poisoned");
+        match pending.get(&reply_to) {
+            Some((callee, _)) if *callee == message.meta.from => {}
+            _ => return false,
+        }
+        let (_, tx) = pending.remove(&reply_to).expect("checked above");
+        let _ = tx.send(message.clone());
+        true
+    }
+
+    /// Reads frames until the transport closes, feeding verified replies to their calls.
+    /// Outstanding calls are cancelled when it returns.
+    pub fn pump<R: Read>(&self, reader: &mut R) -> io::Result<()> {
+        let result = loop {
+            let frame = match read_frame(reader) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                Err(err) => break Err(err),
+            };
+            if let Ok(message) = receive_and_verify(&self.agent, &frame, &self.resolver, now_ms()) {
+                self.handle_message(&message);
+            }
+        };
+        self.cancel_all();
+        result
+    }
+
+    /// Cancels every outstanding call; their waiters get [`RpcError::Cancelled`].
+    pub fn cancel_all(&self) {
+        self.pending.lock().expect("rpc pending poisoned").clear();
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.pending.lock().expect("rpc pending poisoned").len()
+    }
+}
+
+impl RpcCall {
+    /// Blocks until the reply arrives or the deadline passes.
+    pub fn wait<R: DeserializeOwned>(self) -> Result<R, RpcError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let reply = match self.replies.recv_timeout(remaining) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => {
+                self.forget();
+                return Err(RpcError::Timeout);
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(RpcError::Cancelled),
+        };
+
+        if reply.meta.typ == TYPE_ERROR {
+            return Err(RpcError::Remote(reply.decode_body()?));
+        }
+        let body: ResponseBody = reply.decode_body()?;
+        serde_cbor::value::from_value(body.result).map_err(|e| {
+            RpcError::Local(AmpError::invalid_message(format!("RESPONSE result decode failed: {e}")))
+        })
+    }
+
+    /// Stops waiting; a reply that still arrives is ignored.
+    pub fn cancel(self) {
+        self.forget();
+    }
+
+    fn forget(&self) {
+        self.pending.lock().expect("rpc pending poisoned").remove(&self.id);
+    }
+}
+
+type Handler = Box<dyn Fn(&ReceivedMessage, Value) -> Result<Value, ErrorBody> + Send + Sync>;
+
+/// Callee side: dispatches `REQUEST` bodies by method name and answers with `RESPONSE`,
+/// or `ERROR` for unknown methods (`4001`), bad params (`4004`) and handler failures.
+#[derive(Default)]
+pub struct RpcRouter {
+    handlers: HashMap<String, Handler>,
+}
+
+impl RpcRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<P, R, F>(&mut self, method: impl Into<String>, handler: F)
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(&ReceivedMessage, P) -> Result<R, ErrorBody> + Send + Sync + 'static,
+    {
+        let handler: Handler = Box::new(move |request, params| {
+            let params: P = serde_cbor::value::from_value(params)
+                .map_err(|e| ErrorBody::new(4004, format!("params do not match method: {e}")))?;
+            let result = handler(request, params)?;
+            serde_cbor::value::to_value(&result)
+                .map_err(|e| ErrorBody::new(5001, format!("result encode failed: {e}")))
+        });
+        self.handlers.insert(method.into(), handler);
+    }
+
+    /// `RESPONSE` or `ERROR` wire bytes answering a verified `REQUEST`, in its thread.
+    pub fn respond(
+        &self,
+        agent: &AgentKeys,
+        request: &ReceivedMessage,
+        now_ms: u64,
+    ) -> Result<Vec<u8>, AmpError> {
+        if request.meta.typ != TYPE_REQUEST {
+            return Err(AmpError::invalid_message("message typ is not REQUEST"));
+        }
+        let outcome = request
+            .decode_body::<RequestBody>()
+            .map_err(|err| ErrorBody::new(4001, err.detail))
+            .and_then(|body| match self.handlers.get(&body.method) {
+                Some(handler) => handler(request, body.params),
+                None => Err(ErrorBody::new(4001, format!("unknown method: {}", body.method))),
+            });
+
+        let meta = MessageMeta {
+            v: request.meta.v,
+            id: make_message_id(now_ms, OsRng.next_u64()),
+            typ: if outcome.is_ok() { TYPE_RESPONSE } else { TYPE_ERROR },
+            ts_ms: now_ms,
+            ttl_ms: request.meta.ttl_ms,
+            from: String::new(),
+            to: Recipients::One(request.meta.from.clone()),
+            reply_to: Some(request.meta.id),
+            thread_id: request.meta.thread_id.clone(),
+        };
+        match outcome {
+            Ok(result) => build_plain_signed(agent, meta, &ResponseBody { result }),
+            Err(error) => build_plain_signed(agent, meta, &error),
+        }
+    }
+
+    /// Answers every verifiable `REQUEST` read from `reader` until the transport closes.
+    /// Other frames are ignored.
+    pub fn serve<R: Read, W: Write>(
+        &self,
+        agent: &AgentKeys,
+        resolver: &DidResolver,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        loop {
+            let frame = match read_frame(reader) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let now = now_ms();
+            let Ok(request) = receive_and_verify(agent, &frame, resolver, now) else {
+                continue;
+            };
+            if request.meta.typ != TYPE_REQUEST {
+                continue;
+            }
+            if let Ok(reply) = self.respond(agent, &request, now) {
+                write_frame(writer, &reply)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+
+    fn setup() -> (AgentKeys, AgentKeys, DidResolver) {
+        let caller = AgentKeys::from_sign_seed("did:web:example.com:agent:alice", [1_u8; 32]);
+        let callee = AgentKeys::from_sign_seed("did:web:example.com:agent:bob", [2_u8; 32]);
+        let mut resolver = DidResolver::default();
+        resolver.add_agent(&caller);
+        resolver.add_agent(&callee);
+        (caller, callee, resolver)
+    }
+
+    fn router() -> RpcRouter {
+        let mut router = RpcRouter::new();
+        router.register("add", |_, (a, b): (u64, u64)| Ok(a + b));
+        router.register("whoami", |request, ()| Ok(request.meta.from.clone()));
+        router.register("fail", |_, ()| -> Result<(), ErrorBody> {
+            Err(ErrorBody::new(5002, "not today"))
+        });
+        router.register("sleep", |_, ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            Ok(ms)
+        });
+        router
+    }
+
+    /// Caller connected to a callee serving `router()` over loopback TCP.
+    fn connect() -> (Arc<RpcClient<TcpStream>>, TcpStream, String) {
+        let (caller, callee, resolver) = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let callee_did = callee.did.clone();
+        let serve_resolver = resolver.clone();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut writer = stream.try_clone().expect("clone");
+            let _ = router().serve(&callee, &serve_resolver, &mut stream, &mut writer);
+        });
+
+        let stream = TcpStream::connect(addr).expect("connect");
+        let mut reader = stream.try_clone().expect("clone");
+        let control = stream.try_clone().expect("clone");
+        let client = Arc::new(RpcClient::new(caller, resolver, stream));
+        let pump = Arc::clone(&client);
+        thread::spawn(move || pump.pump(&mut reader));
+        (client, control, callee_did)
+    }
+
+    #[test]
+    fn rpc_call_returns_result_by_reply_to() {
+        let (client, _, bob) = connect();
+        let sum: u64 = client.call(&bob, "add", &(2_u64, 40_u64), 5_000).expect("add");
+        assert_eq!(sum, 42);
+        let me: String = client.call(&bob, "whoami", &(), 5_000).expect("whoami");
+        assert_eq!(me, "did:web:example.com:agent:alice");
+
+        // Each reply wakes the call named by its reply_to, whatever order callers wait in.
+        let slow = client.start(&bob, "sleep", &300_u64, 5_000).expect("start slow");
+        let fast = client.start(&bob, "sleep", &0_u64, 5_000).expect("start fast");
+        assert_eq!(fast.wait::<u64>().expect("fast"), 0);
+        assert_eq!(slow.wait::<u64>().expect("slow"), 300);
+        assert_eq!(client.outstanding(), 0);
+    }
+
+    #[test]
+    fn rpc_errors_are_returned_as_remote_errors() {
+        let (client, _, bob) = connect();
+        let err = client.call::<_, ()>(&bob, "fail", &(), 5_000).expect_err("handler error");
+        assert_eq!(err, RpcError::Remote(ErrorBody::new(5002, "not today")));
+
+        let err = client.call::<_, ()>(&bob, "nope", &(), 5_000).expect_err("unknown method");
+        assert!(matches!(err, RpcError::Remote(ref e) if e.code == 4001), "{err}");
+
+        let err = client.call::<_, u64>(&bob, "add", &"two", 5_000).expect_err("bad params");
+        assert!(matches!(err, RpcError::Remote(ref e) if e.code == 4004), "{err}");
+
+        let err = client.call::<_, u64>(&bob, "add", &(1_u64, 2_u64), 0).expect_err("ttl=0");
+        assert!(matches!(err, RpcError::Local(ref e) if e.code == 1001), "{err}");
+    }
+
+    #[test]
+    fn rpc_deadline_cancel_and_disconnect() {
+        let (client, control, bob) = connect();
+        let err = client.call::<_, u64>(&bob, "sleep", &500_u64, 100).expect_err("deadline");
+        assert_eq!(err, RpcError::Timeout);
+        assert_eq!(client.outstanding(), 0, "timed out calls are forgotten");
+
+        let call = client.start(&bob, "sleep", &200_u64, 5_000).expect("start");
+        call.cancel();
+        assert_eq!(client.outstanding(), 0);
+
+        let call = client.start(&bob, "sleep", &2_000_u64, 5_000).expect("start");
+        control.shutdown(Shutdown::Both).expect("shutdown");
+        assert_eq!(call.wait::<u64>().expect_err("connection closed"), RpcError::Cancelled);
+    }
+
+    #[test]
+    fn rpc_replies_only_come_from_the_callee() {
+        let (caller, callee, resolver) = setup();
+        let mallory = AgentKeys::from_sign_seed("did:web:example.com:agent:mallory", [3_u8; 32]);
+        let client = RpcClient::new(caller.clone(), resolver.clone(), io::sink());
+        let call = client.start(&callee.did, "add", &(1_u64, 1_u64), 5_000).expect("start");
+
+        let ts = now_ms();
+        let request = ReceivedMessage {
+            meta: MessageMeta {
+                v: 1,
+                id: call.id,
+                typ: TYPE_REQUEST,
+                ts_ms: ts,
+                ttl_ms: 5_000,
+                from: caller.did.clone(),
+                to: Recipients::One(callee.did.clone()),
+                reply_to: None,
+                thread_id: None,
+            },
+            sig: Vec::new(),
+            body_bytes: serde_cbor::to_vec(&RequestBody {
+                method: "add".to_string(),
+                params: serde_cbor::value::to_value((1_u64, 1_u64)).expect("params"),
+            })
+            .expect("body"),
+        };
+        let mut resolver = resolver;
+        resolver.add_agent(&mallory);
+        let forged = router().respond(&mallory, &request, ts).expect("forged reply");
+        let forged = receive_and_verify(&caller, &forged, &resolver, ts).expect("verify");
+        assert!(!client.handle_message(&forged), "reply from a DID that was not called");
+
+        let genuine = router().respond(&callee, &request, ts).expect("reply");
+        let genuine = receive_and_verify(&caller, &genuine, &resolver, ts).expect("verify");
+        assert!(client.handle_message(&genuine));
+        assert_eq!(call.wait::<u64>().expect("result"), 2);
+    }
+}
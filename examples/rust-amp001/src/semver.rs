@@ -0,0 +1,529 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SemverError {
+    /// Empty version or range string.
+    Empty,
+    /// Not a `major.minor.patch[-pre][+build]` version.
+    InvalidVersion { input: String, reason: String },
+    /// A range comparator that does not parse.
+    InvalidComparator { input: String, reason: String },
+    /// A form other semver dialects accept but RFC 004 §4.4 leaves out.
+    Unsupported { input: String, form: &'static str },
+}
+
+impl fmt::Display for SemverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemverError::Empty => write!(f, "empty version or range"),
+            SemverError::InvalidVersion { input, reason } => {
+                write!(f, "invalid version {input:?}: {reason}")
+            }
+            SemverError::InvalidComparator { input, reason } => {
+                write!(f, "invalid comparator {input:?}: {reason}")
+            }
+            SemverError::Unsupported { input, form } => {
+                write!(f, "{form} ({input:?}) are not part of the RFC 004 range grammar")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SemverError {}
+
+/// Pre-release identifier; numeric identifiers sort before alphanumeric ones.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Identifier {
+    Numeric(u64),
+    Alpha(String),
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier::Numeric(n) => write!(f, "{n}"),
+            Identifier::Alpha(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Semantic Versioning 2.0.0 version. Build metadata is kept for display but takes no
+/// part in equality or ordering.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<Identifier>,
+    pub build: Vec<String>,
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: Vec::new(),
+            build: Vec::new(),
+        }
+    }
+
+    /// Strict `major.minor.patch[-pre][+build]`.
+    pub fn parse(input: &str) -> Result<Self, SemverError> {
+        parse_version(input, 3)
+    }
+
+    /// Like [`Version::parse`] but lets minor and patch default to 0, for `HELLO`
+    /// version lists such as `["1.0", "2.0"]` (RFC 001 §13.1).
+    pub fn parse_loose(input: &str) -> Result<Self, SemverError> {
+        parse_version(input, 1)
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+
+    fn core(&self) -> (u64, u64, u64) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.core().cmp(&other.core()).then_with(|| {
+            match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            }
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(Identifier::to_string).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Version {
+    type Err = SemverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Op {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparator {
+    pub op: Op,
+    pub version: Version,
+}
+
+impl Comparator {
+    pub fn matches(&self, version: &Version) -> bool {
+        let ord = version.cmp(&self.version);
+        match self.op {
+            Op::Eq => ord == Ordering::Equal,
+            Op::Gt => ord == Ordering::Greater,
+            Op::Ge => ord != Ordering::Less,
+            Op::Lt => ord == Ordering::Less,
+            Op::Le => ord != Ordering::Greater,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op {
+            Op::Eq => write!(f, "{}", self.version),
+            op => write!(f, "{}{}", op.as_str(), self.version),
+        }
+    }
+}
+
+/// RFC 004 §4.4 `semver-range`: an exact `x.y.z` or a space-separated comparator set
+/// (`>=1.2.0 <2.0.0`) that must all hold. `||`, wildcards, caret, tilde and hyphen
+/// ranges are rejected rather than guessed at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRange {
+    comparators: Vec<Comparator>,
+}
+
+impl VersionRange {
+    pub fn parse(input: &str) -> Result<Self, SemverError> {
+        let trimmed = input.trim();
+        if trimmed.is_empty() {
+            return Err(SemverError::Empty);
+        }
+        let unsupported = |form| SemverError::Unsupported {
+            input: input.to_string(),
+            form,
+        };
+        if trimmed.contains("||") {
+            return Err(unsupported("OR expressions"));
+        }
+        if trimmed.split_whitespace().any(|token| token == "-") {
+            return Err(unsupported("hyphen ranges"));
+        }
+        let comparators = trimmed
+            .split_whitespace()
+            .map(parse_comparator)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { comparators })
+    }
+
+    /// Range matching exactly `version`.
+    pub fn exact(version: Version) -> Self {
+        Self {
+            comparators: vec![Comparator { op: Op::Eq, version }],
+        }
+    }
+
+    pub fn comparators(&self) -> &[Comparator] {
+        &self.comparators
+    }
+
+    /// Whether `version` satisfies every comparator. A pre-release only matches when a
+    /// comparator names a pre-release of the same `major.minor.patch`, so `<2.0.0` does
+    /// not admit `2.0.0-rc.1` just because SemVer orders it lower.
+    pub fn matches(&self, version: &Version) -> bool {
+        if !self.comparators.iter().all(|c| c.matches(version)) {
+            return false;
+        }
+        !version.is_prerelease()
+            || self
+                .comparators
+                .iter()
+                .any(|c| c.version.is_prerelease() && c.version.core() == version.core())
+    }
+
+    /// Highest of `candidates` the range admits.
+    pub fn max_satisfying<'a, I>(&self, candidates: I) -> Option<&'a Version>
+    where
+        I: IntoIterator<Item = &'a Version>,
+    {
+        candidates.into_iter().filter(|v| self.matches(v)).max()
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.comparators.iter().map(Comparator::to_string).collect();
+        f.write_str(&parts.join(" "))
+    }
+}
+
+impl FromStr for VersionRange {
+    type Err = SemverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn parse_comparator(token: &str) -> Result<Comparator, SemverError> {
+    let invalid = |reason: &str| SemverError::InvalidComparator {
+        input: token.to_string(),
+        reason: reason.to_string(),
+    };
+    let unsupported = |form| SemverError::Unsupported {
+        input: token.to_string(),
+        form,
+    };
+    if token.starts_with('^') {
+        return Err(unsupported("caret ranges"));
+    }
+    if token.starts_with('~') {
+        return Err(unsupported("tilde ranges"));
+    }
+
+    let (op, rest) = [(">=", Op::Ge), ("<=", Op::Le), (">", Op::Gt), ("<", Op::Lt), ("=", Op::Eq)]
+        .into_iter()
+        .find_map(|(prefix, op)| token.strip_prefix(prefix).map(|rest| (op, rest)))
+        .unwrap_or((Op::Eq, token));
+    if rest.is_empty() {
+        return Err(invalid("operator must be followed directly by a version"));
+    }
+    if !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '*') {
+        return Err(invalid("unknown operator"));
+    }
+    let core = rest.split(['-', '+']).next().unwrap_or_default();
+    if core.split('.').any(|part| matches!(part, "x" | "X" | "*")) {
+        return Err(unsupported("wildcards"));
+    }
+    Ok(Comparator {
+        op,
+        version: Version::parse(rest)?,
+    })
+}
+
+fn parse_version(input: &str, min_parts: usize) -> Result<Version, SemverError> {
+    if input.is_empty() {
+        return Err(SemverError::Empty);
+    }
+    let invalid = |reason: String| SemverError::InvalidVersion {
+        input: input.to_string(),
+        reason,
+    };
+    let (rest, build) = match input.split_once('+') {
+        Some((rest, build)) => (rest, Some(build)),
+        None => (input, None),
+    };
+    let (core, pre) = match rest.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (rest, None),
+    };
+
+    let parts: Vec<&str> = core.split('.').collect();
+    if parts.len() < min_parts || parts.len() > 3 {
+        let expected = if min_parts == 3 { "major.minor.patch" } else { "major[.minor[.patch]]" };
+        return Err(invalid(format!("expected {expected}")));
+    }
+    let mut numbers = [0_u64; 3];
+    for (slot, (part, name)) in numbers.iter_mut().zip(parts.iter().zip(["major", "minor", "patch"])) {
+        *slot = parse_numeric(part).map_err(|why| invalid(format!("{name} {why}")))?;
+    }
+
+    let pre = match pre {
+        Some(pre) => pre
+            .split('.')
+            .map(|id| {
+                check_identifier(id).map_err(|why| invalid(format!("pre-release {why}")))?;
+                if id.chars().all(|c| c.is_ascii_digit()) {
+                    parse_numeric(id)
+                        .map(Identifier::Numeric)
+                        .map_err(|why| invalid(format!("pre-release {why}")))
+                } else {
+                    Ok(Identifier::Alpha(id.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let build = match build {
+        Some(build) => build
+            .split('.')
+            .map(|id| {
+                check_identifier(id)
+                    .map(|()| id.to_string())
+                    .map_err(|why| invalid(format!("build {why}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+
+    Ok(Version {
+        major: numbers[0],
+        minor: numbers[1],
+        patch: numbers[2],
+        pre,
+        build,
+    })
+}
+
+fn parse_numeric(part: &str) -> Result<u64, String> {
+    if part.is_empty() {
+        return Err("is empty".to_string());
+    }
+    if !part.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("{part:?} is not a number"));
+    }
+    if part.len() > 1 && part.starts_with('0') {
+        return Err(format!("{part:?} has a leading zero"));
+    }
+    part.parse::<u64>().map_err(|_| format!("{part:?} is too large"))
+}
+
+fn check_identifier(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("identifier is empty".to_string());
+    }
+    if let Some(c) = id.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '-')) {
+        return Err(format!("identifier {id:?} contains {c:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::select_compatible_version;
+
+    fn v(text: &str) -> Version {
+        Version::parse(text).expect("version")
+    }
+
+    fn range(text: &str) -> VersionRange {
+        VersionRange::parse(text).expect("range")
+    }
+
+    #[test]
+    fn versions_order_by_semver_precedence() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.10.0",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(v("1.2.3+build.7"), v("1.2.3"));
+        assert_eq!(v("1.2.3-rc.1+build.7").to_string(), "1.2.3-rc.1+build.7");
+        assert_eq!(Version::parse_loose("1.0").expect("loose"), v("1.0.0"));
+    }
+
+    #[test]
+    fn malformed_versions_and_ranges_are_rejected_precisely() {
+        let cases: &[(&str, &str)] = &[
+            ("1.2", "expected major.minor.patch"),
+            ("01.2.3", "major \"01\" has a leading zero"),
+            ("1.2.x3", "patch \"x3\" is not a number"),
+            ("1.2.3-", "pre-release identifier is empty"),
+            ("1.2.3-rc.01", "pre-release \"01\" has a leading zero"),
+            ("1.2.3+b_1", "build identifier \"b_1\" contains '_'"),
+        ];
+        for (input, reason) in cases {
+            match Version::parse(input) {
+                Err(SemverError::InvalidVersion { reason: got, .. }) => assert_eq!(&got, reason, "{input}"),
+                other => panic!("{input}: {other:?}"),
+            }
+        }
+
+        assert_eq!(VersionRange::parse("  "), Err(SemverError::Empty));
+        for (input, form) in [
+            ("^1.2.0", "caret ranges"),
+            ("~1.2.0", "tilde ranges"),
+            ("1.x", "wildcards"),
+            (">=1.2.* <2.0.0", "wildcards"),
+            (">=1.0.0 || >=2.0.0", "OR expressions"),
+            ("1.0.0 - 2.0.0", "hyphen ranges"),
+        ] {
+            match VersionRange::parse(input) {
+                Err(SemverError::Unsupported { form: got, .. }) => assert_eq!(got, form, "{input}"),
+                other => panic!("{input}: {other:?}"),
+            }
+        }
+        assert!(matches!(
+            VersionRange::parse(">= 1.2.0"),
+            Err(SemverError::InvalidComparator { ref reason, .. }) if reason.contains("followed directly")
+        ));
+        assert!(matches!(
+            VersionRange::parse("!=1.2.0"),
+            Err(SemverError::InvalidComparator { ref reason, .. }) if reason == "unknown operator"
+        ));
+        assert!(matches!(VersionRange::parse(">=1.2 <2.0.0"), Err(SemverError::InvalidVersion { .. })));
+    }
+
+    #[test]
+    fn comparator_sets_match_with_prerelease_rules() {
+        let set = range(">=1.2.0 <2.0.0");
+        assert!(set.matches(&v("1.2.0")));
+        assert!(set.matches(&v("1.9.9")));
+        assert!(!set.matches(&v("1.1.9")));
+        assert!(!set.matches(&v("2.0.0")));
+        assert!(!set.matches(&v("2.0.0-rc.1")), "pre-release of an excluded bound");
+        assert!(!set.matches(&v("1.5.0-beta")), "no comparator opts into 1.5.0 pre-releases");
+        assert!(range(">=1.5.0-alpha <2.0.0").matches(&v("1.5.0-beta")));
+
+        assert!(range("2.1.0").matches(&v("2.1.0+build.1")));
+        assert!(!range("2.1.0").matches(&v("2.1.1")));
+        assert!(range("=1.0.0-rc.1").matches(&v("1.0.0-rc.1")));
+        assert_eq!(range("=1.0.0  >1.0.0-rc.1").to_string(), "1.0.0 >1.0.0-rc.1");
+
+        let candidates = [v("1.1.0"), v("1.4.2"), v("1.5.0-rc.1"), v("2.0.0")];
+        assert_eq!(set.max_satisfying(&candidates), Some(&v("1.4.2")));
+    }
+
+    #[test]
+    fn hello_selection_keeps_local_preference_order() {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            select_compatible_version(&strings(&["1.0", "2.0"]), &strings(&["1.0", "2.0"])),
+            Some("1.0".to_string())
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["2.0", "1.0"]), &strings(&["1.0", "2.0"])),
+            Some("2.0".to_string()),
+            "local order decides, not precedence"
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["0.30.0", "1.0.0"]), &strings(&["1.0"])),
+            Some("1.0.0".to_string())
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["1.2.0", "1.4.0"]), &strings(&["1.3.0"])),
+            Some("1.2.0".to_string()),
+            "same major is compatible"
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["1.0.0"]), &strings(&["1.1.0-rc.1"])),
+            None,
+            "a peer pre-release does not stand in for the major"
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["2.0.0-rc.1", "1.0.0"]), &strings(&["2.0.0"])),
+            None,
+            "a pre-release is only selected when offered exactly"
+        );
+        assert_eq!(
+            select_compatible_version(&strings(&["2.0.0-rc.1"]), &strings(&["2.0.0-rc.1", "junk"])),
+            Some("2.0.0-rc.1".to_string())
+        );
+    }
+}
@@ -0,0 +1,762 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use amp001_example::{AmpError, DidResolver, ErrorBody, ReceivedMessage};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+use crate::{
+    CapInvokeBody, DelegRevokeBody, DelegationCredential, DelegationEvidence, DelegationRevocation,
+    DelegationTarget, TYPE_CAP_INVOKE,
+};
+
+/// `deleg-query-status`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DelegationStatus {
+    Active,
+    Revoked,
+    Expired,
+    Unknown,
+}
+
+/// `deleg-query-result` (§5.4): revocation status of one (`delegator`, `delegation_id`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegQueryResult {
+    pub delegator: String,
+    pub delegation_id: String,
+    pub status: DelegationStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<u64>,
+    pub updated_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_s: Option<u64>,
+}
+
+/// A configured revocation source (§7.2): a delegator's DID service endpoint, an offline
+/// bundle, or anything else that answers `deleg-query-result`. An unreachable source
+/// returns `Err`.
+pub trait RevocationSource: Send + Sync {
+    fn status(&self, delegator: &str, delegation_id: &str) -> Result<DelegQueryResult, AmpError>;
+}
+
+/// `decision` of the §6.4 record.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DelegationVerdict {
+    Allow,
+    Deny,
+}
+
+/// One chain link named in a decision, keyed the way revocation is.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationLinkRef {
+    pub delegator: String,
+    pub delegation_id: String,
+}
+
+/// One §6.1 check as it ran. Evaluation stops at the first failed check.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DelegationAuditStep {
+    pub step: u8,
+    pub check: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// The §6.4 decision record, with every check that ran in `audit`. It holds DIDs,
+/// delegation ids and the target, never credential bytes or invocation params.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DelegationDecision {
+    pub decision: DelegationVerdict,
+    /// RFC 001 error code of a denial; `0` when allowed.
+    pub reason_code: u16,
+    pub detail: String,
+    pub requester_did: String,
+    /// Root delegator (`L1.delegator`) whose authority is exercised, once parsed.
+    pub effective_delegator_did: Option<String>,
+    pub delegation_ids: Vec<DelegationLinkRef>,
+    pub target: DelegationTarget,
+    pub evaluated_at: u64,
+    pub audit: Vec<DelegationAuditStep>,
+    /// `E(n, capabilities)` of an allowed chain, for
+    /// [`DelegationEvaluator::confirm_capability`].
+    #[serde(skip)]
+    capabilities: Option<Vec<String>>,
+}
+
+impl DelegationDecision {
+    pub fn is_allowed(&self) -> bool {
+        self.decision == DelegationVerdict::Allow
+    }
+}
+
+impl fmt::Display for DelegationDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.decision {
+            DelegationVerdict::Allow => write!(f, "allow {}: {}", self.requester_did, self.detail),
+            DelegationVerdict::Deny => {
+                write!(f, "deny {} ({}): {}", self.requester_did, self.reason_code, self.detail)
+            }
+        }
+    }
+}
+
+/// Denials keep a fixed message for `3001`/`3004` so a caller cannot probe which
+/// delegators or credentials exist (§8); the full reason stays in the record.
+impl From<DelegationDecision> for ErrorBody {
+    fn from(decision: DelegationDecision) -> Self {
+        match decision.reason_code {
+            3001 => ErrorBody::new(3001, "invocation not authorized"),
+            3004 => ErrorBody::new(3004, "delegation evidence invalid"),
+            code => ErrorBody::new(code, decision.detail),
+        }
+    }
+}
+
+type ConstraintCheck = Box<dyn Fn(&Value, &DelegationTarget) -> bool + Send + Sync>;
+type DecisionSink = Box<dyn Fn(&DelegationDecision) + Send + Sync>;
+type RevocationKey = (String, String);
+
+#[derive(Default)]
+struct RevocationCache {
+    /// Signed revocations received directly (`DELEG_REVOKE`); never expire.
+    revoked: HashMap<RevocationKey, DelegationRevocation>,
+    /// Last answer of the revocation source per key.
+    statuses: HashMap<RevocationKey, DelegQueryResult>,
+}
+
+/// RFC 005 verifier: evaluates `delegation-evidence` in the §6.1 order and emits a §6.4
+/// decision for every attempt.
+///
+/// Revocation is checked against signed revocations recorded with
+/// [`record_revocation`](Self::record_revocation) and, when configured, a
+/// [`RevocationSource`] whose answers are cached for their `max_age_s`. An unreachable
+/// source is `5002` (strict mode) unless a bounded offline grace is allowed. Constraint
+/// keys fail closed with `3004` unless a check is registered for them.
+pub struct DelegationEvaluator {
+    verifier_did: String,
+    resolver: DidResolver,
+    source: Option<Box<dyn RevocationSource>>,
+    status_max_age_ms: u64,
+    offline_grace_ms: u64,
+    constraints: HashMap<String, ConstraintCheck>,
+    sink: Option<DecisionSink>,
+    cache: Mutex<RevocationCache>,
+}
+
+impl DelegationEvaluator {
+    /// `verifier_did` is matched against `aud`; `resolver` resolves credential `kid`s.
+    pub fn new(verifier_did: impl Into<String>, resolver: DidResolver) -> Self {
+        Self {
+            verifier_did: verifier_did.into(),
+            resolver,
+            source: None,
+            status_max_age_ms: 0,
+            offline_grace_ms: 0,
+            constraints: HashMap::new(),
+            sink: None,
+            cache: Mutex::new(RevocationCache::default()),
+        }
+    }
+
+    pub fn revocation_source(mut self, source: impl RevocationSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Cache lifetime for source answers without `max_age_s`; `0` (the default) queries
+    /// the source on every evaluation.
+    pub fn status_max_age_ms(mut self, max_age_ms: u64) -> Self {
+        self.status_max_age_ms = max_age_ms;
+        self
+    }
+
+    /// Bounded offline mode: while the source is unreachable, a cached answer stays
+    /// usable this long past its freshness. `0` (the default) is strict mode.
+    pub fn offline_grace_ms(mut self, grace_ms: u64) -> Self {
+        self.offline_grace_ms = grace_ms;
+        self
+    }
+
+    /// Enables constraint `key`; every link's value for it must pass `check` against
+    /// the request target.
+    pub fn constraint<F>(mut self, key: impl Into<String>, check: F) -> Self
+    where
+        F: Fn(&Value, &DelegationTarget) -> bool + Send + Sync + 'static,
+    {
+        self.constraints.insert(key.into(), Box::new(check));
+        self
+    }
+
+    /// Receives every decision record, allowed or denied (step 10).
+    pub fn on_decision<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(&DelegationDecision) + Send + Sync + 'static,
+    {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn verifier_did(&self) -> &str {
+        &self.verifier_did
+    }
+
+    /// Verifies a `DELEG_REVOKE` body and marks its delegation revoked from
+    /// `revoked_at` on. Repeats are idempotent; the earliest `revoked_at` wins.
+    pub fn record_revocation(&self, body: &DelegRevokeBody) -> Result<DelegationRevocation, AmpError> {
+        let revocation = body.verify(&self.resolver)?;
+        let key = (revocation.delegator.clone(), revocation.delegation_id.clone());
+        let mut cache = self.lock();
+        let kept = cache.revoked.entry(key).or_insert_with(|| revocation.clone());
+        if revocation.revoked_at < kept.revoked_at {
+            *kept = revocation;
+        }
+        Ok(kept.clone())
+    }
+
+    /// Evaluates the signed `body.delegation` of a verified message. `None` when the
+    /// message carries no delegation (the non-delegated path); a type other than
+    /// `CAP_INVOKE` carrying one is denied with `4001` (A.18). A `CAP_INVOKE` naming an
+    /// exact version is confirmed against it; a negotiated one only against its name.
+    pub fn authorize(&self, request: &ReceivedMessage, now_ms: u64) -> Option<DelegationDecision> {
+        if request.meta.typ == TYPE_CAP_INVOKE {
+            if let Ok(body) = request.decode_body::<CapInvokeBody>() {
+                let decision = self.authorize_invoke(request, &body, now_ms)?;
+                let exact = match (&body.id, &body.capability, &body.version) {
+                    (Some(id), _, _) => Some(id.clone()),
+                    (None, Some(name), Some(version)) => Some(format!("{name}:{version}")),
+                    _ => None,
+                };
+                return Some(match exact {
+                    Some(id) => self.confirm_capability(decision, &id),
+                    None => self.emit(decision),
+                });
+            }
+        }
+        let Ok(Value::Map(mut body)) = request.decode_body::<Value>() else {
+            return None;
+        };
+        let evidence = body.remove(&Value::Text("delegation".into()))?;
+        let target = DelegationTarget::default();
+        Some(self.evaluate(request.meta.typ, &request.meta.from, evidence, &target, now_ms))
+    }
+
+    /// Steps 1-9 for an already decoded `CAP_INVOKE`, before its version is resolved.
+    /// The target capability is the invoked name, admitted when any version of it is in
+    /// scope; action and resource come from the evidence. A denial is final and emitted.
+    /// An allowed decision is provisional: pass it to
+    /// [`confirm_capability`](Self::confirm_capability) with the `capability-id` that
+    /// will actually run.
+    pub fn authorize_invoke(
+        &self,
+        request: &ReceivedMessage,
+        body: &CapInvokeBody,
+        now_ms: u64,
+    ) -> Option<DelegationDecision> {
+        let evidence = body.delegation.clone()?;
+        let capability = match &body.id {
+            Some(id) => id.split_once(':').map(|(name, _)| name.to_string()),
+            None => body.capability.clone(),
+        };
+        let target = DelegationTarget {
+            capability,
+            ..Default::default()
+        };
+        let decision = self.decide(request.meta.typ, &request.meta.from, evidence, &target, now_ms);
+        Some(if decision.is_allowed() { decision } else { self.emit(decision) })
+    }
+
+    /// Completes a provisional [`authorize_invoke`](Self::authorize_invoke) decision:
+    /// `capability_id` must be within the chain's effective capabilities (`3004`), so a
+    /// version picked by negotiation or a compatibility policy is covered too. The final
+    /// decision names `capability_id` as its target and is emitted.
+    pub fn confirm_capability(&self, mut decision: DelegationDecision, capability_id: &str) -> DelegationDecision {
+        if !decision.is_allowed() {
+            return decision;
+        }
+        let covered = decision
+            .capabilities
+            .as_ref()
+            .is_none_or(|selectors| selectors.iter().any(|selector| selector_covers(selector, capability_id)));
+        let concluded = decision.audit.pop();
+        decision.target.capability = Some(capability_id.to_string());
+        if covered {
+            decision.audit.push(DelegationAuditStep {
+                step: 9,
+                check: "selected capability",
+                passed: true,
+                detail: format!("{capability_id} within effective scope"),
+            });
+            decision.audit.extend(concluded);
+        } else {
+            let detail = format!("capability {capability_id} is outside the delegated scope");
+            decision.audit.push(DelegationAuditStep {
+                step: 9,
+                check: "selected capability",
+                passed: false,
+                detail: detail.clone(),
+            });
+            decision.audit.push(DelegationAuditStep {
+                step: 10,
+                check: "decision",
+                passed: false,
+                detail: detail.clone(),
+            });
+            decision.decision = DelegationVerdict::Deny;
+            decision.reason_code = 3004;
+            decision.detail = detail;
+        }
+        self.emit(decision)
+    }
+
+    /// Runs §6.1 steps 1-10 for `requester_did` presenting `evidence` in a message of
+    /// type `typ`. Fields of `target` the verifier does not know itself are taken from
+    /// the evidence `target`; where both are known they must agree (`3004`). A bare
+    /// capability name in the target is admitted when any version of it is in scope.
+    pub fn evaluate(
+        &self,
+        typ: u8,
+        requester_did: &str,
+        evidence: Value,
+        target: &DelegationTarget,
+        now_ms: u64,
+    ) -> DelegationDecision {
+        let decision = self.decide(typ, requester_did, evidence, target, now_ms);
+        self.emit(decision)
+    }
+
+    fn emit(&self, decision: DelegationDecision) -> DelegationDecision {
+        if let Some(sink) = &self.sink {
+            sink(&decision);
+        }
+        decision
+    }
+
+    fn decide(
+        &self,
+        typ: u8,
+        requester_did: &str,
+        evidence: Value,
+        target: &DelegationTarget,
+        now_ms: u64,
+    ) -> DelegationDecision {
+        let mut evaluation = Evaluation {
+            target: target.clone(),
+            links: Vec::new(),
+            capabilities: None,
+            audit: Vec::new(),
+        };
+        let outcome = self.run(&mut evaluation, typ, requester_did, evidence, now_ms);
+        let (decision, reason_code, detail) = match outcome {
+            Ok(()) => (DelegationVerdict::Allow, 0, format!("{} link chain authorizes the target", evaluation.links.len())),
+            Err(e) => (DelegationVerdict::Deny, e.code, e.detail),
+        };
+        evaluation.audit.push(DelegationAuditStep {
+            step: 10,
+            check: "decision",
+            passed: decision == DelegationVerdict::Allow,
+            detail: detail.clone(),
+        });
+        DelegationDecision {
+            decision,
+            reason_code,
+            detail,
+            requester_did: requester_did.to_string(),
+            effective_delegator_did: evaluation.links.first().map(|link| link.delegator.clone()),
+            delegation_ids: evaluation
+                .links
+                .iter()
+                .map(|link| DelegationLinkRef {
+                    delegator: link.delegator.clone(),
+                    delegation_id: link.delegation_id.clone(),
+                })
+                .collect(),
+            target: evaluation.target,
+            evaluated_at: now_ms,
+            audit: evaluation.audit,
+            capabilities: evaluation.capabilities,
+        }
+    }
+
+    fn run(
+        &self,
+        evaluation: &mut Evaluation,
+        typ: u8,
+        requester_did: &str,
+        evidence: Value,
+        now_ms: u64,
+    ) -> Result<(), AmpError> {
+        let capable = if typ == TYPE_CAP_INVOKE {
+            Ok(String::from("CAP_INVOKE"))
+        } else {
+            Err(AmpError::bad_request(format!(
+                "message type 0x{typ:02x} cannot carry delegation evidence"
+            )))
+        };
+        evaluation.check(1, "message type", capable)?;
+
+        let evidence = evaluation.check_with(2, "evidence structure", || {
+            let evidence = DelegationEvidence::from_value(evidence)?;
+            let links = evidence
+                .chain
+                .iter()
+                .map(|envelope| envelope.decode())
+                .collect::<Result<Vec<_>, _>>()?;
+            let detail = format!("{} link chain", links.len());
+            Ok(((evidence, links), detail))
+        })?;
+        let (evidence, links) = evidence;
+        evaluation.links = links;
+        let resolved = merge_target(&evaluation.target, &evidence.target.clone().unwrap_or_default());
+        if let Ok(target) = &resolved {
+            evaluation.target = target.clone();
+        }
+        let links = evaluation.links.clone();
+
+        for (i, pair) in links.windows(2).enumerate() {
+            let continuous = if pair[0].delegate == pair[1].delegator {
+                Ok(format!("L{} -> L{} via {}", i + 1, i + 2, pair[0].delegate))
+            } else {
+                Err(AmpError::delegation_invalid(format!(
+                    "chain broken at L{}: delegate {} is not delegator {}",
+                    i + 2,
+                    pair[0].delegate,
+                    pair[1].delegator
+                )))
+            };
+            evaluation.check(3, "chain continuity", continuous)?;
+        }
+        if links.len() == 1 {
+            evaluation.check(3, "chain continuity", Ok(String::from("single link")))?;
+        }
+
+        for (i, envelope) in evidence.chain.iter().enumerate() {
+            let verified = envelope.verify(&self.resolver).map(|_| format!("L{} signed by {}", i + 1, links[i].delegator));
+            evaluation.check(4, "signature", verified)?;
+        }
+
+        for (i, link) in links.iter().enumerate() {
+            let window = if link.validity.contains(now_ms) {
+                Ok(format!("L{} valid at {now_ms}", i + 1))
+            } else {
+                Err(AmpError::delegation_invalid(format!(
+                    "delegation {} is not valid at {now_ms} (window {}..{})",
+                    link.delegation_id,
+                    link.validity.effective_not_before(),
+                    link.validity.expires_at
+                )))
+            };
+            evaluation.check(5, "validity window", window)?;
+            let audience = if link.admits_audience(&self.verifier_did) {
+                Ok(format!("L{} admits {}", i + 1, self.verifier_did))
+            } else {
+                Err(AmpError::delegation_invalid(format!(
+                    "delegation {} is not addressed to {}",
+                    link.delegation_id, self.verifier_did
+                )))
+            };
+            evaluation.check(5, "audience", audience)?;
+        }
+
+        for link in &links {
+            let status = self.revocation_status(link, now_ms);
+            evaluation.check(6, "revocation", status)?;
+        }
+
+        let n = links.len();
+        for (i, link) in links.iter().enumerate() {
+            let downstream = n - 1 - i;
+            let permitted = if downstream > 0 && !link.allows_subdelegation() {
+                Err(AmpError::delegation_invalid(format!(
+                    "delegation {} does not allow subdelegation",
+                    link.delegation_id
+                )))
+            } else if link.max_chain_depth.is_some_and(|depth| downstream as u64 > depth) {
+                Err(AmpError::delegation_invalid(format!(
+                    "delegation {} allows {} downstream links, chain has {downstream}",
+                    link.delegation_id,
+                    link.max_chain_depth.unwrap_or_default()
+                )))
+            } else {
+                Ok(format!("L{} has {downstream} downstream links", i + 1))
+            };
+            evaluation.check(7, "subdelegation and depth", permitted)?;
+        }
+
+        let scope = evaluation.check_with(8, "scope narrowing", || {
+            let scope = EffectiveScope::narrow(&links)?;
+            let detail = scope.to_string();
+            Ok((scope, detail))
+        })?;
+        evaluation.capabilities = scope.capabilities.clone();
+
+        let last = &links[n - 1];
+        let bound = if last.delegate == requester_did {
+            Ok(format!("requester is {}", last.delegate))
+        } else {
+            Err(AmpError::unauthorized(format!(
+                "requester {requester_did} is not the chain delegate {}",
+                last.delegate
+            )))
+        };
+        evaluation.check(9, "caller binding", bound)?;
+        let target = evaluation.check_with(9, "evidence target", || {
+            let target = resolved?;
+            Ok((target, String::from("evidence target matches the request")))
+        })?;
+        let within = scope.admits(&target);
+        evaluation.check(9, "target in scope", within)?;
+        for (key, values) in &scope.constraints {
+            let checked = match self.constraints.get(key) {
+                None => Err(AmpError::delegation_invalid(format!("constraint {key} is not supported"))),
+                Some(check) if values.iter().all(|value| check(value, &target)) => {
+                    Ok(format!("{key} satisfied by {} link(s)", values.len()))
+                }
+                Some(_) => Err(AmpError::delegation_invalid(format!("constraint {key} not satisfied"))),
+            };
+            evaluation.check(9, "constraint", checked)?;
+        }
+        Ok(())
+    }
+
+    /// §6.3 for one link: recorded revocations first, then the (cached) source.
+    fn revocation_status(&self, link: &DelegationCredential, now_ms: u64) -> Result<String, AmpError> {
+        let key = (link.delegator.clone(), link.delegation_id.clone());
+        let revoked = |revoked_at: u64| {
+            AmpError::delegation_invalid(format!("delegation {} revoked at {revoked_at}", link.delegation_id))
+        };
+        // The source may be a network query, so the cache is not held across it.
+        let cached = {
+            let cache = self.lock();
+            if let Some(revocation) = cache.revoked.get(&key) {
+                if revocation.revoked_at <= now_ms {
+                    return Err(revoked(revocation.revoked_at));
+                }
+            }
+            cache.statuses.get(&key).cloned()
+        };
+        let Some(source) = &self.source else {
+            return Ok(String::from("no revocation recorded"));
+        };
+
+        let fresh_until = |status: &DelegQueryResult| {
+            let max_age_ms = status.max_age_s.map_or(self.status_max_age_ms, |s| s.saturating_mul(1000));
+            status.updated_at.saturating_add(max_age_ms)
+        };
+        let (status, origin) = match cached {
+            Some(status) if now_ms < fresh_until(&status) => (status, "cached"),
+            cached => match source.status(&link.delegator, &link.delegation_id) {
+                Ok(status) if status.delegator == link.delegator && status.delegation_id == link.delegation_id => {
+                    self.lock().statuses.insert(key, status.clone());
+                    (status, "queried")
+                }
+                Ok(status) => {
+                    return Err(AmpError::unavailable(format!(
+                        "revocation source answered for {}/{}",
+                        status.delegator, status.delegation_id
+                    )))
+                }
+                Err(e) => match cached {
+                    Some(status) if now_ms < fresh_until(&status).saturating_add(self.offline_grace_ms) => {
+                        (status, "stale, source unavailable")
+                    }
+                    _ => {
+                        return Err(AmpError::unavailable(format!(
+                            "revocation source unavailable for {}: {}",
+                            link.delegation_id, e.detail
+                        )))
+                    }
+                },
+            },
+        };
+        match status.status {
+            DelegationStatus::Revoked if status.revoked_at.is_none_or(|at| at <= now_ms) => {
+                Err(revoked(status.revoked_at.unwrap_or(status.updated_at)))
+            }
+            DelegationStatus::Expired => Err(AmpError::delegation_invalid(format!(
+                "delegation {} reported expired",
+                link.delegation_id
+            ))),
+            _ => Ok(format!("{:?} ({origin})", status.status).to_lowercase()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RevocationCache> {
+        self.cache.lock().expect("revocation cache poisoned")
+    }
+}
+
+struct Evaluation {
+    target: DelegationTarget,
+    links: Vec<DelegationCredential>,
+    capabilities: Option<Vec<String>>,
+    audit: Vec<DelegationAuditStep>,
+}
+
+impl Evaluation {
+    fn check(&mut self, step: u8, check: &'static str, result: Result<String, AmpError>) -> Result<(), AmpError> {
+        self.check_with(step, check, || result.map(|detail| ((), detail)))
+    }
+
+    fn check_with<T>(
+        &mut self,
+        step: u8,
+        check: &'static str,
+        run: impl FnOnce() -> Result<(T, String), AmpError>,
+    ) -> Result<T, AmpError> {
+        let (result, passed, detail) = match run() {
+            Ok((value, detail)) => (Ok(value), true, detail),
+            Err(e) => {
+                let detail = e.detail.clone();
+                (Err(e), false, detail)
+            }
+        };
+        self.audit.push(DelegationAuditStep {
+            step,
+            check,
+            passed,
+            detail,
+        });
+        result
+    }
+}
+
+/// `E(n, d)` for each dimension (`None` is `ANY_d`) plus every link's constraints.
+struct EffectiveScope {
+    capabilities: Option<Vec<String>>,
+    actions: Option<Vec<String>>,
+    resources: Option<Vec<String>>,
+    constraints: Vec<(String, Vec<Value>)>,
+}
+
+impl EffectiveScope {
+    /// §6.2: a present dimension must be a subset of the one before it (`3004`).
+    fn narrow(links: &[DelegationCredential]) -> Result<Self, AmpError> {
+        let mut scope = Self {
+            capabilities: None,
+            actions: None,
+            resources: None,
+            constraints: Vec::new(),
+        };
+        for link in links {
+            let id = &link.delegation_id;
+            narrow_dimension(&mut scope.capabilities, &link.scope.capabilities, selector_covers, "capabilities", id)?;
+            narrow_dimension(&mut scope.actions, &link.scope.actions, |a, b| a == b, "actions", id)?;
+            narrow_dimension(&mut scope.resources, &link.scope.resources, |a, b| a == b, "resources", id)?;
+            for (key, value) in link.scope.constraints.iter().flatten() {
+                match scope.constraints.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, values)) => values.push(value.clone()),
+                    None => scope.constraints.push((key.clone(), vec![value.clone()])),
+                }
+            }
+        }
+        Ok(scope)
+    }
+
+    fn admits(&self, target: &DelegationTarget) -> Result<String, AmpError> {
+        for (dimension, allowed, wanted, covers) in [
+            ("capability", &self.capabilities, &target.capability, admits_capability as fn(&str, &str) -> bool),
+            ("action", &self.actions, &target.action, |a: &str, b: &str| a == b),
+            ("resource", &self.resources, &target.resource, |a: &str, b: &str| a == b),
+        ] {
+            let Some(allowed) = allowed else { continue };
+            let Some(wanted) = wanted else {
+                return Err(AmpError::delegation_invalid(format!(
+                    "scope restricts {dimension} but the target names none"
+                )));
+            };
+            if !allowed.iter().any(|selector| covers(selector, wanted)) {
+                return Err(AmpError::delegation_invalid(format!("{dimension} {wanted} is outside the delegated scope")));
+            }
+        }
+        Ok(String::from("target within effective scope"))
+    }
+}
+
+impl fmt::Display for EffectiveScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dimension = |values: &Option<Vec<String>>| match values {
+            Some(values) => format!("[{}]", values.join(", ")),
+            None => String::from("ANY"),
+        };
+        write!(
+            f,
+            "capabilities={} actions={} resources={}",
+            dimension(&self.capabilities),
+            dimension(&self.actions),
+            dimension(&self.resources)
+        )?;
+        if !self.constraints.is_empty() {
+            let keys: Vec<&str> = self.constraints.iter().map(|(key, _)| key.as_str()).collect();
+            write!(f, " constraints=[{}]", keys.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn narrow_dimension(
+    effective: &mut Option<Vec<String>>,
+    link: &Option<Vec<String>>,
+    covers: fn(&str, &str) -> bool,
+    dimension: &str,
+    delegation_id: &str,
+) -> Result<(), AmpError> {
+    let Some(values) = link else { return Ok(()) };
+    if let Some(parent) = effective {
+        if let Some(expanded) = values.iter().find(|value| !parent.iter().any(|p| covers(p, value))) {
+            return Err(AmpError::delegation_invalid(format!(
+                "delegation {delegation_id} expands {dimension} with {expanded}"
+            )));
+        }
+    }
+    *effective = Some(values.clone());
+    Ok(())
+}
+
+/// An exact name covers itself and every `capability-id` of that name; an id covers
+/// only itself.
+fn selector_covers(selector: &str, capability: &str) -> bool {
+    selector == capability
+        || (!selector.contains(':')
+            && capability.split_once(':').is_some_and(|(name, _)| name == selector))
+}
+
+/// [`selector_covers`], plus a bare target name is admitted by a `capability-id` of it:
+/// some version is in scope, and the one that runs is confirmed later.
+fn admits_capability(selector: &str, capability: &str) -> bool {
+    selector_covers(selector, capability)
+        || (!capability.contains(':') && selector.split_once(':').is_some_and(|(name, _)| name == capability))
+}
+
+/// Same capability name, and the same version when both name one.
+fn same_capability(a: &str, b: &str) -> bool {
+    match (a.split_once(':'), b.split_once(':')) {
+        (Some(_), Some(_)) => a == b,
+        (Some((name, _)), None) => name == b,
+        (None, Some((name, _))) => a == name,
+        (None, None) => a == b,
+    }
+}
+
+/// Fills unknown target fields from the evidence `target` and rejects claims that
+/// contradict what the verifier knows.
+fn merge_target(known: &DelegationTarget, claimed: &DelegationTarget) -> Result<DelegationTarget, AmpError> {
+    let merge = |dimension: &str, known: &Option<String>, claimed: &Option<String>, agree: fn(&str, &str) -> bool| {
+        match (known, claimed) {
+            (Some(known), Some(claimed)) if !agree(claimed, known) => Err(AmpError::delegation_invalid(format!(
+                "evidence target {dimension} {claimed} does not match {known}"
+            ))),
+            (Some(known), _) => Ok(Some(known.clone())),
+            (None, claimed) => Ok(claimed.clone()),
+        }
+    };
+    Ok(DelegationTarget {
+        capability: merge("capability", &known.capability, &claimed.capability, same_capability)?,
+        action: merge("action", &known.action, &claimed.action, |a, b| a == b)?,
+        resource: merge("resource", &known.resource, &claimed.resource, |a, b| a == b)?,
+    })
+}
@@ -1,22 +1,21 @@
 use std::io;
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use amp001_example::{
-    build_authcrypt_signed, build_plain_signed, demo_agents, make_message_id, now_ms, read_frame,
-    receive_and_verify, validate_ack_semantics, write_frame, AckBody, AckSource, AgentKeys,
-    DidResolver, HelloBody, MessageMeta, Recipients, TextMessageBody, TYPE_ACK, TYPE_HELLO,
-    TYPE_MESSAGE,
+    demo_agents, hex_encode, resolve_target_did, split_first, validate_ack_semantics, AckBody,
+    Agent, AgentEvent, AgentHandle, Backoff, HelloBody, HelloHandshake, InboxQuery,
+    KeepaliveConfig, Mailbox, TextMessageBody, DEFAULT_AGENT_TTL_MS, TYPE_ACK, TYPE_HELLO,
 };
 
+const RECONNECT_INITIAL: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
         return Err(
-            "usage: cargo run --bin amp002-client -- <alice|bob> [server_addr] [--once <alice|bob|did> <text>]"
+            "usage: cargo run --bin amp002-client -- <alice|bob> [server_addr] [--mailbox <dir>] [--once <alice|bob|did> <text>]"
                 .into(),
         );
     }
@@ -30,55 +29,119 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         cursor += 1;
     }
 
-    let once = if let Some(flag) = args.get(cursor) {
-        if flag == "--once" {
-            if cursor + 2 >= args.len() {
-                return Err("--once requires <target> <text>".into());
+    let mut once = None;
+    let mut mailbox = None;
+    while let Some(flag) = args.get(cursor) {
+        match flag.as_str() {
+            "--mailbox" => {
+                let dir = args.get(cursor + 1).ok_or("--mailbox requires <dir>")?;
+                mailbox = Some(Mailbox::open(dir)?);
+                cursor += 2;
             }
-            let target = args[cursor + 1].clone();
-            let text = args[cursor + 2..].join(" ");
-            Some((target, text))
-        } else {
-            return Err(format!("unknown flag: {flag}").into());
+            "--once" => {
+                if cursor + 2 >= args.len() {
+                    return Err("--once requires <target> <text>".into());
+                }
+                let target = args[cursor + 1].clone();
+                let text = args[cursor + 2..].join(" ");
+                once = Some((target, text));
+                break;
+            }
+            _ => return Err(format!("unknown flag: {flag}").into()),
         }
-    } else {
-        None
-    };
+    }
 
     let demo = demo_agents();
     let me = demo
         .by_name(name)
         .ok_or("client name must be one of: alice, bob")?;
     let resolver = demo.resolver();
+    let did = me.did.clone();
 
-    let stream = TcpStream::connect(&server_addr)?;
-    stream.set_nodelay(true)?;
-
+    let mut builder = Agent::builder(me.clone());
+    if let Some(mailbox) = mailbox {
+        println!("[client:{name}] mailbox at {}", mailbox.dir().display());
+        builder = builder.mailbox(mailbox);
+    }
+    let agent = builder
+        .resolver(resolver.clone())
+        .connect_tcp(server_addr.clone())
+        .handshake(HelloHandshake {
+            relay_did: demo.relay.did.clone(),
+            versions: vec!["0.30.0".to_string()],
+        })
+        .keepalive(KeepaliveConfig::default())
+        .reconnect_backoff(Backoff::exponential(RECONNECT_INITIAL, RECONNECT_MAX), None)
+        .replay_unacked(true)
+        .on_message(|agent, message, body| {
+            println!("[recv:{}] from {}: {}", agent.did(), message.meta.from, body.msg);
+            Ok(())
+        })
+        .on(TYPE_ACK, |agent, message, ack: AckBody| {
+            let expected_sender = vec![message.meta.from.clone()];
+            let checked = agent.with_resolver(|r| validate_ack_semantics(message, &expected_sender, r));
+            if let Err(err) = checked {
+                eprintln!("[recv:{}] ACK semantic check failed: {err}", agent.did());
+                return Ok(());
+            }
+            let reply_to = message
+                .meta
+                .reply_to
+                .map(|id| hex_encode(&id))
+                .unwrap_or_else(|| "none".to_string());
+            println!(
+                "[recv:{}] ACK from {} source={:?} reply_to={reply_to}",
+                agent.did(), message.meta.from, ack.ack_source
+            );
+            Ok(())
+        })
+        .on(TYPE_HELLO, |agent, message, hello: HelloBody| {
+            println!(
+                "[recv:{}] HELLO from {} {:?}",
+                agent.did(), message.meta.from, hello.versions
+            );
+            Ok(())
+        })
+        .on_unhandled(|agent, message| {
+            println!(
+                "[recv:{}] typ=0x{:02x} from={}",
+                agent.did(), message.meta.typ, message.meta.from
+            );
+            Ok(())
+        })
+        .on_event(move |event| match event {
+            AgentEvent::Connected { .. } => println!("[client:{did}] registration HELLO sent"),
+            AgentEvent::Rejected { error, .. } => eprintln!("[recv:{did}] rejected frame: {error}"),
+            AgentEvent::HandlerFailed { typ, error, .. } => {
+                eprintln!("[recv:{did}] typ=0x{typ:02x} handling failed: {}", error.message)
+            }
+            AgentEvent::KeepaliveLost(health) => eprintln!(
+                "[client:{did}] relay stopped answering PING (missed={}); closing",
+                health.missed_pongs
+            ),
+            AgentEvent::Replayed { count } => {
+                println!("[client:{did}] re-sent {count} unacknowledged message(s)")
+            }
+            AgentEvent::Disconnected { reason } => {
+                eprintln!("[client:{did}] connection lost: {reason}; reconnecting")
+            }
+            _ => {}
+        })
+        .build()?;
+    let (handle, _run) = agent.spawn();
+    if !handle.wait_connected(Duration::from_secs(5)) {
+        return Err(format!("could not connect to {server_addr}").into());
+    }
     println!("[client:{name}] connected to {server_addr}");
 
-    let reader = stream.try_clone()?;
-    let writer = Arc::new(Mutex::new(stream));
-    let counter = Arc::new(AtomicU64::new(1));
-
-    send_hello_registration(&me, &demo.relay.did, &writer, &counter)?;
-
-    let recv_writer = Arc::clone(&writer);
-    let recv_counter = Arc::clone(&counter);
-    let recv_resolver = resolver.clone();
-    let recv_me = me.clone();
-
-    thread::spawn(move || {
-        if let Err(err) = receiver_loop(reader, recv_writer, recv_counter, recv_me, recv_resolver) {
-            eprintln!("[client] receiver loop stopped: {err}");
-        }
-    });
-
     if let Some((target, text)) = once {
         let target_did = resolve_target_did(&demo, &resolver, &target)
             .map_err(|v| io::Error::new(io::ErrorKind::InvalidInput, v))?;
-        send_text_message(&me, &resolver, &writer, &counter, &target_did, &text)?;
+        handle.send_text(&target_did, &text, DEFAULT_AGENT_TTL_MS)?;
+        println!("[client:{}] sent encrypted MESSAGE to {target_did}", me.did);
         thread::sleep(Duration::from_millis(600));
         println!("[client:{name}] one-shot mode done");
+        handle.shutdown();
         return Ok(());
     }
 
@@ -90,6 +153,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("commands:");
     println!("  /send <alice|bob|did> <text>");
+    println!("  /inbox [alice|bob|did]");
     println!("  /quit");
     println!("default: type plain text to send to {default_target}");
 
@@ -111,259 +175,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             break;
         }
 
-        if let Some(rest) = input.strip_prefix("/send ") {
-            if let Some((target_token, text)) = split_first(rest) {
-                let target_did = match resolve_target_did(&demo, &resolver, target_token) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[client:{name}] {err}");
-                        continue;
-                    }
-                };
-                if let Err(err) =
-                    send_text_message(&me, &resolver, &writer, &counter, &target_did, text)
-                {
-                    eprintln!("[client:{name}] send failed: {err}");
-                }
-            } else {
-                eprintln!("usage: /send <alice|bob|did> <text>");
-            }
+        if let Some(rest) = input.strip_prefix("/inbox") {
+            let from = Some(rest.trim()).filter(|v| !v.is_empty()).map(|v| demo.did_for_alias(v));
+            print_inbox(&handle, InboxQuery { from, ..InboxQuery::default() });
             continue;
         }
 
-        if let Err(err) = send_text_message(&me, &resolver, &writer, &counter, &default_target, input)
-        {
-            eprintln!("[client:{name}] send failed: {err}");
-        }
-    }
-
-    Ok(())
-}
-
-fn receiver_loop(
-    mut reader: TcpStream,
-    writer: Arc<Mutex<TcpStream>>,
-    counter: Arc<AtomicU64>,
-    me: AgentKeys,
-    resolver: DidResolver,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let frame = read_frame(&mut reader)?;
-        let message = match receive_and_verify(&me, &frame, &resolver, now_ms()) {
-            Ok(msg) => msg,
-            Err(err) => {
-                eprintln!("[recv:{}] rejected frame: {}", me.did, err);
+        let (target_did, text) = if let Some(rest) = input.strip_prefix("/send ") {
+            let Some((target_token, text)) = split_first(rest) else {
+                eprintln!("usage: /send <alice|bob|did> <text>");
                 continue;
-            }
-        };
-
-        match message.meta.typ {
-            TYPE_MESSAGE => {
-                let body: TextMessageBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode message body failed: {}", me.did, err);
-                        continue;
-                    }
-                };
-                println!("[recv:{}] from {}: {}", me.did, message.meta.from, body.msg);
-
-                if let Err(err) =
-                    send_ack(&me, &writer, &counter, &message.meta.from, message.meta.id)
-                {
-                    eprintln!("[recv:{}] send ACK failed: {}", me.did, err);
+            };
+            match resolve_target_did(&demo, &resolver, target_token) {
+                Ok(v) => (v, text),
+                Err(err) => {
+                    eprintln!("[client:{name}] {err}");
+                    continue;
                 }
             }
-            TYPE_ACK => {
-                let ack: AckBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode ACK failed: {}", me.did, err);
-                        continue;
-                    }
-                };
+        } else {
+            (default_target.clone(), input)
+        };
 
-                let expected_sender = vec![message.meta.from.clone()];
-                if let Err(err) = validate_ack_semantics(&message, &expected_sender, &resolver) {
-                    eprintln!("[recv:{}] ACK semantic check failed: {}", me.did, err);
-                } else {
-                    let reply_to = message
-                        .meta
-                        .reply_to
-                        .map(|id| hex16(&id))
-                        .unwrap_or_else(|| "none".to_string());
-                    println!(
-                        "[recv:{}] ACK from {} source={:?} reply_to={}",
-                        me.did, message.meta.from, ack.ack_source, reply_to
-                    );
-                }
-            }
-            TYPE_HELLO => {
-                let hello: HelloBody = match message.decode_body() {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("[recv:{}] decode HELLO failed: {}", me.did, err);
-                        continue;
-                    }
-                };
-                println!(
-                    "[recv:{}] HELLO from {} {:?}",
-                    me.did, message.meta.from, hello.versions
-                );
-            }
-            other => {
-                println!("[recv:{}] typ=0x{other:02x} from={}", me.did, message.meta.from);
-            }
+        match handle.send_text(&target_did, text, DEFAULT_AGENT_TTL_MS) {
+            Ok(_) => println!("[client:{}] sent encrypted MESSAGE to {target_did}", me.did),
+            Err(err) => eprintln!("[client:{name}] send failed: {err}"),
         }
     }
-}
-
-fn send_hello_registration(
-    me: &AgentKeys,
-    relay_did: &str,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-    let body = HelloBody {
-        versions: vec!["0.30.0".to_string()],
-    };
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_HELLO,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(relay_did.to_string()),
-        reply_to: None,
-        thread_id: None,
-    };
-
-    let wire = build_plain_signed(me, meta, &body)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
-    println!("[client:{}] registration HELLO sent", me.did);
-
-    Ok(())
-}
-
-fn send_text_message(
-    me: &AgentKeys,
-    resolver: &DidResolver,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-    target_did: &str,
-    text: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if resolver.key_agreement_for(target_did).is_none() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!(
-                "unknown recipient DID or missing keyAgreement key: {target_did}",
-            ),
-        )
-        .into());
-    }
-
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_MESSAGE,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(target_did.to_string()),
-        reply_to: None,
-        thread_id: None,
-    };
 
-    let body = TextMessageBody {
-        msg: text.to_string(),
-    };
-
-    let wire = build_authcrypt_signed(me, target_did, meta, &body, resolver)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
-
-    println!("[client:{}] sent encrypted MESSAGE to {target_did}", me.did);
-    Ok(())
-}
-
-fn send_ack(
-    me: &AgentKeys,
-    writer: &Arc<Mutex<TcpStream>>,
-    counter: &AtomicU64,
-    target_did: &str,
-    reply_to: [u8; 16],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ts = now_ms();
-    let id = make_message_id(ts, counter.fetch_add(1, Ordering::Relaxed));
-    let body = AckBody {
-        ack_source: AckSource::Recipient,
-        received_at: ts,
-        ack_target: None,
-    };
-
-    let meta = MessageMeta {
-        v: 1,
-        id,
-        typ: TYPE_ACK,
-        ts_ms: ts,
-        ttl_ms: 60_000,
-        from: String::new(),
-        to: Recipients::One(target_did.to_string()),
-        reply_to: Some(reply_to),
-        thread_id: None,
-    };
-
-    let wire = build_plain_signed(me, meta, &body)?;
-    let mut guard = writer.lock().expect("writer poisoned");
-    write_frame(&mut *guard, &wire)?;
+    handle.shutdown();
     Ok(())
 }
 
-fn split_first(input: &str) -> Option<(&str, &str)> {
-    let mut parts = input.splitn(2, ' ');
-    let first = parts.next()?.trim();
-    let rest = parts.next()?.trim();
-    if first.is_empty() || rest.is_empty() {
-        return None;
-    }
-    Some((first, rest))
-}
-
-fn resolve_target_did(
-    demo: &amp001_example::DemoAgents,
-    resolver: &DidResolver,
-    target_token: &str,
-) -> Result<String, String> {
-    let token = target_token.trim();
-    let mapped = demo.did_for_alias(token);
-
-    if mapped.starts_with("did:") {
-        if resolver.key_agreement_for(&mapped).is_some() {
-            return Ok(mapped);
+fn print_inbox(handle: &AgentHandle, query: InboxQuery) {
+    let printed = handle.with_mailbox(|mailbox| {
+        for entry in mailbox.query(&query) {
+            let meta = &entry.message.meta;
+            let text = entry
+                .message
+                .decode_body::<TextMessageBody>()
+                .map(|body| body.msg)
+                .unwrap_or_else(|_| format!("typ=0x{:02x}", meta.typ));
+            let acked = if entry.acked_at.is_some() { "acked" } else { "unacked" };
+            println!("  [{}] {acked} {}: {text}", meta.ts_ms, meta.from);
         }
-        return Err(format!(
-            "target DID not available in local resolver: {mapped} (use alice/bob or a known DID)",
-        ));
-    }
-
-    Err(format!(
-        "invalid target '{token}': use alice, bob, relay, or a full DID",
-    ))
-}
-
-fn hex16(id: &[u8; 16]) -> String {
-    let mut out = String::with_capacity(32);
-    for b in id {
-        out.push_str(&format!("{b:02x}"));
+    });
+    if printed.is_none() {
+        eprintln!("no mailbox; start the client with --mailbox <dir>");
     }
-    out
 }
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use amp001_example::{demo_agents, hex_decode, hex_encode, now_ms, AgentKeys};
+use amp002_004_tests::{BundleBuilder, BundleResolver, HashAlg, SCHEMA_MEDIA_TYPE_JSON};
+
+const USAGE: &str = "usage: amp004-bundle build --id <bundle_id> --out <file> [--signer <alice|bob|relay|did>] \
+                     [--key-seed <hex>] [--hash-alg <sha-256|sha-512>] <artifact_key>=<path>...\n       \
+                     amp004-bundle inspect <file> --trust <alice|bob|relay|did[=<public_key_hex>]>...";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("build") => build(&args[1..]),
+        Some("inspect") => inspect(&args[1..]),
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn build(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut bundle_id = None;
+    let mut out = None;
+    let mut signer = "relay".to_string();
+    let mut key_seed = None;
+    let mut hash_alg = HashAlg::Sha256;
+    let mut artifacts = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            let (key, path) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("artifact {arg} must be <artifact_key>=<path>; {USAGE}"))?;
+            artifacts.push((key.to_string(), PathBuf::from(path)));
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{arg} requires a value; {USAGE}"))?;
+        match arg.as_str() {
+            "--id" => bundle_id = Some(value.clone()),
+            "--out" => out = Some(PathBuf::from(value)),
+            "--signer" => signer = value.clone(),
+            "--key-seed" => key_seed = Some(decode_key32(value, "--key-seed")?),
+            "--hash-alg" => {
+                hash_alg = match value.as_str() {
+                    "sha-256" => HashAlg::Sha256,
+                    "sha-512" => HashAlg::Sha512,
+                    other => return Err(format!("unsupported hash alg {other}; {USAGE}").into()),
+                }
+            }
+            other => return Err(format!("unknown option {other}; {USAGE}").into()),
+        }
+    }
+    let (bundle_id, out) = bundle_id.zip(out).ok_or(USAGE)?;
+    if artifacts.is_empty() {
+        return Err(format!("no artifacts given; {USAGE}").into());
+    }
+    // A seed signs as the DID given in --signer; otherwise --signer names a demo identity.
+    let issuer = match key_seed {
+        Some(seed) => AgentKeys::from_sign_seed(&signer, seed),
+        None => demo_agents()
+            .by_name(&signer)
+            .ok_or_else(|| format!("unknown signer {signer}; pass --key-seed to sign as a DID; {USAGE}"))?,
+    };
+
+    let mut builder = BundleBuilder::new(&bundle_id).hash_alg(hash_alg);
+    for (key, path) in &artifacts {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        builder = builder.artifact_with_media_type(key, media_type_for(path), bytes);
+    }
+    let bytes = builder.sign(&issuer, now_ms()).map_err(|e| e.detail)?;
+    fs::write(&out, bytes).map_err(|e| format!("{}: {e}", out.display()))?;
+
+    println!("bundle {bundle_id} signed by {} -> {}", issuer.did, out.display());
+    println!("  issuer key {}", hex_encode(issuer.signing_public_key.as_bytes()));
+    for (key, _) in &artifacts {
+        if let Some(schema_ref) = builder.schema_ref(key) {
+            println!("  {key} {}:{}", hash_alg.as_str(), hex_encode(schema_ref.hash()));
+        }
+    }
+    Ok(())
+}
+
+fn inspect(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (path, rest) = args.split_first().ok_or(USAGE)?;
+    let demo = demo_agents();
+    let mut resolver = demo.resolver();
+    let mut trusted = Vec::new();
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest
+            .next()
+            .ok_or_else(|| format!("{flag} requires a value; {USAGE}"))?;
+        match flag.as_str() {
+            // `did=<hex>` publishes the issuer's key for DIDs outside the demo set.
+            "--trust" => match value.split_once('=') {
+                Some((did, key)) => {
+                    resolver.add_signing_key(did, &decode_key32(key, "--trust key")?).map_err(|e| e.detail)?;
+                    trusted.push(did.to_string());
+                }
+                None => trusted.push(demo.did_for_alias(value)),
+            },
+            other => return Err(format!("unknown option {other}; {USAGE}").into()),
+        }
+    }
+    if trusted.is_empty() {
+        return Err(format!("inspect needs at least one --trust issuer; {USAGE}").into());
+    }
+    let mut bundles = BundleResolver::new(resolver);
+    for did in trusted {
+        bundles = bundles.trust_issuer(did);
+    }
+
+    let manifest = bundles
+        .load_file(Path::new(path))
+        .map_err(|e| format!("{path}: {} {}", e.code, e.detail))?;
+    println!(
+        "bundle {} issued by {} (bundle_v {}, created_ms {})",
+        manifest.bundle_id, manifest.issuer, manifest.bundle_v, manifest.created_ms
+    );
+    for entry in &manifest.artifacts {
+        println!(
+            "  {} {} {} bytes {}:{}",
+            entry.key,
+            entry.media_type,
+            entry.size,
+            entry.hash_alg.as_str(),
+            hex_encode(&entry.hash)
+        );
+    }
+    Ok(())
+}
+
+fn decode_key32(hex: &str, what: &str) -> Result<[u8; 32], String> {
+    hex_decode(hex)
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| format!("{what} must be 32 bytes of hex"))
+}
+
+fn media_type_for(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("cddl") => "application/cddl",
+        _ => SCHEMA_MEDIA_TYPE_JSON,
+    }
+}
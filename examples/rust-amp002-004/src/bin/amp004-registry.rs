@@ -0,0 +1,42 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use amp002_004_tests::{serve_registry, CachePolicy, RegistryStore};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let usage = "usage: amp004-registry [addr] --dir <registry-dir> [--max-age <s>] \
+                 [--stale-while-revalidate <s>]";
+    let mut args = std::env::args().skip(1).peekable();
+    let addr = match args.peek() {
+        Some(first) if !first.starts_with("--") => args.next().unwrap_or_default(),
+        _ => "127.0.0.1:7004".to_string(),
+    };
+    let mut dir: Option<PathBuf> = None;
+    let mut policy = CachePolicy::default();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{flag} requires a value; {usage}"))?;
+        match flag.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value)),
+            "--max-age" => policy.max_age_ms = Some(value.parse::<u64>()? * 1000),
+            "--stale-while-revalidate" => policy.stale_while_revalidate_ms = Some(value.parse::<u64>()? * 1000),
+            other => return Err(format!("unknown option {other}; {usage}").into()),
+        }
+    }
+    let dir = dir.ok_or(usage)?;
+
+    let store = RegistryStore::load_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e.detail))?;
+    let listener = TcpListener::bind(&addr)?;
+    println!("AMP RFC004 registry listening on {}", listener.local_addr()?);
+    for id in store.ids() {
+        println!("publishing {id}");
+    }
+    if let Some(cache_control) = policy.cache_control() {
+        println!("cache-control: {cache_control}");
+    }
+
+    serve_registry(&listener, &Mutex::new(store), policy)?;
+    Ok(())
+}
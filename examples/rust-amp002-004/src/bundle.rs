@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use amp001_example::{cose_sign1_sign, cose_sign1_verify, to_cbor_canonical, AgentKeys, AmpError, DidResolver};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{HashAlg, SchemaRef, SCHEMA_MEDIA_TYPE_JSON};
+
+/// `bundle_v` this implementation writes and accepts.
+pub const BUNDLE_FORMAT_V1: u64 = 1;
+/// File extension `BundleResolver::load_dir` picks up.
+pub const BUNDLE_FILE_EXTENSION: &str = "ampbundle";
+
+/// One artifact as listed in the signed manifest.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BundleArtifactEntry {
+    pub key: String,
+    pub media_type: String,
+    pub hash_alg: HashAlg,
+    pub hash: ByteBuf,
+    pub size: u64,
+}
+
+/// Signed part of a bundle: who issued it and what every artifact must hash to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BundleManifest {
+    pub bundle_v: u64,
+    pub bundle_id: String,
+    pub issuer: String,
+    pub created_ms: u64,
+    pub artifacts: Vec<BundleArtifactEntry>,
+}
+
+impl BundleManifest {
+    pub fn entry(&self, key: &str) -> Option<&BundleArtifactEntry> {
+        self.artifacts.iter().find(|entry| entry.key == key)
+    }
+}
+
+/// On-disk form: `manifest_sig` is a COSE_Sign1 over the deterministic CBOR manifest,
+/// `artifacts` carries the raw bytes keyed by `artifact_key`.
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleFile {
+    manifest_sig: ByteBuf,
+    artifacts: BTreeMap<String, ByteBuf>,
+}
+
+/// Collects artifacts and signs them into a bundle file (RFC 004 §5.3 offline profile).
+#[derive(Debug, Clone)]
+pub struct BundleBuilder {
+    bundle_id: String,
+    hash_alg: HashAlg,
+    artifacts: BTreeMap<String, (String, Vec<u8>)>,
+}
+
+impl BundleBuilder {
+    pub fn new(bundle_id: impl Into<String>) -> Self {
+        Self {
+            bundle_id: bundle_id.into(),
+            hash_alg: HashAlg::Sha256,
+            artifacts: BTreeMap::new(),
+        }
+    }
+
+    /// Digest used for manifest entries; `sha-256` by default.
+    pub fn hash_alg(mut self, hash_alg: HashAlg) -> Self {
+        self.hash_alg = hash_alg;
+        self
+    }
+
+    /// Adds a JSON Schema artifact under `key`, replacing any earlier one.
+    pub fn artifact(self, key: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Self {
+        self.artifact_with_media_type(key, SCHEMA_MEDIA_TYPE_JSON, bytes)
+    }
+
+    pub fn artifact_with_media_type(
+        mut self,
+        key: impl Into<String>,
+        media_type: impl Into<String>,
+        bytes: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.artifacts.insert(key.into(), (media_type.into(), bytes.into()));
+        self
+    }
+
+    /// Offline schema reference for an artifact already added under `key`.
+    pub fn schema_ref(&self, key: &str) -> Option<SchemaRef> {
+        let (media_type, bytes) = self.artifacts.get(key)?;
+        let mut schema_ref = SchemaRef::offline(&self.bundle_id, key, self.hash_alg, bytes);
+        if media_type != SCHEMA_MEDIA_TYPE_JSON {
+            if let SchemaRef::Offline(offline) = &mut schema_ref {
+                offline.media_type = Some(media_type.clone());
+            }
+        }
+        Some(schema_ref)
+    }
+
+    /// Signs the manifest with the `assertionMethod` key of `issuer` and returns the
+    /// bundle file bytes.
+    pub fn sign(&self, issuer: &AgentKeys, now_ms: u64) -> Result<Vec<u8>, AmpError> {
+        if self.bundle_id.is_empty() {
+            return Err(AmpError::invalid_message("bundle_id must not be empty"));
+        }
+        if self.artifacts.is_empty() {
+            return Err(AmpError::invalid_message(format!("bundle {} has no artifacts", self.bundle_id)));
+        }
+        if self.artifacts.keys().any(String::is_empty) {
+            return Err(AmpError::invalid_message("artifact_key must not be empty"));
+        }
+        let manifest = BundleManifest {
+            bundle_v: BUNDLE_FORMAT_V1,
+            bundle_id: self.bundle_id.clone(),
+            issuer: issuer.did.clone(),
+            created_ms: now_ms,
+            artifacts: self
+                .artifacts
+                .iter()
+                .map(|(key, (media_type, bytes))| BundleArtifactEntry {
+                    key: key.clone(),
+                    media_type: media_type.clone(),
+                    hash_alg: self.hash_alg,
+                    hash: ByteBuf::from(self.hash_alg.digest(bytes)),
+                    size: bytes.len() as u64,
+                })
+                .collect(),
+        };
+        let manifest_sig = cose_sign1_sign(issuer, &issuer.assertion_kid(), &to_cbor_canonical(&manifest)?)?;
+        to_cbor_canonical(&BundleFile {
+            manifest_sig: ByteBuf::from(manifest_sig),
+            artifacts: self
+                .artifacts
+                .iter()
+                .map(|(key, (_, bytes))| (key.clone(), ByteBuf::from(bytes.clone())))
+                .collect(),
+        })
+    }
+}
+
+/// A bundle whose signature and artifact hashes have been checked.
+#[derive(Debug, Clone)]
+pub struct RegistryBundle {
+    manifest: BundleManifest,
+    artifacts: BTreeMap<String, Vec<u8>>,
+}
+
+impl RegistryBundle {
+    /// Decodes and verifies a bundle file. Structural problems are `1001`, a manifest
+    /// signature that does not verify or is not made by `issuer` is `3001`, and an
+    /// artifact that is missing or does not match its manifest entry is `5002`.
+    pub fn open(bytes: &[u8], resolver: &DidResolver) -> Result<Self, AmpError> {
+        let file: BundleFile = serde_cbor::from_slice(bytes)
+            .map_err(|e| AmpError::invalid_message(format!("invalid bundle file: {e}")))?;
+        let signed = cose_sign1_verify(&file.manifest_sig, resolver)
+            .map_err(|e| AmpError::unauthorized(format!("bundle manifest signature invalid: {}", e.detail)))?;
+        let manifest: BundleManifest = serde_cbor::from_slice(&signed.payload)
+            .map_err(|e| AmpError::invalid_message(format!("invalid bundle manifest: {e}")))?;
+        if manifest.bundle_v != BUNDLE_FORMAT_V1 {
+            return Err(AmpError::unsupported_version(format!(
+                "unsupported bundle_v={}, expected {BUNDLE_FORMAT_V1}",
+                manifest.bundle_v
+            )));
+        }
+        if manifest.bundle_id.is_empty() {
+            return Err(AmpError::invalid_message("bundle_id must not be empty"));
+        }
+        if signed.kid_did() != manifest.issuer {
+            return Err(AmpError::unauthorized(format!(
+                "bundle {} signed by {} but issued by {}",
+                manifest.bundle_id, signed.kid, manifest.issuer
+            )));
+        }
+
+        let mut artifacts = BTreeMap::new();
+        let mut files = file.artifacts;
+        for entry in &manifest.artifacts {
+            if entry.hash.len() != entry.hash_alg.digest_len() {
+                return Err(AmpError::invalid_message(format!(
+                    "bundle {} artifact {} hash must be {} bytes",
+                    manifest.bundle_id,
+                    entry.key,
+                    entry.hash_alg.digest_len()
+                )));
+            }
+            let bytes = files.remove(&entry.key).ok_or_else(|| {
+                AmpError::unavailable(format!("bundle {} is missing artifact {}", manifest.bundle_id, entry.key))
+            })?;
+            if bytes.len() as u64 != entry.size || entry.hash_alg.digest(&bytes) != entry.hash.as_ref() {
+                return Err(AmpError::unavailable(format!(
+                    "bundle {} artifact {} does not match its manifest entry",
+                    manifest.bundle_id, entry.key
+                )));
+            }
+            if artifacts.insert(entry.key.clone(), bytes.into_vec()).is_some() {
+                return Err(AmpError::invalid_message(format!(
+                    "bundle {} lists artifact {} twice",
+                    manifest.bundle_id, entry.key
+                )));
+            }
+        }
+        if let Some(extra) = files.keys().next() {
+            return Err(AmpError::invalid_message(format!(
+                "bundle {} carries artifact {extra} not covered by the manifest",
+                manifest.bundle_id
+            )));
+        }
+        Ok(Self { manifest, artifacts })
+    }
+
+    pub fn manifest(&self) -> &BundleManifest {
+        &self.manifest
+    }
+
+    pub fn artifact(&self, key: &str) -> Option<&[u8]> {
+        self.artifacts.get(key).map(Vec::as_slice)
+    }
+}
+
+/// Serves schema references from locally loaded bundles; it never touches the network.
+#[derive(Debug, Clone)]
+pub struct BundleResolver {
+    resolver: DidResolver,
+    trusted_issuers: HashSet<String>,
+    bundles: HashMap<String, RegistryBundle>,
+}
+
+impl BundleResolver {
+    /// `resolver` must know the DID documents of every issuer passed to `trust_issuer`.
+    pub fn new(resolver: DidResolver) -> Self {
+        Self {
+            resolver,
+            trusted_issuers: HashSet::new(),
+            bundles: HashMap::new(),
+        }
+    }
+
+    pub fn trust_issuer(mut self, did: impl Into<String>) -> Self {
+        self.trusted_issuers.insert(did.into());
+        self
+    }
+
+    /// Verifies a bundle and makes it available, replacing one with the same `bundle_id`.
+    /// Bundles from issuers that were not trusted are `3001`.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<&BundleManifest, AmpError> {
+        let bundle = RegistryBundle::open(bytes, &self.resolver)?;
+        if !self.trusted_issuers.contains(&bundle.manifest.issuer) {
+            return Err(AmpError::unauthorized(format!(
+                "bundle {} issuer {} is not trusted",
+                bundle.manifest.bundle_id, bundle.manifest.issuer
+            )));
+        }
+        let bundle_id = bundle.manifest.bundle_id.clone();
+        self.bundles.insert(bundle_id.clone(), bundle);
+        Ok(&self.bundles[&bundle_id].manifest)
+    }
+
+    /// Like `load`; a file that cannot be read is `5002`.
+    pub fn load_file(&mut self, path: &Path) -> Result<&BundleManifest, AmpError> {
+        let bytes = fs::read(path)
+            .map_err(|e| AmpError::unavailable(format!("cannot read bundle {}: {e}", path.display())))?;
+        self.load(&bytes)
+    }
+
+    /// Loads every `*.ampbundle` file in `dir` and returns the loaded bundle ids.
+    pub fn load_dir(&mut self, dir: &Path) -> Result<Vec<String>, AmpError> {
+        let unreadable = |e: std::io::Error| AmpError::unavailable(format!("cannot read {}: {e}", dir.display()));
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).map_err(unreadable)? {
+            let path = entry.map_err(unreadable)?.path();
+            if path.extension().is_some_and(|ext| ext == BUNDLE_FILE_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut loaded = Vec::new();
+        for path in paths {
+            loaded.push(self.load_file(&path)?.bundle_id.clone());
+        }
+        Ok(loaded)
+    }
+
+    pub fn bundle_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.bundles.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Verified bytes for a reference with a bundle locator. A reference without one,
+    /// an unknown bundle or key, a media type other than the manifest's, or bytes that
+    /// do not match `hash` are all `5002` (RFC 004 §5.3).
+    pub fn schema(&self, schema_ref: &SchemaRef) -> Result<Vec<u8>, AmpError> {
+        let (bundle_id, artifact_key) = schema_ref.bundle_locator().ok_or_else(|| {
+            AmpError::unavailable(format!(
+                "schema reference {} has no bundle locator",
+                schema_ref.uri().unwrap_or_default()
+            ))
+        })?;
+        let bundle = self
+            .bundles
+            .get(bundle_id)
+            .ok_or_else(|| AmpError::unavailable(format!("bundle {bundle_id} is not loaded")))?;
+        let entry = bundle
+            .manifest
+            .entry(artifact_key)
+            .ok_or_else(|| AmpError::unavailable(format!("bundle {bundle_id} has no artifact {artifact_key}")))?;
+        if entry.media_type != schema_ref.media_type() {
+            return Err(AmpError::unavailable(format!(
+                "bundle {bundle_id} artifact {artifact_key} is {}, reference expects {}",
+                entry.media_type,
+                schema_ref.media_type()
+            )));
+        }
+        let bytes = bundle.artifacts[artifact_key].clone();
+        schema_ref.verify_artifact(&bytes)?;
+        Ok(bytes)
+    }
+}
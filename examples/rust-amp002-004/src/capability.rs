@@ -0,0 +1,500 @@
+use amp001_example::{
+    cose_sign1_sign, cose_sign1_verify, to_cbor_canonical, AgentKeys, AmpError, DidResolver,
+    Version, VersionRange,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256, Sha512};
+
+/// MTI schema media type (RFC 004 §4.2), assumed when `media_type` is omitted.
+pub const SCHEMA_MEDIA_TYPE_JSON: &str = "application/schema+json";
+
+const MAX_CAPABILITY_NAME_LEN: usize = 255;
+const MAX_DNS_LABEL_LEN: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlg {
+    #[serde(rename = "sha-256")]
+    Sha256,
+    #[serde(rename = "sha-512")]
+    Sha512,
+}
+
+impl HashAlg {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha-256",
+            Self::Sha512 => "sha-512",
+        }
+    }
+
+    /// Exact `hash` length the algorithm requires (32 or 64 bytes).
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(bytes).to_vec(),
+            Self::Sha512 => Sha512::digest(bytes).to_vec(),
+        }
+    }
+}
+
+/// `schema-ref-online`: fetched by `uri`, optionally also locatable in a local bundle.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SchemaRefOnline {
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_key: Option<String>,
+    pub hash_alg: HashAlg,
+    pub hash: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// `schema-ref-offline`: resolved from local bundle storage, `uri` is informative only.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SchemaRefOffline {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    pub bundle_id: String,
+    pub artifact_key: String,
+    pub hash_alg: HashAlg,
+    pub hash: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// `schema-ref = schema-ref-online / schema-ref-offline`. A reference carrying a `uri` is
+/// online; one with only `bundle_id` + `artifact_key` is offline.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "WireSchemaRef", into = "WireSchemaRef")]
+pub enum SchemaRef {
+    Online(SchemaRefOnline),
+    Offline(SchemaRefOffline),
+}
+
+/// Both `schema-ref` shapes flattened, so decoding can say which rule a map breaks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WireSchemaRef {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bundle_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_key: Option<String>,
+    hash_alg: HashAlg,
+    hash: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+}
+
+impl TryFrom<WireSchemaRef> for SchemaRef {
+    type Error = String;
+
+    fn try_from(wire: WireSchemaRef) -> Result<Self, Self::Error> {
+        match (wire.uri, wire.bundle_id, wire.artifact_key) {
+            (Some(uri), bundle_id, artifact_key) => {
+                if bundle_id.is_some() != artifact_key.is_some() {
+                    return Err("schema-ref bundle_id and artifact_key must be present together".to_string());
+                }
+                Ok(Self::Online(SchemaRefOnline {
+                    uri,
+                    bundle_id,
+                    artifact_key,
+                    hash_alg: wire.hash_alg,
+                    hash: wire.hash,
+                    media_type: wire.media_type,
+                    updated_at: wire.updated_at,
+                }))
+            }
+            (None, Some(bundle_id), Some(artifact_key)) => Ok(Self::Offline(SchemaRefOffline {
+                uri: None,
+                bundle_id,
+                artifact_key,
+                hash_alg: wire.hash_alg,
+                hash: wire.hash,
+                media_type: wire.media_type,
+                updated_at: wire.updated_at,
+            })),
+            _ => Err("schema-ref needs uri or both bundle_id and artifact_key".to_string()),
+        }
+    }
+}
+
+impl From<SchemaRef> for WireSchemaRef {
+    fn from(schema: SchemaRef) -> Self {
+        match schema {
+            SchemaRef::Online(v) => Self {
+                uri: Some(v.uri),
+                bundle_id: v.bundle_id,
+                artifact_key: v.artifact_key,
+                hash_alg: v.hash_alg,
+                hash: v.hash,
+                media_type: v.media_type,
+                updated_at: v.updated_at,
+            },
+            SchemaRef::Offline(v) => Self {
+                uri: v.uri,
+                bundle_id: Some(v.bundle_id),
+                artifact_key: Some(v.artifact_key),
+                hash_alg: v.hash_alg,
+                hash: v.hash,
+                media_type: v.media_type,
+                updated_at: v.updated_at,
+            },
+        }
+    }
+}
+
+impl SchemaRef {
+    /// Online reference whose hash is computed over `artifact`.
+    pub fn online(uri: impl Into<String>, hash_alg: HashAlg, artifact: &[u8]) -> Self {
+        Self::Online(SchemaRefOnline {
+            uri: uri.into(),
+            bundle_id: None,
+            artifact_key: None,
+            hash_alg,
+            hash: ByteBuf::from(hash_alg.digest(artifact)),
+            media_type: None,
+            updated_at: None,
+        })
+    }
+
+    /// Offline reference whose hash is computed over `artifact`.
+    pub fn offline(
+        bundle_id: impl Into<String>,
+        artifact_key: impl Into<String>,
+        hash_alg: HashAlg,
+        artifact: &[u8],
+    ) -> Self {
+        Self::Offline(SchemaRefOffline {
+            uri: None,
+            bundle_id: bundle_id.into(),
+            artifact_key: artifact_key.into(),
+            hash_alg,
+            hash: ByteBuf::from(hash_alg.digest(artifact)),
+            media_type: None,
+            updated_at: None,
+        })
+    }
+
+    pub fn uri(&self) -> Option<&str> {
+        match self {
+            Self::Online(v) => Some(&v.uri),
+            Self::Offline(v) => v.uri.as_deref(),
+        }
+    }
+
+    /// Local bundle locator (`bundle_id`, `artifact_key`), when the reference has one.
+    pub fn bundle_locator(&self) -> Option<(&str, &str)> {
+        match self {
+            Self::Online(v) => v.bundle_id.as_deref().zip(v.artifact_key.as_deref()),
+            Self::Offline(v) => Some((&v.bundle_id, &v.artifact_key)),
+        }
+    }
+
+    pub fn hash_alg(&self) -> HashAlg {
+        match self {
+            Self::Online(v) => v.hash_alg,
+            Self::Offline(v) => v.hash_alg,
+        }
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        match self {
+            Self::Online(v) => &v.hash,
+            Self::Offline(v) => &v.hash,
+        }
+    }
+
+    /// Declared media type, or the MTI `application/schema+json` default.
+    pub fn media_type(&self) -> &str {
+        let declared = match self {
+            Self::Online(v) => v.media_type.as_deref(),
+            Self::Offline(v) => v.media_type.as_deref(),
+        };
+        declared.unwrap_or(SCHEMA_MEDIA_TYPE_JSON)
+    }
+
+    pub fn updated_at(&self) -> Option<&str> {
+        match self {
+            Self::Online(v) => v.updated_at.as_deref(),
+            Self::Offline(v) => v.updated_at.as_deref(),
+        }
+    }
+
+    /// Checks the §4.2 shape rules that decoding alone does not (hash length, empty
+    /// locators); violations are `1001`.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        let alg = self.hash_alg();
+        if self.hash().len() != alg.digest_len() {
+            return Err(AmpError::invalid_message(format!(
+                "{} hash must be {} bytes, got {}",
+                alg.as_str(),
+                alg.digest_len(),
+                self.hash().len()
+            )));
+        }
+        if self.uri().is_some_and(str::is_empty) {
+            return Err(AmpError::invalid_message("schema-ref uri must not be empty"));
+        }
+        if let Some((bundle_id, artifact_key)) = self.bundle_locator() {
+            if bundle_id.is_empty() || artifact_key.is_empty() {
+                return Err(AmpError::invalid_message(
+                    "schema-ref bundle_id and artifact_key must not be empty",
+                ));
+            }
+        }
+        if self.media_type().is_empty() {
+            return Err(AmpError::invalid_message("schema-ref media_type must not be empty"));
+        }
+        Ok(())
+    }
+
+    /// Verifies retrieved schema bytes against `hash_alg`/`hash`. Schema bytes that do not
+    /// match cannot be used, so a mismatch is `5002` (RFC 004 §5.3, §9).
+    pub fn verify_artifact(&self, artifact: &[u8]) -> Result<(), AmpError> {
+        let alg = self.hash_alg();
+        if alg.digest(artifact) != self.hash() {
+            return Err(AmpError::unavailable(format!(
+                "schema artifact {} mismatch for {}",
+                alg.as_str(),
+                self.uri()
+                    .map(str::to_string)
+                    .or_else(|| self.bundle_locator().map(|(b, k)| format!("{b}/{k}")))
+                    .unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// RFC 004 §4.2 `capability-descriptor`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapabilityDescriptor {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub input_schema: SchemaRef,
+    pub output_schema: SchemaRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor_sig: Option<ByteBuf>,
+    /// Absent means only the exact `version` is supported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_ranges: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deprecated_ranges: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+impl CapabilityDescriptor {
+    /// Unsigned descriptor with `id` derived from `name` and `version`.
+    pub fn new(
+        name: impl Into<String>,
+        version: impl Into<String>,
+        input_schema: SchemaRef,
+        output_schema: SchemaRef,
+    ) -> Self {
+        let name = name.into();
+        let version = version.into();
+        Self {
+            id: format!("{name}:{version}"),
+            name,
+            version,
+            input_schema,
+            output_schema,
+            descriptor_sig: None,
+            supported_ranges: None,
+            deprecated_ranges: None,
+            notes: None,
+        }
+    }
+
+    /// Identifier, namespace, version and range format plus the §4.2 consistency rules.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        let (name, version) = parse_capability_id(&self.id)?;
+        if name != self.name || version != self.version {
+            return Err(AmpError::invalid_message(format!(
+                "descriptor id {} must equal name:version ({}:{})",
+                self.id, self.name, self.version
+            )));
+        }
+        self.supported_ranges()?;
+        for range in self.deprecated_ranges.iter().flatten() {
+            VersionRange::parse(range)?;
+        }
+        self.input_schema.validate()?;
+        self.output_schema.validate()
+    }
+
+    pub fn semver(&self) -> Result<Version, AmpError> {
+        Ok(Version::parse(&self.version)?)
+    }
+
+    /// Parsed `supported_ranges`; when absent, only the exact `version` (§4.2).
+    pub fn supported_ranges(&self) -> Result<Vec<VersionRange>, AmpError> {
+        match &self.supported_ranges {
+            Some(ranges) => Ok(ranges
+                .iter()
+                .map(|range| VersionRange::parse(range))
+                .collect::<Result<_, _>>()?),
+            None => Ok(vec![VersionRange::exact(self.semver()?)]),
+        }
+    }
+
+    /// Whether any supported range admits `version`.
+    pub fn supports(&self, version: &Version) -> Result<bool, AmpError> {
+        Ok(self.supported_ranges()?.iter().any(|range| range.matches(version)))
+    }
+
+    /// Whether `version` falls in a `deprecated_ranges` entry.
+    pub fn is_deprecated(&self, version: &Version) -> Result<bool, AmpError> {
+        for range in self.deprecated_ranges.iter().flatten() {
+            if VersionRange::parse(range)?.matches(version) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Deterministic CBOR of the whole descriptor, `descriptor_sig` included when set.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, AmpError> {
+        to_cbor_canonical(self)
+    }
+
+    /// Decodes and validates a descriptor; any structural failure is `1001`.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, AmpError> {
+        let descriptor: Self = serde_cbor::from_slice(bytes)
+            .map_err(|e| AmpError::invalid_message(format!("invalid capability descriptor: {e}")))?;
+        descriptor.validate()?;
+        Ok(descriptor)
+    }
+
+    /// Bytes `descriptor_sig` covers: deterministic CBOR with `descriptor_sig` omitted.
+    pub fn signing_payload(&self) -> Result<Vec<u8>, AmpError> {
+        to_cbor_canonical(&Self {
+            descriptor_sig: None,
+            ..self.clone()
+        })
+    }
+
+    /// Signs the descriptor (§5.2) with the `assertionMethod` key of `owner`, which must
+    /// be the namespace owner DID.
+    pub fn sign(&mut self, owner: &AgentKeys) -> Result<(), AmpError> {
+        self.validate()?;
+        let expected = namespace_owner_did(&self.name)?;
+        if owner.did != expected {
+            return Err(AmpError::unauthorized(format!(
+                "{} does not own namespace of {} (expected {expected})",
+                owner.did, self.name
+            )));
+        }
+        let sig = cose_sign1_sign(owner, &owner.assertion_kid(), &self.signing_payload()?)?;
+        self.descriptor_sig = Some(ByteBuf::from(sig));
+        Ok(())
+    }
+
+    /// Public Registry Trust Profile check: `descriptor_sig` must be present, signed by an
+    /// `assertionMethod` of the namespace owner DID and cover this descriptor. Every
+    /// failure is `3001`.
+    pub fn verify_signature(&self, resolver: &DidResolver) -> Result<(), AmpError> {
+        let sig = self
+            .descriptor_sig
+            .as_ref()
+            .ok_or_else(|| AmpError::unauthorized(format!("{} has no descriptor_sig", self.id)))?;
+        let signed = cose_sign1_verify(sig, resolver)
+            .map_err(|e| AmpError::unauthorized(format!("descriptor_sig invalid: {}", e.detail)))?;
+        let owner = namespace_owner_did(&self.name).map_err(|e| AmpError::unauthorized(e.detail))?;
+        if signed.kid_did() != owner {
+            return Err(AmpError::unauthorized(format!(
+                "descriptor_sig kid {} is not under namespace owner {owner}",
+                signed.kid
+            )));
+        }
+        if signed.payload != self.signing_payload()? {
+            return Err(AmpError::unauthorized(format!(
+                "descriptor_sig does not cover descriptor {}",
+                self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Splits `name:semver` into its checked parts.
+pub fn parse_capability_id(id: &str) -> Result<(&str, &str), AmpError> {
+    let (name, version) = id
+        .rsplit_once(':')
+        .ok_or_else(|| AmpError::invalid_message(format!("capability id {id} must be name:semver")))?;
+    validate_capability_name(name)?;
+    Version::parse(version)?;
+    Ok((name, version))
+}
+
+/// `capability-name = <reverse-domain namespace> "." <slug>`. The namespace needs at least
+/// two lowercase DNS labels (`com.acme`), so byte comparison of names is unambiguous; the
+/// slug is ASCII letters, digits, `-` and `_`, starting with a letter or digit.
+pub fn validate_capability_name(name: &str) -> Result<(), AmpError> {
+    let invalid = |why: &str| AmpError::invalid_message(format!("capability name {name:?} {why}"));
+    if name.len() > MAX_CAPABILITY_NAME_LEN {
+        return Err(invalid("is too long"));
+    }
+    let (namespace, slug) = name
+        .rsplit_once('.')
+        .ok_or_else(|| invalid("must be <reverse-domain namespace>.<slug>"))?;
+    let labels: Vec<&str> = namespace.split('.').collect();
+    if labels.len() < 2 {
+        return Err(invalid("needs a reverse-domain namespace of at least two labels"));
+    }
+    for label in &labels {
+        if !is_dns_label(label) {
+            return Err(invalid(&format!("has invalid namespace label {label:?}")));
+        }
+    }
+    if labels[0].starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(invalid("must start with a top-level domain label"));
+    }
+    let slug_ok = slug.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !slug_ok {
+        return Err(invalid(&format!("has invalid slug {slug:?}")));
+    }
+    Ok(())
+}
+
+/// Namespace part of a capability name (`org.agentries.code-review` -> `org.agentries`).
+pub fn capability_namespace(name: &str) -> Result<&str, AmpError> {
+    validate_capability_name(name)?;
+    Ok(name.rsplit_once('.').map(|(namespace, _)| namespace).unwrap_or_default())
+}
+
+/// DID of the namespace controller (§4.3): the `did:web` of the namespace domain, e.g.
+/// `com.acme.risk-evaluator` -> `did:web:acme.com`.
+pub fn namespace_owner_did(name: &str) -> Result<String, AmpError> {
+    let domain: Vec<&str> = capability_namespace(name)?.split('.').rev().collect();
+    Ok(format!("did:web:{}", domain.join(".")))
+}
+
+fn is_dns_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_DNS_LABEL_LEN
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
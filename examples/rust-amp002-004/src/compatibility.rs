@@ -0,0 +1,441 @@
+use std::fmt;
+use std::sync::Arc;
+
+use amp001_example::{AmpError, ErrorBody, Version, VersionRange};
+use serde_cbor::Value;
+
+use crate::{
+    negotiate, parse_capability_id, CapFilter, CapInvokeBody, CapabilityDescriptor, CapabilitySet,
+    JsonSchema, NegotiationRule, SchemaChange, SchemaEffect,
+};
+
+/// Provider compatibility policy (RFC 004 §8).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatibilityMode {
+    /// Only the exact requested version is served.
+    Strict,
+    /// A declared version whose `supported_ranges` admit the request may serve it, and
+    /// an older version may if allowed and the params survive unchanged.
+    Range,
+    /// `Range`, plus the documented bridges, which are the only way across majors.
+    Bridge,
+}
+
+type Adapter = Arc<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// A documented adapter from requests in `from` to the declared version `to`.
+#[derive(Clone)]
+struct VersionBridge {
+    from: VersionRange,
+    to: Version,
+    description: String,
+    adapt: Adapter,
+}
+
+/// Decides how an invocation is served when its exact version may not be.
+#[derive(Clone)]
+pub struct CompatibilityPolicy {
+    mode: CompatibilityMode,
+    bridges: Vec<VersionBridge>,
+    allow_downgrade: bool,
+    reject_deprecated: bool,
+}
+
+/// The four §8 outcomes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecisionKind {
+    Exact,
+    CompatibleUpgrade,
+    DowngradeFallback,
+    Reject,
+}
+
+/// Why the policy decided what it did; its `Display` is meant for logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecisionReason {
+    ExactMatch,
+    /// The request carried `negotiate` hints and §6.3 picked the version.
+    Negotiated(NegotiationRule),
+    /// The selected descriptor lists the requested version in `supported_ranges`.
+    SupportedRange,
+    /// A registered bridge maps the requested version to the selected one.
+    Bridge { description: String },
+    /// The older version's input schema accepts the params and describes all of them.
+    SafeDowngrade,
+    InvalidRequest { code: u16, detail: String },
+    NotFound,
+    StrictMode,
+    Deprecated,
+    /// Only other majors are declared and no bridge reaches them.
+    IncompatibleMajor,
+    /// Every older version would reject or silently drop part of the params.
+    UnsafeDowngrade { detail: String },
+    NoCompatibleVersion,
+}
+
+impl fmt::Display for DecisionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecisionReason::ExactMatch => write!(f, "exact version declared"),
+            DecisionReason::Negotiated(rule) => write!(f, "negotiated by {rule:?} hint"),
+            DecisionReason::SupportedRange => write!(f, "newer version declares the requested one supported"),
+            DecisionReason::Bridge { description } => write!(f, "bridge: {description}"),
+            DecisionReason::SafeDowngrade => write!(f, "older version accepts every param unchanged"),
+            DecisionReason::InvalidRequest { detail, .. } => write!(f, "invalid request: {detail}"),
+            DecisionReason::NotFound => write!(f, "capability not declared"),
+            DecisionReason::StrictMode => write!(f, "strict policy accepts only the exact version"),
+            DecisionReason::Deprecated => write!(f, "requested version is deprecated and no replacement supports it"),
+            DecisionReason::IncompatibleMajor => write!(f, "only incompatible major versions are declared"),
+            DecisionReason::UnsafeDowngrade { detail } => write!(f, "downgrade would not preserve params: {detail}"),
+            DecisionReason::NoCompatibleVersion => write!(f, "no declared version is compatible"),
+        }
+    }
+}
+
+/// Outcome of [`CompatibilityPolicy::decide`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatibilityDecision {
+    pub kind: DecisionKind,
+    pub capability: String,
+    /// Version the invocation asked for; `None` for negotiated requests.
+    pub requested: Option<String>,
+    /// `capability-id` that will serve the invocation; `None` on reject.
+    pub selected: Option<String>,
+    /// Params for the selected version: the request's own, or a bridge's output.
+    pub params: Option<Value>,
+    /// The selected version falls in its descriptor's `deprecated_ranges`.
+    pub deprecated: bool,
+    pub reason: DecisionReason,
+}
+
+impl CompatibilityDecision {
+    /// Error code of a rejection: `4001`, `4002` or `4003`.
+    pub fn code(&self) -> Option<u16> {
+        if self.kind != DecisionKind::Reject {
+            return None;
+        }
+        Some(match &self.reason {
+            DecisionReason::InvalidRequest { code, .. } => *code,
+            DecisionReason::NotFound => 4002,
+            _ => 4003,
+        })
+    }
+}
+
+impl fmt::Display for CompatibilityDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requested = self.requested.as_deref().unwrap_or("negotiated");
+        write!(f, "{:?} {}@{requested}", self.kind, self.capability)?;
+        if let Some(selected) = &self.selected {
+            write!(f, " -> {selected}")?;
+        }
+        if self.deprecated {
+            write!(f, " (deprecated)")?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+impl From<CompatibilityDecision> for ErrorBody {
+    fn from(decision: CompatibilityDecision) -> Self {
+        let code = decision.code().unwrap_or(4003);
+        ErrorBody::new(code, decision.to_string())
+    }
+}
+
+impl CompatibilityPolicy {
+    /// Downgrades are off and deprecated versions are still served.
+    pub fn new(mode: CompatibilityMode) -> Self {
+        Self {
+            mode,
+            bridges: Vec::new(),
+            allow_downgrade: false,
+            reject_deprecated: false,
+        }
+    }
+
+    pub fn mode(&self) -> CompatibilityMode {
+        self.mode
+    }
+
+    /// Lets `Range` and `Bridge` fall back to an older version of the same major.
+    pub fn allow_downgrade(mut self, allow: bool) -> Self {
+        self.allow_downgrade = allow;
+        self
+    }
+
+    /// Stops selecting versions inside their descriptor's `deprecated_ranges`.
+    pub fn reject_deprecated(mut self, reject: bool) -> Self {
+        self.reject_deprecated = reject;
+        self
+    }
+
+    /// Registers a bridge, consulted in registration order in `Bridge` mode. `adapt`
+    /// rewrites params for `to`; it must be deterministic.
+    pub fn bridge<F>(mut self, from: &str, to: &str, description: impl Into<String>, adapt: F) -> Result<Self, AmpError>
+    where
+        F: Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.bridges.push(VersionBridge {
+            from: VersionRange::parse(from)?,
+            to: Version::parse(to)?,
+            description: description.into(),
+            adapt: Arc::new(adapt),
+        });
+        Ok(self)
+    }
+
+    /// Chooses the declared version that serves `request`, in this order: exact match,
+    /// bridge (`Bridge` mode), compatible upgrade, downgrade fallback, reject.
+    ///
+    /// Upgrades pick the highest newer version of the same major whose
+    /// `supported_ranges` admit the requested version. Downgrades try older versions
+    /// of the same major, highest first, and only where `input_schema` of that
+    /// `capability-id` accepts the params and declares all of them, so nothing is
+    /// silently dropped. Without a bridge a different major is never selected.
+    pub fn decide<'a>(
+        &self,
+        declared: &CapabilitySet,
+        request: &CapInvokeBody,
+        input_schema: impl Fn(&str) -> Option<&'a JsonSchema>,
+    ) -> CompatibilityDecision {
+        let mut decision = CompatibilityDecision {
+            kind: DecisionKind::Reject,
+            capability: request.capability.clone().or_else(|| request.type_alias.clone()).unwrap_or_default(),
+            requested: request.version.clone(),
+            selected: None,
+            params: None,
+            deprecated: false,
+            reason: DecisionReason::NoCompatibleVersion,
+        };
+        let invalid = |mut decision: CompatibilityDecision, err: AmpError| {
+            decision.reason = DecisionReason::InvalidRequest {
+                code: err.code,
+                detail: err.detail,
+            };
+            decision
+        };
+        if let Err(err) = request.validate() {
+            return invalid(decision, err);
+        }
+
+        let name = match &request.id {
+            Some(id) => match parse_capability_id(id) {
+                Ok((name, version)) => {
+                    decision.requested = Some(version.to_string());
+                    name.to_string()
+                }
+                Err(err) => return invalid(decision, err),
+            },
+            None => {
+                let filter = CapFilter {
+                    capability: request.capability.clone(),
+                    type_alias: request.type_alias.clone(),
+                    version: None,
+                };
+                match declared.resolve_name(&filter) {
+                    Ok(name) => name.to_string(),
+                    Err(err) => return invalid(decision, AmpError::bad_request(err.message)),
+                }
+            }
+        };
+        decision.capability = name.clone();
+        let mut offered: Vec<(Version, &CapabilityDescriptor)> = declared
+            .descriptors()
+            .iter()
+            .filter(|d| d.name == name)
+            .filter_map(|d| d.semver().ok().map(|v| (v, d)))
+            .collect();
+        if offered.is_empty() {
+            decision.reason = DecisionReason::NotFound;
+            return decision;
+        }
+        // Highest version first; equal precedence falls back to the bytewise-smallest id.
+        offered.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.id.cmp(&b.1.id)));
+        let deprecated = |(version, descriptor): &(Version, &CapabilityDescriptor)| {
+            descriptor.is_deprecated(version).unwrap_or(false)
+        };
+        let usable = |offer: &(Version, &CapabilityDescriptor)| !(self.reject_deprecated && deprecated(offer));
+        let select = |mut decision: CompatibilityDecision, kind, offer, params, reason| {
+            decision.kind = kind;
+            decision.selected = Some(offer_id(offer));
+            decision.deprecated = deprecated(offer);
+            decision.params = Some(params);
+            decision.reason = reason;
+            decision
+        };
+
+        if let Some(hints) = &request.negotiate {
+            // Negotiation only sees what this policy is willing to serve.
+            let candidates: Vec<CapabilityDescriptor> =
+                offered.iter().filter(|offer| usable(offer)).map(|(_, d)| (*d).clone()).collect();
+            if candidates.is_empty() {
+                decision.reason = DecisionReason::Deprecated;
+                return decision;
+            }
+            return match negotiate(&name, &candidates, hints) {
+                Ok(chosen) => {
+                    let offer = offered.iter().find(|(_, d)| d.id == chosen.id).expect("negotiated from offered");
+                    let reason = DecisionReason::Negotiated(chosen.rule);
+                    select(decision, DecisionKind::Exact, offer, request.params.clone(), reason)
+                }
+                Err(err) => {
+                    decision.reason = match err.code() {
+                        4002 => DecisionReason::NotFound,
+                        4003 => DecisionReason::NoCompatibleVersion,
+                        code => DecisionReason::InvalidRequest {
+                            code,
+                            detail: err.to_string(),
+                        },
+                    };
+                    decision
+                }
+            };
+        }
+
+        let requested = match decision.requested.as_deref().map(Version::parse) {
+            Some(Ok(version)) => version,
+            Some(Err(err)) => return invalid(decision, AmpError::bad_request(err.to_string())),
+            None => return invalid(decision, AmpError::bad_request("CAP_INVOKE has no version")),
+        };
+
+        let exact = offered.iter().filter(|(v, _)| *v == requested).min_by(|a, b| a.1.id.cmp(&b.1.id));
+        if let Some(offer) = exact.filter(|offer| usable(offer)) {
+            return select(decision, DecisionKind::Exact, offer, request.params.clone(), DecisionReason::ExactMatch);
+        }
+        if self.mode == CompatibilityMode::Strict {
+            decision.reason = if exact.is_some() { DecisionReason::Deprecated } else { DecisionReason::StrictMode };
+            return decision;
+        }
+
+        if self.mode == CompatibilityMode::Bridge {
+            for bridge in self.bridges.iter().filter(|b| b.from.matches(&requested)) {
+                let Some(offer) = offered.iter().find(|(v, _)| *v == bridge.to).filter(|offer| usable(offer)) else {
+                    continue;
+                };
+                let kind = if bridge.to > requested { DecisionKind::CompatibleUpgrade } else { DecisionKind::DowngradeFallback };
+                return match (bridge.adapt)(request.params.clone()) {
+                    Ok(params) => {
+                        let reason = DecisionReason::Bridge {
+                            description: bridge.description.clone(),
+                        };
+                        select(decision, kind, offer, params, reason)
+                    }
+                    Err(detail) => {
+                        decision.reason = DecisionReason::InvalidRequest {
+                            code: 4001,
+                            detail: format!("bridge {}: {detail}", bridge.description),
+                        };
+                        decision
+                    }
+                };
+            }
+        }
+
+        let same_major: Vec<&(Version, &CapabilityDescriptor)> = offered
+            .iter()
+            .filter(|offer| offer.0.major == requested.major && usable(offer))
+            .collect();
+        let upgrade = same_major
+            .iter()
+            .find(|(v, d)| *v > requested && d.supports(&requested).unwrap_or(false));
+        if let Some(offer) = upgrade {
+            let reason = DecisionReason::SupportedRange;
+            return select(decision, DecisionKind::CompatibleUpgrade, offer, request.params.clone(), reason);
+        }
+
+        let older: Vec<_> = same_major.iter().filter(|(v, _)| *v < requested).collect();
+        if self.allow_downgrade && !older.is_empty() {
+            let mut unsafe_detail = Vec::new();
+            for offer in older {
+                let id = offer_id(offer);
+                let Some(schema) = input_schema(&id) else {
+                    unsafe_detail.push(format!("{id} has no input schema"));
+                    continue;
+                };
+                if let Err(violation) = schema.validate(&request.params) {
+                    unsafe_detail.push(format!("{id} rejects {violation}"));
+                    continue;
+                }
+                let dropped = schema.undeclared(&request.params);
+                if !dropped.is_empty() {
+                    unsafe_detail.push(format!("{id} would drop {}", dropped.join(", ")));
+                    continue;
+                }
+                let params = request.params.clone();
+                return select(decision, DecisionKind::DowngradeFallback, offer, params, DecisionReason::SafeDowngrade);
+            }
+            decision.reason = DecisionReason::UnsafeDowngrade {
+                detail: unsafe_detail.join("; "),
+            };
+            return decision;
+        }
+
+        decision.reason = if exact.is_some() {
+            DecisionReason::Deprecated
+        } else if offered.iter().all(|(v, _)| v.major != requested.major) {
+            DecisionReason::IncompatibleMajor
+        } else {
+            DecisionReason::NoCompatibleVersion
+        };
+        decision
+    }
+}
+
+fn offer_id((_, descriptor): &(Version, &CapabilityDescriptor)) -> String {
+    descriptor.id.clone()
+}
+
+/// Whether an older schema version can be replaced by a newer one without breaking
+/// its users: senders of inputs (`Input`) or readers of outputs (`Output`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaRole {
+    Input,
+    Output,
+}
+
+impl SchemaRole {
+    /// An input schema must keep accepting what it accepted; an output schema must not
+    /// start producing what readers of the old one would reject.
+    pub fn is_breaking(self, change: &SchemaChange) -> bool {
+        match (self, change.effect) {
+            (_, SchemaEffect::Changed) => true,
+            (SchemaRole::Input, effect) => effect == SchemaEffect::Narrowed,
+            (SchemaRole::Output, effect) => effect == SchemaEffect::Widened,
+        }
+    }
+}
+
+/// A schema change that breaks a minor or patch release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakingChange {
+    pub role: SchemaRole,
+    pub change: SchemaChange,
+}
+
+impl fmt::Display for BreakingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self.role {
+            SchemaRole::Input => "input",
+            SchemaRole::Output => "output",
+        };
+        write!(f, "{role} {}", self.change)
+    }
+}
+
+/// Breaking changes from one release's input and output schemas to the next.
+pub fn breaking_changes(
+    (old_input, old_output): (&JsonSchema, &JsonSchema),
+    (new_input, new_output): (&JsonSchema, &JsonSchema),
+) -> Vec<BreakingChange> {
+    let mut breaking = Vec::new();
+    for (role, old, new) in [(SchemaRole::Input, old_input, new_input), (SchemaRole::Output, old_output, new_output)] {
+        breaking.extend(
+            old.diff(new)
+                .into_iter()
+                .filter(|change| role.is_breaking(change))
+                .map(|change| BreakingChange { role, change }),
+        );
+    }
+    breaking
+}
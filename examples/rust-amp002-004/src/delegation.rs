@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+
+use amp001_example::{
+    cose_sign1_peek, cose_sign1_sign, cose_sign1_verify, to_cbor_canonical, AgentKeys, AmpError,
+    CoseSign1, DidResolver, COSE_ALG_EDDSA,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+
+use crate::{parse_capability_id, validate_capability_name};
+
+/// `cred_v` this implementation issues and accepts.
+pub const DELEGATION_CREDENTIAL_V1: u64 = 1;
+/// The only `delegation-credential-envelope` format (RFC 005 §4.4).
+pub const DELEGATION_FORMAT_COSE_SIGN1: &str = "cose_sign1";
+/// `rev_v` this implementation issues and accepts.
+pub const DELEGATION_REVOCATION_V1: u64 = 1;
+
+/// `delegation-scope` (RFC 005 §4.2). An omitted dimension is unrestricted in the first
+/// link of a chain and inherited in later ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DelegationScope {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constraints: Option<BTreeMap<String, Value>>,
+}
+
+impl DelegationScope {
+    /// Shape problems (no dimension, empty list or entry) are `1001`. A capability
+    /// selector that is not an exact name or `capability-id` (wildcards, negation,
+    /// patterns) is `3004`.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        if self.capabilities.is_none() && self.actions.is_none() && self.resources.is_none() {
+            return Err(AmpError::invalid_message(
+                "delegation scope needs capabilities, actions or resources",
+            ));
+        }
+        for (dimension, values) in [
+            ("capabilities", &self.capabilities),
+            ("actions", &self.actions),
+            ("resources", &self.resources),
+        ] {
+            let Some(values) = values else { continue };
+            if values.is_empty() || values.iter().any(String::is_empty) {
+                return Err(AmpError::invalid_message(format!(
+                    "delegation scope {dimension} must be a non-empty list of non-empty strings"
+                )));
+            }
+        }
+        for selector in self.capabilities.iter().flatten() {
+            let exact = if selector.contains(':') {
+                parse_capability_id(selector).map(|_| ())
+            } else {
+                validate_capability_name(selector)
+            };
+            exact.map_err(|e| {
+                AmpError::delegation_invalid(format!("unsupported capability selector {selector:?}: {}", e.detail))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// `delegation-validity`, epoch milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationValidity {
+    pub issued_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    pub expires_at: u64,
+}
+
+impl DelegationValidity {
+    pub fn new(issued_at: u64, expires_at: u64) -> Self {
+        Self {
+            issued_at,
+            not_before: None,
+            expires_at,
+        }
+    }
+
+    /// `not_before`, or `issued_at` when absent.
+    pub fn effective_not_before(&self) -> u64 {
+        self.not_before.unwrap_or(self.issued_at)
+    }
+
+    /// `not_before <= now < expires_at` (§4.3).
+    pub fn contains(&self, now_ms: u64) -> bool {
+        self.effective_not_before() <= now_ms && now_ms < self.expires_at
+    }
+}
+
+/// `delegation-credential-payload`: a direct grant from `delegator` to `delegate`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DelegationCredential {
+    pub cred_v: u64,
+    pub delegation_id: String,
+    pub delegator: String,
+    pub delegate: String,
+    pub scope: DelegationScope,
+    pub validity: DelegationValidity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_subdelegation: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_chain_depth: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<ByteBuf>,
+}
+
+impl DelegationCredential {
+    pub fn new(
+        delegation_id: impl Into<String>,
+        delegator: impl Into<String>,
+        delegate: impl Into<String>,
+        scope: DelegationScope,
+        validity: DelegationValidity,
+    ) -> Self {
+        Self {
+            cred_v: DELEGATION_CREDENTIAL_V1,
+            delegation_id: delegation_id.into(),
+            delegator: delegator.into(),
+            delegate: delegate.into(),
+            scope,
+            validity,
+            allow_subdelegation: None,
+            max_chain_depth: None,
+            aud: None,
+            nonce: None,
+        }
+    }
+
+    /// `allow_subdelegation`, `false` when absent.
+    pub fn allows_subdelegation(&self) -> bool {
+        self.allow_subdelegation.unwrap_or(false)
+    }
+
+    /// Whether a verifier with `verifier_did` may accept the credential; audience-unbound
+    /// credentials admit every verifier.
+    pub fn admits_audience(&self, verifier_did: &str) -> bool {
+        self.aud.as_ref().is_none_or(|aud| aud.iter().any(|did| did == verifier_did))
+    }
+
+    /// Checks the §4.4 rules that decoding alone does not: unsupported `cred_v` is
+    /// `1004`, shape problems are `1001`, and a broken consistency rule or selector is
+    /// `3004`.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        if self.cred_v != DELEGATION_CREDENTIAL_V1 {
+            return Err(AmpError::unsupported_version(format!(
+                "unsupported cred_v={}, expected {DELEGATION_CREDENTIAL_V1}",
+                self.cred_v
+            )));
+        }
+        for (field, value) in [
+            ("delegation_id", &self.delegation_id),
+            ("delegator", &self.delegator),
+            ("delegate", &self.delegate),
+        ] {
+            if value.is_empty() {
+                return Err(AmpError::invalid_message(format!("delegation {field} must not be empty")));
+            }
+        }
+        if self.aud.as_ref().is_some_and(|aud| aud.is_empty() || aud.iter().any(String::is_empty)) {
+            return Err(AmpError::invalid_message("delegation aud must be a non-empty list of DIDs"));
+        }
+        self.scope.validate()?;
+        if self.validity.expires_at <= self.validity.effective_not_before() {
+            return Err(AmpError::delegation_invalid(format!(
+                "delegation {} expires_at must be after not_before",
+                self.delegation_id
+            )));
+        }
+        if self.max_chain_depth == Some(0) {
+            return Err(AmpError::delegation_invalid(format!(
+                "delegation {} max_chain_depth must be at least 1",
+                self.delegation_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Signs the deterministic CBOR payload with the `assertionMethod` key of
+    /// `delegator`, which must be the credential's `delegator` (§4.5).
+    pub fn sign(&self, delegator: &AgentKeys) -> Result<DelegationEnvelope, AmpError> {
+        self.validate()?;
+        if delegator.did != self.delegator {
+            return Err(AmpError::unauthorized(format!(
+                "{} cannot sign delegation {} issued by {}",
+                delegator.did, self.delegation_id, self.delegator
+            )));
+        }
+        let credential = cose_sign1_sign(delegator, &delegator.assertion_kid(), &to_cbor_canonical(self)?)?;
+        Ok(DelegationEnvelope {
+            format: DELEGATION_FORMAT_COSE_SIGN1.to_string(),
+            credential: ByteBuf::from(credential),
+        })
+    }
+}
+
+/// `delegation-credential-envelope`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationEnvelope {
+    pub format: String,
+    pub credential: ByteBuf,
+}
+
+impl DelegationEnvelope {
+    /// Decodes an envelope carried as a CBOR value (for example inside evidence); any
+    /// structural failure is `1001`.
+    pub fn from_value(value: Value) -> Result<Self, AmpError> {
+        serde_cbor::value::from_value(value)
+            .map_err(|e| AmpError::invalid_message(format!("invalid delegation credential envelope: {e}")))
+    }
+
+    pub fn to_value(&self) -> Result<Value, AmpError> {
+        serde_cbor::value::to_value(self)
+            .map_err(|e| AmpError::invalid_message(format!("delegation envelope encode failed: {e}")))
+    }
+
+    /// Decodes and checks the payload without verifying the signature, as §6.1 step 2
+    /// parses the chain before any signature is checked.
+    pub fn decode(&self) -> Result<DelegationCredential, AmpError> {
+        self.check_format()?;
+        let peeked = peek_delegation_artifact(&self.credential)?;
+        decode_credential(&peeked.payload)
+    }
+
+    /// Verifies the signature against the `kid` resolved through `resolver` and returns
+    /// the checked payload. Malformed envelopes or payloads are `1001`, an unsupported
+    /// `cred_v` is `1004`, and a non-EdDSA `alg`, a bad signature or a signer other than
+    /// `delegator` are `3004`.
+    pub fn verify(&self, resolver: &DidResolver) -> Result<DelegationCredential, AmpError> {
+        self.check_format()?;
+        let signed = verify_delegation_artifact(&self.credential, resolver)?;
+        let credential = decode_credential(&signed.payload)?;
+        if signed.kid_did() != credential.delegator {
+            return Err(AmpError::delegation_invalid(format!(
+                "delegation {} signed by {} but issued by {}",
+                credential.delegation_id, signed.kid, credential.delegator
+            )));
+        }
+        Ok(credential)
+    }
+
+    fn check_format(&self) -> Result<(), AmpError> {
+        if self.format != DELEGATION_FORMAT_COSE_SIGN1 {
+            return Err(AmpError::invalid_message(format!(
+                "unsupported delegation credential format {:?}",
+                self.format
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// `target` of `delegation-evidence`: what the presenter claims to exercise.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationTarget {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capability: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+}
+
+/// `delegation-evidence` (§5.4), carried in the signed `CAP_INVOKE.body.delegation`.
+/// `chain` runs from the root grant (`L1`) to the link naming the caller (`Ln`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationEvidence {
+    pub chain: Vec<DelegationEnvelope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<DelegationTarget>,
+}
+
+impl DelegationEvidence {
+    pub fn new(chain: Vec<DelegationEnvelope>) -> Self {
+        Self {
+            chain,
+            proof: None,
+            target: None,
+        }
+    }
+
+    /// Decodes `body.delegation`; a wrong shape or an empty chain is `1001`.
+    pub fn from_value(value: Value) -> Result<Self, AmpError> {
+        let evidence: Self = serde_cbor::value::from_value(value)
+            .map_err(|e| AmpError::invalid_message(format!("invalid delegation evidence: {e}")))?;
+        if evidence.chain.is_empty() {
+            return Err(AmpError::invalid_message("delegation evidence chain must not be empty"));
+        }
+        Ok(evidence)
+    }
+
+    pub fn to_value(&self) -> Result<Value, AmpError> {
+        serde_cbor::value::to_value(self)
+            .map_err(|e| AmpError::invalid_message(format!("delegation evidence encode failed: {e}")))
+    }
+}
+
+/// `delegation-revocation-payload` (§7.1).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegationRevocation {
+    pub rev_v: u64,
+    pub delegation_id: String,
+    pub delegator: String,
+    pub revoked_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl DelegationRevocation {
+    pub fn new(delegation_id: impl Into<String>, delegator: impl Into<String>, revoked_at: u64) -> Self {
+        Self {
+            rev_v: DELEGATION_REVOCATION_V1,
+            delegation_id: delegation_id.into(),
+            delegator: delegator.into(),
+            revoked_at,
+            reason: None,
+        }
+    }
+
+    /// COSE_Sign1 over the deterministic CBOR payload, signed by the delegator (§7.1).
+    pub fn sign(&self, delegator: &AgentKeys) -> Result<Vec<u8>, AmpError> {
+        if delegator.did != self.delegator {
+            return Err(AmpError::unauthorized(format!(
+                "{} cannot revoke delegation {} issued by {}",
+                delegator.did, self.delegation_id, self.delegator
+            )));
+        }
+        cose_sign1_sign(delegator, &delegator.assertion_kid(), &to_cbor_canonical(self)?)
+    }
+
+    /// Verifies a signed revocation with the same rules as a credential: `1001` for
+    /// malformed bytes, `1004` for an unsupported `rev_v`, and `3004` for a non-EdDSA
+    /// `alg`, a bad signature or a signer other than `delegator`.
+    pub fn verify(bytes: &[u8], resolver: &DidResolver) -> Result<Self, AmpError> {
+        let signed = verify_delegation_artifact(bytes, resolver)?;
+        let payload = decode_versioned(&signed.payload, "rev_v", DELEGATION_REVOCATION_V1, "delegation revocation")?;
+        let revocation: Self = serde_cbor::value::from_value(payload)
+            .map_err(|e| AmpError::invalid_message(format!("invalid delegation revocation payload: {e}")))?;
+        if revocation.delegation_id.is_empty() || revocation.delegator.is_empty() {
+            return Err(AmpError::invalid_message("delegation revocation needs delegation_id and delegator"));
+        }
+        if signed.kid_did() != revocation.delegator {
+            return Err(AmpError::delegation_invalid(format!(
+                "revocation of {} signed by {} but issued by {}",
+                revocation.delegation_id, signed.kid, revocation.delegator
+            )));
+        }
+        Ok(revocation)
+    }
+}
+
+/// `deleg-revoke-body` (§5.4).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegRevokeBody {
+    pub delegation_id: String,
+    pub revocation: ByteBuf,
+}
+
+impl DelegRevokeBody {
+    pub fn new(delegation_id: impl Into<String>, revocation: Vec<u8>) -> Self {
+        Self {
+            delegation_id: delegation_id.into(),
+            revocation: ByteBuf::from(revocation),
+        }
+    }
+
+    /// The verified revocation; a top-level `delegation_id` that differs from the signed
+    /// one is `4001` (§5.2).
+    pub fn verify(&self, resolver: &DidResolver) -> Result<DelegationRevocation, AmpError> {
+        let revocation = DelegationRevocation::verify(&self.revocation, resolver)?;
+        if revocation.delegation_id != self.delegation_id {
+            return Err(AmpError::bad_request(format!(
+                "DELEG_REVOKE delegation_id {} does not match signed {}",
+                self.delegation_id, revocation.delegation_id
+            )));
+        }
+        Ok(revocation)
+    }
+}
+
+/// COSE decoding for delegation artifacts: `1001` for malformed bytes, `3004` for
+/// anything about the signature header.
+fn peek_delegation_artifact(bytes: &[u8]) -> Result<CoseSign1, AmpError> {
+    let peeked = cose_sign1_peek(bytes).map_err(|e| match e.code {
+        1001 => e,
+        _ => AmpError::delegation_invalid(e.detail),
+    })?;
+    if peeked.alg != COSE_ALG_EDDSA {
+        return Err(AmpError::delegation_invalid(format!(
+            "unsupported delegation signature alg {}",
+            peeked.alg
+        )));
+    }
+    Ok(peeked)
+}
+
+fn verify_delegation_artifact(bytes: &[u8], resolver: &DidResolver) -> Result<CoseSign1, AmpError> {
+    peek_delegation_artifact(bytes)?;
+    cose_sign1_verify(bytes, resolver)
+        .map_err(|e| AmpError::delegation_invalid(format!("delegation signature invalid: {}", e.detail)))
+}
+
+fn decode_credential(payload: &[u8]) -> Result<DelegationCredential, AmpError> {
+    let payload = decode_versioned(payload, "cred_v", DELEGATION_CREDENTIAL_V1, "delegation credential")?;
+    let credential: DelegationCredential = serde_cbor::value::from_value(payload)
+        .map_err(|e| AmpError::invalid_message(format!("invalid delegation credential payload: {e}")))?;
+    credential.validate()?;
+    Ok(credential)
+}
+
+/// Decodes a payload map and checks its version field first, so a future version is
+/// `1004` even when the rest of its shape is unknown.
+fn decode_versioned(payload: &[u8], field: &str, expected: u64, what: &str) -> Result<Value, AmpError> {
+    let payload: Value = serde_cbor::from_slice(payload)
+        .map_err(|e| AmpError::invalid_message(format!("invalid {what} payload: {e}")))?;
+    let Value::Map(fields) = &payload else {
+        return Err(AmpError::invalid_message(format!("{what} payload must be a map")));
+    };
+    match fields.get(&Value::Text(field.into())) {
+        Some(Value::Integer(v)) if *v == i128::from(expected) => Ok(payload),
+        Some(Value::Integer(v)) => Err(AmpError::unsupported_version(format!(
+            "unsupported {field}={v}, expected {expected}"
+        ))),
+        _ => Err(AmpError::invalid_message(format!("{what} {field} is required"))),
+    }
+}
@@ -0,0 +1,473 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use amp001_example::{
+    build_plain_signed, hex_decode, hex_encode, make_message_id, now_ms, read_frame,
+    receive_and_verify, to_cbor_canonical, write_frame, AgentKeys, AmpError, DidResolver,
+    ErrorBody, MessageMeta, ReceivedMessage, Recipients, RpcError, Version, VersionRange,
+    TYPE_ERROR,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+use crate::CapabilityDescriptor;
+
+pub const TYPE_CAP_QUERY: u8 = 0x20;
+pub const TYPE_CAP_DECLARE: u8 = 0x21;
+
+/// RFC 001 §14 suggested `CAP_QUERY` timeout.
+pub const DEFAULT_CAP_QUERY_TTL_MS: u64 = 30_000;
+/// Largest page a responder returns, whatever `limit` asks for.
+pub const MAX_CAP_QUERY_LIMIT: u64 = 100;
+
+const CURSOR_FINGERPRINT_LEN: usize = 16;
+
+/// `cap-filter`: `capability` wins over the legacy `type` alias when both are present.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capability: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_alias: Option<String>,
+    /// §4.4 `semver-range` the descriptor `version` must satisfy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl CapFilter {
+    pub fn capability(name: impl Into<String>) -> Self {
+        Self {
+            capability: Some(name.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_version(mut self, range: impl Into<String>) -> Self {
+        self.version = Some(range.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CapOrder {
+    /// `(name ASC, version DESC)`, the default when `order` is absent.
+    #[default]
+    #[serde(rename = "newest-first")]
+    NewestFirst,
+    /// `(name ASC, version ASC)`.
+    #[serde(rename = "oldest-first")]
+    OldestFirst,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapQueryBody {
+    pub filter: CapFilter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<CapOrder>,
+}
+
+impl CapQueryBody {
+    pub fn new(filter: CapFilter) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    /// Same query for the page after `cursor`; `filter` and `order` must not change.
+    pub fn next_page(&self, cursor: impl Into<String>) -> Self {
+        Self {
+            cursor: Some(cursor.into()),
+            ..self.clone()
+        }
+    }
+}
+
+/// `cap-declare-body`. `next_cursor` is set while more pages remain; the RFC 004 CDDL does
+/// not name the returned cursor, so this follows the RFC 002 poll response.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapDeclareBody {
+    pub capabilities: Vec<CapabilityDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl CapDeclareBody {
+    /// §6.2: a non-empty list of descriptors that each pass §4.2.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        self.validate_page(false)
+    }
+
+    /// [`validate`](Self::validate) for a page answering a cursor: when the records
+    /// after the cursor were removed in between, the last page may be empty.
+    pub fn validate_page(&self, after_cursor: bool) -> Result<(), AmpError> {
+        if self.capabilities.is_empty() && (!after_cursor || self.next_cursor.is_some()) {
+            return Err(AmpError::invalid_message("CAP_DECLARE capabilities must not be empty"));
+        }
+        self.capabilities.iter().try_for_each(CapabilityDescriptor::validate)
+    }
+}
+
+/// Where a page ended, bound to the query it belongs to. Clients only see it hex-encoded.
+/// It is not authenticated: a forged cursor only moves the forger's own page boundary.
+#[derive(Debug, Serialize, Deserialize)]
+struct CursorState {
+    query: ByteBuf,
+    after: String,
+}
+
+/// Local capability descriptors answering `CAP_QUERY` (§6.1, §6.5). Pages are cut by
+/// position in the sort order rather than by offset, so records present for the whole
+/// walk are returned exactly once even if others are added or removed in between.
+#[derive(Debug, Clone, Default)]
+pub struct CapabilitySet {
+    descriptors: Vec<CapabilityDescriptor>,
+    aliases: HashMap<String, String>,
+}
+
+impl CapabilitySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a descriptor after §4.2 validation, replacing one with the same `id`.
+    pub fn insert(&mut self, descriptor: CapabilityDescriptor) -> Result<(), AmpError> {
+        descriptor.validate()?;
+        self.descriptors.retain(|d| d.id != descriptor.id);
+        self.descriptors.push(descriptor);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<CapabilityDescriptor> {
+        let index = self.descriptors.iter().position(|d| d.id == id)?;
+        Some(self.descriptors.remove(index))
+    }
+
+    /// Maps a legacy `type` alias to its canonical capability name.
+    pub fn alias(&mut self, type_alias: impl Into<String>, name: impl Into<String>) {
+        self.aliases.insert(type_alias.into(), name.into());
+    }
+
+    pub fn get(&self, id: &str) -> Option<&CapabilityDescriptor> {
+        self.descriptors.iter().find(|d| d.id == id)
+    }
+
+    pub fn descriptors(&self) -> &[CapabilityDescriptor] {
+        &self.descriptors
+    }
+
+    /// Canonical capability name a filter asks for.
+    pub fn resolve_name<'a>(&'a self, filter: &'a CapFilter) -> Result<&'a str, ErrorBody> {
+        if let Some(name) = &filter.capability {
+            return Ok(name);
+        }
+        let alias = filter
+            .type_alias
+            .as_deref()
+            .ok_or_else(|| ErrorBody::new(1001, "cap-filter needs capability or type"))?;
+        Ok(self.aliases.get(alias).map(String::as_str).unwrap_or(alias))
+    }
+
+    /// Descriptors with the requested name, in `order`: `4002` when there are none and
+    /// `4003` when none satisfies the version range.
+    pub fn matching(&self, filter: &CapFilter, order: CapOrder) -> Result<Vec<&CapabilityDescriptor>, ErrorBody> {
+        let name = self.resolve_name(filter)?;
+        let range = filter
+            .version
+            .as_deref()
+            .map(VersionRange::parse)
+            .transpose()
+            .map_err(|e| ErrorBody::new(4001, format!("cap-filter version: {e}")))?;
+
+        let mut named: Vec<(&CapabilityDescriptor, Version)> = self
+            .descriptors
+            .iter()
+            .filter(|d| d.name == name)
+            .filter_map(|d| d.semver().ok().map(|v| (d, v)))
+            .collect();
+        if named.is_empty() {
+            return Err(ErrorBody::new(4002, format!("capability {name} not found")));
+        }
+        if let Some(range) = &range {
+            named.retain(|(_, version)| range.matches(version));
+            if named.is_empty() {
+                return Err(ErrorBody::new(4003, format!("no {name} version satisfies {range}")));
+            }
+        }
+        named.sort_by(|a, b| sort_key(order, (&a.0.name, &a.1, &a.0.id), (&b.0.name, &b.1, &b.0.id)));
+        Ok(named.into_iter().map(|(d, _)| d).collect())
+    }
+
+    /// Answers one `CAP_QUERY` body. Malformed cursors, and cursors replayed with another
+    /// `filter` or `order`, are `4001`. A cursor past every remaining record gets an
+    /// empty last page.
+    pub fn query(&self, query: &CapQueryBody) -> Result<CapDeclareBody, ErrorBody> {
+        let order = query.order.unwrap_or_default();
+        let limit = match query.limit {
+            Some(0) => return Err(ErrorBody::new(4001, "CAP_QUERY limit must be positive")),
+            Some(limit) => limit.min(MAX_CAP_QUERY_LIMIT),
+            None => MAX_CAP_QUERY_LIMIT,
+        } as usize;
+        let matching = self.matching(&query.filter, order)?;
+        let fingerprint = query_fingerprint(query)?;
+
+        let start = match &query.cursor {
+            None => 0,
+            Some(cursor) => {
+                let after = decode_cursor(cursor, &fingerprint)?;
+                let after_key = split_id(&after)
+                    .ok_or_else(|| ErrorBody::new(4001, "CAP_QUERY cursor is malformed"))?;
+                matching
+                    .iter()
+                    .position(|d| {
+                        let version = d.semver().unwrap_or_else(|_| Version::new(0, 0, 0));
+                        sort_key(order, (&d.name, &version, &d.id), (after_key.0, &after_key.1, &after))
+                            == Ordering::Greater
+                    })
+                    .unwrap_or(matching.len())
+            }
+        };
+
+        let page: Vec<CapabilityDescriptor> = matching[start..]
+            .iter()
+            .take(limit)
+            .map(|d| (*d).clone())
+            .collect();
+        let next_cursor = match page.last() {
+            Some(last) if start + page.len() < matching.len() => Some(encode_cursor(&fingerprint, &last.id)?),
+            _ => None,
+        };
+        Ok(CapDeclareBody {
+            capabilities: page,
+            next_cursor,
+        })
+    }
+
+    /// `CAP_DECLARE` or `ERROR` wire bytes answering a verified `CAP_QUERY`, with
+    /// `reply_to` set to the query id and the query's thread kept.
+    pub fn respond(
+        &self,
+        agent: &AgentKeys,
+        request: &ReceivedMessage,
+        now_ms: u64,
+    ) -> Result<Vec<u8>, AmpError> {
+        if request.meta.typ != TYPE_CAP_QUERY {
+            return Err(AmpError::invalid_message("message typ is not CAP_QUERY"));
+        }
+        let outcome = request
+            .decode_body::<CapQueryBody>()
+            .map_err(ErrorBody::from)
+            .and_then(|query| self.query(&query));
+
+        let meta = MessageMeta {
+            v: request.meta.v,
+            id: make_message_id(now_ms, OsRng.next_u64()),
+            typ: if outcome.is_ok() { TYPE_CAP_DECLARE } else { TYPE_ERROR },
+            ts_ms: now_ms,
+            ttl_ms: request.meta.ttl_ms,
+            from: String::new(),
+            to: Recipients::One(request.meta.from.clone()),
+            reply_to: Some(request.meta.id),
+            thread_id: request.meta.thread_id.clone(),
+        };
+        match outcome {
+            Ok(declare) => build_plain_signed(agent, meta, &declare),
+            Err(error) => build_plain_signed(agent, meta, &error),
+        }
+    }
+
+    /// Answers every verifiable `CAP_QUERY` read from `reader` until the transport
+    /// closes. Other frames are ignored.
+    pub fn serve<R: Read, W: Write>(
+        &self,
+        agent: &AgentKeys,
+        resolver: &DidResolver,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        loop {
+            let frame = match read_frame(reader) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let now = now_ms();
+            let Ok(request) = receive_and_verify(agent, &frame, resolver, now) else {
+                continue;
+            };
+            if request.meta.typ != TYPE_CAP_QUERY {
+                continue;
+            }
+            if let Ok(reply) = self.respond(agent, &request, now) {
+                write_frame(writer, &reply)?;
+            }
+        }
+    }
+}
+
+/// Signed `CAP_QUERY` to `to`; returns its id for matching the `CAP_DECLARE`.
+pub fn build_cap_query(
+    agent: &AgentKeys,
+    to: &str,
+    query: &CapQueryBody,
+    ttl_ms: u64,
+    now_ms: u64,
+) -> Result<([u8; 16], Vec<u8>), AmpError> {
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ: TYPE_CAP_QUERY,
+        ts_ms: now_ms,
+        ttl_ms,
+        from: String::new(),
+        to: Recipients::One(to.to_string()),
+        reply_to: None,
+        thread_id: None,
+    };
+    let id = meta.id;
+    Ok((id, build_plain_signed(agent, meta, query)?))
+}
+
+/// Checks a verified reply to `query`, sent to `provider` as `query_id`. A reply that
+/// does not correlate (wrong `reply_to` or sender) is `4001` (§6.2, A.13); an `ERROR`
+/// reply comes back as [`RpcError::Remote`].
+pub fn accept_cap_declare(
+    query_id: &[u8; 16],
+    query: &CapQueryBody,
+    provider: &str,
+    reply: &ReceivedMessage,
+) -> Result<CapDeclareBody, RpcError> {
+    if reply.meta.reply_to.as_ref() != Some(query_id) || reply.meta.from != provider {
+        return Err(AmpError::bad_request("CAP_DECLARE reply_to does not match the CAP_QUERY id").into());
+    }
+    match reply.meta.typ {
+        TYPE_ERROR => Err(RpcError::Remote(reply.decode_body()?)),
+        TYPE_CAP_DECLARE => {
+            let declare: CapDeclareBody = reply.decode_body()?;
+            declare.validate_page(query.cursor.is_some())?;
+            Ok(declare)
+        }
+        typ => Err(AmpError::bad_request(format!("typ 0x{typ:02x} does not answer CAP_QUERY")).into()),
+    }
+}
+
+/// Follows `next_cursor` from `first` until the last page, collecting every descriptor.
+/// `page` performs one `CAP_QUERY` round trip over whatever transport the caller uses.
+pub fn query_all_pages<F>(first: &CapQueryBody, mut page: F) -> Result<Vec<CapabilityDescriptor>, RpcError>
+where
+    F: FnMut(&CapQueryBody) -> Result<CapDeclareBody, RpcError>,
+{
+    let mut query = first.clone();
+    let mut all = Vec::new();
+    loop {
+        let declare = page(&query)?;
+        all.extend(declare.capabilities);
+        match declare.next_cursor {
+            Some(cursor) if query.cursor.as_deref() == Some(cursor.as_str()) => {
+                return Err(AmpError::bad_request("CAP_DECLARE cursor did not advance").into());
+            }
+            Some(cursor) => query = first.next_page(cursor),
+            None => return Ok(all),
+        }
+    }
+}
+
+/// Requester side of `CAP_QUERY` and `CAP_INVOKE` over a framed stream to the provider
+/// (directly or via a relay that forwards both ways). Unrelated frames read while
+/// waiting are skipped.
+pub struct CapClient<S: Read + Write> {
+    pub(crate) agent: AgentKeys,
+    pub(crate) resolver: DidResolver,
+    pub(crate) stream: S,
+    ttl_ms: u64,
+}
+
+impl<S: Read + Write> CapClient<S> {
+    pub fn new(agent: AgentKeys, resolver: DidResolver, stream: S) -> Self {
+        Self {
+            agent,
+            resolver,
+            stream,
+            ttl_ms: DEFAULT_CAP_QUERY_TTL_MS,
+        }
+    }
+
+    /// Envelope `ttl_ms` for `CAP_QUERY`; invocations use their own `timeout_ms`.
+    pub fn ttl_ms(mut self, ttl_ms: u64) -> Self {
+        self.ttl_ms = ttl_ms;
+        self
+    }
+
+    /// One page: sends `query` to `provider` and waits for the correlated reply.
+    pub fn query(&mut self, provider: &str, query: &CapQueryBody) -> Result<CapDeclareBody, RpcError> {
+        let (id, wire) = build_cap_query(&self.agent, provider, query, self.ttl_ms, now_ms())?;
+        write_frame(&mut self.stream, &wire)
+            .map_err(|e| AmpError::endpoint_unreachable(format!("CAP_QUERY write failed: {e}")))?;
+        loop {
+            let frame = read_frame(&mut self.stream).map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => RpcError::Timeout,
+                _ => AmpError::endpoint_unreachable(format!("CAP_DECLARE read failed: {e}")).into(),
+            })?;
+            let Ok(reply) = receive_and_verify(&self.agent, &frame, &self.resolver, now_ms()) else {
+                continue;
+            };
+            if reply.meta.reply_to == Some(id) {
+                return accept_cap_declare(&id, query, provider, &reply);
+            }
+        }
+    }
+
+    /// Every descriptor matching `query`, paging with the returned cursors.
+    pub fn query_all(&mut self, provider: &str, query: &CapQueryBody) -> Result<Vec<CapabilityDescriptor>, RpcError> {
+        query_all_pages(query, |page| self.query(provider, page))
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// `(name ASC, version DESC|ASC, id ASC)`; the id only separates build-metadata variants.
+fn sort_key(order: CapOrder, a: (&str, &Version, &str), b: (&str, &Version, &str)) -> Ordering {
+    let versions = match order {
+        CapOrder::NewestFirst => b.1.cmp(a.1),
+        CapOrder::OldestFirst => a.1.cmp(b.1),
+    };
+    a.0.cmp(b.0).then(versions).then_with(|| a.2.cmp(b.2))
+}
+
+fn split_id(id: &str) -> Option<(&str, Version)> {
+    let (name, version) = id.rsplit_once(':')?;
+    Some((name, Version::parse(version).ok()?))
+}
+
+/// Digest of what a cursor must be replayed with: the filter and the effective order.
+fn query_fingerprint(query: &CapQueryBody) -> Result<Vec<u8>, ErrorBody> {
+    let bytes = to_cbor_canonical(&(&query.filter, query.order.unwrap_or_default()))?;
+    Ok(Sha256::digest(bytes)[..CURSOR_FINGERPRINT_LEN].to_vec())
+}
+
+fn encode_cursor(fingerprint: &[u8], after: &str) -> Result<String, ErrorBody> {
+    let state = CursorState {
+        query: ByteBuf::from(fingerprint.to_vec()),
+        after: after.to_string(),
+    };
+    Ok(hex_encode(&to_cbor_canonical(&state)?))
+}
+
+fn decode_cursor(cursor: &str, fingerprint: &[u8]) -> Result<String, ErrorBody> {
+    let state: CursorState = hex_decode(cursor)
+        .and_then(|bytes| serde_cbor::from_slice(&bytes).ok())
+        .ok_or_else(|| ErrorBody::new(4001, "CAP_QUERY cursor is malformed"))?;
+    if state.query.as_ref() != fingerprint {
+        return Err(ErrorBody::new(4001, "CAP_QUERY cursor belongs to another filter or order"));
+    }
+    Ok(state.after)
+}
@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use amp001_example::{
+    build_plain_signed, make_message_id, now_ms, read_frame, receive_and_verify, write_frame,
+    AgentKeys, AmpError, DidResolver, ErrorBody, MessageMeta, ReceivedMessage, Recipients,
+    Version, TYPE_ERROR,
+};
+use rand_core::{OsRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+
+use crate::{
+    negotiate, parse_capability_id, CapClient, CapFilter, CapNegotiateHints, CapabilityDescriptor,
+    CapabilitySet, CompatibilityDecision, CompatibilityPolicy, DecisionKind, DelegationDecision,
+    DelegationEvaluator, JsonSchema, SCHEMA_MEDIA_TYPE_JSON, TYPE_CAP_QUERY,
+};
+
+pub const TYPE_CAP_INVOKE: u8 = 0x22;
+pub const TYPE_CAP_RESULT: u8 = 0x23;
+
+/// RFC 001 §16.4 suggested timeout for a simple `CAP_INVOKE`.
+pub const DEFAULT_CAP_INVOKE_TIMEOUT_MS: u64 = 60_000;
+
+const SESSION_ID_LEN: usize = 16;
+
+/// `cap-session-context`, the RFC 006 extension carried by `CAP_INVOKE` and `CAP_RESULT`.
+/// It never changes how the invocation itself is resolved or validated.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapSessionContext {
+    pub session_id: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_scope: Option<bool>,
+}
+
+impl CapSessionContext {
+    pub fn new(session_id: [u8; SESSION_ID_LEN]) -> Self {
+        Self {
+            session_id: ByteBuf::from(session_id.to_vec()),
+            session_scope: None,
+        }
+    }
+
+    /// Shape errors in the session extension are `1001` (§9).
+    pub fn validate(&self) -> Result<(), AmpError> {
+        if self.session_id.len() != SESSION_ID_LEN {
+            return Err(AmpError::invalid_message("session_id must be a 16-byte bstr"));
+        }
+        if self.session_scope == Some(false) {
+            return Err(AmpError::invalid_message("session_scope may only be true"));
+        }
+        Ok(())
+    }
+}
+
+/// `cap-invoke-body`, covering both the by-id and by-name forms. [`validate`] enforces
+/// which field combinations are allowed.
+///
+/// [`validate`]: CapInvokeBody::validate
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CapInvokeBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capability: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negotiate: Option<CapNegotiateHints>,
+    pub params: Value,
+    /// RFC 005 delegation evidence, only ever read from the signed body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delegation: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<CapSessionContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl CapInvokeBody {
+    /// The recommended form: a concrete `capability-id`.
+    pub fn by_id(id: impl Into<String>, params: Value) -> Self {
+        Self {
+            id: Some(id.into()),
+            ..Self::empty(params)
+        }
+    }
+
+    pub fn by_name(capability: impl Into<String>, version: impl Into<String>, params: Value) -> Self {
+        Self {
+            capability: Some(capability.into()),
+            version: Some(version.into()),
+            ..Self::empty(params)
+        }
+    }
+
+    /// Lets the provider pick the version with [`negotiate`](crate::negotiate).
+    pub fn negotiated(capability: impl Into<String>, hints: CapNegotiateHints, params: Value) -> Self {
+        Self {
+            capability: Some(capability.into()),
+            negotiate: Some(hints),
+            ..Self::empty(params)
+        }
+    }
+
+    fn empty(params: Value) -> Self {
+        Self {
+            id: None,
+            capability: None,
+            type_alias: None,
+            version: None,
+            negotiate: None,
+            params,
+            delegation: None,
+            session: None,
+            timeout_ms: None,
+        }
+    }
+
+    /// §7.1/§7.4 identity form: `id`, or a name plus exactly one of `version` and
+    /// `negotiate`. `id` with `negotiate`, or with a `capability`/`version` that names
+    /// something else (A.8, A.16), is `4001`; a malformed `session` is `1001`.
+    pub fn validate(&self) -> Result<(), AmpError> {
+        if let Some(session) = &self.session {
+            session.validate()?;
+        }
+        if self.timeout_ms == Some(0) {
+            return Err(AmpError::bad_request("CAP_INVOKE timeout_ms must be positive"));
+        }
+        if let Some(id) = &self.id {
+            if self.negotiate.is_some() {
+                return Err(AmpError::bad_request("CAP_INVOKE id excludes negotiate"));
+            }
+            let (name, version) =
+                parse_capability_id(id).map_err(|e| AmpError::bad_request(e.detail))?;
+            if self.capability.as_deref().is_some_and(|c| c != name)
+                || self.version.as_deref().is_some_and(|v| v != version)
+            {
+                return Err(AmpError::bad_request(format!(
+                    "CAP_INVOKE id {id} conflicts with capability/version"
+                )));
+            }
+            return Ok(());
+        }
+        if self.capability.is_none() && self.type_alias.is_none() {
+            return Err(AmpError::bad_request("CAP_INVOKE needs id, capability or type"));
+        }
+        match (&self.version, &self.negotiate) {
+            (Some(version), None) => Version::parse(version)
+                .map(drop)
+                .map_err(|e| AmpError::bad_request(format!("CAP_INVOKE version: {e}"))),
+            (None, Some(_)) => Ok(()),
+            _ => Err(AmpError::bad_request("CAP_INVOKE by name needs exactly one of version and negotiate")),
+        }
+    }
+}
+
+/// `cap-result-error`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CapResultError {
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl CapResultError {
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            name: None,
+            message: Some(message.into()),
+            details: None,
+        }
+    }
+}
+
+impl From<AmpError> for CapResultError {
+    fn from(err: AmpError) -> Self {
+        Self {
+            code: err.code,
+            name: Some(err.name.to_string()),
+            message: Some(err.detail),
+            details: None,
+        }
+    }
+}
+
+/// `cap-result-body`: the terminal answer to an accepted invocation.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum CapResultBody {
+    Success {
+        result: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<CapSessionContext>,
+    },
+    Error {
+        error: CapResultError,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<CapSessionContext>,
+    },
+}
+
+impl CapResultBody {
+    pub fn session(&self) -> Option<&CapSessionContext> {
+        match self {
+            CapResultBody::Success { session, .. } | CapResultBody::Error { session, .. } => session.as_ref(),
+        }
+    }
+
+    /// The typed `result`, or [`CapInvokeError::Failed`] for `status = "error"`.
+    pub fn into_result<R: DeserializeOwned>(self) -> Result<R, CapInvokeError> {
+        match self {
+            CapResultBody::Success { result, .. } => serde_cbor::value::from_value(result).map_err(|e| {
+                AmpError::invalid_message(format!("CAP_RESULT result decode failed: {e}")).into()
+            }),
+            CapResultBody::Error { error, .. } => Err(CapInvokeError::Failed(error)),
+        }
+    }
+}
+
+/// How an invocation ended for the invoker (§7.3).
+#[derive(Debug, Clone, PartialEq)]
+pub enum CapInvokeError {
+    /// The invocation could not be sent, or its reply was unusable or uncorrelated.
+    Local(AmpError),
+    /// The provider rejected it before execution with `ERROR`.
+    Rejected(ErrorBody),
+    /// The provider accepted it and answered `CAP_RESULT` with `status = "error"`.
+    Failed(CapResultError),
+    /// No reply before the transport read deadline.
+    Timeout,
+}
+
+impl CapInvokeError {
+    pub fn code(&self) -> u16 {
+        match self {
+            CapInvokeError::Local(err) => err.code,
+            CapInvokeError::Rejected(err) => err.code,
+            CapInvokeError::Failed(err) => err.code,
+            CapInvokeError::Timeout => 5003,
+        }
+    }
+}
+
+impl fmt::Display for CapInvokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapInvokeError::Local(err) => write!(f, "{err}"),
+            CapInvokeError::Rejected(err) => write!(f, "CAP_INVOKE rejected {}: {}", err.code, err.message),
+            CapInvokeError::Failed(err) => {
+                write!(f, "CAP_RESULT error {}: {}", err.code, err.message.as_deref().unwrap_or(""))
+            }
+            CapInvokeError::Timeout => write!(f, "CAP_INVOKE deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for CapInvokeError {}
+
+impl From<AmpError> for CapInvokeError {
+    fn from(err: AmpError) -> Self {
+        CapInvokeError::Local(err)
+    }
+}
+
+/// What a handler gets for one accepted invocation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapCall {
+    pub invoke_id: [u8; 16],
+    pub caller: String,
+    /// The descriptor the invocation resolved to, after negotiation.
+    pub descriptor: CapabilityDescriptor,
+    /// Params that already passed the input schema.
+    pub params: Value,
+    /// Evidence accepted at validation step 3, if the invocation was delegated.
+    pub delegation: Option<Value>,
+    /// The RFC 005 decision that accepted `delegation`, when an evaluator is installed.
+    pub authorization: Option<DelegationDecision>,
+    pub session: Option<CapSessionContext>,
+    /// How the compatibility policy chose `descriptor`, when one is installed.
+    pub compatibility: Option<CompatibilityDecision>,
+}
+
+/// Where an invocation ended on the executor side (§7.3).
+#[derive(Debug, Clone, PartialEq)]
+pub enum CapOutcome {
+    /// `VALIDATING -> REJECTED`: answered with `ERROR`.
+    Rejected(ErrorBody),
+    /// `EXECUTING -> DONE`: answered with `CAP_RESULT`.
+    Completed(CapResultBody),
+}
+
+type Handler = Arc<dyn Fn(&CapCall) -> Result<Value, CapResultError> + Send + Sync>;
+type CallerPolicy = Box<dyn Fn(&ReceivedMessage) -> bool + Send + Sync>;
+type DelegationCheck = Box<dyn Fn(&ReceivedMessage, &Value) -> bool + Send + Sync>;
+type CapabilityPolicy = Box<dyn Fn(&ReceivedMessage, &str) -> bool + Send + Sync>;
+
+struct Registered {
+    input_schema: JsonSchema,
+    handler: Handler,
+}
+
+/// Provider side of `CAP_INVOKE`. Every request goes through the §7.2 order:
+///
+/// 1. body shape (`1001`, or `4001` for a missing field or identity conflict);
+/// 2. the caller policy (`3001`);
+/// 3. delegation evidence, when present (`3004`, or the evaluator's denial code);
+/// 4. capability identity (`4002`);
+/// 5. the capability policy (`3001`);
+/// 6. version, exact or negotiated (`4003`);
+/// 7. params against the registered input schema (`4004`).
+///
+/// A rejection is answered with `ERROR`. Accepted invocations run their handler on a
+/// worker thread and always end in `CAP_RESULT`: handler errors and panics become
+/// `status = "error"`, and a handler still running at the deadline is reported as
+/// `5003` (the worker is left to finish on its own). Policy denials carry a fixed
+/// message so they never reveal whether the capability exists.
+pub struct CapDispatcher {
+    capabilities: CapabilitySet,
+    registered: HashMap<String, Registered>,
+    caller_policy: Option<CallerPolicy>,
+    delegation_check: Option<DelegationCheck>,
+    delegation_evaluator: Option<DelegationEvaluator>,
+    capability_policy: Option<CapabilityPolicy>,
+    compatibility: Option<CompatibilityPolicy>,
+    max_timeout_ms: u64,
+}
+
+impl Default for CapDispatcher {
+    fn default() -> Self {
+        Self {
+            capabilities: CapabilitySet::new(),
+            registered: HashMap::new(),
+            caller_policy: None,
+            delegation_check: None,
+            delegation_evaluator: None,
+            capability_policy: None,
+            compatibility: None,
+            max_timeout_ms: DEFAULT_CAP_INVOKE_TIMEOUT_MS,
+        }
+    }
+}
+
+impl CapDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for `descriptor`. `input_schema` are the schema bytes the
+    /// descriptor references: they must match its hash (`5002`) and be an
+    /// `application/schema+json` document this crate can enforce.
+    pub fn register<P, R, F>(
+        &mut self,
+        descriptor: CapabilityDescriptor,
+        input_schema: &[u8],
+        handler: F,
+    ) -> Result<(), AmpError>
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(&CapCall, P) -> Result<R, CapResultError> + Send + Sync + 'static,
+    {
+        descriptor.input_schema.verify_artifact(input_schema)?;
+        let media_type = descriptor.input_schema.media_type();
+        if media_type != SCHEMA_MEDIA_TYPE_JSON {
+            return Err(AmpError::invalid_message(format!(
+                "no validator for input schema media type {media_type}"
+            )));
+        }
+        let input_schema = JsonSchema::from_slice(input_schema)?;
+
+        let handler: Handler = Arc::new(move |call| {
+            let params: P = serde_cbor::value::from_value(call.params.clone())
+                .map_err(|e| AmpError::schema_violation(format!("params do not match handler: {e}")))?;
+            let result = handler(call, params)?;
+            serde_cbor::value::to_value(&result)
+                .map_err(|e| AmpError::internal_error(format!("result encode failed: {e}")).into())
+        });
+        let id = descriptor.id.clone();
+        self.capabilities.insert(descriptor)?;
+        self.registered.insert(id, Registered { input_schema, handler });
+        Ok(())
+    }
+
+    /// Maps a legacy `type` alias to its canonical capability name.
+    pub fn alias(&mut self, type_alias: impl Into<String>, name: impl Into<String>) {
+        self.capabilities.alias(type_alias, name);
+    }
+
+    /// The registered descriptors, for answering `CAP_QUERY`.
+    pub fn capabilities(&self) -> &CapabilitySet {
+        &self.capabilities
+    }
+
+    /// Step 2: generic caller policy that does not depend on the capability.
+    pub fn authorize_caller<F>(&mut self, policy: F)
+    where
+        F: Fn(&ReceivedMessage) -> bool + Send + Sync + 'static,
+    {
+        self.caller_policy = Some(Box::new(policy));
+    }
+
+    /// Step 3: RFC 005 validation of `body.delegation`. Without a check installed every
+    /// delegated invocation is refused with `3004`.
+    pub fn verify_delegation<F>(&mut self, check: F)
+    where
+        F: Fn(&ReceivedMessage, &Value) -> bool + Send + Sync + 'static,
+    {
+        self.delegation_check = Some(Box::new(check));
+    }
+
+    /// Step 3 through the RFC 005 §6.1 evaluator, which replaces any `verify_delegation`
+    /// check. A denial answers with its own code (`1001`, `1004`, `3001`, `3004` or
+    /// `5002`). Once step 6 has picked the version to run, it must be in the delegated
+    /// scope as well (`3004`); the confirmed decision is passed to the handler in `CapCall`.
+    pub fn evaluate_delegation(&mut self, evaluator: DelegationEvaluator) {
+        self.delegation_evaluator = Some(evaluator);
+    }
+
+    /// Step 5: capability-level ACL, given the resolved capability name.
+    pub fn authorize_capability<F>(&mut self, policy: F)
+    where
+        F: Fn(&ReceivedMessage, &str) -> bool + Send + Sync + 'static,
+    {
+        self.capability_policy = Some(Box::new(policy));
+    }
+
+    /// Step 6: resolves versions through an RFC 004 §8 policy instead of exact match and
+    /// §6.3 negotiation alone. The decision is passed to the handler in `CapCall`.
+    pub fn compatibility(&mut self, policy: CompatibilityPolicy) {
+        self.compatibility = Some(policy);
+    }
+
+    /// Upper bound on handler run time; a request's `timeout_ms` can only shorten it.
+    pub fn max_timeout_ms(&mut self, timeout_ms: u64) {
+        self.max_timeout_ms = timeout_ms;
+    }
+
+    /// Validates and, if accepted, executes one verified `CAP_INVOKE`.
+    pub fn handle(&self, request: &ReceivedMessage) -> CapOutcome {
+        match self.accept(request) {
+            Ok((call, timeout_ms)) => CapOutcome::Completed(self.execute(call, timeout_ms)),
+            Err(error) => CapOutcome::Rejected(error),
+        }
+    }
+
+    /// `CAP_RESULT` or `ERROR` wire bytes answering a verified `CAP_INVOKE`, with
+    /// `reply_to` set to the invocation id and the request's thread kept.
+    pub fn respond(
+        &self,
+        agent: &AgentKeys,
+        request: &ReceivedMessage,
+        now_ms: u64,
+    ) -> Result<Vec<u8>, AmpError> {
+        if request.meta.typ != TYPE_CAP_INVOKE {
+            return Err(AmpError::invalid_message("message typ is not CAP_INVOKE"));
+        }
+        let outcome = self.handle(request);
+
+        let meta = MessageMeta {
+            v: request.meta.v,
+            id: make_message_id(now_ms, OsRng.next_u64()),
+            typ: match outcome {
+                CapOutcome::Completed(_) => TYPE_CAP_RESULT,
+                CapOutcome::Rejected(_) => TYPE_ERROR,
+            },
+            ts_ms: now_ms,
+            ttl_ms: request.meta.ttl_ms,
+            from: String::new(),
+            to: Recipients::One(request.meta.from.clone()),
+            reply_to: Some(request.meta.id),
+            thread_id: request.meta.thread_id.clone(),
+        };
+        match outcome {
+            CapOutcome::Completed(result) => build_plain_signed(agent, meta, &result),
+            CapOutcome::Rejected(error) => build_plain_signed(agent, meta, &error),
+        }
+    }
+
+    /// Answers every verifiable `CAP_INVOKE` and `CAP_QUERY` read from `reader` until the
+    /// transport closes, one at a time. Other frames are ignored.
+    pub fn serve<R: Read, W: Write>(
+        &self,
+        agent: &AgentKeys,
+        resolver: &DidResolver,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        loop {
+            let frame = match read_frame(reader) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let now = now_ms();
+            let Ok(request) = receive_and_verify(agent, &frame, resolver, now) else {
+                continue;
+            };
+            let reply = match request.meta.typ {
+                TYPE_CAP_INVOKE => self.respond(agent, &request, now_ms()),
+                TYPE_CAP_QUERY => self.capabilities.respond(agent, &request, now),
+                _ => continue,
+            };
+            if let Ok(reply) = reply {
+                write_frame(writer, &reply)?;
+            }
+        }
+    }
+
+    /// Steps 1-7; on success the call to run and its deadline.
+    fn accept(&self, request: &ReceivedMessage) -> Result<(CapCall, u64), ErrorBody> {
+        let unauthorized = || ErrorBody::new(3001, "invocation not authorized");
+
+        let body = decode_cap_invoke(request)?;
+
+        if !self.caller_policy.as_ref().is_none_or(|allow| allow(request)) {
+            return Err(unauthorized());
+        }
+
+        let mut authorization = None;
+        if let Some(evaluator) = &self.delegation_evaluator {
+            if let Some(decision) = evaluator.authorize_invoke(request, &body, now_ms()) {
+                if !decision.is_allowed() {
+                    return Err(decision.into());
+                }
+                authorization = Some(decision);
+            }
+        } else if let Some(evidence) = &body.delegation {
+            if !self.delegation_check.as_ref().is_some_and(|check| check(request, evidence)) {
+                return Err(ErrorBody::new(3004, "delegation evidence invalid"));
+            }
+        }
+
+        let name = match &body.id {
+            Some(id) => parse_capability_id(id)?.0.to_string(),
+            None => self
+                .capabilities
+                .resolve_name(&CapFilter {
+                    capability: body.capability.clone(),
+                    type_alias: body.type_alias.clone(),
+                    version: None,
+                })?
+                .to_string(),
+        };
+        let name = name.as_str();
+        let declared: Vec<CapabilityDescriptor> = self
+            .capabilities
+            .descriptors()
+            .iter()
+            .filter(|d| d.name == name)
+            .cloned()
+            .collect();
+        if declared.is_empty() {
+            return Err(ErrorBody::new(4002, format!("capability {name} not found")));
+        }
+
+        if !self.capability_policy.as_ref().is_none_or(|allow| allow(request, name)) {
+            return Err(unauthorized());
+        }
+
+        if let Some(policy) = &self.compatibility {
+            let decision = policy.decide(&self.capabilities, &body, |id| {
+                self.registered.get(id).map(|registered| &registered.input_schema)
+            });
+            if decision.kind == DecisionKind::Reject {
+                return Err(decision.into());
+            }
+            let selected = decision.selected.as_deref().unwrap_or_default();
+            let descriptor = declared.iter().find(|d| d.id == selected).cloned();
+            let (Some(descriptor), Some(params)) = (descriptor, decision.params.clone()) else {
+                return Err(ErrorBody::new(5001, "compatibility policy selected no capability"));
+            };
+            let authorization = self.confirm_delegation(authorization, &descriptor)?;
+            self.registered[&descriptor.id].input_schema.validate(&params)?;
+            return Ok(self.call(request, body, descriptor, params, Some(decision), authorization));
+        }
+
+        let mismatch = || ErrorBody::new(4003, format!("no registered {name} version matches"));
+        let descriptor = match (&body.id, &body.version, &body.negotiate) {
+            (Some(id), _, _) => declared.iter().find(|d| d.id == *id).ok_or_else(mismatch)?,
+            (None, Some(version), _) => {
+                let wanted = Version::parse(version).map_err(|e| ErrorBody::new(4001, e.to_string()))?;
+                declared
+                    .iter()
+                    .filter(|d| d.semver().is_ok_and(|v| v == wanted))
+                    .min_by(|a, b| a.id.cmp(&b.id))
+                    .ok_or_else(mismatch)?
+            }
+            (None, None, Some(hints)) => {
+                let chosen = negotiate(name, &declared, hints)?;
+                declared.iter().find(|d| d.id == chosen.id).ok_or_else(mismatch)?
+            }
+            (None, None, None) => return Err(ErrorBody::new(4001, "CAP_INVOKE has no version")),
+        };
+
+        let authorization = self.confirm_delegation(authorization, descriptor)?;
+        let registered = &self.registered[&descriptor.id];
+        registered.input_schema.validate(&body.params)?;
+
+        let (descriptor, params) = (descriptor.clone(), body.params.clone());
+        Ok(self.call(request, body, descriptor, params, None, authorization))
+    }
+
+    /// Step 3 checked the invoked name; the version that will run must be delegated too.
+    fn confirm_delegation(
+        &self,
+        authorization: Option<DelegationDecision>,
+        descriptor: &CapabilityDescriptor,
+    ) -> Result<Option<DelegationDecision>, ErrorBody> {
+        let (Some(decision), Some(evaluator)) = (authorization, &self.delegation_evaluator) else {
+            return Ok(None);
+        };
+        let decision = evaluator.confirm_capability(decision, &descriptor.id);
+        if !decision.is_allowed() {
+            return Err(decision.into());
+        }
+        Ok(Some(decision))
+    }
+
+    fn call(
+        &self,
+        request: &ReceivedMessage,
+        body: CapInvokeBody,
+        descriptor: CapabilityDescriptor,
+        params: Value,
+        compatibility: Option<CompatibilityDecision>,
+        authorization: Option<DelegationDecision>,
+    ) -> (CapCall, u64) {
+        let timeout_ms = body.timeout_ms.unwrap_or(self.max_timeout_ms).min(self.max_timeout_ms);
+        let call = CapCall {
+            invoke_id: request.meta.id,
+            caller: request.meta.from.clone(),
+            descriptor,
+            params,
+            delegation: body.delegation,
+            authorization,
+            session: body.session,
+            compatibility,
+        };
+        (call, timeout_ms)
+    }
+
+    fn execute(&self, call: CapCall, timeout_ms: u64) -> CapResultBody {
+        let handler = Arc::clone(&self.registered[&call.descriptor.id].handler);
+        let session = call.session.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(handler(&call));
+        });
+        let outcome = match rx.recv_timeout(Duration::from_millis(timeout_ms)) {
+            Ok(outcome) => outcome,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(AmpError::timeout(format!("handler exceeded {timeout_ms} ms")).into())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(AmpError::internal_error("handler panicked").into()),
+        };
+        match outcome {
+            Ok(result) => CapResultBody::Success { result, session },
+            Err(error) => CapResultBody::Error { error, session },
+        }
+    }
+}
+
+/// Step 1: `1001` when the body is not a well-formed `cap-invoke-body`, `4001` when
+/// `params` is missing or the identity fields conflict.
+fn decode_cap_invoke(request: &ReceivedMessage) -> Result<CapInvokeBody, ErrorBody> {
+    let raw: Value = request.decode_body()?;
+    match &raw {
+        Value::Map(map) if !map.contains_key(&Value::Text("params".to_string())) => {
+            return Err(ErrorBody::new(4001, "CAP_INVOKE is missing params"));
+        }
+        Value::Map(_) => {}
+        _ => return Err(ErrorBody::new(1001, "CAP_INVOKE body must be a map")),
+    }
+    let body: CapInvokeBody = serde_cbor::value::from_value(raw)
+        .map_err(|e| ErrorBody::new(1001, format!("CAP_INVOKE body: {e}")))?;
+    body.validate()?;
+    Ok(body)
+}
+
+/// Signed `CAP_INVOKE` to `to`; returns its id for matching the `CAP_RESULT`.
+pub fn build_cap_invoke(
+    agent: &AgentKeys,
+    to: &str,
+    body: &CapInvokeBody,
+    ttl_ms: u64,
+    now_ms: u64,
+) -> Result<([u8; 16], Vec<u8>), AmpError> {
+    body.validate()?;
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ: TYPE_CAP_INVOKE,
+        ts_ms: now_ms,
+        ttl_ms,
+        from: String::new(),
+        to: Recipients::One(to.to_string()),
+        reply_to: None,
+        thread_id: None,
+    };
+    let id = meta.id;
+    Ok((id, build_plain_signed(agent, meta, body)?))
+}
+
+/// Checks a verified reply to the `CAP_INVOKE` `invoke_id` sent to `provider`. A reply
+/// that does not correlate is `4001` and must not touch the invocation (§9, A.12); an
+/// `ERROR` reply is [`CapInvokeError::Rejected`].
+pub fn accept_cap_result(
+    invoke_id: &[u8; 16],
+    provider: &str,
+    reply: &ReceivedMessage,
+) -> Result<CapResultBody, CapInvokeError> {
+    if reply.meta.reply_to.as_ref() != Some(invoke_id) || reply.meta.from != provider {
+        return Err(AmpError::bad_request("CAP_RESULT reply_to does not match the CAP_INVOKE id").into());
+    }
+    match reply.meta.typ {
+        TYPE_ERROR => Err(CapInvokeError::Rejected(reply.decode_body()?)),
+        TYPE_CAP_RESULT => {
+            let result: CapResultBody = reply.decode_body()?;
+            if let Some(session) = result.session() {
+                session.validate()?;
+            }
+            Ok(result)
+        }
+        typ => Err(AmpError::bad_request(format!("typ 0x{typ:02x} does not answer CAP_INVOKE")).into()),
+    }
+}
+
+impl<S: Read + Write> CapClient<S> {
+    /// Invokes a capability on `provider` and decodes a successful `result` as `R`.
+    pub fn invoke<R: DeserializeOwned>(&mut self, provider: &str, body: &CapInvokeBody) -> Result<R, CapInvokeError> {
+        self.invoke_body(provider, body)?.into_result()
+    }
+
+    /// Sends `body` and waits in `CAP_INVOKE_SENT` for the terminal reply: `CAP_RESULT`
+    /// (either status) or `ERROR`. Any other correlated reply (`ACK`, `PROC_OK`,
+    /// `PROC_FAIL`, progress updates) keeps waiting. The envelope
+    /// `ttl_ms` is the body's `timeout_ms`, or the RFC 001 default.
+    pub fn invoke_body(&mut self, provider: &str, body: &CapInvokeBody) -> Result<CapResultBody, CapInvokeError> {
+        let ttl_ms = body.timeout_ms.unwrap_or(DEFAULT_CAP_INVOKE_TIMEOUT_MS);
+        let (id, wire) = build_cap_invoke(&self.agent, provider, body, ttl_ms, now_ms())?;
+        write_frame(&mut self.stream, &wire)
+            .map_err(|e| AmpError::endpoint_unreachable(format!("CAP_INVOKE write failed: {e}")))?;
+        loop {
+            let frame = read_frame(&mut self.stream).map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => CapInvokeError::Timeout,
+                _ => AmpError::endpoint_unreachable(format!("CAP_RESULT read failed: {e}")).into(),
+            })?;
+            let Ok(reply) = receive_and_verify(&self.agent, &frame, &self.resolver, now_ms()) else {
+                continue;
+            };
+            if reply.meta.reply_to == Some(id) && matches!(reply.meta.typ, TYPE_CAP_RESULT | TYPE_ERROR) {
+                return accept_cap_result(&id, provider, &reply);
+            }
+        }
+    }
+}
@@ -2,6 +2,59 @@ use amp001_example::{peek_routing, AmpError, RoutingEnvelope, TRANSPORT_WRAPPER_
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
+mod authorization;
+mod bundle;
+mod capability;
+mod compatibility;
+mod delegation;
+mod discovery;
+mod invocation;
+mod negotiation;
+mod registry;
+mod schema;
+
+pub use authorization::{
+    DelegQueryResult, DelegationAuditStep, DelegationDecision, DelegationEvaluator,
+    DelegationLinkRef, DelegationStatus, DelegationVerdict, RevocationSource,
+};
+pub use bundle::{
+    BundleArtifactEntry, BundleBuilder, BundleManifest, BundleResolver, RegistryBundle,
+    BUNDLE_FILE_EXTENSION, BUNDLE_FORMAT_V1,
+};
+pub use capability::{
+    capability_namespace, namespace_owner_did, parse_capability_id, validate_capability_name,
+    CapabilityDescriptor, HashAlg, SchemaRef, SchemaRefOffline, SchemaRefOnline,
+    SCHEMA_MEDIA_TYPE_JSON,
+};
+pub use compatibility::{
+    breaking_changes, BreakingChange, CompatibilityDecision, CompatibilityMode,
+    CompatibilityPolicy, DecisionKind, DecisionReason, SchemaRole,
+};
+pub use delegation::{
+    DelegRevokeBody, DelegationCredential, DelegationEnvelope, DelegationEvidence,
+    DelegationRevocation, DelegationScope, DelegationTarget, DelegationValidity,
+    DELEGATION_CREDENTIAL_V1, DELEGATION_FORMAT_COSE_SIGN1, DELEGATION_REVOCATION_V1,
+};
+pub use discovery::{
+    accept_cap_declare, build_cap_query, query_all_pages, CapDeclareBody, CapFilter, CapOrder,
+    CapQueryBody, CapClient, CapabilitySet, DEFAULT_CAP_QUERY_TTL_MS, MAX_CAP_QUERY_LIMIT,
+    TYPE_CAP_DECLARE, TYPE_CAP_QUERY,
+};
+pub use invocation::{
+    accept_cap_result, build_cap_invoke, CapCall, CapDispatcher, CapInvokeBody, CapInvokeError,
+    CapOutcome, CapResultBody, CapResultError, CapSessionContext, DEFAULT_CAP_INVOKE_TIMEOUT_MS,
+    TYPE_CAP_INVOKE, TYPE_CAP_RESULT,
+};
+pub use negotiation::{
+    negotiate, CapNegotiateHints, Negotiated, NegotiationError, NegotiationRule,
+};
+pub use registry::{
+    registry_path, serve_registry, CachePolicy, Fetched, Freshness, HttpFetcher, RegistryArtifact,
+    RegistryClient, RegistryFetch, RegistryStore, TrustProfile, DESCRIPTOR_MEDIA_TYPE,
+    MAX_REGISTRY_BODY_BYTES,
+};
+pub use schema::{JsonSchema, SchemaChange, SchemaEffect, SchemaViolation};
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PollResponse {
     pub messages: Vec<ByteBuf>,
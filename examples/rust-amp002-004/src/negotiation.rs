@@ -0,0 +1,149 @@
+use std::fmt;
+
+use amp001_example::{AmpError, ErrorBody, SemverError, Version, VersionRange};
+use serde::{Deserialize, Serialize};
+
+use crate::CapabilityDescriptor;
+
+/// `cap-negotiate-hints`: what the requester would accept, most specific first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CapNegotiateHints {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acceptable: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+}
+
+/// Which §6.3 step picked the version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiationRule {
+    Preferred,
+    Acceptable,
+    Range,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Concrete `capability-id` of the chosen descriptor.
+    pub id: String,
+    pub version: Version,
+    pub rule: NegotiationRule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NegotiationError {
+    /// A hint is not a valid semver or §4.4 range (`4001`).
+    InvalidHints(String),
+    /// The provider declares no version of the capability (`4002`).
+    NotFound { capability: String },
+    /// No declared version satisfies the hints (`4003`).
+    VersionMismatch { capability: String },
+}
+
+impl NegotiationError {
+    pub fn code(&self) -> u16 {
+        match self {
+            NegotiationError::InvalidHints(_) => 4001,
+            NegotiationError::NotFound { .. } => 4002,
+            NegotiationError::VersionMismatch { .. } => 4003,
+        }
+    }
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NegotiationError::InvalidHints(detail) => write!(f, "invalid negotiate hints: {detail}"),
+            NegotiationError::NotFound { capability } => write!(f, "capability {capability} not found"),
+            NegotiationError::VersionMismatch { capability } => {
+                write!(f, "no declared {capability} version satisfies the negotiate hints")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NegotiationError {}
+
+impl From<NegotiationError> for ErrorBody {
+    fn from(err: NegotiationError) -> Self {
+        ErrorBody::new(err.code(), err.to_string())
+    }
+}
+
+impl From<NegotiationError> for AmpError {
+    fn from(err: NegotiationError) -> Self {
+        let detail = err.to_string();
+        match err {
+            NegotiationError::InvalidHints(_) => AmpError::bad_request(detail),
+            NegotiationError::NotFound { .. } => AmpError::capability_not_found(detail),
+            NegotiationError::VersionMismatch { .. } => AmpError::version_mismatch(detail),
+        }
+    }
+}
+
+/// RFC 004 §6.3 negotiation, the reference every implementation must agree with:
+///
+/// 1. `preferred`, if the provider declares it;
+/// 2. else the first `acceptable` version the provider declares;
+/// 3. else the highest declared version inside `range`;
+/// 4. else `4003`.
+///
+/// Only `declared` descriptors named `capability` with a valid semver count. Versions are
+/// compared by SemVer precedence; when several descriptors share a precedence (build
+/// metadata only) the bytewise-smallest `id` wins. All hints are checked before any step
+/// runs, so a malformed hint fails the same way whichever step would have matched.
+pub fn negotiate(
+    capability: &str,
+    declared: &[CapabilityDescriptor],
+    hints: &CapNegotiateHints,
+) -> Result<Negotiated, NegotiationError> {
+    let invalid = |e: SemverError| NegotiationError::InvalidHints(e.to_string());
+    let preferred = hints.preferred.as_deref().map(Version::parse).transpose().map_err(invalid)?;
+    let acceptable = hints
+        .acceptable
+        .iter()
+        .flatten()
+        .map(|v| Version::parse(v))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let range = hints.range.as_deref().map(VersionRange::parse).transpose().map_err(invalid)?;
+
+    let mut offered: Vec<(Version, &str)> = declared
+        .iter()
+        .filter(|d| d.name == capability)
+        .filter_map(|d| Version::parse(&d.version).ok().map(|v| (v, d.id.as_str())))
+        .collect();
+    if offered.is_empty() {
+        return Err(NegotiationError::NotFound {
+            capability: capability.to_string(),
+        });
+    }
+    offered.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+    let exact = |wanted: &Version| offered.iter().find(|(v, _)| v == wanted);
+    let chosen = |(version, id): &(Version, &str), rule| Negotiated {
+        id: id.to_string(),
+        version: version.clone(),
+        rule,
+    };
+
+    if let Some(hit) = preferred.as_ref().and_then(exact) {
+        return Ok(chosen(hit, NegotiationRule::Preferred));
+    }
+    if let Some(hit) = acceptable.iter().find_map(exact) {
+        return Ok(chosen(hit, NegotiationRule::Acceptable));
+    }
+    if let Some(range) = &range {
+        let highest = offered
+            .iter()
+            .filter(|(v, _)| range.matches(v))
+            .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)));
+        if let Some(hit) = highest {
+            return Ok(chosen(hit, NegotiationRule::Range));
+        }
+    }
+    Err(NegotiationError::VersionMismatch {
+        capability: capability.to_string(),
+    })
+}
@@ -0,0 +1,595 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use amp001_example::{AmpError, DidResolver, Version};
+
+use crate::{
+    breaking_changes, parse_capability_id, BreakingChange, CapabilityDescriptor, JsonSchema, SchemaRef,
+    SCHEMA_MEDIA_TYPE_JSON,
+};
+
+/// Media type of `descriptor.cbor`.
+pub const DESCRIPTOR_MEDIA_TYPE: &str = "application/cbor";
+/// Largest registry response body [`HttpFetcher`] reads; descriptors and schemas are small.
+pub const MAX_REGISTRY_BODY_BYTES: usize = 1024 * 1024;
+
+const REGISTRY_ROOT: &str = "cap-registry";
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// The three artifacts a registry publishes per capability id (§5.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryArtifact {
+    Descriptor,
+    InputSchema,
+    OutputSchema,
+}
+
+impl RegistryArtifact {
+    pub const ALL: [RegistryArtifact; 3] = [
+        RegistryArtifact::Descriptor,
+        RegistryArtifact::InputSchema,
+        RegistryArtifact::OutputSchema,
+    ];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            RegistryArtifact::Descriptor => "descriptor.cbor",
+            RegistryArtifact::InputSchema => "input.schema.json",
+            RegistryArtifact::OutputSchema => "output.schema.json",
+        }
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            RegistryArtifact::Descriptor => DESCRIPTOR_MEDIA_TYPE,
+            _ => SCHEMA_MEDIA_TYPE_JSON,
+        }
+    }
+}
+
+/// `/cap-registry/{capability-name}/{version}/{artifact}` for `id`.
+pub fn registry_path(id: &str, artifact: RegistryArtifact) -> Result<String, AmpError> {
+    let (name, version) = parse_capability_id(id)?;
+    Ok(format!("/{REGISTRY_ROOT}/{name}/{version}/{}", artifact.file_name()))
+}
+
+/// Freshness a registry attaches to a response, from `Cache-Control`. Without `max-age`
+/// the artifact never expires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub max_age_ms: Option<u64>,
+    /// How long past `max_age_ms` a cached copy may still be served while it is
+    /// refreshed in the background.
+    pub stale_while_revalidate_ms: Option<u64>,
+}
+
+impl CachePolicy {
+    pub fn new(max_age_ms: u64, stale_while_revalidate_ms: u64) -> Self {
+        Self {
+            max_age_ms: Some(max_age_ms),
+            stale_while_revalidate_ms: Some(stale_while_revalidate_ms),
+        }
+    }
+
+    /// Reads `max-age`, `stale-while-revalidate` and `no-cache`; other directives are
+    /// ignored.
+    pub fn from_cache_control(value: &str) -> Self {
+        let mut policy = Self::default();
+        for directive in value.split(',').map(str::trim) {
+            let (name, arg) = directive.split_once('=').unwrap_or((directive, ""));
+            let seconds = arg.trim_matches('"').parse::<u64>().ok().map(|s| s.saturating_mul(1000));
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => policy.max_age_ms = seconds.or(Some(0)),
+                "stale-while-revalidate" => policy.stale_while_revalidate_ms = seconds,
+                "no-cache" => policy.max_age_ms = Some(0),
+                _ => {}
+            }
+        }
+        policy
+    }
+
+    /// `Cache-Control` value in whole seconds, rounded down; `None` without `max-age`.
+    pub fn cache_control(&self) -> Option<String> {
+        let max_age = self.max_age_ms? / 1000;
+        Some(match self.stale_while_revalidate_ms {
+            Some(swr) => format!("max-age={max_age}, stale-while-revalidate={}", swr / 1000),
+            None => format!("max-age={max_age}"),
+        })
+    }
+}
+
+/// A registry response body and its freshness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched {
+    pub body: Vec<u8>,
+    pub cache: CachePolicy,
+}
+
+/// Transport a [`RegistryClient`] fetches artifacts with. Unreachable registries and
+/// missing artifacts are `5002`.
+pub trait RegistryFetch: Send + Sync {
+    fn get(&self, uri: &str) -> Result<Fetched, AmpError>;
+}
+
+/// Plain `http://` GET. `https://` needs a TLS-capable [`RegistryFetch`] and fails with
+/// `5002` here.
+#[derive(Debug, Clone)]
+pub struct HttpFetcher {
+    timeout: Duration,
+}
+
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl HttpFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl RegistryFetch for HttpFetcher {
+    fn get(&self, uri: &str) -> Result<Fetched, AmpError> {
+        let unavailable = |detail: String| AmpError::unavailable(format!("GET {uri}: {detail}"));
+        let rest = uri
+            .strip_prefix("http://")
+            .ok_or_else(|| unavailable("only http:// registries are supported".to_string()))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let addr = if authority.contains(':') { authority.to_string() } else { format!("{authority}:80") };
+        let response = http_get(&addr, authority, path, self.timeout).map_err(|e| unavailable(e.to_string()))?;
+        if response.status != 200 {
+            return Err(unavailable(format!("status {}", response.status)));
+        }
+        let cache = response
+            .headers
+            .get("cache-control")
+            .map(|v| CachePolicy::from_cache_control(v))
+            .unwrap_or_default();
+        Ok(Fetched {
+            body: response.body,
+            cache,
+        })
+    }
+}
+
+/// Whether descriptors must carry a namespace-owner signature (§5.2).
+#[derive(Debug, Clone, Default)]
+pub enum TrustProfile {
+    /// Source authenticity comes from deployment policy; `descriptor_sig` is optional.
+    #[default]
+    Private,
+    /// `descriptor_sig` is required and verified against the owner DID (`3001`, A.22).
+    Public(DidResolver),
+}
+
+/// How a [`RegistryClient::descriptor`] answer was produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Fetched from the registry by this call.
+    Fetched,
+    /// Served from cache within `max-age`.
+    Fresh,
+    /// Served from cache past `max-age` but inside `stale-while-revalidate`; a background
+    /// refresh was started.
+    Stale,
+}
+
+#[derive(Debug, Clone)]
+struct CachedDescriptor {
+    descriptor: CapabilityDescriptor,
+    fetched_at_ms: u64,
+    cache: CachePolicy,
+}
+
+impl CachedDescriptor {
+    fn freshness(&self, now_ms: u64) -> Option<Freshness> {
+        let Some(max_age) = self.cache.max_age_ms else {
+            return Some(Freshness::Fresh);
+        };
+        let age = now_ms.saturating_sub(self.fetched_at_ms);
+        if age < max_age {
+            Some(Freshness::Fresh)
+        } else if age < max_age.saturating_add(self.cache.stale_while_revalidate_ms.unwrap_or(0)) {
+            Some(Freshness::Stale)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegistryCache {
+    descriptors: HashMap<String, CachedDescriptor>,
+    /// Verified schema bytes keyed by `uri + hash`; immutable while the hash is unchanged.
+    schemas: HashMap<(String, Vec<u8>), Vec<u8>>,
+    revalidating: HashSet<String>,
+}
+
+/// Resolves descriptors and schema artifacts from a registry (§5.1-5.2). Descriptors are
+/// cached by id and honour the registry's `max-age` and `stale-while-revalidate`; schema
+/// bytes are hash-verified before they are cached or returned, so a cached schema is
+/// always one some descriptor vouched for.
+pub struct RegistryClient<F: RegistryFetch + 'static> {
+    base_uri: String,
+    fetcher: Arc<F>,
+    trust: TrustProfile,
+    cache: Arc<Mutex<RegistryCache>>,
+    refreshes: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<F: RegistryFetch + 'static> RegistryClient<F> {
+    /// `base_uri` is the registry root, e.g. `http://127.0.0.1:7004`.
+    pub fn new(base_uri: impl Into<String>, fetcher: F) -> Self {
+        Self {
+            base_uri: base_uri.into().trim_end_matches('/').to_string(),
+            fetcher: Arc::new(fetcher),
+            trust: TrustProfile::default(),
+            cache: Arc::new(Mutex::new(RegistryCache::default())),
+            refreshes: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn trust_profile(mut self, trust: TrustProfile) -> Self {
+        self.trust = trust;
+        self
+    }
+
+    /// The descriptor for `id`: from cache while fresh, from cache with a background
+    /// refresh while stale-but-revalidatable, otherwise fetched now. A failed background
+    /// refresh keeps the old copy until its stale window ends.
+    pub fn descriptor(&self, id: &str, now_ms: u64) -> Result<(CapabilityDescriptor, Freshness), AmpError> {
+        let uri = format!("{}{}", self.base_uri, registry_path(id, RegistryArtifact::Descriptor)?);
+        let cached = self.lock().descriptors.get(id).cloned();
+        if let Some(cached) = cached {
+            match cached.freshness(now_ms) {
+                Some(Freshness::Stale) => {
+                    self.refresh_in_background(id, uri, now_ms);
+                    return Ok((cached.descriptor, Freshness::Stale));
+                }
+                Some(freshness) => return Ok((cached.descriptor, freshness)),
+                None => {}
+            }
+        }
+        let descriptor = refresh(&*self.fetcher, &self.trust, &self.cache, id, &uri, now_ms)?;
+        Ok((descriptor, Freshness::Fetched))
+    }
+
+    /// Verified bytes of an online schema reference: `5002` when the registry cannot
+    /// serve them or they do not match `hash`.
+    pub fn schema(&self, schema_ref: &SchemaRef) -> Result<Vec<u8>, AmpError> {
+        let uri = schema_ref
+            .uri()
+            .ok_or_else(|| AmpError::unavailable("schema reference has no uri; resolve it from a bundle"))?;
+        let key = (uri.to_string(), schema_ref.hash().to_vec());
+        if let Some(bytes) = self.lock().schemas.get(&key) {
+            return Ok(bytes.clone());
+        }
+        let fetched = self.fetcher.get(uri)?;
+        schema_ref.verify_artifact(&fetched.body)?;
+        self.lock().schemas.insert(key, fetched.body.clone());
+        Ok(fetched.body)
+    }
+
+    /// The descriptor for `id` together with its verified input schema bytes.
+    pub fn input_schema(&self, id: &str, now_ms: u64) -> Result<(CapabilityDescriptor, Vec<u8>), AmpError> {
+        let (descriptor, _) = self.descriptor(id, now_ms)?;
+        let schema = self.schema(&descriptor.input_schema)?;
+        Ok((descriptor, schema))
+    }
+
+    /// Drops every cached descriptor and schema.
+    pub fn clear(&self) {
+        let mut cache = self.lock();
+        cache.descriptors.clear();
+        cache.schemas.clear();
+    }
+
+    /// Blocks until background refreshes started so far have finished.
+    pub fn wait_for_refreshes(&self) {
+        let handles: Vec<_> = self.refreshes.lock().expect("registry refreshes poisoned").drain(..).collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+
+    fn refresh_in_background(&self, id: &str, uri: String, now_ms: u64) {
+        if !self.lock().revalidating.insert(id.to_string()) {
+            return;
+        }
+        let (fetcher, trust, cache, id) = (Arc::clone(&self.fetcher), self.trust.clone(), Arc::clone(&self.cache), id.to_string());
+        let handle = thread::spawn(move || {
+            let _ = refresh(&*fetcher, &trust, &cache, &id, &uri, now_ms);
+            cache.lock().expect("registry cache poisoned").revalidating.remove(&id);
+        });
+        let mut refreshes = self.refreshes.lock().expect("registry refreshes poisoned");
+        // Finished refreshes have nothing left to join; only running ones are kept.
+        refreshes.retain(|refresh| !refresh.is_finished());
+        refreshes.push(handle);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RegistryCache> {
+        self.cache.lock().expect("registry cache poisoned")
+    }
+}
+
+/// Fetches, checks and caches the descriptor for `id`. A body that is not the requested
+/// descriptor is `5002`; a missing or bad signature under the public profile is `3001`.
+fn refresh(
+    fetcher: &dyn RegistryFetch,
+    trust: &TrustProfile,
+    cache: &Mutex<RegistryCache>,
+    id: &str,
+    uri: &str,
+    now_ms: u64,
+) -> Result<CapabilityDescriptor, AmpError> {
+    let fetched = fetcher.get(uri)?;
+    let descriptor = CapabilityDescriptor::from_cbor(&fetched.body)
+        .map_err(|e| AmpError::unavailable(format!("GET {uri}: unusable descriptor: {}", e.detail)))?;
+    if descriptor.id != id {
+        return Err(AmpError::unavailable(format!("GET {uri}: registry returned {}", descriptor.id)));
+    }
+    if let TrustProfile::Public(resolver) = trust {
+        descriptor.verify_signature(resolver)?;
+    }
+    cache.lock().expect("registry cache poisoned").descriptors.insert(
+        id.to_string(),
+        CachedDescriptor {
+            descriptor: descriptor.clone(),
+            fetched_at_ms: now_ms,
+            cache: fetched.cache,
+        },
+    );
+    Ok(descriptor)
+}
+
+/// What a registry publishes, keyed by request path. Every descriptor is checked against
+/// its schema bytes when published, so the store never serves a mismatched pair.
+#[derive(Debug, Clone, Default)]
+pub struct RegistryStore {
+    artifacts: HashMap<String, (RegistryArtifact, Vec<u8>)>,
+}
+
+impl RegistryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes (or replaces) `descriptor` with its input and output schema bytes.
+    pub fn publish(
+        &mut self,
+        descriptor: &CapabilityDescriptor,
+        input_schema: &[u8],
+        output_schema: &[u8],
+    ) -> Result<(), AmpError> {
+        descriptor.validate()?;
+        descriptor.input_schema.verify_artifact(input_schema)?;
+        descriptor.output_schema.verify_artifact(output_schema)?;
+        for (artifact, bytes) in [
+            (RegistryArtifact::Descriptor, descriptor.to_cbor()?),
+            (RegistryArtifact::InputSchema, input_schema.to_vec()),
+            (RegistryArtifact::OutputSchema, output_schema.to_vec()),
+        ] {
+            self.artifacts.insert(registry_path(&descriptor.id, artifact)?, (artifact, bytes));
+        }
+        Ok(())
+    }
+
+    /// Breaking schema changes `descriptor` would ship relative to the highest published
+    /// older version of the same major (RFC 004 §8, §10). A new major may break freely,
+    /// so it has nothing to be compared with. Schemas this crate cannot load are `1001`.
+    pub fn check_release(
+        &self,
+        descriptor: &CapabilityDescriptor,
+        input_schema: &[u8],
+        output_schema: &[u8],
+    ) -> Result<Vec<BreakingChange>, AmpError> {
+        let version = descriptor.semver()?;
+        let previous = self
+            .ids()
+            .into_iter()
+            .filter_map(|id| {
+                let (name, published) = parse_capability_id(&id).ok()?;
+                let published = Version::parse(published).ok()?;
+                (name == descriptor.name && published.major == version.major && published < version)
+                    .then_some((published, id))
+            })
+            .max();
+        let Some((_, previous)) = previous else {
+            return Ok(Vec::new());
+        };
+        let published = |artifact| {
+            let path = registry_path(&previous, artifact)?;
+            let (_, bytes) = self.get(&path).ok_or_else(|| AmpError::unavailable(format!("{path} is not published")))?;
+            JsonSchema::from_slice(bytes)
+        };
+        Ok(breaking_changes(
+            (&published(RegistryArtifact::InputSchema)?, &published(RegistryArtifact::OutputSchema)?),
+            (&JsonSchema::from_slice(input_schema)?, &JsonSchema::from_slice(output_schema)?),
+        ))
+    }
+
+    /// Publishes every `{name}/{version}/` directory under `dir` holding the three
+    /// artifact files, the same layout as the request paths.
+    pub fn load_dir(dir: &Path) -> Result<Self, AmpError> {
+        let io_err = |path: &Path, e: io::Error| AmpError::unavailable(format!("{}: {e}", path.display()));
+        let mut store = Self::new();
+        for name in fs::read_dir(dir).map_err(|e| io_err(dir, e))? {
+            let name = name.map_err(|e| io_err(dir, e))?.path();
+            if !name.is_dir() {
+                continue;
+            }
+            for version in fs::read_dir(&name).map_err(|e| io_err(&name, e))? {
+                let version = version.map_err(|e| io_err(&name, e))?.path();
+                let read = |artifact: RegistryArtifact| {
+                    let path = version.join(artifact.file_name());
+                    fs::read(&path).map_err(|e| io_err(&path, e))
+                };
+                if !version.join(RegistryArtifact::Descriptor.file_name()).is_file() {
+                    continue;
+                }
+                let descriptor = CapabilityDescriptor::from_cbor(&read(RegistryArtifact::Descriptor)?)?;
+                store.publish(
+                    &descriptor,
+                    &read(RegistryArtifact::InputSchema)?,
+                    &read(RegistryArtifact::OutputSchema)?,
+                )?;
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn get(&self, path: &str) -> Option<(RegistryArtifact, &[u8])> {
+        self.artifacts.get(path).map(|(artifact, bytes)| (*artifact, bytes.as_slice()))
+    }
+
+    /// Published capability ids, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .artifacts
+            .iter()
+            .filter(|(_, (artifact, _))| *artifact == RegistryArtifact::Descriptor)
+            .filter_map(|(path, _)| {
+                let mut parts = path.rsplit('/').skip(1);
+                let version = parts.next()?;
+                let name = parts.next()?;
+                Some(format!("{name}:{version}"))
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+/// Answers registry `GET`s from `store` until the listener fails, one connection at a
+/// time. Responses carry `policy` as `Cache-Control`; unknown paths are `404`.
+pub fn serve_registry(listener: &TcpListener, store: &Mutex<RegistryStore>, policy: CachePolicy) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let _ = answer_registry_request(&mut stream, store, policy);
+    }
+    Ok(())
+}
+
+fn answer_registry_request(stream: &mut TcpStream, store: &Mutex<RegistryStore>, policy: CachePolicy) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let head = read_head(stream)?;
+    let request_line = head.split("\r\n").next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    if method != "GET" {
+        return write_response(stream, "405 Method Not Allowed", None, b"");
+    }
+    let store = store.lock().expect("registry store poisoned");
+    match store.get(path) {
+        Some((artifact, bytes)) => {
+            let cache_control = policy.cache_control();
+            let mut headers = vec![("Content-Type", artifact.media_type().to_string())];
+            headers.extend(cache_control.map(|v| ("Cache-Control", v)));
+            write_response(stream, "200 OK", Some(&headers), bytes)
+        }
+        None => write_response(stream, "404 Not Found", None, b""),
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn http_get(addr: &str, host: &str, path: &str, timeout: Duration) -> io::Result<HttpResponse> {
+    let socket = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&socket, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n")?;
+    stream.flush()?;
+
+    let limit = MAX_HEAD_BYTES + MAX_REGISTRY_BODY_BYTES;
+    let mut buf = Vec::new();
+    (&mut stream).take(limit as u64 + 1).read_to_end(&mut buf)?;
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "http response too large");
+    if buf.len() > limit {
+        return Err(too_large());
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid http response");
+    let header_end = buf.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(invalid)? + 4;
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.split("\r\n").filter(|l| !l.is_empty());
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    let mut body = buf[header_end..].to_vec();
+    if body.len() > MAX_REGISTRY_BODY_BYTES {
+        return Err(too_large());
+    }
+    if let Some(len) = headers.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+        if len > MAX_REGISTRY_BODY_BYTES {
+            return Err(too_large());
+        }
+        if body.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated http body"));
+        }
+        body.truncate(len);
+    }
+    Ok(HttpResponse { status, headers, body })
+}
+
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0_u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before header"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: Option<&[(&str, String)]>,
+    body: &[u8],
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n", body.len());
+    for (name, value) in headers.unwrap_or_default() {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
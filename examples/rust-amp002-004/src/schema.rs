@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use amp001_example::{AmpError, ErrorBody};
+use serde_cbor::Value;
+use serde_json::{Map, Number, Value as Json};
+
+/// Keywords that only describe a schema and never reject an instance.
+const ANNOTATIONS: &[&str] = &[
+    "$schema", "$id", "$comment", "title", "description", "default", "examples", "format",
+    "deprecated", "readOnly", "writeOnly",
+];
+
+/// The JSON Schema subset this crate can enforce on CBOR `params`: `type`, `enum`,
+/// `const`, numeric and length bounds, `properties`/`required`/`additionalProperties`,
+/// `items`, and `allOf`/`anyOf`/`oneOf`/`not`. Anything else that could reject an
+/// instance (`$ref`, `pattern`, ...) is refused when the schema is loaded, so a schema
+/// is never enforced more loosely than its author wrote it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonSchema {
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Bool(bool),
+    Rules(Box<Rules>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Rules {
+    types: Option<Vec<String>>,
+    enumeration: Option<Vec<Json>>,
+    constant: Option<Json>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    min_items: Option<u64>,
+    max_items: Option<u64>,
+    properties: BTreeMap<String, Node>,
+    required: Vec<String>,
+    additional_properties: Option<Node>,
+    items: Option<Node>,
+    all_of: Vec<Node>,
+    any_of: Vec<Node>,
+    one_of: Vec<Node>,
+    not: Option<Node>,
+}
+
+/// First place a value breaks its schema, as a JSON pointer into the value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        write!(f, "{path}: {}", self.reason)
+    }
+}
+
+impl std::error::Error for SchemaViolation {}
+
+impl From<SchemaViolation> for ErrorBody {
+    fn from(err: SchemaViolation) -> Self {
+        ErrorBody::new(4004, format!("params violate input schema at {err}"))
+    }
+}
+
+/// Whether a schema change lets fewer values through, more, or neither comparably.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaEffect {
+    Narrowed,
+    Widened,
+    Changed,
+}
+
+/// One difference between two versions of a schema, located by a pointer into the
+/// older schema document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaChange {
+    pub path: String,
+    pub effect: SchemaEffect,
+    pub detail: String,
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.detail)
+    }
+}
+
+impl JsonSchema {
+    /// Loads `application/schema+json` artifact bytes.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, AmpError> {
+        let json: Json = serde_json::from_slice(bytes)
+            .map_err(|e| AmpError::invalid_message(format!("schema is not JSON: {e}")))?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, AmpError> {
+        Ok(Self {
+            root: compile(json, "#")?,
+        })
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), SchemaViolation> {
+        check(&self.root, value, &mut String::new())
+    }
+
+    /// Object members of `value` that no `properties` or `additionalProperties` entry
+    /// describes, as JSON pointers. A consumer of this schema would ignore them.
+    pub fn undeclared(&self, value: &Value) -> Vec<String> {
+        let mut found = Vec::new();
+        undeclared(&self.root, value, &mut String::new(), &mut found);
+        found
+    }
+
+    /// What changed from `self` to `newer`. Adding a property to an object that already
+    /// allowed unknown members is not reported; composition keywords (`allOf`, `anyOf`,
+    /// `oneOf`, `not`) are compared as a whole and any edit is `Changed`.
+    pub fn diff(&self, newer: &JsonSchema) -> Vec<SchemaChange> {
+        let mut changes = Vec::new();
+        diff(&self.root, &newer.root, &mut "#".to_string(), &mut changes);
+        changes
+    }
+}
+
+fn compile(json: &Json, at: &str) -> Result<Node, AmpError> {
+    let unsupported = |detail: String| AmpError::invalid_message(format!("schema {at}: {detail}"));
+    let object = match json {
+        Json::Bool(b) => return Ok(Node::Bool(*b)),
+        Json::Object(object) => object,
+        other => return Err(unsupported(format!("expected an object or boolean, found {other}"))),
+    };
+
+    let mut rules = Rules::default();
+    for (keyword, value) in object {
+        let at = format!("{at}/{keyword}");
+        let number = || value.as_f64().ok_or_else(|| unsupported(format!("{keyword} must be a number")));
+        let count = || value.as_u64().ok_or_else(|| unsupported(format!("{keyword} must be a non-negative integer")));
+        let list = || {
+            value
+                .as_array()
+                .filter(|items| !items.is_empty())
+                .ok_or_else(|| unsupported(format!("{keyword} must be a non-empty array")))
+        };
+        match keyword.as_str() {
+            "type" => {
+                let names: Vec<&Json> = match value {
+                    Json::Array(names) => names.iter().collect(),
+                    name => vec![name],
+                };
+                let mut types = Vec::new();
+                for name in names {
+                    match name.as_str() {
+                        Some(t @ ("null" | "boolean" | "object" | "array" | "number" | "integer" | "string")) => {
+                            types.push(t.to_string())
+                        }
+                        _ => return Err(unsupported(format!("unknown type {name}"))),
+                    }
+                }
+                rules.types = Some(types);
+            }
+            "enum" => rules.enumeration = Some(list()?.clone()),
+            "const" => rules.constant = Some(value.clone()),
+            "minimum" => rules.minimum = Some(number()?),
+            "maximum" => rules.maximum = Some(number()?),
+            "exclusiveMinimum" => rules.exclusive_minimum = Some(number()?),
+            "exclusiveMaximum" => rules.exclusive_maximum = Some(number()?),
+            "minLength" => rules.min_length = Some(count()?),
+            "maxLength" => rules.max_length = Some(count()?),
+            "minItems" => rules.min_items = Some(count()?),
+            "maxItems" => rules.max_items = Some(count()?),
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .ok_or_else(|| unsupported("properties must be an object".to_string()))?;
+                for (name, schema) in properties {
+                    rules.properties.insert(name.clone(), compile(schema, &format!("{at}/{name}"))?);
+                }
+            }
+            "required" => {
+                rules.required = value
+                    .as_array()
+                    .and_then(|names| names.iter().map(|n| n.as_str().map(str::to_string)).collect())
+                    .ok_or_else(|| unsupported("required must be an array of strings".to_string()))?;
+            }
+            "additionalProperties" => rules.additional_properties = Some(compile(value, &at)?),
+            "items" => rules.items = Some(compile(value, &at)?),
+            "allOf" | "anyOf" | "oneOf" => {
+                let nodes = list()?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, schema)| compile(schema, &format!("{at}/{i}")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match keyword.as_str() {
+                    "allOf" => rules.all_of = nodes,
+                    "anyOf" => rules.any_of = nodes,
+                    _ => rules.one_of = nodes,
+                }
+            }
+            "not" => rules.not = Some(compile(value, &at)?),
+            annotation if ANNOTATIONS.contains(&annotation) => {}
+            other => return Err(unsupported(format!("keyword {other} is not supported"))),
+        }
+    }
+    Ok(Node::Rules(Box::new(rules)))
+}
+
+fn check(node: &Node, value: &Value, path: &mut String) -> Result<(), SchemaViolation> {
+    let fail = |path: &str, reason: String| {
+        Err(SchemaViolation {
+            path: path.to_string(),
+            reason,
+        })
+    };
+    let rules = match node {
+        Node::Bool(true) => return Ok(()),
+        Node::Bool(false) => return fail(path, "no value is allowed here".to_string()),
+        Node::Rules(rules) => rules,
+    };
+    let value = untag(value);
+
+    if let Some(types) = &rules.types {
+        if !types.iter().any(|t| has_type(value, t)) {
+            return fail(path, format!("expected {}, found {}", types.join(" or "), type_name(value)));
+        }
+    }
+    if let Some(allowed) = &rules.enumeration {
+        if !allowed.iter().any(|json| same(value, json)) {
+            return fail(path, "value is not one of the enum members".to_string());
+        }
+    }
+    if let Some(constant) = &rules.constant {
+        if !same(value, constant) {
+            return fail(path, format!("value must equal {constant}"));
+        }
+    }
+
+    if let Some(n) = as_number(value) {
+        let bounds = [
+            (rules.minimum, n >= rules.minimum.unwrap_or(n), "minimum"),
+            (rules.maximum, n <= rules.maximum.unwrap_or(n), "maximum"),
+            (rules.exclusive_minimum, rules.exclusive_minimum.is_none_or(|m| n > m), "exclusiveMinimum"),
+            (rules.exclusive_maximum, rules.exclusive_maximum.is_none_or(|m| n < m), "exclusiveMaximum"),
+        ];
+        for (bound, ok, keyword) in bounds {
+            if let (Some(bound), false) = (bound, ok) {
+                return fail(path, format!("{n} breaks {keyword} {bound}"));
+            }
+        }
+    }
+    if let Value::Text(text) = value {
+        let len = text.chars().count() as u64;
+        if rules.min_length.is_some_and(|min| len < min) || rules.max_length.is_some_and(|max| len > max) {
+            return fail(path, format!("string length {len} is out of bounds"));
+        }
+    }
+    if let Value::Array(items) = value {
+        let len = items.len() as u64;
+        if rules.min_items.is_some_and(|min| len < min) || rules.max_items.is_some_and(|max| len > max) {
+            return fail(path, format!("array length {len} is out of bounds"));
+        }
+        if let Some(schema) = &rules.items {
+            for (i, item) in items.iter().enumerate() {
+                nested(path, &i.to_string(), |path| check(schema, item, path))?;
+            }
+        }
+    }
+    if let Value::Map(map) = value {
+        for name in &rules.required {
+            if !map.contains_key(&Value::Text(name.clone())) {
+                return fail(path, format!("missing required property {name}"));
+            }
+        }
+        for (key, member) in map {
+            let Value::Text(name) = key else {
+                return fail(path, "object keys must be text".to_string());
+            };
+            let schema = match (rules.properties.get(name), &rules.additional_properties) {
+                (Some(schema), _) => schema,
+                (None, Some(schema)) => schema,
+                (None, None) => continue,
+            };
+            nested(path, name, |path| check(schema, member, path))?;
+        }
+    }
+
+    for schema in &rules.all_of {
+        check(schema, value, path)?;
+    }
+    if !rules.any_of.is_empty() && !rules.any_of.iter().any(|s| check(s, value, &mut path.clone()).is_ok()) {
+        return fail(path, "value matches none of anyOf".to_string());
+    }
+    if !rules.one_of.is_empty() {
+        let matched = rules.one_of.iter().filter(|s| check(s, value, &mut path.clone()).is_ok()).count();
+        if matched != 1 {
+            return fail(path, format!("value matches {matched} of oneOf, expected exactly 1"));
+        }
+    }
+    if let Some(schema) = &rules.not {
+        if check(schema, value, &mut path.clone()).is_ok() {
+            return fail(path, "value matches a not schema".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn undeclared(node: &Node, value: &Value, path: &mut String, found: &mut Vec<String>) {
+    let (Node::Rules(rules), Value::Map(map)) = (node, untag(value)) else {
+        return;
+    };
+    for (key, member) in map {
+        let Value::Text(name) = key else { continue };
+        match (rules.properties.get(name), &rules.additional_properties) {
+            (Some(schema), _) => nested(path, name, |path| undeclared(schema, member, path, found)),
+            (None, Some(_)) => {}
+            (None, None) => nested(path, name, |path| found.push(path.clone())),
+        }
+    }
+}
+
+fn diff(old: &Node, new: &Node, path: &mut String, changes: &mut Vec<SchemaChange>) {
+    let mut note = |path: &str, effect, detail: String| {
+        changes.push(SchemaChange {
+            path: path.to_string(),
+            effect,
+            detail,
+        })
+    };
+    let (old, new) = match (old, new) {
+        (Node::Bool(a), Node::Bool(b)) if a == b => return,
+        (Node::Bool(true), _) | (_, Node::Bool(false)) => {
+            return note(path, SchemaEffect::Narrowed, "schema now constrains values".to_string())
+        }
+        (_, Node::Bool(true)) | (Node::Bool(false), _) => {
+            return note(path, SchemaEffect::Widened, "schema no longer constrains values".to_string())
+        }
+        (Node::Rules(old), Node::Rules(new)) => (old, new),
+    };
+
+    let covers = |types: &[String], t: &str| types.iter().any(|u| u == t || (t == "integer" && u == "number"));
+    match (&old.types, &new.types) {
+        (None, Some(types)) => note(path, SchemaEffect::Narrowed, format!("type restricted to {}", types.join(" or "))),
+        (Some(_), None) => note(path, SchemaEffect::Widened, "type restriction removed".to_string()),
+        (Some(before), Some(after)) => {
+            for t in before.iter().filter(|t| !covers(after, t)) {
+                note(path, SchemaEffect::Narrowed, format!("type {t} no longer allowed"));
+            }
+            for t in after.iter().filter(|t| !covers(before, t)) {
+                note(path, SchemaEffect::Widened, format!("type {t} now allowed"));
+            }
+        }
+        (None, None) => {}
+    }
+    match (&old.enumeration, &new.enumeration) {
+        (None, Some(_)) => note(path, SchemaEffect::Narrowed, "enum added".to_string()),
+        (Some(_), None) => note(path, SchemaEffect::Widened, "enum removed".to_string()),
+        (Some(before), Some(after)) => {
+            for member in before.iter().filter(|m| !after.contains(m)) {
+                note(path, SchemaEffect::Narrowed, format!("enum member {member} removed"));
+            }
+            for member in after.iter().filter(|m| !before.contains(m)) {
+                note(path, SchemaEffect::Widened, format!("enum member {member} added"));
+            }
+        }
+        (None, None) => {}
+    }
+    match (&old.constant, &new.constant) {
+        (None, Some(c)) => note(path, SchemaEffect::Narrowed, format!("const {c} added")),
+        (Some(_), None) => note(path, SchemaEffect::Widened, "const removed".to_string()),
+        (Some(a), Some(b)) if a != b => note(path, SchemaEffect::Changed, format!("const changed from {a} to {b}")),
+        _ => {}
+    }
+
+    // A lower bound that rises, or an upper bound that falls, admits fewer values.
+    let lower = [
+        ("minimum", old.minimum, new.minimum),
+        ("exclusiveMinimum", old.exclusive_minimum, new.exclusive_minimum),
+        ("minLength", old.min_length.map(|n| n as f64), new.min_length.map(|n| n as f64)),
+        ("minItems", old.min_items.map(|n| n as f64), new.min_items.map(|n| n as f64)),
+    ];
+    let upper = [
+        ("maximum", old.maximum, new.maximum),
+        ("exclusiveMaximum", old.exclusive_maximum, new.exclusive_maximum),
+        ("maxLength", old.max_length.map(|n| n as f64), new.max_length.map(|n| n as f64)),
+        ("maxItems", old.max_items.map(|n| n as f64), new.max_items.map(|n| n as f64)),
+    ];
+    for (is_lower, (keyword, before, after)) in lower.map(|b| (true, b)).into_iter().chain(upper.map(|b| (false, b))) {
+        let tighter = match (before, after) {
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) if a != b => (b > a) == is_lower,
+            _ => continue,
+        };
+        let effect = if tighter { SchemaEffect::Narrowed } else { SchemaEffect::Widened };
+        let show = |bound: Option<f64>| bound.map_or("none".to_string(), |b| b.to_string());
+        note(path, effect, format!("{keyword} changed from {} to {}", show(before), show(after)));
+    }
+
+    for name in new.required.iter().filter(|n| !old.required.contains(n)) {
+        note(path, SchemaEffect::Narrowed, format!("property {name} is now required"));
+    }
+    for name in old.required.iter().filter(|n| !new.required.contains(n)) {
+        note(path, SchemaEffect::Widened, format!("property {name} is no longer required"));
+    }
+
+    let open = Node::Bool(true);
+    let old_additional = old.additional_properties.as_ref().unwrap_or(&open);
+    let new_additional = new.additional_properties.as_ref().unwrap_or(&open);
+    nested(path, "properties", |path| {
+        for (name, before) in &old.properties {
+            let after = new.properties.get(name).unwrap_or(new_additional);
+            nested(path, name, |path| diff(before, after, path, changes));
+        }
+        for (name, after) in new.properties.iter().filter(|(n, _)| !old.properties.contains_key(*n)) {
+            if old_additional != &open {
+                nested(path, name, |path| diff(old_additional, after, path, changes));
+            }
+        }
+    });
+    nested(path, "additionalProperties", |path| diff(old_additional, new_additional, path, changes));
+    let items = (old.items.as_ref().unwrap_or(&open), new.items.as_ref().unwrap_or(&open));
+    nested(path, "items", |path| diff(items.0, items.1, path, changes));
+
+    let composed = [
+        ("allOf", old.all_of != new.all_of),
+        ("anyOf", old.any_of != new.any_of),
+        ("oneOf", old.one_of != new.one_of),
+        ("not", old.not != new.not),
+    ];
+    for (keyword, _) in composed.into_iter().filter(|(_, changed)| *changed) {
+        changes.push(SchemaChange {
+            path: path.clone(),
+            effect: SchemaEffect::Changed,
+            detail: format!("{keyword} changed"),
+        });
+    }
+}
+
+/// Runs `f` with `segment` pushed onto the JSON pointer `path`.
+fn nested<T>(path: &mut String, segment: &str, f: impl FnOnce(&mut String) -> T) -> T {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    let out = f(path);
+    path.truncate(len);
+    out
+}
+
+/// CBOR tags carry no JSON meaning; the tagged item is what gets validated.
+fn untag(mut value: &Value) -> &Value {
+    while let Value::Tag(_, inner) = value {
+        value = inner;
+    }
+    value
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("integer", Value::Float(f)) => f.fract() == 0.0,
+        _ => type_name(value) == name || (name == "number" && type_name(value) == "integer"),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "number",
+        Value::Text(_) => "string",
+        Value::Array(_) => "array",
+        Value::Map(_) => "object",
+        Value::Bytes(_) => "byte string",
+        Value::Tag(_, inner) => type_name(inner),
+        _ => "unknown",
+    }
+}
+
+/// JSON equality of a CBOR value, with `1` and `1.0` equal as JSON Schema requires.
+fn same(value: &Value, json: &Json) -> bool {
+    match (untag(value), json) {
+        (Value::Null, Json::Null) => true,
+        (Value::Bool(a), Json::Bool(b)) => a == b,
+        (Value::Text(a), Json::String(b)) => a == b,
+        (value, Json::Number(n)) => as_number(value).is_some_and(|a| number_eq(value, a, n)),
+        (Value::Array(a), Json::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b)),
+        (Value::Map(a), Json::Object(b)) => a.len() == b.len() && object_eq(a, b),
+        _ => false,
+    }
+}
+
+fn number_eq(value: &Value, as_f64: f64, n: &Number) -> bool {
+    match (value, n.as_i64(), n.as_u64()) {
+        (Value::Integer(i), Some(j), _) => *i == i128::from(j),
+        (Value::Integer(i), None, Some(j)) => *i == i128::from(j),
+        _ => n.as_f64() == Some(as_f64),
+    }
+}
+
+fn object_eq(a: &BTreeMap<Value, Value>, b: &Map<String, Json>) -> bool {
+    a.iter().all(|(key, value)| match key {
+        Value::Text(name) => b.get(name).is_some_and(|json| same(value, json)),
+        _ => false,
+    })
+}
+
@@ -0,0 +1,46 @@
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+
+use amp001_example::{demo_agents, now_ms, receive_and_verify, AgentKeys, AmpError, ReceivedMessage};
+use amp002_004_tests::{
+    build_cap_invoke, registry_path, CapInvokeBody, CapabilityDescriptor, HashAlg, RegistryArtifact, SchemaRef,
+};
+use serde_cbor::Value;
+use serde_json::Value as Json;
+
+/// Descriptor for `name:version` published on the agentries.org registry, taking any
+/// output.
+pub fn descriptor(name: &str, version: &str, input: &[u8]) -> CapabilityDescriptor {
+    hosted_descriptor("https://agentries.org", name, version, input, b"true")
+}
+
+/// Descriptor whose schemas live at their `/cap-registry/...` paths on the registry at `base`.
+pub fn hosted_descriptor(base: &str, name: &str, version: &str, input: &[u8], output: &[u8]) -> CapabilityDescriptor {
+    let id = format!("{name}:{version}");
+    let uri = |artifact| format!("{base}{}", registry_path(&id, artifact).expect("registry path"));
+    CapabilityDescriptor::new(
+        name,
+        version,
+        SchemaRef::online(uri(RegistryArtifact::InputSchema), HashAlg::Sha256, input),
+        SchemaRef::online(uri(RegistryArtifact::OutputSchema), HashAlg::Sha256, output),
+    )
+}
+
+/// JSON `json` as CBOR `params`.
+pub fn params(json: Json) -> Value {
+    serde_cbor::value::to_value(json).expect("params")
+}
+
+/// `body` as bob receives it from `from`.
+pub fn received(from: &AgentKeys, body: &CapInvokeBody) -> ReceivedMessage {
+    let demo = demo_agents();
+    let now = now_ms();
+    let (_, wire) = build_cap_invoke(from, &demo.bob.did, body, 60_000, now).expect("build");
+    receive_and_verify(&demo.bob, &wire, &demo.resolver(), now).expect("verify")
+}
+
+/// Code of a call expected to fail.
+pub fn code<T: Debug>(result: Result<T, AmpError>) -> u16 {
+    result.expect_err("expected an error").code
+}
@@ -0,0 +1,128 @@
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    demo_agents, Agent, AgentHandle, AgentKeys, Backoff, HelloHandshake, DEFAULT_AGENT_TTL_MS,
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+    listener.local_addr().expect("local addr").to_string()
+}
+
+fn start_server(addr: &str) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_amp002-server"))
+        .arg(addr)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp002-server");
+    let server = Server(child);
+    assert!(
+        wait_until(|| TcpStream::connect(addr).is_ok()),
+        "server did not start on {addr}"
+    );
+    server
+}
+
+fn wait_until(check: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + WAIT;
+    while !check() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    true
+}
+
+/// Reconnecting agent that replays unacknowledged sends; received texts go to the channel.
+fn start_agent(keys: &AgentKeys, addr: &str) -> (AgentHandle, Receiver<String>) {
+    let demo = demo_agents();
+    let (texts_tx, texts) = mpsc::channel();
+    let agent = Agent::builder(keys.clone())
+        .resolver(demo.resolver())
+        .connect_tcp(addr)
+        .handshake(HelloHandshake {
+            relay_did: demo.relay.did.clone(),
+            versions: vec!["0.30.0".to_string()],
+        })
+        .reconnect_backoff(
+            Backoff::exponential(Duration::from_millis(20), Duration::from_millis(200)),
+            None,
+        )
+        .replay_unacked(true)
+        .on_message(move |_, _, body| {
+            let _ = texts_tx.send(body.msg);
+            Ok(())
+        })
+        .build()
+        .expect("agent");
+    let (handle, _run) = agent.spawn();
+    assert!(handle.wait_connected(WAIT), "{} connected", handle.did());
+    (handle, texts)
+}
+
+#[test]
+fn rfc002_client_survives_relay_restart_and_replays_unacked_messages() {
+    let demo = demo_agents();
+    let addr = free_addr();
+    let server = start_server(&addr);
+    let (alice, _) = start_agent(&demo.alice, &addr);
+    let (bob, bob_texts) = start_agent(&demo.bob, &addr);
+
+    alice.send_text(&demo.bob.did, "before restart", DEFAULT_AGENT_TTL_MS).expect("send");
+    assert_eq!(bob_texts.recv_timeout(WAIT).expect("delivered"), "before restart");
+    assert!(wait_until(|| alice.pending().is_empty()), "bob's ACK settles the send");
+
+    drop(server);
+    assert!(wait_until(|| !alice.is_connected() && !bob.is_connected()), "both notice the drop");
+    let id = alice
+        .send_text(&demo.bob.did, "while relay down", DEFAULT_AGENT_TTL_MS)
+        .expect("queued while disconnected");
+    assert_eq!(alice.pending(), vec![id]);
+
+    let _server = start_server(&addr);
+    assert!(alice.wait_connected(WAIT) && bob.wait_connected(WAIT), "both reconnect");
+    assert_eq!(bob_texts.recv_timeout(WAIT).expect("replayed"), "while relay down");
+    assert!(wait_until(|| alice.pending().is_empty()), "replayed send acknowledged");
+    assert!(
+        bob_texts.recv_timeout(Duration::from_millis(300)).is_err(),
+        "delivered exactly once"
+    );
+
+    alice.shutdown();
+    bob.shutdown();
+}
+
+#[test]
+fn rfc002_relay_backlog_reaches_a_recipient_when_it_registers() {
+    let demo = demo_agents();
+    let addr = free_addr();
+    let _server = start_server(&addr);
+    let (alice, _) = start_agent(&demo.alice, &addr);
+
+    alice.send_text(&demo.bob.did, "sent while bob was away", DEFAULT_AGENT_TTL_MS).expect("send");
+    assert_eq!(alice.pending().len(), 1, "no ACK while bob is offline");
+
+    let (bob, bob_texts) = start_agent(&demo.bob, &addr);
+    assert_eq!(bob_texts.recv_timeout(WAIT).expect("backlog flush"), "sent while bob was away");
+    assert!(wait_until(|| alice.pending().is_empty()), "bob acknowledges the stored message");
+
+    alice.shutdown();
+    bob.shutdown();
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use amp001_example::{demo_agents, hex_encode, now_ms, AgentKeys};
+use amp002_004_tests::{
+    BundleBuilder, BundleResolver, CapDispatcher, CapInvokeBody, CapOutcome,
+    CapResultBody, CapabilityDescriptor, HashAlg, RegistryBundle, SchemaRef,
+};
+use serde_cbor::Value;
+use common::{code, received};
+
+const BUNDLE_ID: &str = "agentries-airgap-2026.10";
+const REVIEW: &str = "com.example.review";
+const INPUT: &[u8] = br#"{"type":"object","required":["repo"],"properties":{"repo":{"type":"string"}}}"#;
+const OUTPUT: &[u8] = br#"{"type":"object"}"#;
+
+fn builder() -> BundleBuilder {
+    BundleBuilder::new(BUNDLE_ID)
+        .artifact("review-1.0.0-input", INPUT)
+        .artifact("review-1.0.0-output", OUTPUT)
+}
+
+fn signed_bundle(issuer: &AgentKeys) -> Vec<u8> {
+    builder().sign(issuer, now_ms()).expect("sign")
+}
+
+fn bundle_resolver() -> BundleResolver {
+    let demo = demo_agents();
+    BundleResolver::new(demo.resolver()).trust_issuer(demo.relay.did.clone())
+}
+
+/// Rewrites one artifact inside an encoded bundle file, leaving the signature alone.
+fn tamper_artifact(bundle: &[u8], key: &str, bytes: &[u8]) -> Vec<u8> {
+    let mut file: Value = serde_cbor::from_slice(bundle).expect("decode");
+    let Value::Map(fields) = &mut file else { panic!("bundle is not a map") };
+    let Some(Value::Map(artifacts)) = fields.get_mut(&Value::Text("artifacts".into())) else {
+        panic!("bundle has no artifacts")
+    };
+    artifacts.insert(Value::Text(key.into()), Value::Bytes(bytes.to_vec()));
+    serde_cbor::to_vec(&file).expect("encode")
+}
+
+#[test]
+fn rfc004_bundle_offline_refs_validate_without_network() {
+    let demo = demo_agents();
+    let mut bundles = bundle_resolver();
+    let manifest = bundles.load(&signed_bundle(&demo.relay)).expect("load");
+    assert_eq!(manifest.bundle_id, BUNDLE_ID);
+    assert_eq!(manifest.issuer, demo.relay.did);
+    assert_eq!(manifest.artifacts.len(), 2);
+    assert_eq!(bundles.bundle_ids(), [BUNDLE_ID]);
+
+    // A.10: offline refs resolve from the bundle and drive schema validation.
+    let descriptor = CapabilityDescriptor::new(
+        REVIEW,
+        "1.0.0",
+        builder().schema_ref("review-1.0.0-input").expect("input ref"),
+        builder().schema_ref("review-1.0.0-output").expect("output ref"),
+    );
+    assert!(descriptor.input_schema.uri().is_none());
+    let input = bundles.schema(&descriptor.input_schema).expect("input schema");
+    assert_eq!(input, INPUT);
+    assert_eq!(bundles.schema(&descriptor.output_schema).expect("output schema"), OUTPUT);
+
+    let mut dispatcher = CapDispatcher::new();
+    dispatcher
+        .register(descriptor, &input, |_, params: Value| Ok(params))
+        .expect("register");
+    let now = now_ms();
+    let invoke = |params: Value| {
+        let body = CapInvokeBody::by_id(format!("{REVIEW}:1.0.0"), params);
+        dispatcher.handle(&received(&demo.alice, &body))
+    };
+    let repo = Value::Map([(Value::Text("repo".into()), Value::Text("agentries/rfcs".into()))].into());
+    assert!(matches!(invoke(repo), CapOutcome::Completed(CapResultBody::Success { .. })));
+    match invoke(Value::Map(Default::default())) {
+        CapOutcome::Rejected(error) => assert_eq!(error.code, 4004),
+        other => panic!("expected 4004, got {other:?}"),
+    }
+
+    // Online refs may name a bundle copy too; the resolver then never needs the uri.
+    let SchemaRef::Offline(offline) = builder().schema_ref("review-1.0.0-input").expect("ref") else {
+        panic!("offline ref expected")
+    };
+    let mut online = SchemaRef::online("https://example.com/cap-registry/input.schema.json", HashAlg::Sha256, INPUT);
+    if let SchemaRef::Online(v) = &mut online {
+        v.bundle_id = Some(offline.bundle_id);
+        v.artifact_key = Some(offline.artifact_key);
+    }
+    assert_eq!(bundles.schema(&online).expect("bundle copy"), INPUT);
+
+    // sha-512 manifests are verified just the same.
+    let wide = BundleBuilder::new("wide").hash_alg(HashAlg::Sha512).artifact("input", INPUT);
+    bundles.load(&wide.sign(&demo.relay, now).expect("sign")).expect("load sha-512");
+    assert_eq!(bundles.schema(&wide.schema_ref("input").expect("ref")).expect("schema"), INPUT);
+}
+
+#[test]
+fn rfc004_bundle_unresolvable_refs_are_5002() {
+    let demo = demo_agents();
+    let mut bundles = bundle_resolver();
+    bundles.load(&signed_bundle(&demo.relay)).expect("load");
+
+    // A.11: local miss, hash mismatch and refs that would need the network.
+    let cases = [
+        SchemaRef::offline("other-bundle", "review-1.0.0-input", HashAlg::Sha256, INPUT),
+        SchemaRef::offline(BUNDLE_ID, "review-9.9.9-input", HashAlg::Sha256, INPUT),
+        SchemaRef::offline(BUNDLE_ID, "review-1.0.0-input", HashAlg::Sha256, OUTPUT),
+        SchemaRef::online("https://example.com/input.schema.json", HashAlg::Sha256, INPUT),
+    ];
+    for schema_ref in &cases {
+        assert_eq!(code(bundles.schema(schema_ref)), 5002, "{schema_ref:?}");
+    }
+
+    let mut cddl = SchemaRef::offline(BUNDLE_ID, "review-1.0.0-input", HashAlg::Sha256, INPUT);
+    if let SchemaRef::Offline(v) = &mut cddl {
+        v.media_type = Some("application/cddl".into());
+    }
+    assert_eq!(code(bundles.schema(&cddl)), 5002);
+
+    let missing = std::env::temp_dir().join(format!("amp004-missing-{}.ampbundle", now_ms()));
+    assert_eq!(code(bundles.load_file(&missing)), 5002);
+}
+
+#[test]
+fn rfc004_bundle_rejects_tampered_or_untrusted_bundles() {
+    let demo = demo_agents();
+    let bundle = signed_bundle(&demo.relay);
+    let resolver = demo.resolver();
+    assert!(RegistryBundle::open(&bundle, &resolver).is_ok());
+
+    assert_eq!(code(RegistryBundle::open(b"not a bundle", &resolver)), 1001);
+
+    // Swapped artifact bytes no longer match the signed manifest.
+    let swapped = tamper_artifact(&bundle, "review-1.0.0-input", br#"{"type":"object"}"#);
+    assert_eq!(code(RegistryBundle::open(&swapped, &resolver)), 5002);
+    let extra = tamper_artifact(&bundle, "unlisted", b"{}");
+    assert_eq!(code(RegistryBundle::open(&extra, &resolver)), 1001);
+
+    let mut file: Value = serde_cbor::from_slice(&bundle).expect("decode");
+    if let Value::Map(fields) = &mut file {
+        if let Some(Value::Bytes(sig)) = fields.get_mut(&Value::Text("manifest_sig".into())) {
+            let last = sig.len() - 1;
+            sig[last] ^= 0x01;
+        }
+    }
+    let forged = serde_cbor::to_vec(&file).expect("encode");
+    assert_eq!(code(RegistryBundle::open(&forged, &resolver)), 3001);
+
+    // Valid signature, but alice is not a trusted bundle issuer here.
+    let mut bundles = bundle_resolver();
+    assert_eq!(code(bundles.load(&signed_bundle(&demo.alice))), 3001);
+    assert!(bundles.bundle_ids().is_empty());
+
+    assert_eq!(code(BundleBuilder::new(BUNDLE_ID).sign(&demo.relay, now_ms())), 1001);
+}
+
+#[test]
+fn rfc004_bundle_cli_builds_and_inspects() {
+    let dir = std::env::temp_dir().join(format!("amp004-bundle-{}-{}", std::process::id(), now_ms()));
+    fs::create_dir_all(&dir).expect("mkdir");
+    fs::write(dir.join("input.schema.json"), INPUT).expect("write");
+    fs::write(dir.join("output.schema.json"), OUTPUT).expect("write");
+    let out = dir.join("review.ampbundle");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+        .current_dir(&dir)
+        .args(["build", "--id", BUNDLE_ID, "--out", "review.ampbundle", "--signer", "relay"])
+        .args(["review-1.0.0-input=input.schema.json", "review-1.0.0-output=output.schema.json"])
+        .output()
+        .expect("run build");
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+
+    let mut bundles = bundle_resolver();
+    assert_eq!(bundles.load_dir(&dir).expect("load dir"), [BUNDLE_ID]);
+    let input = SchemaRef::offline(BUNDLE_ID, "review-1.0.0-input", HashAlg::Sha256, INPUT);
+    assert_eq!(bundles.schema(&input).expect("schema"), INPUT);
+
+    let inspect = Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+        .arg("inspect")
+        .arg(&out)
+        .args(["--trust", "relay"])
+        .output()
+        .expect("run inspect");
+    assert!(inspect.status.success(), "{}", String::from_utf8_lossy(&inspect.stderr));
+    let stdout = String::from_utf8_lossy(&inspect.stdout);
+    assert!(stdout.starts_with(&format!("bundle {BUNDLE_ID} issued by {}", demo_agents().relay.did)), "{stdout}");
+    assert!(stdout.contains("review-1.0.0-input application/schema+json"), "{stdout}");
+
+    let untrusted = Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+        .arg("inspect")
+        .arg(&out)
+        .args(["--trust", "alice"])
+        .output()
+        .expect("run inspect");
+    assert!(!untrusted.status.success());
+    let unspecified = Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+        .arg("inspect")
+        .arg(&out)
+        .output()
+        .expect("run inspect");
+    assert!(!unspecified.status.success(), "inspect must not pick its own trust anchors");
+    assert!(String::from_utf8_lossy(&unspecified.stderr).contains("--trust"));
+
+    // Any DID can sign from a seed; inspect then needs that DID's public key.
+    let publisher = AgentKeys::from_sign_seed("did:web:registry.example.com", [7_u8; 32]);
+    let build = Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+        .current_dir(&dir)
+        .args(["build", "--id", BUNDLE_ID, "--out", "publisher.ampbundle", "--signer", &publisher.did])
+        .args(["--key-seed", &hex_encode(&[7_u8; 32]), "review-1.0.0-input=input.schema.json"])
+        .output()
+        .expect("run build");
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let public = hex_encode(publisher.signing_public_key.as_bytes());
+    assert!(String::from_utf8_lossy(&build.stdout).contains(&public));
+    let inspect = |trust: &str| {
+        Command::new(env!("CARGO_BIN_EXE_amp004-bundle"))
+            .arg("inspect")
+            .arg(dir.join("publisher.ampbundle"))
+            .args(["--trust", trust])
+            .output()
+            .expect("run inspect")
+    };
+    let trusted = inspect(&format!("{}={public}", publisher.did));
+    assert!(trusted.status.success(), "{}", String::from_utf8_lossy(&trusted.stderr));
+    assert!(String::from_utf8_lossy(&trusted.stdout).contains(&publisher.did));
+    assert!(!inspect(&publisher.did).status.success(), "the key is not known without =<hex>");
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,317 @@
+mod common;
+
+use std::collections::HashMap;
+
+use amp001_example::demo_agents;
+use amp002_004_tests::{
+    breaking_changes, CapDispatcher, CapInvokeBody, CapNegotiateHints, CapOutcome,
+    CapResultBody, CapabilityDescriptor, CapabilitySet, CompatibilityDecision, CompatibilityMode,
+    CompatibilityPolicy, DecisionKind, DecisionReason, HashAlg, JsonSchema, NegotiationRule,
+    RegistryStore, SchemaEffect, SchemaRef, SchemaRole,
+};
+use serde_cbor::Value;
+use serde_json::{json, Value as Json};
+use common::{params, received};
+
+const REVIEW: &str = "org.agentries.code-review";
+
+fn input_schema(version: &str) -> Json {
+    match version {
+        "1.4.0" => json!({"type": "object", "required": ["repository"], "properties": {"repository": {"type": "string"}}}),
+        "2.0.0" => json!({"type": "object", "required": ["repo"], "properties": {"repo": {"type": "string"}}}),
+        _ => json!({
+            "type": "object",
+            "required": ["repo"],
+            "properties": {"repo": {"type": "string"}, "depth": {"type": "integer", "minimum": 1}}
+        }),
+    }
+}
+
+fn schema_bytes(version: &str) -> Vec<u8> {
+    serde_json::to_vec(&input_schema(version)).expect("schema")
+}
+
+fn descriptor(version: &str) -> CapabilityDescriptor {
+    let mut descriptor = common::descriptor(REVIEW, version, &schema_bytes(version));
+    match version {
+        "1.4.0" => descriptor.deprecated_ranges = Some(vec![">=1.0.0 <2.0.0".into()]),
+        "2.3.0" => descriptor.supported_ranges = Some(vec![">=2.1.0 <2.4.0".into()]),
+        _ => {}
+    }
+    descriptor
+}
+
+const VERSIONS: [&str; 3] = ["1.4.0", "2.0.0", "2.3.0"];
+
+struct Provider {
+    declared: CapabilitySet,
+    schemas: HashMap<String, JsonSchema>,
+}
+
+impl Provider {
+    fn new() -> Self {
+        let mut declared = CapabilitySet::new();
+        let mut schemas = HashMap::new();
+        for version in VERSIONS {
+            declared.insert(descriptor(version)).expect("insert");
+            schemas.insert(format!("{REVIEW}:{version}"), JsonSchema::from_json(&input_schema(version)).expect("schema"));
+        }
+        Self { declared, schemas }
+    }
+
+    fn decide(&self, policy: &CompatibilityPolicy, body: &CapInvokeBody) -> CompatibilityDecision {
+        policy.decide(&self.declared, body, |id| self.schemas.get(id))
+    }
+}
+
+fn request(version: &str, json: Json) -> CapInvokeBody {
+    CapInvokeBody::by_name(REVIEW, version, params(json))
+}
+
+fn selected(decision: &CompatibilityDecision) -> (DecisionKind, &str) {
+    (decision.kind, decision.selected.as_deref().unwrap_or("-"))
+}
+
+#[test]
+fn rfc004_compatibility_policy_decisions() {
+    let provider = Provider::new();
+    let repo = || json!({"repo": "agentries/rfcs"});
+    let range = CompatibilityPolicy::new(CompatibilityMode::Range);
+
+    let exact = provider.decide(&range, &request("2.0.0", repo()));
+    assert_eq!(selected(&exact), (DecisionKind::Exact, "org.agentries.code-review:2.0.0"));
+    assert_eq!(exact.reason, DecisionReason::ExactMatch);
+    assert_eq!(exact.params, Some(params(repo())));
+
+    // 2.3.0 declares >=2.1.0 <2.4.0 supported.
+    let upgrade = provider.decide(&range, &request("2.2.0", repo()));
+    assert_eq!(selected(&upgrade), (DecisionKind::CompatibleUpgrade, "org.agentries.code-review:2.3.0"));
+    assert_eq!(upgrade.reason, DecisionReason::SupportedRange);
+    assert_eq!(
+        upgrade.to_string(),
+        "CompatibleUpgrade org.agentries.code-review@2.2.0 -> org.agentries.code-review:2.3.0: \
+         newer version declares the requested one supported"
+    );
+
+    // Newer than anything declared: only with downgrades allowed, and only if safe.
+    let newer = provider.decide(&range, &request("2.5.0", repo()));
+    assert_eq!((newer.kind, newer.code()), (DecisionKind::Reject, Some(4003)));
+    assert_eq!(newer.reason, DecisionReason::NoCompatibleVersion);
+    let fallback = range.clone().allow_downgrade(true);
+    let downgrade = provider.decide(&fallback, &request("2.5.0", repo()));
+    assert_eq!(selected(&downgrade), (DecisionKind::DowngradeFallback, "org.agentries.code-review:2.3.0"));
+    assert_eq!(downgrade.reason, DecisionReason::SafeDowngrade);
+    let labelled = provider.decide(&fallback, &request("2.5.0", json!({"repo": "r", "labels": ["x"]})));
+    assert_eq!(labelled.code(), Some(4003));
+    match &labelled.reason {
+        DecisionReason::UnsafeDowngrade { detail } => {
+            assert!(detail.contains("org.agentries.code-review:2.3.0 would drop /labels"), "{detail}");
+            assert!(detail.contains("org.agentries.code-review:2.0.0 would drop /labels"), "{detail}");
+        }
+        other => panic!("expected unsafe downgrade, got {other:?}"),
+    }
+    let invalid = provider.decide(&fallback, &request("2.5.0", json!({"repo": "r", "depth": 0})));
+    assert!(matches!(invalid.reason, DecisionReason::UnsafeDowngrade { .. }));
+
+    let other_major = provider.decide(&fallback, &request("3.0.0", repo()));
+    assert_eq!((other_major.code(), other_major.reason), (Some(4003), DecisionReason::IncompatibleMajor));
+
+    let strict = CompatibilityPolicy::new(CompatibilityMode::Strict);
+    assert_eq!(provider.decide(&strict, &request("2.0.0", repo())).kind, DecisionKind::Exact);
+    assert_eq!(provider.decide(&strict, &request("2.2.0", repo())).reason, DecisionReason::StrictMode);
+
+    let unknown = provider.decide(&range, &CapInvokeBody::by_name("org.agentries.lint", "1.0.0", params(repo())));
+    assert_eq!((unknown.code(), unknown.reason), (Some(4002), DecisionReason::NotFound));
+    let malformed = provider.decide(&range, &request("2.x", repo()));
+    assert_eq!(malformed.code(), Some(4001));
+
+    let hints = CapNegotiateHints {
+        preferred: Some("2.0.0".into()),
+        ..Default::default()
+    };
+    let negotiated = provider.decide(&range, &CapInvokeBody::negotiated(REVIEW, hints, params(repo())));
+    assert_eq!(selected(&negotiated), (DecisionKind::Exact, "org.agentries.code-review:2.0.0"));
+    assert_eq!(negotiated.reason, DecisionReason::Negotiated(NegotiationRule::Preferred));
+}
+
+#[test]
+fn rfc004_compatibility_bridges_and_deprecation() {
+    let provider = Provider::new();
+    let legacy = || json!({"repository": "agentries/rfcs"});
+
+    // 1.4.0 is declared but deprecated: served and flagged, or refused on request.
+    let range = CompatibilityPolicy::new(CompatibilityMode::Range);
+    let deprecated = provider.decide(&range, &request("1.4.0", legacy()));
+    assert_eq!(deprecated.kind, DecisionKind::Exact);
+    assert!(deprecated.deprecated);
+    assert!(deprecated.to_string().contains("(deprecated)"));
+    let refused = provider.decide(&range.clone().reject_deprecated(true), &request("1.4.0", legacy()));
+    assert_eq!((refused.code(), refused.reason), (Some(4003), DecisionReason::Deprecated));
+
+    // Negotiation skips what the policy refuses to serve.
+    let negotiate = |preferred: &str, acceptable: &[&str]| {
+        let hints = CapNegotiateHints {
+            preferred: Some(preferred.into()),
+            acceptable: Some(acceptable.iter().map(|v| v.to_string()).collect()),
+            ..Default::default()
+        };
+        CapInvokeBody::negotiated(REVIEW, hints, params(legacy()))
+    };
+    let flagged = provider.decide(&range, &negotiate("1.4.0", &["2.0.0"]));
+    assert_eq!(selected(&flagged), (DecisionKind::Exact, "org.agentries.code-review:1.4.0"));
+    assert!(flagged.deprecated);
+    let strict_range = range.clone().reject_deprecated(true);
+    let negotiated = provider.decide(&strict_range, &negotiate("1.4.0", &["2.0.0"]));
+    assert_eq!(selected(&negotiated), (DecisionKind::Exact, "org.agentries.code-review:2.0.0"));
+    assert_eq!(negotiated.reason, DecisionReason::Negotiated(NegotiationRule::Acceptable));
+    assert!(!negotiated.deprecated);
+    let nothing = provider.decide(&strict_range, &negotiate("1.4.0", &[]));
+    assert_eq!((nothing.code(), nothing.reason), (Some(4003), DecisionReason::NoCompatibleVersion));
+
+    // A documented bridge is the only way across majors, and adapts the params.
+    let bridge = CompatibilityPolicy::new(CompatibilityMode::Bridge)
+        .reject_deprecated(true)
+        .bridge(">=1.0.0 <2.0.0", "2.0.0", "1.x repository renamed to repo", |params| {
+            let Value::Map(mut map) = params else {
+                return Err("params must be a map".into());
+            };
+            let repo = map.remove(&Value::Text("repository".into())).ok_or("repository is required")?;
+            map.insert(Value::Text("repo".into()), repo);
+            Ok(Value::Map(map))
+        })
+        .expect("bridge");
+    let bridged = provider.decide(&bridge, &request("1.4.0", legacy()));
+    assert_eq!(selected(&bridged), (DecisionKind::CompatibleUpgrade, "org.agentries.code-review:2.0.0"));
+    assert_eq!(
+        bridged.reason,
+        DecisionReason::Bridge {
+            description: "1.x repository renamed to repo".into()
+        }
+    );
+    assert_eq!(bridged.params, Some(params(json!({"repo": "agentries/rfcs"}))));
+    let broken = provider.decide(&bridge, &request("1.4.0", json!({"repo": "r"})));
+    assert_eq!(broken.code(), Some(4001));
+
+    // Without the bridge the same request has nowhere to go.
+    let no_bridge = CompatibilityPolicy::new(CompatibilityMode::Bridge).reject_deprecated(true);
+    assert_eq!(provider.decide(&no_bridge, &request("1.4.0", legacy())).reason, DecisionReason::Deprecated);
+    assert_eq!(provider.decide(&no_bridge, &request("4.0.0", legacy())).reason, DecisionReason::IncompatibleMajor);
+}
+
+#[test]
+fn rfc004_compatibility_policy_drives_the_dispatcher() {
+    let alice = demo_agents().alice;
+    let mut dispatcher = CapDispatcher::new();
+    for version in VERSIONS {
+        dispatcher
+            .register(descriptor(version), &schema_bytes(version), |call, _: Value| {
+                let decision = call.compatibility.as_ref().map(ToString::to_string).unwrap_or_default();
+                Ok((call.descriptor.version.clone(), decision))
+            })
+            .expect("register");
+    }
+    let upgrade = received(&alice, &request("2.2.0", json!({"repo": "agentries/rfcs"})));
+    match dispatcher.handle(&upgrade) {
+        CapOutcome::Rejected(error) => assert_eq!(error.code, 4003),
+        other => panic!("exact-only dispatcher must reject, got {other:?}"),
+    }
+
+    dispatcher.compatibility(CompatibilityPolicy::new(CompatibilityMode::Range));
+    let (version, decision): (String, String) = match dispatcher.handle(&upgrade) {
+        CapOutcome::Completed(result @ CapResultBody::Success { .. }) => result.into_result().expect("result"),
+        other => panic!("expected success, got {other:?}"),
+    };
+    assert_eq!(version, "2.3.0");
+    assert!(decision.starts_with("CompatibleUpgrade"), "{decision}");
+
+    let rejected = received(&alice, &request("3.1.0", json!({"repo": "agentries/rfcs"})));
+    match dispatcher.handle(&rejected) {
+        CapOutcome::Rejected(error) => {
+            assert_eq!(error.code, 4003);
+            assert!(error.message.contains("only incompatible major versions"), "{}", error.message);
+        }
+        other => panic!("expected 4003, got {other:?}"),
+    }
+    // The selected version's schema still applies after the decision.
+    let invalid = received(&alice, &request("2.2.0", json!({"repo": "agentries/rfcs", "depth": 0})));
+    match dispatcher.handle(&invalid) {
+        CapOutcome::Rejected(error) => assert_eq!(error.code, 4004),
+        other => panic!("expected 4004, got {other:?}"),
+    }
+}
+
+#[test]
+fn rfc004_schema_diff_flags_breaking_changes() {
+    let schema = |json: Json| JsonSchema::from_json(&json).expect("schema");
+    let old = schema(json!({
+        "type": "object",
+        "required": ["repo"],
+        "properties": {
+            "repo": {"type": "string", "minLength": 1},
+            "mode": {"enum": ["fast", "full"]},
+            "limit": {"type": "integer", "maximum": 100}
+        }
+    }));
+
+    // Additive changes: optional property, relaxed bounds, extra enum member.
+    let additive = schema(json!({
+        "type": "object",
+        "required": ["repo"],
+        "properties": {
+            "repo": {"type": "string"},
+            "mode": {"enum": ["fast", "full", "deep"]},
+            "limit": {"type": "number", "maximum": 500},
+            "depth": {"type": "integer"}
+        }
+    }));
+    let changes = old.diff(&additive);
+    assert!(changes.iter().all(|c| c.effect == SchemaEffect::Widened), "{changes:?}");
+    assert!(!changes.iter().any(|c| SchemaRole::Input.is_breaking(c)));
+    assert!(changes.iter().any(|c| SchemaRole::Output.is_breaking(c)));
+
+    let narrowed = schema(json!({
+        "type": "object",
+        "required": ["repo", "mode"],
+        "additionalProperties": false,
+        "properties": {
+            "repo": {"type": "string", "minLength": 3},
+            "mode": {"enum": ["full"]}
+        }
+    }));
+    let breaking = breaking_changes((&old, &schema(json!(true))), (&narrowed, &schema(json!(true))));
+    let found: Vec<String> = breaking.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        found,
+        [
+            "input #: property mode is now required",
+            "input #/properties/limit: schema now constrains values",
+            "input #/properties/mode: enum member \"fast\" removed",
+            "input #/properties/repo: minLength changed from 1 to 3",
+            "input #/additionalProperties: schema now constrains values",
+        ]
+    );
+    assert!(old.diff(&old).is_empty());
+}
+
+#[test]
+fn rfc004_registry_flags_breaking_releases_before_publishing() {
+    let mut store = RegistryStore::new();
+    store.publish(&descriptor("2.0.0"), &schema_bytes("2.0.0"), b"true").expect("publish");
+
+    // 2.3.0 only adds an optional property.
+    assert!(store.check_release(&descriptor("2.3.0"), &schema_bytes("2.3.0"), b"true").expect("check").is_empty());
+
+    let stricter = br#"{"type":"object","required":["repo","depth"]}"#;
+    let mut next = descriptor("2.4.0");
+    next.input_schema = SchemaRef::online("https://agentries.org/input.schema.json", HashAlg::Sha256, stricter);
+    let breaking = store.check_release(&next, stricter, b"true").expect("check");
+    assert_eq!(breaking.len(), 1, "{breaking:?}");
+    assert!(breaking.iter().all(|b| b.role == SchemaRole::Input));
+    assert!(breaking[0].to_string().contains("property depth is now required"));
+
+    // A new major is free to break; there is nothing earlier to compare against.
+    let mut major = descriptor("3.0.0");
+    major.input_schema = next.input_schema.clone();
+    assert!(store.check_release(&major, stricter, b"true").expect("check").is_empty());
+    assert_eq!(store.check_release(&next, b"{\"pattern\":\"x\"}", b"true").expect_err("unsupported").code, 1001);
+}
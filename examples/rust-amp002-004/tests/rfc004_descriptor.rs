@@ -0,0 +1,151 @@
+mod common;
+
+use amp001_example::{cose_sign1_sign, AgentKeys, DidResolver, Version};
+use amp002_004_tests::{
+    namespace_owner_did, parse_capability_id, validate_capability_name, CapabilityDescriptor,
+    HashAlg, SchemaRef, SCHEMA_MEDIA_TYPE_JSON,
+};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+
+const INPUT_SCHEMA: &[u8] = br#"{"type":"object","required":["diff"]}"#;
+const OUTPUT_SCHEMA: &[u8] = br#"{"type":"object","required":["comments"]}"#;
+
+fn descriptor() -> CapabilityDescriptor {
+    let mut descriptor = common::descriptor("com.example.code-review", "2.1.0", INPUT_SCHEMA);
+    descriptor.output_schema =
+        SchemaRef::offline("bundle-2026-01", "code-review/2.1.0/output", HashAlg::Sha512, OUTPUT_SCHEMA);
+    descriptor.supported_ranges = Some(vec![">=2.0.0 <3.0.0".to_string()]);
+    descriptor
+}
+
+fn owner() -> (AgentKeys, DidResolver) {
+    let owner = AgentKeys::from_sign_seed("did:web:example.com", [41_u8; 32]);
+    let mut resolver = DidResolver::default();
+    resolver.add_agent(&owner);
+    (owner, resolver)
+}
+
+#[test]
+fn rfc004_capability_identifier_and_namespace_format() {
+    assert_eq!(
+        parse_capability_id("org.agentries.code-review:2.1.0").expect("id"),
+        ("org.agentries.code-review", "2.1.0")
+    );
+    assert!(parse_capability_id("com.acme.risk-evaluator:1.4.2-rc.1+build.7").is_ok());
+    assert_eq!(namespace_owner_did("com.acme.risk-evaluator").expect("owner"), "did:web:acme.com");
+
+    for name in ["code-review", "acme.review", "Com.acme.review", "com..review", "com.-acme.review", "com.acme.-x"] {
+        assert_eq!(validate_capability_name(name).expect_err(name).code, 1001, "{name}");
+    }
+    for id in ["com.acme.review", "com.acme.review:1.2", "com.acme.review:01.2.3", "com.acme.review:1.2.3-"] {
+        assert_eq!(parse_capability_id(id).expect_err(id).code, 1001, "{id}");
+    }
+}
+
+#[test]
+fn rfc004_descriptor_roundtrips_as_deterministic_cbor() {
+    let descriptor = descriptor();
+    descriptor.validate().expect("valid descriptor");
+
+    let bytes = descriptor.to_cbor().expect("encode");
+    assert_eq!(CapabilityDescriptor::from_cbor(&bytes).expect("decode"), descriptor);
+
+    let value: Value = serde_cbor::from_slice(&bytes).expect("cbor");
+    let Value::Map(map) = value else { panic!("descriptor must be a map") };
+    let keys: Vec<Value> = map.keys().cloned().collect();
+    assert_eq!(
+        keys,
+        ["id", "name", "version", "input_schema", "output_schema", "supported_ranges"]
+            .map(|k| Value::Text(k.to_string()))
+            .to_vec()
+    );
+    let mut canonical = keys.clone();
+    canonical.sort();
+    assert_eq!(keys, canonical);
+    assert_eq!(descriptor.input_schema.media_type(), SCHEMA_MEDIA_TYPE_JSON);
+    assert_eq!(
+        descriptor.output_schema.bundle_locator(),
+        Some(("bundle-2026-01", "code-review/2.1.0/output"))
+    );
+}
+
+#[test]
+fn rfc004_descriptor_consistency_rules() {
+    let mut mismatched = descriptor();
+    mismatched.id = "com.example.code-review:2.2.0".to_string();
+    assert_eq!(mismatched.validate().expect_err("id != name:version").code, 1001);
+
+    let mut short_hash = descriptor();
+    let SchemaRef::Online(online) = &mut short_hash.input_schema else { unreachable!() };
+    online.hash = ByteBuf::from(vec![0_u8; 64]);
+    assert_eq!(short_hash.validate().expect_err("sha-256 needs 32 bytes").code, 1001);
+
+    let mut half_locator = descriptor();
+    let SchemaRef::Online(online) = &mut half_locator.input_schema else { unreachable!() };
+    online.bundle_id = Some("bundle-2026-01".to_string());
+    let bytes = serde_cbor::to_vec(&half_locator).expect("encode");
+    let err = CapabilityDescriptor::from_cbor(&bytes).expect_err("bundle_id without artifact_key");
+    assert_eq!(err.code, 1001);
+    assert!(err.detail.contains("together"), "{err}");
+
+    let mut caret = descriptor();
+    caret.supported_ranges = Some(vec!["^2.0.0".to_string()]);
+    let err = caret.validate().expect_err("caret is outside the §4.4 grammar");
+    assert_eq!(err.code, 1001);
+    assert!(err.detail.contains("caret"), "{err}");
+}
+
+#[test]
+fn rfc004_descriptor_supported_and_deprecated_ranges() {
+    let mut descriptor = descriptor();
+    descriptor.deprecated_ranges = Some(vec![">=2.0.0 <2.1.0".to_string()]);
+    let version = |text: &str| Version::parse(text).expect("version");
+    assert!(descriptor.supports(&version("2.0.3")).expect("ranges"));
+    assert!(!descriptor.supports(&version("3.0.0")).expect("ranges"));
+    assert!(descriptor.is_deprecated(&version("2.0.3")).expect("ranges"));
+    assert!(!descriptor.is_deprecated(&version("2.1.0")).expect("ranges"));
+
+    descriptor.supported_ranges = None;
+    assert!(descriptor.supports(&version("2.1.0")).expect("exact"));
+    assert!(!descriptor.supports(&version("2.1.1")).expect("exact only"));
+}
+
+#[test]
+fn rfc004_schema_artifacts_are_hash_verified() {
+    let descriptor = descriptor();
+    descriptor.input_schema.verify_artifact(INPUT_SCHEMA).expect("sha-256 match");
+    descriptor.output_schema.verify_artifact(OUTPUT_SCHEMA).expect("sha-512 match");
+
+    let err = descriptor
+        .input_schema
+        .verify_artifact(OUTPUT_SCHEMA)
+        .expect_err("wrong bytes");
+    assert_eq!(err.code, 5002);
+    assert_eq!(descriptor.output_schema.hash().len(), 64);
+}
+
+#[test]
+fn rfc004_descriptor_signature_binds_namespace_owner() {
+    let (owner, mut resolver) = owner();
+    let mut descriptor = descriptor();
+    assert_eq!(descriptor.verify_signature(&resolver).expect_err("unsigned").code, 3001);
+
+    descriptor.sign(&owner).expect("sign");
+    descriptor.verify_signature(&resolver).expect("owner signature");
+    let decoded = CapabilityDescriptor::from_cbor(&descriptor.to_cbor().expect("encode")).expect("decode");
+    decoded.verify_signature(&resolver).expect("signature survives encoding");
+
+    let mut tampered = descriptor.clone();
+    tampered.notes = Some("widened".to_string());
+    assert_eq!(tampered.verify_signature(&resolver).expect_err("tampered").code, 3001);
+
+    let outsider = AgentKeys::from_sign_seed("did:web:example.org", [42_u8; 32]);
+    resolver.add_agent(&outsider);
+    let mut foreign = descriptor.clone();
+    assert_eq!(foreign.sign(&outsider).expect_err("not the owner").code, 3001);
+    let payload = foreign.signing_payload().expect("payload");
+    let sig = cose_sign1_sign(&outsider, &outsider.assertion_kid(), &payload).expect("sign");
+    foreign.descriptor_sig = Some(ByteBuf::from(sig));
+    assert_eq!(foreign.verify_signature(&resolver).expect_err("foreign signer").code, 3001);
+}
@@ -0,0 +1,199 @@
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use amp001_example::{demo_agents, now_ms, receive_and_verify, ErrorBody, RpcError, TYPE_ERROR};
+use amp002_004_tests::{
+    accept_cap_declare, build_cap_query, query_all_pages, CapFilter, CapOrder, CapQueryBody,
+    CapClient, CapabilityDescriptor, CapabilitySet, TYPE_CAP_DECLARE,
+};
+use common::descriptor;
+
+fn capability_set() -> CapabilitySet {
+    let mut set = CapabilitySet::new();
+    for (name, version) in [
+        ("org.agentries.code-review", "2.0.0"),
+        ("org.agentries.code-review", "1.0.0"),
+        ("org.agentries.code-review", "2.1.0"),
+        ("org.agentries.code-review", "2.1.0-rc.1"),
+        ("org.agentries.code-review", "10.0.0"),
+        ("org.agentries.translate", "1.0.0"),
+    ] {
+        set.insert(descriptor(name, version, b"{}")).expect("valid descriptor");
+    }
+    set.alias("code-review", "org.agentries.code-review");
+    set
+}
+
+fn versions(descriptors: &[CapabilityDescriptor]) -> Vec<&str> {
+    descriptors.iter().map(|d| d.version.as_str()).collect()
+}
+
+fn remote_code(err: RpcError) -> u16 {
+    match err {
+        RpcError::Remote(body) => body.code,
+        RpcError::Local(err) => err.code,
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn rfc004_cap_query_pages_in_stable_order_without_duplicates() {
+    let set = capability_set();
+    let mut query = CapQueryBody::new(CapFilter::capability("org.agentries.code-review"));
+    query.limit = Some(2);
+
+    let mut pages = Vec::new();
+    let all = query_all_pages(&query, |page| {
+        let declare = set.query(page).map_err(RpcError::Remote)?;
+        pages.push(versions(&declare.capabilities).join(","));
+        Ok(declare)
+    })
+    .expect("page through");
+    assert_eq!(pages, ["10.0.0,2.1.0", "2.1.0-rc.1,2.0.0", "1.0.0"]);
+    assert_eq!(versions(&all), ["10.0.0", "2.1.0", "2.1.0-rc.1", "2.0.0", "1.0.0"]);
+
+    query.order = Some(CapOrder::OldestFirst);
+    query.limit = Some(4);
+    let oldest = query_all_pages(&query, |page| set.query(page).map_err(RpcError::Remote)).expect("oldest-first");
+    assert_eq!(versions(&oldest), ["1.0.0", "2.0.0", "2.1.0-rc.1", "2.1.0", "10.0.0"]);
+
+    let ranged = CapFilter::capability("org.agentries.code-review").with_version(">=2.0.0 <3.0.0");
+    let first = set.query(&CapQueryBody::new(ranged)).expect("range");
+    assert_eq!(versions(&first.capabilities), ["2.1.0", "2.0.0"], "pre-releases need an opt-in comparator");
+    assert_eq!(first.next_cursor, None);
+}
+
+#[test]
+fn rfc004_cap_query_cursor_survives_inserts_between_pages() {
+    let mut set = capability_set();
+    let mut query = CapQueryBody::new(CapFilter::capability("org.agentries.code-review"));
+    query.limit = Some(2);
+    let first = set.query(&query).expect("first page");
+    set.insert(descriptor("org.agentries.code-review", "11.0.0", b"{}")).expect("insert");
+    set.remove("org.agentries.code-review:2.1.0-rc.1");
+
+    let second_page = query.next_page(first.next_cursor.expect("more pages"));
+    let second = set.query(&second_page).expect("second page");
+    assert_eq!(versions(&second.capabilities), ["2.0.0", "1.0.0"]);
+
+    // Everything after the cursor is gone: the walk ends on an empty page, not an error.
+    set.remove("org.agentries.code-review:2.0.0");
+    set.remove("org.agentries.code-review:1.0.0");
+    let last = set.query(&second_page).expect("last page");
+    assert!(last.capabilities.is_empty());
+    assert_eq!(last.next_cursor, None);
+    last.validate_page(true).expect("empty page after a cursor");
+    assert_eq!(last.validate().expect_err("empty first page").code, 1001);
+}
+
+#[test]
+fn rfc004_cap_query_error_mapping() {
+    let set = capability_set();
+    let code = |query: &CapQueryBody| set.query(query).expect_err("must fail").code;
+
+    let missing = CapQueryBody::new(CapFilter::capability("org.agentries.nonexistent"));
+    assert_eq!(code(&missing), 4002, "A.17: no empty CAP_DECLARE");
+    let no_version = CapQueryBody::new(CapFilter::capability("org.agentries.translate").with_version(">=3.0.0 <4.0.0"));
+    assert_eq!(code(&no_version), 4003);
+    let bad_range = CapQueryBody::new(CapFilter::capability("org.agentries.translate").with_version("^1.0.0"));
+    assert_eq!(code(&bad_range), 4001);
+    assert_eq!(code(&CapQueryBody::new(CapFilter::default())), 1001);
+
+    let mut paged = CapQueryBody::new(CapFilter::capability("org.agentries.code-review"));
+    paged.limit = Some(1);
+    let cursor = set.query(&paged).expect("page").next_cursor.expect("cursor");
+    assert_eq!(code(&paged.next_page("not-a-cursor")), 4001, "A.15 malformed cursor");
+    let mut reordered = paged.next_page(cursor.clone());
+    reordered.order = Some(CapOrder::OldestFirst);
+    assert_eq!(code(&reordered), 4001, "order changed under the cursor");
+    let mut refiltered = paged.next_page(cursor.clone());
+    refiltered.filter.version = Some(">=1.0.0".to_string());
+    assert_eq!(code(&refiltered), 4001, "filter changed under the cursor");
+    let mut relimited = paged.next_page(cursor);
+    relimited.limit = Some(3);
+    assert!(set.query(&relimited).is_ok(), "limit may change between pages");
+    paged.limit = Some(0);
+    assert_eq!(code(&paged), 4001);
+}
+
+#[test]
+fn rfc004_cap_query_legacy_type_alias() {
+    let set = capability_set();
+    let by_type = CapQueryBody::new(CapFilter {
+        type_alias: Some("code-review".to_string()),
+        version: Some("1.0.0".to_string()),
+        ..CapFilter::default()
+    });
+    let declare = set.query(&by_type).expect("A.7 alias accepted");
+    assert_eq!(declare.capabilities[0].id, "org.agentries.code-review:1.0.0");
+
+    let both = CapQueryBody::new(CapFilter {
+        capability: Some("org.agentries.translate".to_string()),
+        type_alias: Some("code-review".to_string()),
+        version: None,
+    });
+    assert_eq!(set.query(&both).expect("capability wins").capabilities[0].name, "org.agentries.translate");
+}
+
+#[test]
+fn rfc004_cap_declare_must_correlate_with_the_query() {
+    let demo = demo_agents();
+    let resolver = demo.resolver();
+    let set = capability_set();
+    let now = now_ms();
+    let query = CapQueryBody::new(CapFilter::capability("org.agentries.translate"));
+
+    let (query_id, wire) = build_cap_query(&demo.alice, &demo.bob.did, &query, 30_000, now).expect("query");
+    let received = receive_and_verify(&demo.bob, &wire, &resolver, now).expect("verify query");
+    let reply = set.respond(&demo.bob, &received, now).expect("respond");
+    let reply = receive_and_verify(&demo.alice, &reply, &resolver, now).expect("verify declare");
+    assert_eq!(reply.meta.typ, TYPE_CAP_DECLARE);
+    assert_eq!(reply.meta.reply_to, Some(query_id));
+    let declare = accept_cap_declare(&query_id, &query, &demo.bob.did, &reply).expect("A.1 declare accepted");
+    assert_eq!(declare.capabilities[0].id, "org.agentries.translate:1.0.0");
+
+    let other_id = [7_u8; 16];
+    let err = accept_cap_declare(&other_id, &query, &demo.bob.did, &reply).expect_err("A.13 wrong reply_to");
+    assert_eq!(remote_code(err), 4001);
+
+    let missing = CapQueryBody::new(CapFilter::capability("org.agentries.nonexistent"));
+    let (missing_id, wire) = build_cap_query(&demo.alice, &demo.bob.did, &missing, 30_000, now).expect("query");
+    let received = receive_and_verify(&demo.bob, &wire, &resolver, now).expect("verify query");
+    let reply = set.respond(&demo.bob, &received, now).expect("respond");
+    let reply = receive_and_verify(&demo.alice, &reply, &resolver, now).expect("verify error");
+    assert_eq!(reply.meta.typ, TYPE_ERROR);
+    let body: ErrorBody = reply.decode_body().expect("error body");
+    assert_eq!(body.code, 4002);
+    assert_eq!(remote_code(accept_cap_declare(&missing_id, &missing, &demo.bob.did, &reply).expect_err("ERROR")), 4002);
+}
+
+#[test]
+fn rfc004_cap_query_client_pages_over_tcp() {
+    let demo = demo_agents();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    let provider = demo.bob.clone();
+    let provider_resolver = demo.resolver();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("accept");
+        let mut reader = stream.try_clone().expect("clone");
+        let mut writer = stream;
+        capability_set()
+            .serve(&provider, &provider_resolver, &mut reader, &mut writer)
+            .expect("serve");
+    });
+
+    let stream = TcpStream::connect(addr).expect("connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).expect("timeout");
+    let mut client = CapClient::new(demo.alice.clone(), demo.resolver(), stream);
+    let mut query = CapQueryBody::new(CapFilter::capability("org.agentries.code-review"));
+    query.limit = Some(2);
+    let all = client.query_all(&demo.bob.did, &query).expect("query all");
+    assert_eq!(versions(&all), ["10.0.0", "2.1.0", "2.1.0-rc.1", "2.0.0", "1.0.0"]);
+
+    let missing = CapQueryBody::new(CapFilter::capability("org.agentries.nonexistent"));
+    assert_eq!(remote_code(client.query(&demo.bob.did, &missing).expect_err("4002")), 4002);
+}
@@ -0,0 +1,386 @@
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use amp001_example::{
+    build_plain_signed, build_proc_result, demo_agents, now_ms, read_frame, receive_and_verify, write_frame, AgentKeys,
+    DidResolver, ProcOkBody, ReceivedMessage, Recipients, TYPE_ERROR,
+};
+use amp002_004_tests::{
+    accept_cap_result, build_cap_invoke, CapCall, CapClient, CapDispatcher, CapFilter,
+    CapInvokeBody, CapInvokeError, CapNegotiateHints, CapOutcome, CapQueryBody, CapResultBody,
+    CapResultError, CapSessionContext, JsonSchema,
+    TYPE_CAP_RESULT,
+};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use serde_json::{json, Value as Json};
+use common::{descriptor, params, received};
+
+const CODE_REVIEW: &str = "org.agentries.code-review";
+
+const REVIEW_INPUT: &str = r#"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "type": "object",
+  "required": ["repo", "files"],
+  "properties": {
+    "repo": {"type": "string", "minLength": 1},
+    "files": {"type": "array", "minItems": 1, "items": {"type": "string"}},
+    "sleep_ms": {"type": "integer", "minimum": 0}
+  },
+  "additionalProperties": false
+}"#;
+
+#[derive(Debug, Deserialize)]
+struct ReviewParams {
+    repo: String,
+    files: Vec<String>,
+    sleep_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Review {
+    version: String,
+    comments: Vec<String>,
+}
+
+fn review(call: &CapCall, params: ReviewParams) -> Result<Review, CapResultError> {
+    if let Some(ms) = params.sleep_ms {
+        thread::sleep(Duration::from_millis(ms));
+    }
+    if params.repo == "broken" {
+        return Err(CapResultError::new(5001, "review backend crashed"));
+    }
+    Ok(Review {
+        version: call.descriptor.version.clone(),
+        comments: params.files.iter().map(|f| format!("{}: looks good", f)).collect(),
+    })
+}
+
+fn dispatcher() -> CapDispatcher {
+    let mut dispatcher = CapDispatcher::new();
+    for version in ["2.0.0", "2.1.0"] {
+        dispatcher
+            .register(descriptor(CODE_REVIEW, version, REVIEW_INPUT.as_bytes()), REVIEW_INPUT.as_bytes(), review)
+            .expect("register");
+    }
+    dispatcher
+        .register(descriptor("org.agentries.admin", "1.0.0", b"true"), b"true", |_, ()| Ok("ok"))
+        .expect("register");
+    dispatcher.alias("code-review", CODE_REVIEW);
+    dispatcher
+}
+
+fn review_params() -> Value {
+    params(json!({"repo": "agentries/rfcs", "files": ["004.md"]}))
+}
+
+/// Raw CBOR body, for shapes `CapInvokeBody` cannot express.
+fn received_raw(body: &Value) -> ReceivedMessage {
+    let demo = demo_agents();
+    let mut message = received(&demo.alice, &CapInvokeBody::by_id("org.agentries.admin:1.0.0", Value::Null));
+    message.body_bytes = serde_cbor::to_vec(body).expect("encode");
+    message
+}
+
+fn rejected(outcome: CapOutcome) -> u16 {
+    match outcome {
+        CapOutcome::Rejected(error) => error.code,
+        other => panic!("expected rejection, got {other:?}"),
+    }
+}
+
+fn completed(outcome: CapOutcome) -> CapResultBody {
+    match outcome {
+        CapOutcome::Completed(result) => result,
+        other => panic!("expected CAP_RESULT, got {other:?}"),
+    }
+}
+
+#[test]
+fn rfc004_json_schema_subset() {
+    let schema = JsonSchema::from_slice(REVIEW_INPUT.as_bytes()).expect("schema");
+    assert_eq!(schema.validate(&review_params()), Ok(()));
+    let err = |value: Json| schema.validate(&params(value)).expect_err("violation");
+    assert_eq!(err(json!({"repo": "r"})).reason, "missing required property files");
+    assert_eq!(err(json!({"repo": "r", "files": ["a", 7]})).path, "/files/1");
+    assert_eq!(err(json!({"repo": "r", "files": ["a"], "sleep_ms": -1})).path, "/sleep_ms");
+    assert_eq!(err(json!({"repo": "r", "files": ["a"], "extra": 1})).path, "/extra");
+    assert_eq!(err(json!(["not", "an", "object"])).path, "");
+
+    let combined = JsonSchema::from_slice(br#"{"oneOf": [{"type": "integer"}, {"minimum": 10}], "not": {"const": 3}}"#)
+        .expect("schema");
+    assert!(combined.validate(&Value::Integer(1)).is_ok());
+    assert!(combined.validate(&Value::Float(10.5)).is_ok());
+    assert!(combined.validate(&Value::Integer(12)).is_err(), "matches both oneOf branches");
+    assert!(combined.validate(&Value::Integer(3)).is_err());
+    let text = JsonSchema::from_slice(br#"{"type": "string", "enum": ["a"]}"#).expect("schema");
+    assert!(text.validate(&Value::Bytes(b"a".to_vec())).is_err(), "bstr is not a JSON string");
+
+    // Keywords that could reject an instance are never silently ignored.
+    for unsupported in [&br##"{"$ref": "#/defs/x"}"##[..], br#"{"pattern": "^a"}"#, br#"{"type": "uuid"}"#, b"7", b"{"] {
+        let err = JsonSchema::from_slice(unsupported).expect_err("unsupported");
+        assert_eq!(err.code, 1001);
+    }
+}
+
+#[test]
+fn rfc004_invoke_appendix_a_vectors() {
+    let demo = demo_agents();
+    let dispatcher = dispatcher();
+    let handle = |body: &CapInvokeBody| dispatcher.handle(&received(&demo.alice, body));
+
+    // A.5: valid params for the negotiated schema give one CAP_RESULT(success).
+    let hints = CapNegotiateHints {
+        preferred: Some("2.2.0".to_string()),
+        acceptable: Some(vec!["2.1.0".to_string()]),
+        range: None,
+    };
+    let result = completed(handle(&CapInvokeBody::negotiated(CODE_REVIEW, hints, review_params())));
+    let review: Review = result.into_result().expect("success");
+    assert_eq!(review.version, "2.1.0");
+
+    // A.6: missing required field in params.
+    let missing = params(json!({"repo": "agentries/rfcs"}));
+    assert_eq!(rejected(handle(&CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.0.0"), missing))), 4004);
+
+    // A.8: id disagrees with capability/version.
+    let mut conflict = CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.1.0"), review_params());
+    conflict.capability = Some("org.agentries.translate".to_string());
+    conflict.version = Some("1.0.0".to_string());
+    let raw = |body: &CapInvokeBody| received_raw(&serde_cbor::value::to_value(body).expect("cbor"));
+    assert_eq!(rejected(dispatcher.handle(&raw(&conflict))), 4001);
+
+    // A.16: id together with negotiate.
+    let mut both = CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.1.0"), review_params());
+    both.negotiate = Some(CapNegotiateHints::default());
+    assert_eq!(rejected(dispatcher.handle(&raw(&both))), 4001);
+
+    // A.20/A.21: the session extension is accepted and echoed without changing the result.
+    let mut in_session = CapInvokeBody::by_name("code-review", "2.0.0", review_params());
+    in_session.capability = None;
+    in_session.type_alias = Some("code-review".to_string());
+    let mut session = CapSessionContext::new([9; 16]);
+    session.session_scope = Some(true);
+    in_session.session = Some(session.clone());
+    let result = completed(handle(&in_session));
+    assert_eq!(result.session(), Some(&session));
+    assert_eq!(result.into_result::<Review>().expect("success").version, "2.0.0");
+}
+
+#[test]
+fn rfc004_invoke_follows_validation_order() {
+    let demo = demo_agents();
+    let mut dispatcher = dispatcher();
+    let alice = demo.alice.did.clone();
+    dispatcher.authorize_caller(move |request| request.meta.from == alice);
+    dispatcher.authorize_capability(|_, name| name != "org.agentries.admin");
+    dispatcher.verify_delegation(|_, evidence| *evidence == Value::Text("valid".to_string()));
+    let code = |from: &AgentKeys, body: &CapInvokeBody| rejected(dispatcher.handle(&received(from, body)));
+
+    let unknown = CapInvokeBody::by_id("org.agentries.nonexistent:1.0.0", Value::Null);
+    let admin = CapInvokeBody::by_id("org.agentries.admin:1.0.0", Value::Null);
+    let bad_version_and_params = CapInvokeBody::by_name(CODE_REVIEW, "9.0.0", Value::Null);
+
+    // Step 1: shape beats everything. Missing params is 4001, malformed session 1001.
+    let mut no_params = serde_cbor::value::to_value(&unknown).expect("cbor");
+    if let Value::Map(map) = &mut no_params {
+        map.remove(&Value::Text("params".to_string()));
+    }
+    assert_eq!(rejected(dispatcher.handle(&received_raw(&no_params))), 4001);
+    let mut short_session = serde_cbor::value::to_value(&admin).expect("cbor");
+    if let Value::Map(map) = &mut short_session {
+        let session = serde_cbor::value::to_value(json!({"session_id": "not-bytes"})).expect("cbor");
+        map.insert(Value::Text("session".to_string()), session);
+    }
+    assert_eq!(rejected(dispatcher.handle(&received_raw(&short_session))), 1001);
+    assert_eq!(rejected(dispatcher.handle(&received_raw(&Value::Text("hi".to_string())))), 1001);
+
+    // A.9, step 2: a caller outside policy gets the same 3001 whether or not the
+    // capability exists.
+    let denied = |body: &CapInvokeBody| match dispatcher.handle(&received(&demo.relay, body)) {
+        CapOutcome::Rejected(error) => error,
+        other => panic!("expected rejection, got {other:?}"),
+    };
+    assert_eq!(denied(&unknown), denied(&admin));
+    assert_eq!(denied(&unknown).code, 3001);
+
+    // Step 3: delegation evidence is checked before the capability is looked up (A.19);
+    // valid evidence continues into the pipeline (A.18).
+    let mut delegated = unknown.clone();
+    delegated.delegation = Some(Value::Text("revoked".to_string()));
+    assert_eq!(code(&demo.alice, &delegated), 3004);
+    delegated.delegation = Some(Value::Text("valid".to_string()));
+    assert_eq!(code(&demo.alice, &delegated), 4002);
+
+    // Steps 4 and 5: identity first, then the capability ACL.
+    assert_eq!(code(&demo.alice, &unknown), 4002);
+    assert_eq!(code(&demo.alice, &admin), 3001);
+
+    // Step 6 before step 7.
+    assert_eq!(code(&demo.alice, &bad_version_and_params), 4003);
+    let mut unsatisfiable = CapInvokeBody::negotiated(CODE_REVIEW, CapNegotiateHints::default(), Value::Null);
+    unsatisfiable.negotiate.as_mut().expect("hints").range = Some(">=3.0.0".to_string());
+    assert_eq!(code(&demo.alice, &unsatisfiable), 4003);
+    unsatisfiable.negotiate.as_mut().expect("hints").range = Some("^2.0.0".to_string());
+    assert_eq!(code(&demo.alice, &unsatisfiable), 4001);
+    assert_eq!(code(&demo.alice, &CapInvokeBody::by_name(CODE_REVIEW, "2.0.0", Value::Null)), 4004);
+}
+
+#[test]
+fn rfc004_invoke_execution_failures_end_in_cap_result() {
+    let demo = demo_agents();
+    let dispatcher = dispatcher();
+    let failed = |body: &CapInvokeBody| match completed(dispatcher.handle(&received(&demo.alice, body))) {
+        CapResultBody::Error { error, .. } => error,
+        other => panic!("expected status=error, got {other:?}"),
+    };
+    let id = format!("{CODE_REVIEW}:2.1.0");
+
+    let broken = params(json!({"repo": "broken", "files": ["a"]}));
+    let error = failed(&CapInvokeBody::by_id(&id, broken));
+    assert_eq!((error.code, error.message.as_deref()), (5001, Some("review backend crashed")));
+
+    let mut slow = CapInvokeBody::by_id(&id, params(json!({"repo": "r", "files": ["a"], "sleep_ms": 2_000})));
+    slow.timeout_ms = Some(50);
+    let error = failed(&slow);
+    assert_eq!((error.code, error.name.as_deref()), (5003, Some("TIMEOUT")));
+
+    let mut quick = CapInvokeBody::by_id(&id, params(json!({"repo": "r", "files": ["a"], "sleep_ms": 10})));
+    quick.timeout_ms = Some(5_000);
+    assert!(matches!(completed(dispatcher.handle(&received(&demo.alice, &quick))), CapResultBody::Success { .. }));
+
+    let mut capped = dispatcher;
+    capped.max_timeout_ms(50);
+    slow.timeout_ms = None;
+    match completed(capped.handle(&received(&demo.alice, &slow))) {
+        CapResultBody::Error { error, .. } => assert_eq!(error.code, 5003),
+        other => panic!("provider limit must apply, got {other:?}"),
+    }
+}
+
+#[test]
+fn rfc004_cap_result_must_correlate_with_the_invoke() {
+    let demo = demo_agents();
+    let resolver = demo.resolver();
+    let dispatcher = dispatcher();
+    let now = now_ms();
+    let body = CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.1.0"), review_params());
+
+    let (invoke_id, wire) = build_cap_invoke(&demo.alice, &demo.bob.did, &body, 60_000, now).expect("invoke");
+    let request = receive_and_verify(&demo.bob, &wire, &resolver, now).expect("verify invoke");
+    let reply = dispatcher.respond(&demo.bob, &request, now).expect("respond");
+    let reply = receive_and_verify(&demo.alice, &reply, &resolver, now).expect("verify result");
+    assert_eq!(reply.meta.typ, TYPE_CAP_RESULT);
+    assert_eq!(reply.meta.reply_to, Some(invoke_id));
+    let result = accept_cap_result(&invoke_id, &demo.bob.did, &reply).expect("correlated");
+    assert!(matches!(result, CapResultBody::Success { .. }));
+
+    // A.12: a CAP_RESULT for another invocation is rejected, not applied.
+    let err = accept_cap_result(&[7; 16], &demo.bob.did, &reply).expect_err("wrong reply_to");
+    assert_eq!(err.code(), 4001);
+    let err = accept_cap_result(&invoke_id, &demo.relay.did, &reply).expect_err("wrong sender");
+    assert_eq!(err.code(), 4001);
+
+    // Pre-execution rejection travels as ERROR, still correlated by reply_to.
+    let unknown = CapInvokeBody::by_id("org.agentries.nonexistent:1.0.0", Value::Null);
+    let (unknown_id, wire) = build_cap_invoke(&demo.alice, &demo.bob.did, &unknown, 60_000, now).expect("invoke");
+    let request = receive_and_verify(&demo.bob, &wire, &resolver, now).expect("verify invoke");
+    let reply = dispatcher.respond(&demo.bob, &request, now).expect("respond");
+    let reply = receive_and_verify(&demo.alice, &reply, &resolver, now).expect("verify error");
+    assert_eq!(reply.meta.typ, TYPE_ERROR);
+    let err = accept_cap_result(&unknown_id, &demo.bob.did, &reply).expect_err("ERROR");
+    assert!(matches!(err, CapInvokeError::Rejected(ref e) if e.code == 4002), "{err}");
+
+    // Malformed bodies never leave the invoker.
+    let err = build_cap_invoke(&demo.alice, &demo.bob.did, &CapInvokeBody::by_name(CODE_REVIEW, "2", Value::Null), 60_000, now)
+        .expect_err("bad version");
+    assert_eq!(err.code, 4001);
+}
+
+fn serve(dispatcher: CapDispatcher, provider: AgentKeys, resolver: DidResolver) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("accept");
+        let mut reader = stream.try_clone().expect("clone");
+        let mut writer = stream;
+        dispatcher.serve(&provider, &resolver, &mut reader, &mut writer).expect("serve");
+    });
+    let stream = TcpStream::connect(addr).expect("connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).expect("timeout");
+    stream
+}
+
+#[test]
+fn rfc004_cap_client_invokes_over_tcp() {
+    let demo = demo_agents();
+    let stream = serve(dispatcher(), demo.bob.clone(), demo.resolver());
+    let mut client = CapClient::new(demo.alice.clone(), demo.resolver(), stream);
+    let bob = demo.bob.did.clone();
+
+    // Discovery and invocation share the connection.
+    let declared = client
+        .query_all(&bob, &CapQueryBody::new(CapFilter::capability(CODE_REVIEW)))
+        .expect("query");
+    let newest = &declared[0].id;
+    let review: Review = client
+        .invoke(&bob, &CapInvokeBody::by_id(newest, review_params()))
+        .expect("invoke");
+    assert_eq!(
+        review,
+        Review {
+            version: "2.1.0".to_string(),
+            comments: vec!["004.md: looks good".to_string()],
+        }
+    );
+
+    let err = client
+        .invoke::<Review>(&bob, &CapInvokeBody::by_id(newest, params(json!({"repo": "x"}))))
+        .expect_err("schema");
+    assert!(matches!(err, CapInvokeError::Rejected(ref e) if e.code == 4004), "{err}");
+
+    let broken = params(json!({"repo": "broken", "files": ["a"]}));
+    let err = client.invoke::<Review>(&bob, &CapInvokeBody::by_id(newest, broken)).expect_err("failed");
+    assert!(matches!(err, CapInvokeError::Failed(ref e) if e.code == 5001), "{err}");
+
+    let err = client
+        .invoke::<u64>(&bob, &CapInvokeBody::by_id(newest, review_params()))
+        .expect_err("result is not a u64");
+    assert_eq!(err.code(), 1001);
+}
+
+#[test]
+fn rfc004_cap_client_waits_past_interim_replies() {
+    let demo = demo_agents();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("addr");
+    let (bob, resolver) = (demo.bob.clone(), demo.resolver());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("accept");
+        let frame = read_frame(&mut stream).expect("read");
+        let now = now_ms();
+        let request = receive_and_verify(&bob, &frame, &resolver, now).expect("verify invoke");
+        // PROC_OK and a progress update are correlated but not terminal.
+        let proc_ok = ProcOkBody::with_details(&"accepted").expect("details");
+        write_frame(&mut stream, &build_proc_result(&bob, &request.meta, &Ok(proc_ok), now).expect("proc ok"))
+            .expect("write");
+        let mut progress = request.meta.clone();
+        progress.typ = 0x2f;
+        progress.to = Recipients::One(request.meta.from.clone());
+        progress.reply_to = Some(request.meta.id);
+        write_frame(&mut stream, &build_plain_signed(&bob, progress, &"50%").expect("progress")).expect("write");
+        let result = dispatcher().respond(&bob, &request, now).expect("respond");
+        write_frame(&mut stream, &result).expect("write");
+    });
+    let stream = TcpStream::connect(addr).expect("connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).expect("timeout");
+    let mut client = CapClient::new(demo.alice.clone(), demo.resolver(), stream);
+
+    let review: Review = client
+        .invoke(&demo.bob.did, &CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.1.0"), review_params()))
+        .expect("invoke");
+    assert_eq!(review.version, "2.1.0");
+}
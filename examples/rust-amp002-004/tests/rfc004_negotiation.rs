@@ -0,0 +1,114 @@
+mod common;
+
+use amp001_example::{AmpError, ErrorBody};
+use amp002_004_tests::{
+    negotiate, CapNegotiateHints, CapabilityDescriptor, NegotiationError, NegotiationRule,
+};
+use common::descriptor;
+
+const CODE_REVIEW: &str = "org.agentries.code-review";
+
+fn declared(versions: &[&str]) -> Vec<CapabilityDescriptor> {
+    versions
+        .iter()
+        .map(|version| descriptor(CODE_REVIEW, version, b"{}"))
+        .collect()
+}
+
+fn hints(preferred: Option<&str>, acceptable: &[&str], range: Option<&str>) -> CapNegotiateHints {
+    CapNegotiateHints {
+        preferred: preferred.map(str::to_string),
+        acceptable: (!acceptable.is_empty()).then(|| acceptable.iter().map(|v| v.to_string()).collect()),
+        range: range.map(str::to_string),
+    }
+}
+
+fn chosen(versions: &[&str], hints: &CapNegotiateHints) -> Result<(String, NegotiationRule), u16> {
+    negotiate(CODE_REVIEW, &declared(versions), hints)
+        .map(|n| (n.id, n.rule))
+        .map_err(|e| e.code())
+}
+
+#[test]
+fn rfc004_negotiation_appendix_a_vectors() {
+    // A.2 Negotiation Exact Version Positive
+    assert_eq!(
+        chosen(&["2.0.0", "2.1.0"], &hints(Some("2.1.0"), &[], None)),
+        Ok(("org.agentries.code-review:2.1.0".to_string(), NegotiationRule::Preferred))
+    );
+    // A.3 Negotiation Fallback Positive
+    assert_eq!(
+        chosen(&["2.0.0", "2.1.0"], &hints(Some("2.2.0"), &["2.1.0", "2.0.0"], None)),
+        Ok(("org.agentries.code-review:2.1.0".to_string(), NegotiationRule::Acceptable))
+    );
+    // A.4 Negotiation Mismatch Negative
+    assert_eq!(chosen(&["2.0.0", "2.1.0", "2.4.7"], &hints(None, &[], Some(">=3.0.0 <4.0.0"))), Err(4003));
+}
+
+#[test]
+fn rfc004_negotiation_follows_selection_order() {
+    let provider = ["1.0.0", "2.0.0", "2.1.0", "2.2.0-rc.1", "3.0.0"];
+
+    // The preferred version beats an acceptable list and a range.
+    assert_eq!(
+        chosen(&provider, &hints(Some("1.0.0"), &["2.1.0"], Some(">=2.0.0"))),
+        Ok(("org.agentries.code-review:1.0.0".to_string(), NegotiationRule::Preferred))
+    );
+    // The acceptable list keeps the requester's order, not the highest version.
+    assert_eq!(
+        chosen(&provider, &hints(None, &["9.9.9", "2.0.0", "3.0.0"], None)),
+        Ok(("org.agentries.code-review:2.0.0".to_string(), NegotiationRule::Acceptable))
+    );
+    // The range picks the highest declared version in it, skipping pre-releases it does
+    // not opt into.
+    assert_eq!(
+        chosen(&provider, &hints(Some("4.0.0"), &["5.0.0"], Some(">=2.0.0 <3.0.0"))),
+        Ok(("org.agentries.code-review:2.1.0".to_string(), NegotiationRule::Range))
+    );
+    assert_eq!(
+        chosen(&provider, &hints(None, &[], Some(">=2.2.0-rc.1 <3.0.0"))),
+        Ok(("org.agentries.code-review:2.2.0-rc.1".to_string(), NegotiationRule::Range))
+    );
+    // Exact pre-release hints match exactly.
+    assert_eq!(
+        chosen(&provider, &hints(Some("2.2.0-rc.1"), &[], None)).map(|(_, rule)| rule),
+        Ok(NegotiationRule::Preferred)
+    );
+    // No hints at all select nothing.
+    assert_eq!(chosen(&provider, &CapNegotiateHints::default()), Err(4003));
+}
+
+#[test]
+fn rfc004_negotiation_is_independent_of_declaration_order() {
+    let hints = hints(Some("2.2.0"), &["0.9.0"], Some(">=1.0.0 <3.0.0"));
+    let forward = ["1.0.0", "2.0.0", "2.1.0+build.2", "2.1.0+build.1", "3.0.0"];
+    let mut reverse = forward;
+    reverse.reverse();
+    let rotated = ["2.1.0+build.1", "3.0.0", "1.0.0", "2.1.0+build.2", "2.0.0"];
+
+    let expected = Ok(("org.agentries.code-review:2.1.0+build.1".to_string(), NegotiationRule::Range));
+    for order in [&forward, &reverse, &rotated] {
+        assert_eq!(chosen(order, &hints), expected, "{order:?}");
+    }
+}
+
+#[test]
+fn rfc004_negotiation_failures_are_typed() {
+    let err = negotiate("org.agentries.translate", &declared(&["1.0.0"]), &hints(Some("1.0.0"), &[], None))
+        .expect_err("other capability");
+    assert!(matches!(err, NegotiationError::NotFound { .. }));
+    assert_eq!(err.code(), 4002);
+
+    for bad in [hints(Some("2.1"), &[], None), hints(None, &["2.0.0", "v2"], None), hints(None, &[], Some("^2.0.0"))] {
+        let err = negotiate(CODE_REVIEW, &declared(&["2.0.0"]), &bad).expect_err("malformed hint");
+        assert!(matches!(err, NegotiationError::InvalidHints(_)), "{bad:?}");
+        assert_eq!(AmpError::from(err).code, 4001);
+    }
+
+    // A.23: 4003 is carried as CBOR uint 19 0f a3.
+    let mismatch = negotiate(CODE_REVIEW, &declared(&["2.0.0"]), &hints(None, &[], Some(">=3.0.0 <4.0.0")))
+        .expect_err("mismatch");
+    let body = ErrorBody::from(mismatch);
+    assert_eq!(serde_cbor::to_vec(&body.code).expect("encode"), [0x19, 0x0f, 0xa3]);
+    assert_eq!(body.category, "client");
+}
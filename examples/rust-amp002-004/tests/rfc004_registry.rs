@@ -0,0 +1,282 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use amp001_example::{now_ms, AgentKeys, AmpError, DidResolver};
+use amp002_004_tests::{
+    registry_path, serve_registry, CachePolicy, CapabilityDescriptor, Fetched, Freshness,
+    HashAlg, HttpFetcher, RegistryArtifact, RegistryClient, RegistryFetch, RegistryStore,
+    SchemaRef, TrustProfile, MAX_REGISTRY_BODY_BYTES,
+};
+use common::hosted_descriptor;
+
+const REVIEW: &str = "com.example.review";
+const INPUT: &[u8] = br#"{"type":"object","required":["repo"]}"#;
+const OUTPUT: &[u8] = br#"{"type":"object"}"#;
+
+fn descriptor(base: &str, version: &str) -> CapabilityDescriptor {
+    hosted_descriptor(base, REVIEW, version, INPUT, OUTPUT)
+}
+
+/// In-process registry on an ephemeral port; returns its base URI and store.
+fn start_registry(policy: CachePolicy) -> (String, Arc<Mutex<RegistryStore>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let base = format!("http://{}", listener.local_addr().expect("addr"));
+    let store = Arc::new(Mutex::new(RegistryStore::new()));
+    let serving = Arc::clone(&store);
+    thread::spawn(move || serve_registry(&listener, &serving, policy));
+    (base, store)
+}
+
+/// HTTP fetcher that counts requests, to tell cache hits from network fetches.
+#[derive(Clone, Default)]
+struct Counting {
+    http: HttpFetcher,
+    gets: Arc<AtomicUsize>,
+}
+
+impl RegistryFetch for Counting {
+    fn get(&self, uri: &str) -> Result<Fetched, AmpError> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        self.http.get(uri)
+    }
+}
+
+/// Registry stand-in without sockets, whose answers the test swaps at will.
+#[derive(Clone, Default)]
+struct Scripted(Arc<Mutex<HashMap<String, Result<Fetched, AmpError>>>>);
+
+impl Scripted {
+    fn set(&self, uri: &str, answer: Result<Fetched, AmpError>) {
+        self.0.lock().expect("script").insert(uri.to_string(), answer);
+    }
+}
+
+impl RegistryFetch for Scripted {
+    fn get(&self, uri: &str) -> Result<Fetched, AmpError> {
+        self.0
+            .lock()
+            .expect("script")
+            .get(uri)
+            .cloned()
+            .unwrap_or_else(|| Err(AmpError::unavailable(format!("{uri} not scripted"))))
+    }
+}
+
+fn served(descriptor: &CapabilityDescriptor, cache: CachePolicy) -> Result<Fetched, AmpError> {
+    Ok(Fetched {
+        body: descriptor.to_cbor().expect("cbor"),
+        cache,
+    })
+}
+
+#[test]
+fn rfc004_registry_cache_control_round_trip() {
+    let policy = CachePolicy::from_cache_control("public, max-age=60, stale-while-revalidate=\"30\"");
+    assert_eq!(policy, CachePolicy::new(60_000, 30_000));
+    assert_eq!(policy.cache_control().as_deref(), Some("max-age=60, stale-while-revalidate=30"));
+    assert_eq!(CachePolicy::from_cache_control("no-cache").max_age_ms, Some(0));
+    assert_eq!(CachePolicy::from_cache_control("immutable"), CachePolicy::default());
+    assert_eq!(CachePolicy::default().cache_control(), None);
+}
+
+#[test]
+fn rfc004_registry_client_verifies_and_caches_over_http() {
+    let (base, store) = start_registry(CachePolicy::new(60_000, 0));
+    let published = descriptor(&base, "1.0.0");
+    store.lock().expect("store").publish(&published, INPUT, OUTPUT).expect("publish");
+    assert_eq!(
+        store.lock().expect("store").publish(&published, b"{}", OUTPUT).expect_err("mismatched schema").code,
+        5002
+    );
+
+    let fetcher = Counting::default();
+    let gets = Arc::clone(&fetcher.gets);
+    let client = RegistryClient::new(&base, fetcher);
+    let now = now_ms();
+
+    let (descriptor, freshness) = client.descriptor(&published.id, now).expect("descriptor");
+    assert_eq!((descriptor, freshness), (published.clone(), Freshness::Fetched));
+    let (_, input) = client.input_schema(&published.id, now + 1_000).expect("input schema");
+    assert_eq!(input, INPUT);
+    assert_eq!(gets.load(Ordering::SeqCst), 2, "descriptor and schema fetched once each");
+
+    // Within max-age, and with the schema hash unchanged, nothing is fetched again.
+    assert_eq!(client.descriptor(&published.id, now + 59_000).expect("cached").1, Freshness::Fresh);
+    assert_eq!(client.schema(&published.input_schema).expect("cached"), INPUT);
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+    // Past max-age without a stale window the descriptor is fetched synchronously.
+    assert_eq!(client.descriptor(&published.id, now + 61_000).expect("expired").1, Freshness::Fetched);
+    assert_eq!(gets.load(Ordering::SeqCst), 3);
+
+    let missing = client.descriptor(&format!("{REVIEW}:9.9.9"), now).expect_err("404");
+    assert_eq!(missing.code, 5002);
+}
+
+#[test]
+fn rfc004_registry_stale_while_revalidate() {
+    let base = "http://registry.test";
+    let v1 = descriptor(base, "1.0.0");
+    let uri = format!("{base}{}", registry_path(&v1.id, RegistryArtifact::Descriptor).expect("path"));
+    let registry = Scripted::default();
+    let policy = CachePolicy::new(1_000, 5_000);
+    registry.set(&uri, served(&v1, policy));
+    let client = RegistryClient::new(base, registry.clone());
+    let t0 = 1_000_000;
+
+    assert_eq!(client.descriptor(&v1.id, t0).expect("fetch").1, Freshness::Fetched);
+    let mut v1_noted = v1.clone();
+    v1_noted.notes = Some("republished".to_string());
+    registry.set(&uri, served(&v1_noted, policy));
+
+    // Stale: the old copy is served at once and refreshed in the background.
+    let (stale, freshness) = client.descriptor(&v1.id, t0 + 2_000).expect("stale");
+    assert_eq!((stale.notes, freshness), (None, Freshness::Stale));
+    client.wait_for_refreshes();
+    let (fresh, freshness) = client.descriptor(&v1.id, t0 + 2_500).expect("refreshed");
+    assert_eq!((fresh.notes.as_deref(), freshness), (Some("republished"), Freshness::Fresh));
+
+    // A failed refresh keeps serving the old copy until the stale window closes.
+    registry.set(&uri, Err(AmpError::unavailable("registry down")));
+    assert_eq!(client.descriptor(&v1.id, t0 + 4_000).expect("stale").1, Freshness::Stale);
+    client.wait_for_refreshes();
+    assert_eq!(client.descriptor(&v1.id, t0 + 6_000).expect("still stale").1, Freshness::Stale);
+    client.wait_for_refreshes();
+    assert_eq!(client.descriptor(&v1.id, t0 + 8_001).expect_err("window closed").code, 5002);
+}
+
+#[test]
+fn rfc004_registry_rejects_unverifiable_artifacts() {
+    let base = "http://registry.test";
+    let registry = Scripted::default();
+    let client = RegistryClient::new(base, registry.clone());
+    let v1 = descriptor(base, "1.0.0");
+    let path = |id: &str, artifact| format!("{base}{}", registry_path(id, artifact).expect("path"));
+
+    // Schema bytes that do not match the descriptor hash are never returned or cached.
+    let input_uri = path(&v1.id, RegistryArtifact::InputSchema);
+    registry.set(&input_uri, Ok(Fetched { body: b"{}".to_vec(), cache: CachePolicy::default() }));
+    assert_eq!(client.schema(&v1.input_schema).expect_err("hash mismatch").code, 5002);
+    registry.set(&input_uri, Ok(Fetched { body: INPUT.to_vec(), cache: CachePolicy::default() }));
+    assert_eq!(client.schema(&v1.input_schema).expect("verified"), INPUT);
+
+    // The registry must answer with the descriptor that was asked for.
+    let v2 = descriptor(base, "2.0.0");
+    registry.set(&path(&v2.id, RegistryArtifact::Descriptor), served(&v1, CachePolicy::default()));
+    assert_eq!(client.descriptor(&v2.id, 0).expect_err("wrong descriptor").code, 5002);
+    registry.set(
+        &path(&v2.id, RegistryArtifact::Descriptor),
+        Ok(Fetched { body: b"not cbor".to_vec(), cache: CachePolicy::default() }),
+    );
+    assert_eq!(client.descriptor(&v2.id, 0).expect_err("garbage").code, 5002);
+
+    let offline = SchemaRef::offline("bundle-1", "input", HashAlg::Sha256, INPUT);
+    assert_eq!(client.schema(&offline).expect_err("no uri").code, 5002);
+    let https = RegistryClient::new("https://registry.example", HttpFetcher::new());
+    assert_eq!(https.descriptor(&v1.id, 0).expect_err("no TLS").code, 5002);
+
+    // A.22: the public trust profile needs a namespace-owner signature.
+    let owner = AgentKeys::from_sign_seed("did:web:example.com", [41_u8; 32]);
+    let mut resolver = DidResolver::default();
+    resolver.add_agent(&owner);
+    let public = RegistryClient::new(base, registry.clone()).trust_profile(TrustProfile::Public(resolver));
+    registry.set(&path(&v1.id, RegistryArtifact::Descriptor), served(&v1, CachePolicy::default()));
+    assert_eq!(public.descriptor(&v1.id, 0).expect_err("unsigned").code, 3001);
+    let mut signed = v1.clone();
+    signed.sign(&owner).expect("sign");
+    registry.set(&path(&v1.id, RegistryArtifact::Descriptor), served(&signed, CachePolicy::default()));
+    assert_eq!(public.descriptor(&v1.id, 0).expect("signed").0, signed);
+}
+
+#[test]
+fn rfc004_registry_http_bodies_are_capped() {
+    // A registry that keeps talking past the cap, with or without announcing it.
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let base = format!("http://{}", listener.local_addr().expect("addr"));
+    thread::spawn(move || {
+        for (stream, length) in listener.incoming().zip([None, Some(MAX_REGISTRY_BODY_BYTES + 1)]) {
+            let mut stream = stream.expect("accept");
+            let mut request = BufReader::new(stream.try_clone().expect("clone")).lines();
+            while request.next().is_some_and(|line| !line.expect("request").is_empty()) {}
+            let length = length.map(|len| format!("Content-Length: {len}\r\n")).unwrap_or_default();
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\n{length}\r\n");
+            let _ = stream.write_all(&vec![b'x'; MAX_REGISTRY_BODY_BYTES + 1]);
+        }
+    });
+
+    let fetcher = HttpFetcher::new();
+    for _ in 0..2 {
+        let err = fetcher.get(&format!("{base}/cap-registry/{REVIEW}/1.0.0/input.schema.json")).expect_err("too large");
+        assert_eq!(err.code, 5002);
+        assert!(err.detail.contains("too large"), "{err}");
+    }
+}
+
+struct Registry(Child);
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn rfc004_registry_binary_publishes_a_directory() {
+    let dir = std::env::temp_dir().join(format!("amp004-registry-{}-{}", std::process::id(), now_ms()));
+    let base = "http://placeholder";
+    for version in ["1.0.0", "1.1.0"] {
+        let published = descriptor(base, version);
+        let version_dir: PathBuf = dir.join(REVIEW).join(version);
+        fs::create_dir_all(&version_dir).expect("mkdir");
+        for (artifact, bytes) in [
+            (RegistryArtifact::Descriptor, published.to_cbor().expect("cbor")),
+            (RegistryArtifact::InputSchema, INPUT.to_vec()),
+            (RegistryArtifact::OutputSchema, OUTPUT.to_vec()),
+        ] {
+            fs::write(version_dir.join(artifact.file_name()), bytes).expect("write");
+        }
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amp004-registry"))
+        .args(["127.0.0.1:0", "--dir"])
+        .arg(&dir)
+        .args(["--max-age", "60", "--stale-while-revalidate", "30"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp004-registry");
+    let stdout = child.stdout.take().expect("stdout");
+    let registry = Registry(child);
+    let mut lines = BufReader::new(stdout).lines();
+    let banner = lines.next().expect("banner").expect("read");
+    let addr = banner.rsplit(' ').next().expect("addr").to_string();
+    let published: Vec<String> = lines.by_ref().take(2).map(|l| l.expect("read")).collect();
+    assert_eq!(published, [format!("publishing {REVIEW}:1.0.0"), format!("publishing {REVIEW}:1.1.0")]);
+
+    let client = RegistryClient::new(format!("http://{addr}"), HttpFetcher::new());
+    let (descriptor, _) = client.descriptor(&format!("{REVIEW}:1.1.0"), now_ms()).expect("descriptor");
+    assert_eq!(descriptor.version, "1.1.0");
+
+    // The binary sends the configured freshness.
+    let mut stream = std::net::TcpStream::connect(&addr).expect("connect");
+    let path = registry_path(&descriptor.id, RegistryArtifact::InputSchema).expect("path");
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n").expect("request");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("response");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("Cache-Control: max-age=60, stale-while-revalidate=30"), "{response}");
+    assert!(response.contains("Content-Type: application/schema+json"), "{response}");
+
+    drop(registry);
+    let _ = fs::remove_dir_all(&dir);
+}
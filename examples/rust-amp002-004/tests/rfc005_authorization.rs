@@ -0,0 +1,588 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use amp001_example::{
+    cose_sign1_sign, demo_agents, now_ms, to_cbor_canonical, AgentKeys, AmpError, ErrorBody,
+    TYPE_PING,
+};
+use amp002_004_tests::{
+    CapDispatcher, CapInvokeBody, CapNegotiateHints, CapOutcome, CapResultBody, CompatibilityMode,
+    CompatibilityPolicy, DelegQueryResult, DelegRevokeBody, DelegationCredential, DelegationDecision,
+    DelegationEvaluator, DelegationEvidence, DelegationRevocation, DelegationScope, DelegationStatus,
+    DelegationTarget, DelegationValidity, DelegationVerdict, RevocationSource, TYPE_CAP_INVOKE,
+};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use common::{descriptor, received};
+
+const NOW: u64 = 1_780_000_000_000;
+const HOUR: u64 = 3_600_000;
+const VERIFIER: &str = "did:web:service-x";
+const MALLORY: &str = "did:web:example.com:agent:mallory";
+const CODE_REVIEW: &str = "org.agentries.code-review";
+
+fn scope(capabilities: Option<&[&str]>, actions: Option<&[&str]>) -> DelegationScope {
+    let list = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+    DelegationScope {
+        capabilities: capabilities.map(list),
+        actions: actions.map(list),
+        ..Default::default()
+    }
+}
+
+fn link(from: &AgentKeys, to: &AgentKeys, id: &str, scope: DelegationScope) -> DelegationCredential {
+    let mut credential =
+        DelegationCredential::new(id, &from.did, &to.did, scope, DelegationValidity::new(NOW - HOUR, NOW + HOUR));
+    credential.allow_subdelegation = Some(true);
+    credential
+}
+
+fn evidence(chain: &[(&AgentKeys, &DelegationCredential)], target: DelegationTarget) -> Value {
+    let chain = chain.iter().map(|(keys, credential)| credential.sign(keys).expect("sign")).collect();
+    let mut evidence = DelegationEvidence::new(chain);
+    evidence.target = Some(target);
+    evidence.to_value().expect("value")
+}
+
+fn invoke_target(action: &str) -> DelegationTarget {
+    DelegationTarget {
+        capability: Some(format!("{CODE_REVIEW}:2.1.0")),
+        action: Some(action.into()),
+        resource: None,
+    }
+}
+
+fn evaluator() -> DelegationEvaluator {
+    DelegationEvaluator::new(VERIFIER, demo_agents().resolver())
+}
+
+fn evaluate(evaluator: &DelegationEvaluator, requester: &str, evidence: Value) -> DelegationDecision {
+    evaluator.evaluate(TYPE_CAP_INVOKE, requester, evidence, &DelegationTarget::default(), NOW)
+}
+
+/// Failing check of a denial as `(step, check)`.
+fn failed_at(decision: &DelegationDecision) -> (u8, &'static str) {
+    let step = decision.audit.iter().find(|step| !step.passed).expect("a failed check");
+    (step.step, step.check)
+}
+
+/// Revocation source whose answer the test controls; clones share state.
+#[derive(Clone)]
+struct FakeSource {
+    answer: Arc<Mutex<Result<DelegQueryResult, AmpError>>>,
+    queries: Arc<AtomicUsize>,
+}
+
+impl FakeSource {
+    fn new(answer: Result<DelegQueryResult, AmpError>) -> Self {
+        Self {
+            answer: Arc::new(Mutex::new(answer)),
+            queries: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl RevocationSource for FakeSource {
+    fn status(&self, _delegator: &str, _delegation_id: &str) -> Result<DelegQueryResult, AmpError> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+        self.answer.lock().expect("lock").clone()
+    }
+}
+
+fn status(credential: &DelegationCredential, status: DelegationStatus, max_age_s: Option<u64>) -> DelegQueryResult {
+    DelegQueryResult {
+        delegator: credential.delegator.clone(),
+        delegation_id: credential.delegation_id.clone(),
+        status,
+        expires_at: Some(credential.validity.expires_at),
+        revoked_at: None,
+        updated_at: NOW,
+        max_age_s,
+    }
+}
+
+#[test]
+fn rfc005_authorization_appendix_a_vectors() {
+    let demo = demo_agents();
+    let (alice, bob, relay) = (&demo.alice, &demo.bob, &demo.relay);
+    let evaluator = evaluator();
+    let review = scope(Some(&[CODE_REVIEW]), Some(&["invoke", "read"]));
+    let ab = link(alice, bob, "delegation:ab", review.clone());
+
+    // A.1: a single link authorizes its delegate.
+    let single = evaluate(&evaluator, &bob.did, evidence(&[(alice, &ab)], invoke_target("invoke")));
+    assert!(single.is_allowed(), "{single}");
+    assert_eq!(single.reason_code, 0);
+
+    // A.3: B->C narrows to the same capability and a subset of actions.
+    let bc = link(bob, relay, "delegation:bc", scope(Some(&[&format!("{CODE_REVIEW}:2.1.0")]), Some(&["invoke"])));
+    let narrowed = evaluate(&evaluator, &relay.did, evidence(&[(alice, &ab), (bob, &bc)], invoke_target("invoke")));
+    assert!(narrowed.is_allowed(), "{narrowed}");
+    let outside = evaluate(&evaluator, &relay.did, evidence(&[(alice, &ab), (bob, &bc)], invoke_target("read")));
+    assert_eq!(outside.reason_code, 3004);
+    assert_eq!(failed_at(&outside), (9, "target in scope"));
+
+    // A.2: expired.
+    let mut expired = ab.clone();
+    expired.validity = DelegationValidity::new(NOW - 2 * HOUR, NOW - 1);
+    let decision = evaluate(&evaluator, &bob.did, evidence(&[(alice, &expired)], invoke_target("invoke")));
+    assert_eq!(decision.reason_code, 3004);
+    assert_eq!(failed_at(&decision), (5, "validity window"));
+
+    // A.4: A->B allows read, B->C asks for write.
+    let read_only = link(alice, bob, "delegation:ab-read", scope(None, Some(&["read"])));
+    let write = link(bob, relay, "delegation:bc-write", scope(None, Some(&["write"])));
+    let decision = evaluate(&evaluator, &relay.did, evidence(&[(alice, &read_only), (bob, &write)], invoke_target("write")));
+    assert_eq!(decision.reason_code, 3004);
+    assert_eq!(failed_at(&decision), (8, "scope narrowing"));
+    // A bare name under a capability-id parent is an expansion too.
+    let pinned = link(alice, bob, "delegation:ab-pinned", scope(Some(&[&format!("{CODE_REVIEW}:2.1.0")]), None));
+    let widened = link(bob, relay, "delegation:bc-widened", scope(Some(&[CODE_REVIEW]), None));
+    let decision = evaluate(&evaluator, &relay.did, evidence(&[(alice, &pinned), (bob, &widened)], invoke_target("invoke")));
+    assert_eq!(failed_at(&decision), (8, "scope narrowing"));
+
+    // A.5: subdelegation forbidden.
+    let mut leaf = ab.clone();
+    leaf.allow_subdelegation = None;
+    let decision = evaluate(&evaluator, &relay.did, evidence(&[(alice, &leaf), (bob, &bc)], invoke_target("invoke")));
+    assert_eq!(decision.reason_code, 3004);
+    assert_eq!(failed_at(&decision), (7, "subdelegation and depth"));
+
+    // A.6: the last delegate is C, the requester is D.
+    let decision = evaluate(&evaluator, MALLORY, evidence(&[(alice, &ab), (bob, &bc)], invoke_target("invoke")));
+    assert_eq!(decision.reason_code, 3001);
+    assert_eq!(failed_at(&decision), (9, "caller binding"));
+
+    // A.11: bound to another verifier.
+    let mut elsewhere = ab.clone();
+    elsewhere.aud = Some(vec!["did:web:service-y".into()]);
+    let decision = evaluate(&evaluator, &bob.did, evidence(&[(alice, &elsewhere)], invoke_target("invoke")));
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (5, "audience")));
+    elsewhere.aud = Some(vec![VERIFIER.into()]);
+    assert!(evaluate(&evaluator, &bob.did, evidence(&[(alice, &elsewhere)], invoke_target("invoke"))).is_allowed());
+
+    // A.15: L1 allows one downstream link, the chain has two.
+    let mut shallow = ab.clone();
+    shallow.max_chain_depth = Some(1);
+    let ca = link(relay, alice, "delegation:ca", scope(None, Some(&["invoke"])));
+    let chain = [(alice, &shallow), (bob, &bc), (relay, &ca)];
+    let decision = evaluate(&evaluator, &alice.did, evidence(&chain, invoke_target("invoke")));
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (7, "subdelegation and depth")));
+    shallow.max_chain_depth = Some(2);
+    let chain = [(alice, &shallow), (bob, &bc), (relay, &ca)];
+    assert!(evaluate(&evaluator, &alice.did, evidence(&chain, invoke_target("invoke"))).is_allowed());
+
+    // A.18: evidence on a message type that cannot carry it.
+    let ping = evaluator.evaluate(TYPE_PING, &bob.did, evidence(&[(alice, &ab)], invoke_target("invoke")), &DelegationTarget::default(), NOW);
+    assert_eq!((ping.reason_code, failed_at(&ping)), (4001, (1, "message type")));
+
+    // A.12 and malformed evidence are caught while parsing, before any signature check.
+    let mut payload = serde_cbor::value::to_value(&ab).expect("value");
+    if let Value::Map(fields) = &mut payload {
+        fields.insert(Value::Text("cred_v".into()), Value::Integer(2));
+    }
+    let payload = to_cbor_canonical(&payload).expect("cbor");
+    let mut future = DelegationEvidence::from_value(evidence(&[(alice, &ab)], invoke_target("invoke"))).expect("evidence");
+    future.chain[0].credential = ByteBuf::from(cose_sign1_sign(alice, &alice.assertion_kid(), &payload).expect("cose"));
+    let future = future.to_value().expect("value");
+    let decision = evaluate(&evaluator, &bob.did, future);
+    assert_eq!((decision.reason_code, failed_at(&decision)), (1004, (2, "evidence structure")));
+    let empty = DelegationEvidence::new(Vec::new()).to_value().expect("value");
+    assert_eq!(evaluate(&evaluator, &bob.did, empty).reason_code, 1001);
+    assert_eq!(evaluate(&evaluator, &bob.did, Value::Text("valid".into())).reason_code, 1001);
+}
+
+#[test]
+fn rfc005_authorization_follows_validation_order() {
+    let demo = demo_agents();
+    let (alice, bob, relay) = (&demo.alice, &demo.bob, &demo.relay);
+    let evaluator = evaluator();
+    let ab = link(alice, bob, "delegation:ab", scope(Some(&[CODE_REVIEW]), None));
+    let bc = link(bob, relay, "delegation:bc", scope(None, Some(&["invoke"])));
+
+    // Step 3 before step 4: a broken chain is reported even when a link is forged.
+    let mut forged = DelegationEvidence::from_value(evidence(&[(alice, &ab), (bob, &bc)], invoke_target("invoke")))
+        .expect("evidence");
+    let last = forged.chain[1].credential.len() - 1;
+    forged.chain[1].credential[last] ^= 0x01;
+    let decision = evaluate(&evaluator, &relay.did, forged.to_value().expect("value"));
+    assert_eq!(failed_at(&decision), (4, "signature"));
+    let mut broken = forged.clone();
+    broken.chain.swap(0, 1);
+    let decision = evaluate(&evaluator, &relay.did, broken.to_value().expect("value"));
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (3, "chain continuity")));
+
+    // Step 5 before step 6: an expired and revoked link is reported as expired.
+    let mut stale = bc.clone();
+    stale.validity = DelegationValidity::new(NOW - 2 * HOUR, NOW - 1);
+    let revoke = DelegationRevocation::new("delegation:bc", &bob.did, NOW - HOUR).sign(bob).expect("sign");
+    evaluator.record_revocation(&DelegRevokeBody::new("delegation:bc", revoke)).expect("revoke");
+    let decision = evaluate(&evaluator, &relay.did, evidence(&[(alice, &ab), (bob, &stale)], invoke_target("invoke")));
+    assert_eq!(failed_at(&decision), (5, "validity window"));
+    let decision = evaluate(&evaluator, &relay.did, evidence(&[(alice, &ab), (bob, &bc)], invoke_target("invoke")));
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (6, "revocation")));
+
+    // The record names every link, the root delegator and the target, and the audit
+    // trail runs the steps in order, ending in the decision.
+    assert_eq!(decision.decision, DelegationVerdict::Deny);
+    assert_eq!(decision.requester_did, relay.did);
+    assert_eq!(decision.effective_delegator_did.as_deref(), Some(alice.did.as_str()));
+    let ids: Vec<(&str, &str)> = decision
+        .delegation_ids
+        .iter()
+        .map(|link| (link.delegator.as_str(), link.delegation_id.as_str()))
+        .collect();
+    assert_eq!(ids, [(alice.did.as_str(), "delegation:ab"), (bob.did.as_str(), "delegation:bc")]);
+    assert_eq!(decision.target, invoke_target("invoke"));
+    assert_eq!(decision.evaluated_at, NOW);
+    let steps: Vec<u8> = decision.audit.iter().map(|step| step.step).collect();
+    assert!(steps.windows(2).all(|pair| pair[0] <= pair[1]), "{steps:?}");
+    assert_eq!(steps.last(), Some(&10));
+
+    let allowed = {
+        let fresh = DelegationEvaluator::new(VERIFIER, demo.resolver());
+        evaluate(&fresh, &relay.did, evidence(&[(alice, &ab), (bob, &bc)], invoke_target("invoke")))
+    };
+    let checks: Vec<(u8, &str)> = allowed.audit.iter().map(|step| (step.step, step.check)).collect();
+    assert_eq!(
+        checks,
+        [
+            (1, "message type"),
+            (2, "evidence structure"),
+            (3, "chain continuity"),
+            (4, "signature"),
+            (4, "signature"),
+            (5, "validity window"),
+            (5, "audience"),
+            (5, "validity window"),
+            (5, "audience"),
+            (6, "revocation"),
+            (6, "revocation"),
+            (7, "subdelegation and depth"),
+            (7, "subdelegation and depth"),
+            (8, "scope narrowing"),
+            (9, "caller binding"),
+            (9, "evidence target"),
+            (9, "target in scope"),
+            (10, "decision"),
+        ]
+    );
+    assert!(allowed.audit.iter().all(|step| step.passed));
+    let record = serde_json::to_value(&allowed).expect("json");
+    assert_eq!(record["decision"], "allow");
+    assert_eq!(record["delegation_ids"][1]["delegation_id"], "delegation:bc");
+}
+
+#[test]
+fn rfc005_authorization_revocation_sources() {
+    let demo = demo_agents();
+    let (alice, bob) = (&demo.alice, &demo.bob);
+    let ab = link(alice, bob, "delegation:ab", scope(Some(&[CODE_REVIEW]), None));
+    let chain = || evidence(&[(alice, &ab)], invoke_target("invoke"));
+
+    // A.7 and A.13: DELEG_REVOKE from the delegator; the top-level id must match.
+    let evaluator = evaluator();
+    let signed = DelegationRevocation::new("delegation:ab", &alice.did, NOW).sign(alice).expect("sign");
+    let mismatch = DelegRevokeBody::new("delegation:def", signed.clone());
+    assert_eq!(evaluator.record_revocation(&mismatch).expect_err("mismatch").code, 4001);
+    assert!(evaluate(&evaluator, &bob.did, chain()).is_allowed());
+    let forged = DelegationRevocation::new("delegation:ab", &alice.did, NOW);
+    assert_eq!(forged.sign(bob).expect_err("not the delegator").code, 3001);
+    let mut future = forged.clone();
+    future.revoked_at = NOW + HOUR;
+    let later = future.sign(alice).expect("sign");
+    evaluator.record_revocation(&DelegRevokeBody::new("delegation:ab", later)).expect("revoke later");
+    assert!(evaluate(&evaluator, &bob.did, chain()).is_allowed(), "revoked_at is in the future");
+    let recorded = evaluator.record_revocation(&DelegRevokeBody::new("delegation:ab", signed)).expect("revoke");
+    assert_eq!(recorded.revoked_at, NOW);
+    assert_eq!(evaluate(&evaluator, &bob.did, chain()).reason_code, 3004);
+
+    // A.17: the source answer is cached for its max_age_s.
+    let source = FakeSource::new(Ok(status(&ab, DelegationStatus::Active, Some(60))));
+    let evaluator = evaluator_with(&source);
+    assert!(evaluate(&evaluator, &bob.did, chain()).is_allowed());
+    assert!(evaluate(&evaluator, &bob.did, chain()).is_allowed());
+    assert_eq!(source.queries.load(Ordering::SeqCst), 1);
+
+    // A.10: once the cache has expired, an unreachable source is 5002 in strict mode...
+    *source.answer.lock().expect("lock") = Err(AmpError::unavailable("connection refused"));
+    let later = |evaluator: &DelegationEvaluator| {
+        evaluator.evaluate(TYPE_CAP_INVOKE, &bob.did, chain(), &DelegationTarget::default(), NOW + 61_000)
+    };
+    let decision = later(&evaluator);
+    assert_eq!((decision.reason_code, failed_at(&decision)), (5002, (6, "revocation")));
+    assert_eq!(ErrorBody::from(decision).retry, Some(true));
+
+    // ...while bounded offline mode keeps using the stale answer for its grace.
+    let offline = evaluator_with(&source).offline_grace_ms(HOUR);
+    *source.answer.lock().expect("lock") = Ok(status(&ab, DelegationStatus::Active, Some(60)));
+    assert!(evaluate(&offline, &bob.did, chain()).is_allowed());
+    *source.answer.lock().expect("lock") = Err(AmpError::unavailable("connection refused"));
+    assert!(later(&offline).is_allowed());
+
+    // A revoked or expired status denies; so does an answer about another delegation.
+    let mut revoked = status(&ab, DelegationStatus::Revoked, None);
+    revoked.revoked_at = Some(NOW - 1);
+    for (answer, code) in [
+        (revoked, 3004),
+        (status(&ab, DelegationStatus::Expired, None), 3004),
+        (status(&link(bob, alice, "delegation:other", scope(None, Some(&["x"]))), DelegationStatus::Active, None), 5002),
+    ] {
+        let evaluator = evaluator_with(&FakeSource::new(Ok(answer)));
+        assert_eq!(evaluate(&evaluator, &bob.did, chain()).reason_code, code);
+    }
+}
+
+/// Revocation source that answers only once released, like a slow DID endpoint.
+struct SlowSource {
+    answer: DelegQueryResult,
+    entered: Mutex<mpsc::Sender<()>>,
+    release: Mutex<mpsc::Receiver<()>>,
+}
+
+impl RevocationSource for SlowSource {
+    fn status(&self, _delegator: &str, _delegation_id: &str) -> Result<DelegQueryResult, AmpError> {
+        self.entered.lock().expect("lock").send(()).expect("entered");
+        self.release.lock().expect("lock").recv().expect("release");
+        Ok(self.answer.clone())
+    }
+}
+
+#[test]
+fn rfc005_authorization_revocation_query_does_not_hold_the_cache() {
+    let demo = demo_agents();
+    let (alice, bob) = (&demo.alice, &demo.bob);
+    let ab = link(alice, bob, "delegation:ab", scope(Some(&[CODE_REVIEW]), None));
+    let (entered_tx, entered) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel();
+    let evaluator = evaluator().revocation_source(SlowSource {
+        answer: status(&ab, DelegationStatus::Active, Some(60)),
+        entered: Mutex::new(entered_tx),
+        release: Mutex::new(release_rx),
+    });
+
+    thread::scope(|scope| {
+        let pending = scope.spawn(|| evaluate(&evaluator, &bob.did, evidence(&[(alice, &ab)], invoke_target("invoke"))));
+        entered.recv_timeout(Duration::from_secs(5)).expect("query started");
+
+        // While the source is still answering, a revocation is recorded without waiting.
+        let (recorded_tx, recorded) = mpsc::channel();
+        let evaluator = &evaluator;
+        scope.spawn(move || {
+            let signed = DelegationRevocation::new("delegation:ab", &alice.did, NOW).sign(alice).expect("sign");
+            let revocation = evaluator.record_revocation(&DelegRevokeBody::new("delegation:ab", signed));
+            recorded_tx.send(revocation.is_ok()).expect("recorded");
+        });
+        assert!(recorded.recv_timeout(Duration::from_secs(2)).expect("not blocked by the query"));
+
+        release.send(()).expect("release");
+        assert!(pending.join().expect("evaluation").is_allowed(), "started before the revocation");
+    });
+    let decision = evaluate(&evaluator, &bob.did, evidence(&[(alice, &ab)], invoke_target("invoke")));
+    assert_eq!(decision.reason_code, 3004);
+}
+
+fn evaluator_with(source: &FakeSource) -> DelegationEvaluator {
+    evaluator().revocation_source(source.clone())
+}
+
+#[test]
+fn rfc005_authorization_constraints_and_targets() {
+    let demo = demo_agents();
+    let (alice, bob) = (&demo.alice, &demo.bob);
+    let mut limited = link(alice, bob, "delegation:ab", scope(Some(&[CODE_REVIEW]), None));
+    limited.scope.resources = Some(vec!["repo:agentries/rfcs".into(), "repo:agentries/site".into()]);
+    limited.scope.constraints = Some(BTreeMap::from([(
+        "resource_prefix".to_string(),
+        Value::Text("repo:agentries/rfcs".into()),
+    )]));
+    let target = |resource: &str| DelegationTarget {
+        resource: Some(resource.into()),
+        ..invoke_target("invoke")
+    };
+    let chain = |target: DelegationTarget| evidence(&[(alice, &limited)], target);
+
+    // Unknown constraint keys fail closed.
+    let decision = evaluate(&evaluator(), &bob.did, chain(target("repo:agentries/rfcs")));
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (9, "constraint")));
+
+    let prefix = evaluator().constraint("resource_prefix", |value, target| match (value, &target.resource) {
+        (Value::Text(prefix), Some(resource)) => resource.starts_with(prefix.as_str()),
+        _ => false,
+    });
+    assert!(evaluate(&prefix, &bob.did, chain(target("repo:agentries/rfcs"))).is_allowed());
+    let decision = evaluate(&prefix, &bob.did, chain(target("repo:agentries/site")));
+    assert_eq!(failed_at(&decision), (9, "constraint"));
+    let decision = evaluate(&prefix, &bob.did, chain(target("repo:other/rfcs")));
+    assert_eq!(failed_at(&decision), (9, "target in scope"));
+    let decision = evaluate(&prefix, &bob.did, chain(invoke_target("invoke")));
+    assert_eq!(failed_at(&decision), (9, "target in scope"), "scope restricts resources");
+
+    // The verifier's own view of the target wins; evidence may not contradict it.
+    let known = DelegationTarget {
+        capability: Some(format!("{CODE_REVIEW}:2.0.0")),
+        ..Default::default()
+    };
+    let decision = prefix.evaluate(TYPE_CAP_INVOKE, &bob.did, chain(target("repo:agentries/rfcs")), &known, NOW);
+    assert_eq!((decision.reason_code, failed_at(&decision)), (3004, (9, "evidence target")));
+    let by_name = DelegationTarget {
+        capability: Some(format!("{CODE_REVIEW}:2.1.0")),
+        ..Default::default()
+    };
+    let mut claimed = target("repo:agentries/rfcs");
+    claimed.capability = Some(CODE_REVIEW.into());
+    assert!(prefix.evaluate(TYPE_CAP_INVOKE, &bob.did, chain(claimed), &by_name, NOW).is_allowed());
+}
+
+#[test]
+fn rfc005_authorization_on_the_cap_invoke_path() {
+    let demo = demo_agents();
+    let (alice, bob, relay) = (&demo.alice, &demo.bob, &demo.relay);
+    let now = now_ms();
+    let input = br#"{"type":"object"}"#;
+    let descriptor = descriptor(CODE_REVIEW, "2.1.0", input);
+
+    // Bob provides code review; alice lets relay invoke it on her behalf.
+    let decisions = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&decisions);
+    let mut dispatcher = CapDispatcher::new();
+    dispatcher
+        .register(descriptor, input, |call, _: Value| {
+            Ok(call.authorization.as_ref().and_then(|decision| decision.effective_delegator_did.clone()))
+        })
+        .expect("register");
+    dispatcher.evaluate_delegation(
+        DelegationEvaluator::new(&bob.did, demo.resolver())
+            .on_decision(move |decision| sink.lock().expect("lock").push(decision.clone())),
+    );
+
+    let mut grant = DelegationCredential::new(
+        "delegation:alice-relay",
+        &alice.did,
+        &relay.did,
+        scope(Some(&[CODE_REVIEW]), Some(&["invoke"])),
+        DelegationValidity::new(now - HOUR, now + HOUR),
+    );
+    grant.aud = Some(vec![bob.did.clone()]);
+    let chain = vec![grant.sign(alice).expect("sign")];
+    let handle = |from: &AgentKeys, body: &CapInvokeBody| dispatcher.handle(&received(from, body));
+    let delegated = |id: &str, action: &str| {
+        let mut evidence = DelegationEvidence::new(chain.clone());
+        evidence.target = Some(DelegationTarget {
+            action: Some(action.into()),
+            ..Default::default()
+        });
+        let mut body = CapInvokeBody::by_id(id, Value::Map(Default::default()));
+        body.delegation = Some(evidence.to_value().expect("value"));
+        body
+    };
+
+    let invoke = delegated(&format!("{CODE_REVIEW}:2.1.0"), "invoke");
+    match handle(relay, &invoke) {
+        CapOutcome::Completed(CapResultBody::Success { result, .. }) => {
+            assert_eq!(result, Value::Text(alice.did.clone()));
+        }
+        other => panic!("expected success, got {other:?}"),
+    }
+
+    // Denials answer with the evaluator's code and a message that reveals nothing.
+    let rejected = |outcome: CapOutcome| match outcome {
+        CapOutcome::Rejected(error) => error,
+        other => panic!("expected rejection, got {other:?}"),
+    };
+    let error = rejected(handle(alice, &invoke));
+    assert_eq!((error.code, error.message.as_str()), (3001, "invocation not authorized"));
+    let error = rejected(handle(relay, &delegated(&format!("{CODE_REVIEW}:2.1.0"), "admin")));
+    assert_eq!((error.code, error.message.as_str()), (3004, "delegation evidence invalid"));
+    // Step 3 runs before the capability is looked up.
+    assert_eq!(rejected(handle(relay, &delegated("org.agentries.nonexistent:1.0.0", "invoke"))).code, 3004);
+    // Non-delegated calls never reach the evaluator.
+    let plain = CapInvokeBody::by_id(format!("{CODE_REVIEW}:2.1.0"), Value::Map(Default::default()));
+    match handle(relay, &plain) {
+        CapOutcome::Completed(CapResultBody::Success { result, .. }) => assert_eq!(result, Value::Null),
+        other => panic!("expected success, got {other:?}"),
+    }
+
+    // The evaluator reads evidence from any verified message on its own too.
+    let standalone = DelegationEvaluator::new(&bob.did, demo.resolver());
+    assert!(standalone.authorize(&received(relay, &plain), now).is_none());
+    let decision = standalone.authorize(&received(relay, &invoke), now).expect("delegated");
+    assert!(decision.is_allowed(), "{decision}");
+
+    let decisions = decisions.lock().expect("lock");
+    let verdicts: Vec<(DelegationVerdict, u16)> =
+        decisions.iter().map(|decision| (decision.decision, decision.reason_code)).collect();
+    assert_eq!(
+        verdicts,
+        [
+            (DelegationVerdict::Allow, 0),
+            (DelegationVerdict::Deny, 3001),
+            (DelegationVerdict::Deny, 3004),
+            (DelegationVerdict::Deny, 3004),
+        ]
+    );
+    assert_eq!(decisions[0].target.capability.as_deref(), Some(format!("{CODE_REVIEW}:2.1.0").as_str()));
+    assert_eq!(decisions[0].target.action.as_deref(), Some("invoke"));
+}
+
+#[test]
+fn rfc005_authorization_covers_the_version_that_runs() {
+    let demo = demo_agents();
+    let (alice, bob, relay) = (&demo.alice, &demo.bob, &demo.relay);
+    let now = now_ms();
+    let input = br#"{"type":"object"}"#;
+    let descriptor = |version: &str| {
+        let mut descriptor = descriptor(CODE_REVIEW, version, input);
+        descriptor.supported_ranges = Some(vec![">=2.1.0 <2.4.0".into()]);
+        descriptor
+    };
+    let mut dispatcher = CapDispatcher::new();
+    dispatcher
+        .register(descriptor("2.3.0"), input, |call, _: Value| Ok(call.descriptor.version.clone()))
+        .expect("register");
+    dispatcher.compatibility(CompatibilityPolicy::new(CompatibilityMode::Range));
+    dispatcher.evaluate_delegation(DelegationEvaluator::new(&bob.did, demo.resolver()));
+
+    let handle = |scope: &str, mut body: CapInvokeBody| {
+        let grant = DelegationCredential::new(
+            "delegation:alice-relay",
+            &alice.did,
+            &relay.did,
+            DelegationScope {
+                capabilities: Some(vec![scope.to_string()]),
+                ..Default::default()
+            },
+            DelegationValidity::new(now - HOUR, now + HOUR),
+        );
+        let evidence = DelegationEvidence::new(vec![grant.sign(alice).expect("sign")]);
+        body.delegation = Some(evidence.to_value().expect("value"));
+        match dispatcher.handle(&received(relay, &body)) {
+            CapOutcome::Completed(result) => Ok(result.into_result::<String>().expect("success")),
+            CapOutcome::Rejected(error) => Err(error.code),
+        }
+    };
+    let pinned = |version: &str| format!("{CODE_REVIEW}:{version}");
+    let by_version = || CapInvokeBody::by_name(CODE_REVIEW, "2.2.0", Value::Map(Default::default()));
+    let negotiated = || {
+        let hints = CapNegotiateHints {
+            range: Some(">=2.0.0 <3.0.0".into()),
+            ..Default::default()
+        };
+        CapInvokeBody::negotiated(CODE_REVIEW, hints, Value::Map(Default::default()))
+    };
+
+    // A grant for exactly 2.2.0 does not stretch to the 2.3.0 the policy upgrades to...
+    assert_eq!(handle(&pinned("2.2.0"), by_version()), Err(3004));
+    // ...while a grant for the version that runs, or for the whole name, does.
+    assert_eq!(handle(&pinned("2.3.0"), by_version()).as_deref(), Ok("2.3.0"));
+    assert_eq!(handle(CODE_REVIEW, by_version()).as_deref(), Ok("2.3.0"));
+    // Negotiated requests are checked against the version negotiation picked.
+    assert_eq!(handle(&pinned("2.3.0"), negotiated()).as_deref(), Ok("2.3.0"));
+    assert_eq!(handle(&pinned("2.1.0"), negotiated()), Err(3004));
+    assert_eq!(handle("org.agentries.other", negotiated()), Err(3004));
+}
@@ -0,0 +1,199 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use amp001_example::{cose_sign1_sign, demo_agents, to_cbor_canonical, DidResolver};
+use amp002_004_tests::{
+    DelegationCredential, DelegationEnvelope, DelegationScope, DelegationValidity,
+    DELEGATION_FORMAT_COSE_SIGN1,
+};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use common::code;
+
+const NOW: u64 = 1_780_000_000_000;
+const HOUR: u64 = 3_600_000;
+
+fn review_scope() -> DelegationScope {
+    DelegationScope {
+        capabilities: Some(vec!["org.agentries.code-review".into()]),
+        actions: Some(vec!["invoke".into()]),
+        ..Default::default()
+    }
+}
+
+/// Alice lets bob invoke code review for an hour.
+fn credential() -> DelegationCredential {
+    let demo = demo_agents();
+    DelegationCredential::new(
+        "delegation:alice-bob-1",
+        &demo.alice.did,
+        &demo.bob.did,
+        review_scope(),
+        DelegationValidity::new(NOW, NOW + HOUR),
+    )
+}
+
+/// Envelope over `payload` whose COSE header claims `alg`, signed by nobody.
+fn unsigned_envelope(alg: i64, kid: &str, payload: &[u8]) -> DelegationEnvelope {
+    let mut protected = BTreeMap::new();
+    protected.insert(1_i64, Value::Integer(alg.into()));
+    protected.insert(4_i64, Value::Bytes(kid.as_bytes().to_vec()));
+    let wire = (
+        ByteBuf::from(serde_cbor::to_vec(&protected).expect("header")),
+        BTreeMap::<i64, Value>::new(),
+        ByteBuf::from(payload.to_vec()),
+        ByteBuf::from(vec![0_u8; 64]),
+    );
+    DelegationEnvelope {
+        format: DELEGATION_FORMAT_COSE_SIGN1.into(),
+        credential: ByteBuf::from(serde_cbor::to_vec(&wire).expect("cose")),
+    }
+}
+
+#[test]
+fn rfc005_credential_round_trips_as_signed_envelope() {
+    let demo = demo_agents();
+    let mut issued = credential();
+    issued.allow_subdelegation = Some(true);
+    issued.max_chain_depth = Some(2);
+    issued.aud = Some(vec![demo.relay.did.clone()]);
+    issued.nonce = Some(ByteBuf::from(vec![7_u8; 16]));
+    issued.scope.constraints = Some(BTreeMap::from([("max_calls".to_string(), Value::Integer(10))]));
+
+    let envelope = issued.sign(&demo.alice).expect("sign");
+    assert_eq!(envelope.format, "cose_sign1");
+    let carried = DelegationEnvelope::from_value(envelope.to_value().expect("value")).expect("decode");
+    assert_eq!(carried, envelope);
+    assert_eq!(carried.verify(&demo.resolver()).expect("verify"), issued);
+
+    // The signature covers the deterministic CBOR payload.
+    let resigned = issued.sign(&demo.alice).expect("sign");
+    assert_eq!(resigned, envelope);
+    let expected = cose_sign1_sign(&demo.alice, &demo.alice.assertion_kid(), &to_cbor_canonical(&issued).expect("cbor"))
+        .expect("cose");
+    assert_eq!(envelope.credential.as_ref(), expected.as_slice());
+
+    // Absent optional fields stay absent and default as §4.4 says.
+    let plain = credential();
+    assert!(!plain.allows_subdelegation());
+    let wire: BTreeMap<String, Value> = serde_cbor::value::from_value(serde_cbor::value::to_value(&plain).expect("value"))
+        .expect("map");
+    let keys: Vec<&str> = wire.keys().map(String::as_str).collect();
+    assert_eq!(keys, ["cred_v", "delegate", "delegation_id", "delegator", "scope", "validity"]);
+}
+
+#[test]
+fn rfc005_credential_validity_and_audience() {
+    let demo = demo_agents();
+    let mut validity = DelegationValidity::new(NOW, NOW + HOUR);
+    assert!(!validity.contains(NOW - 1));
+    assert!(validity.contains(NOW));
+    assert!(!validity.contains(NOW + HOUR), "expires_at is exclusive");
+    validity.not_before = Some(NOW + 10);
+    assert_eq!(validity.effective_not_before(), NOW + 10);
+    assert!(!validity.contains(NOW));
+
+    // A.11: an audience-bound credential only admits the listed verifiers.
+    let mut bound = credential();
+    assert!(bound.admits_audience(&demo.relay.did));
+    bound.aud = Some(vec!["did:web:service-x".into()]);
+    assert!(bound.admits_audience("did:web:service-x"));
+    assert!(!bound.admits_audience("did:web:service-y"));
+}
+
+#[test]
+fn rfc005_credential_consistency_rules() {
+    let demo = demo_agents();
+    assert!(credential().validate().is_ok());
+
+    let with_scope = |scope: DelegationScope| DelegationCredential {
+        scope,
+        ..credential()
+    };
+    assert_eq!(code(with_scope(DelegationScope::default()).validate()), 1001);
+    let empty = DelegationScope {
+        actions: Some(vec![]),
+        ..Default::default()
+    };
+    assert_eq!(code(with_scope(empty).validate()), 1001);
+    // A.9: wildcards, negation and patterns are not MTI selectors.
+    for selector in ["org.agentries.*", "*", "!org.agentries.code-review", "org\\.agentries\\..+"] {
+        let scope = DelegationScope {
+            capabilities: Some(vec![selector.into()]),
+            ..Default::default()
+        };
+        assert_eq!(code(with_scope(scope).validate()), 3004, "{selector}");
+    }
+    let exact_id = DelegationScope {
+        capabilities: Some(vec!["org.agentries.code-review:2.1.0".into()]),
+        ..Default::default()
+    };
+    assert!(with_scope(exact_id).validate().is_ok());
+
+    let mut backwards = credential();
+    backwards.validity.not_before = Some(NOW + HOUR);
+    assert_eq!(code(backwards.validate()), 3004);
+    let mut no_depth = credential();
+    no_depth.max_chain_depth = Some(0);
+    assert_eq!(code(no_depth.validate()), 3004);
+    let mut no_aud = credential();
+    no_aud.aud = Some(vec![]);
+    assert_eq!(code(no_aud.validate()), 1001);
+    let mut future = credential();
+    future.cred_v = 2;
+    assert_eq!(code(future.validate()), 1004);
+
+    // Only the delegator may issue.
+    assert_eq!(code(credential().sign(&demo.bob)), 3001);
+}
+
+#[test]
+fn rfc005_credential_verification_failures() {
+    let demo = demo_agents();
+    let resolver = demo.resolver();
+    let issued = credential();
+    let envelope = issued.sign(&demo.alice).expect("sign");
+
+    let mut tampered = envelope.clone();
+    let last = tampered.credential.len() - 1;
+    tampered.credential[last] ^= 0x01;
+    assert_eq!(code(tampered.verify(&resolver)), 3004);
+
+    // Signed correctly, but by someone other than the delegator.
+    let payload = to_cbor_canonical(&issued).expect("cbor");
+    let foreign = DelegationEnvelope {
+        format: DELEGATION_FORMAT_COSE_SIGN1.into(),
+        credential: ByteBuf::from(cose_sign1_sign(&demo.bob, &demo.bob.assertion_kid(), &payload).expect("cose")),
+    };
+    assert_eq!(code(foreign.verify(&resolver)), 3004);
+
+    // A.16: non-MTI algorithm.
+    assert_eq!(code(unsigned_envelope(-7, &demo.alice.assertion_kid(), &payload).verify(&resolver)), 3004);
+    // The kid must resolve through DID resolution.
+    assert_eq!(code(envelope.verify(&DidResolver::default())), 3004);
+
+    // A.12: a signed credential with an unsupported cred_v.
+    let mut future = serde_cbor::value::to_value(&issued).expect("value");
+    if let Value::Map(fields) = &mut future {
+        fields.insert(Value::Text("cred_v".into()), Value::Integer(2));
+    }
+    let future = DelegationEnvelope {
+        format: DELEGATION_FORMAT_COSE_SIGN1.into(),
+        credential: ByteBuf::from(
+            cose_sign1_sign(&demo.alice, &demo.alice.assertion_kid(), &to_cbor_canonical(&future).expect("cbor"))
+                .expect("cose"),
+        ),
+    };
+    assert_eq!(code(future.verify(&resolver)), 1004);
+
+    let mut jws = envelope.clone();
+    jws.format = "jws".into();
+    assert_eq!(code(jws.verify(&resolver)), 1001);
+    let garbage = DelegationEnvelope {
+        format: DELEGATION_FORMAT_COSE_SIGN1.into(),
+        credential: ByteBuf::from(b"not cose".to_vec()),
+    };
+    assert_eq!(code(garbage.verify(&resolver)), 1001);
+    assert_eq!(code(DelegationEnvelope::from_value(Value::Text("credential".into()))), 1001);
+}
@@ -0,0 +1,107 @@
+use amp001_example::{
+    build_plain_signed, make_message_id, select_compatible_version, AckBody, AckSource, AgentKeys,
+    ErrorBody, HelloAckBody, HelloBody, MessageMeta, Recipients, RoutingEnvelope, TYPE_ACK,
+    TYPE_ERROR, TYPE_HELLO_ACK, TYPE_HELLO_REJECT,
+};
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
+
+use crate::RelayError;
+
+/// AMP versions the relay offers in `HELLO_ACK` selection.
+pub const RELAY_SUPPORTED_VERSIONS: &[&str] = &["1.0"];
+pub const RELAY_CONTROL_TTL_MS: u64 = 60_000;
+
+/// Version the relay selects for a peer's `HELLO` version list, if any.
+pub fn select_session_version(peer_versions: &[String]) -> Option<String> {
+    let local: Vec<String> = RELAY_SUPPORTED_VERSIONS.iter().map(|v| v.to_string()).collect();
+    select_compatible_version(&local, peer_versions)
+}
+
+/// `HELLO_ACK {selected}` from the relay, answering the agent's `HELLO`.
+pub fn build_hello_ack(
+    relay: &AgentKeys,
+    to: &str,
+    hello_id: [u8; 16],
+    selected: &str,
+    now_ms: u64,
+) -> Result<Vec<u8>, RelayError> {
+    let body = HelloAckBody {
+        selected: selected.to_string(),
+    };
+    build_control(relay, TYPE_HELLO_ACK, to, Some(hello_id), &body, now_ms)
+}
+
+/// `HELLO_REJECT` listing the versions the relay would have accepted.
+pub fn build_hello_reject(
+    relay: &AgentKeys,
+    to: &str,
+    hello_id: [u8; 16],
+    now_ms: u64,
+) -> Result<Vec<u8>, RelayError> {
+    let body = HelloBody {
+        versions: RELAY_SUPPORTED_VERSIONS.iter().map(|v| v.to_string()).collect(),
+    };
+    build_control(relay, TYPE_HELLO_REJECT, to, Some(hello_id), &body, now_ms)
+}
+
+/// RFC 001 §15.1 `ERROR` carrying a relay error code, replying to the refused message
+/// when its id is known.
+pub fn build_error_reply(
+    relay: &AgentKeys,
+    to: &str,
+    reply_to: Option<[u8; 16]>,
+    err: &RelayError,
+    now_ms: u64,
+) -> Result<Vec<u8>, RelayError> {
+    let body = ErrorBody::new(err.code, err.detail.clone());
+    build_control(relay, TYPE_ERROR, to, reply_to, &body, now_ms)
+}
+
+/// Relay `ACK`s (`ack_source = relay`) telling the sender its message is stored: one for a
+/// single recipient, otherwise one per recipient naming it in `ack_target` (RFC 001 §16.1).
+/// A relay ACK means "stored by relay", never "delivered".
+pub fn build_relay_acks(
+    relay: &AgentKeys,
+    original: &RoutingEnvelope,
+    received_at: u64,
+) -> Result<Vec<Vec<u8>>, RelayError> {
+    let targets: Vec<Option<String>> = if original.to.len() > 1 {
+        original.to.iter().cloned().map(Some).collect()
+    } else {
+        vec![None]
+    };
+    targets
+        .into_iter()
+        .map(|ack_target| {
+            let body = AckBody {
+                ack_source: AckSource::Relay,
+                received_at,
+                ack_target,
+            };
+            build_control(relay, TYPE_ACK, &original.from, Some(original.id), &body, received_at)
+        })
+        .collect()
+}
+
+fn build_control<T: Serialize>(
+    relay: &AgentKeys,
+    typ: u8,
+    to: &str,
+    reply_to: Option<[u8; 16]>,
+    body: &T,
+    now_ms: u64,
+) -> Result<Vec<u8>, RelayError> {
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, OsRng.next_u64()),
+        typ,
+        ts_ms: now_ms,
+        ttl_ms: RELAY_CONTROL_TTL_MS,
+        from: String::new(),
+        to: Recipients::One(to.to_string()),
+        reply_to,
+        thread_id: None,
+    };
+    Ok(build_plain_signed(relay, meta, body)?)
+}
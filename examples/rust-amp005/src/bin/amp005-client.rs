@@ -1,20 +1,35 @@
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::io;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
+
+use amp001_example::{
+    demo_agents, hex_encode, now_ms, split_first, AckBody, AckSource, Agent, AgentEvent,
+    AgentHandle, ErrorBody, KeepaliveConfig, ProcFailBody, ProcOkBody, ReceivedMessage, TextMessageBody, TYPE_ACK,
+    TYPE_ERROR, TYPE_MESSAGE, TYPE_PROC_FAIL, TYPE_PROC_OK,
+};
+use amp005_rfc003_tests::{add_relay_key, DeliveryState, Outbox, SessionHandshake};
+
+const HELLO_VERSIONS: &[&str] = &["1.0"];
+const OUTBOX_TICK: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECTS: u32 = 5;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
+    let usage = "usage: cargo run --bin amp005-client -- <alice|bob|did> [server_addr] \
+                 --relay-key <relay_did>=<hex> [--once <to> <text>]";
     if args.len() < 2 {
-        return Err(
-            "usage: cargo run --bin amp005-client -- <alice|bob|did> [server_addr] [--once <to> <text>]"
-                .into(),
-        );
+        return Err(usage.into());
     }
 
-    let me = resolve_alias(&args[1]);
+    let demo = demo_agents();
+    let me_did = demo.did_for_alias(&args[1]);
+    let me = [demo.alice.clone(), demo.bob.clone()]
+        .into_iter()
+        .find(|agent| agent.did == me_did)
+        .ok_or_else(|| format!("no demo keys for {me_did}; the relay requires proof of DID control"))?;
     let mut cursor = 2;
     let mut server_addr = "127.0.0.1:7103".to_string();
     if args.get(cursor).is_some() && !args[cursor].starts_with("--") {
@@ -22,57 +37,183 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         cursor += 1;
     }
 
-    let once = if let Some(flag) = args.get(cursor) {
-        if flag == "--once" {
-            if cursor + 2 >= args.len() {
-                return Err("--once requires <to> <text>".into());
+    let mut resolver = demo.resolver();
+    let mut relay = None;
+    let mut once = None;
+    while let Some(flag) = args.get(cursor) {
+        match flag.as_str() {
+            "--relay-key" => {
+                let (relay_did, key) = args
+                    .get(cursor + 1)
+                    .and_then(|value| value.rsplit_once('='))
+                    .ok_or_else(|| format!("--relay-key requires <relay_did>=<hex>; {usage}"))?;
+                add_relay_key(&mut resolver, relay_did, key)
+                    .map_err(|err| format!("--relay-key {relay_did}: {}", err.detail))?;
+                relay = Some(relay_did.to_string());
+                cursor += 2;
             }
-            let to = resolve_alias(&args[cursor + 1]);
-            let text = args[cursor + 2..].join(" ");
-            Some((to, text))
-        } else {
-            return Err(format!("unknown option: {flag}").into());
+            "--once" => {
+                if cursor + 2 >= args.len() {
+                    return Err("--once requires <to> <text>".into());
+                }
+                let to = demo.did_for_alias(&args[cursor + 1]);
+                let text = args[cursor + 2..].join(" ");
+                once = Some((to, text));
+                break;
+            }
+            _ => return Err(format!("unknown option: {flag}; {usage}").into()),
         }
-    } else {
-        None
+    }
+    let Some(relay_did) = relay else {
+        return Err(format!("the relay's key is required (printed by amp005-server); {usage}").into());
     };
 
-    let stream = TcpStream::connect(&server_addr)?;
-    stream.set_nodelay(true)?;
-    println!("[client:{me}] connected to {server_addr}");
-
-    let writer = Arc::new(Mutex::new(stream.try_clone()?));
-    send_line(&writer, &format!("HELLO {me}"))?;
+    let outbox = Arc::new(Mutex::new(Outbox::new()));
+    {
+        let me_did = me.did.clone();
+        outbox.lock().expect("outbox poisoned").on_change(move |update| {
+            println!(
+                "[outbox:{me_did}] msg_id={} recipient={} {:?} -> {:?}",
+                hex_encode(&update.msg_id),
+                update.recipient,
+                update.previous,
+                update.state
+            );
+        });
+    }
 
-    let recv_writer = Arc::clone(&writer);
-    let recv_me = me.clone();
-    thread::spawn(move || {
-        if let Err(err) = receiver_loop(stream, recv_writer, recv_me) {
-            eprintln!("[client] receiver loop stopped: {err}");
-        }
-    });
+    let did = me.did.clone();
+    let ack_outbox = Arc::clone(&outbox);
+    let proc_outbox = Arc::clone(&outbox);
+    let fail_outbox = Arc::clone(&outbox);
+    let error_outbox = Arc::clone(&outbox);
+    let agent = Agent::builder(me.clone())
+        .resolver(resolver)
+        .connect_tcp(server_addr.clone())
+        .handshake(SessionHandshake {
+            relay_did,
+            versions: HELLO_VERSIONS.iter().map(|v| v.to_string()).collect(),
+        })
+        .report_processing(true)
+        .keepalive(KeepaliveConfig::default())
+        .reconnect(RECONNECT_DELAY, Some(MAX_RECONNECTS))
+        .on_message(|agent, message, body: TextMessageBody| {
+            println!(
+                "[recv:{}] from {} msg_id={}: {}",
+                agent.did(),
+                message.meta.from,
+                hex_encode(&message.meta.id),
+                body.msg
+            );
+            Ok(())
+        })
+        .on(TYPE_ACK, move |agent, message, ack: AckBody| {
+            track(agent, &ack_outbox, message);
+            let reply_to = reply_to_hex(message);
+            match ack.ack_source {
+                AckSource::Relay => println!(
+                    "[recv:{}] stored by relay msg_id={reply_to} target={}",
+                    agent.did(),
+                    ack.ack_target.as_deref().unwrap_or("all")
+                ),
+                AckSource::Recipient => println!(
+                    "[recv:{}] delivery confirmed msg_id={reply_to} recipient={}",
+                    agent.did(),
+                    message.meta.from
+                ),
+            }
+            Ok(())
+        })
+        .on(TYPE_PROC_OK, move |agent, message, _: ProcOkBody| {
+            track(agent, &proc_outbox, message);
+            println!(
+                "[recv:{}] processed msg_id={} recipient={}",
+                agent.did(),
+                reply_to_hex(message),
+                message.meta.from
+            );
+            Ok(())
+        })
+        .on(TYPE_PROC_FAIL, move |agent, message, _: ProcFailBody| {
+            track(agent, &fail_outbox, message);
+            println!(
+                "[recv:{}] processing failed msg_id={} recipient={}",
+                agent.did(),
+                reply_to_hex(message),
+                message.meta.from
+            );
+            Ok(())
+        })
+        .on(TYPE_ERROR, move |agent, message, body: ErrorBody| {
+            track(agent, &error_outbox, message);
+            println!(
+                "[recv:{}] ERROR {} {} reply_to={}",
+                agent.did(),
+                body.code,
+                body.message,
+                reply_to_hex(message)
+            );
+            Ok(())
+        })
+        .on_unhandled(|agent, message| {
+            println!(
+                "[recv:{}] typ=0x{:02x} from={}",
+                agent.did(),
+                message.meta.typ,
+                message.meta.from
+            );
+            Ok(())
+        })
+        .on_event(move |event| match event {
+            AgentEvent::Connected { version } => println!(
+                "[client:{did}] session open version={}",
+                version.as_deref().unwrap_or("?")
+            ),
+            AgentEvent::HandshakeFailed(err) => eprintln!("[client:{did}] session refused: {err}"),
+            AgentEvent::KeepaliveLost(health) => eprintln!(
+                "[client:{did}] relay stopped answering PING (missed={}); reconnecting",
+                health.missed_pongs
+            ),
+            AgentEvent::Disconnected { reason } => eprintln!("[client:{did}] disconnected: {reason}"),
+            AgentEvent::Replayed { count } => println!("[client:{did}] re-sent {count} message(s)"),
+            AgentEvent::Duplicate { typ, from, .. } => {
+                println!("[recv:{did}] duplicate typ=0x{typ:02x} from {from} acknowledged again")
+            }
+            AgentEvent::Rejected { error, .. } => eprintln!("[recv:{did}] rejected frame: {error}"),
+            AgentEvent::HandlerFailed { typ, error, .. } => {
+                eprintln!("[recv:{did}] typ=0x{typ:02x} handling failed: {}", error.message)
+            }
+            AgentEvent::Stopped => println!("[client:{did}] stopped"),
+        })
+        .build()?;
+    let (agent, _run) = agent.spawn();
+    if !agent.wait_connected(CONNECT_TIMEOUT) {
+        agent.shutdown();
+        return Err(format!("no relay session with {server_addr}").into());
+    }
+    println!("[client:{}] connected to {server_addr}", me.did);
 
-    let counter = AtomicU64::new(1);
+    let tick_agent = agent.clone();
+    let tick_outbox = Arc::clone(&outbox);
+    thread::spawn(move || outbox_ticker(tick_agent, tick_outbox));
 
     if let Some((to, text)) = once {
-        let msg_id = next_msg_id(&counter);
-        send_line(&writer, &format!("SEND {msg_id} 60000 {to} {text}"))?;
-        send_line(&writer, "POLL")?;
+        send_text_message(&agent, &outbox, (&to, &text, 60_000))?;
         thread::sleep(Duration::from_millis(700));
-        send_line(&writer, "QUIT")?;
+        agent.shutdown();
         return Ok(());
     }
 
-    let default_target = if me.contains(":alice") {
-        "did:web:example.com:agent:bob".to_string()
+    let default_target = if me.did == demo.alice.did {
+        demo.bob.did.clone()
     } else {
-        "did:web:example.com:agent:alice".to_string()
+        demo.alice.did.clone()
     };
 
     println!("commands:");
     println!("  /send <alice|bob|did> <text>");
     println!("  /send0 <alice|bob|did> <text>   (ttl=0)");
-    println!("  /poll");
+    println!("  /status");
     println!("  /quit");
     println!("default: plain text sends to {default_target}");
 
@@ -88,127 +229,123 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             continue;
         }
 
-        if input == "/quit" {
-            send_line(&writer, "QUIT")?;
-            println!("[client:{me}] quitting");
-            break;
-        }
-
-        if input == "/poll" {
-            send_line(&writer, "POLL")?;
+        if input == "/status" {
+            print_outbox(&me.did, &outbox);
             continue;
         }
 
-        if let Some(rest) = input.strip_prefix("/send0 ") {
-            if let Some((target, text)) = split_first(rest) {
-                let to = resolve_alias(target);
-                let msg_id = next_msg_id(&counter);
-                send_line(&writer, &format!("SEND {msg_id} 0 {to} {text}"))?;
-            } else {
-                eprintln!("usage: /send0 <alice|bob|did> <text>");
-            }
-            continue;
+        if input == "/quit" {
+            println!("[client:{}] quitting", me.did);
+            break;
         }
 
-        if let Some(rest) = input.strip_prefix("/send ") {
-            if let Some((target, text)) = split_first(rest) {
-                let to = resolve_alias(target);
-                let msg_id = next_msg_id(&counter);
-                send_line(&writer, &format!("SEND {msg_id} 60000 {to} {text}"))?;
-            } else {
-                eprintln!("usage: /send <alice|bob|did> <text>");
+        let (target, text, ttl_ms) = if let Some(rest) = input.strip_prefix("/send0 ") {
+            match split_first(rest) {
+                Some((target, text)) => (demo.did_for_alias(target), text, 0),
+                None => {
+                    eprintln!("usage: /send0 <alice|bob|did> <text>");
+                    continue;
+                }
             }
-            continue;
-        }
+        } else if let Some(rest) = input.strip_prefix("/send ") {
+            match split_first(rest) {
+                Some((target, text)) => (demo.did_for_alias(target), text, 60_000),
+                None => {
+                    eprintln!("usage: /send <alice|bob|did> <text>");
+                    continue;
+                }
+            }
+        } else {
+            (default_target.clone(), input, 60_000)
+        };
 
-        let msg_id = next_msg_id(&counter);
-        send_line(
-            &writer,
-            &format!("SEND {msg_id} 60000 {default_target} {input}"),
-        )?;
+        let message = (target.as_str(), text, ttl_ms);
+        if let Err(err) = send_text_message(&agent, &outbox, message) {
+            eprintln!("[client:{}] send failed: {err}", me.did);
+        }
     }
 
+    agent.shutdown();
     Ok(())
 }
 
-fn receiver_loop(
-    stream: TcpStream,
-    writer: Arc<Mutex<TcpStream>>,
-    me: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut reader = BufReader::new(stream);
-    loop {
-        let mut line = String::new();
-        let n = reader.read_line(&mut line)?;
-        if n == 0 {
-            break;
-        }
-
-        let msg = line.trim();
-        if msg.is_empty() {
-            continue;
-        }
-
-        if let Some(rest) = msg.strip_prefix("MSG ") {
-            let mut parts = rest.splitn(3, ' ');
-            let from = parts.next().unwrap_or_default();
-            let msg_id = parts.next().unwrap_or_default();
-            let text = parts.next().unwrap_or_default();
-            println!("[recv:{me}] from {from} msg_id={msg_id}: {text}");
-            if !from.is_empty() && !msg_id.is_empty() {
-                send_line(&writer, &format!("ACK {from} {msg_id}"))?;
-            }
-            continue;
-        }
-
-        if let Some(rest) = msg.strip_prefix("DELIVERED ") {
-            let mut parts = rest.splitn(2, ' ');
-            let msg_id = parts.next().unwrap_or_default();
-            let recipient = parts.next().unwrap_or_default();
-            println!("[recv:{me}] delivery confirmed msg_id={msg_id} recipient={recipient}");
-            continue;
-        }
-
-        println!("[recv:{me}] {msg}");
+/// Feeds a verified reply to the outbox; replies that break the ACK/PROC source rules
+/// are reported and otherwise ignored.
+fn track(agent: &AgentHandle, outbox: &Mutex<Outbox>, message: &ReceivedMessage) {
+    let observed = agent.with_resolver(|resolver| {
+        outbox
+            .lock()
+            .expect("outbox poisoned")
+            .observe(message, resolver, now_ms())
+    });
+    if let Err(err) = observed {
+        eprintln!("[recv:{}] ignored reply: {}", agent.did(), err.detail);
     }
+}
 
-    Ok(())
+fn reply_to_hex(message: &ReceivedMessage) -> String {
+    message
+        .meta
+        .reply_to
+        .map(|id| hex_encode(&id))
+        .unwrap_or_else(|| "none".to_string())
 }
 
-fn send_line(writer: &Arc<Mutex<TcpStream>>, line: &str) -> io::Result<()> {
-    let mut guard = writer.lock().expect("writer poisoned");
-    guard.write_all(line.as_bytes())?;
-    guard.write_all(b"\n")?;
-    guard.flush()?;
+fn send_text_message(
+    agent: &AgentHandle,
+    outbox: &Mutex<Outbox>,
+    (target_did, text, ttl_ms): (&str, &str, u64),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let body = TextMessageBody {
+        msg: text.to_string(),
+    };
+    let (id, wire) = agent.seal(target_did, TYPE_MESSAGE, &body, ttl_ms)?;
+    // Track before writing so a fast relay ACK always finds its entry.
+    outbox
+        .lock()
+        .expect("outbox poisoned")
+        .submit(&wire, now_ms())
+        .map_err(|err| err.detail)?;
+    agent.send_wire(&wire)?;
+    println!(
+        "[client:{}] sent MESSAGE msg_id={} to={target_did} ttl_ms={ttl_ms}",
+        agent.did(),
+        hex_encode(&id)
+    );
     Ok(())
 }
 
-fn split_first(input: &str) -> Option<(&str, &str)> {
-    let mut parts = input.splitn(2, ' ');
-    let first = parts.next()?.trim();
-    let rest = parts.next()?.trim();
-    if first.is_empty() || rest.is_empty() {
-        return None;
+/// Resubmits messages the relay has not ACKed and expires undelivered ones. Retries that
+/// find no connection are counted as attempts like any other.
+fn outbox_ticker(agent: AgentHandle, outbox: Arc<Mutex<Outbox>>) {
+    loop {
+        thread::sleep(OUTBOX_TICK);
+        let now = now_ms();
+        let retries = {
+            let mut guard = outbox.lock().expect("outbox poisoned");
+            guard.expire(now);
+            guard.retries_due(now)
+        };
+        for wire in retries {
+            let _ = agent.send_wire(&wire);
+        }
     }
-    Some((first, rest))
 }
 
-fn resolve_alias(token: &str) -> String {
-    match token {
-        "alice" => "did:web:example.com:agent:alice".to_string(),
-        "bob" => "did:web:example.com:agent:bob".to_string(),
-        _ => token.to_string(),
+fn print_outbox(me_did: &str, outbox: &Mutex<Outbox>) {
+    let guard = outbox.lock().expect("outbox poisoned");
+    for entry in guard.entries() {
+        for (recipient, status) in &entry.recipients {
+            let detail = match (&status.state, &status.error) {
+                (DeliveryState::Rejected, Some((code, message))) => format!(" ({code} {message})"),
+                _ => String::new(),
+            };
+            println!(
+                "[outbox:{me_did}] msg_id={} recipient={recipient} state={:?} attempts={}{detail}",
+                hex_encode(&entry.msg_id),
+                status.state,
+                entry.attempts
+            );
+        }
     }
 }
-
-fn next_msg_id(counter: &AtomicU64) -> String {
-    let n = counter.fetch_add(1, Ordering::Relaxed);
-    format!("{:016x}{:016x}", now_ms(), n)
-}
-
-fn now_ms() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|v| v.as_millis() as u64)
-        .unwrap_or(0)
-}
@@ -1,48 +1,170 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::ErrorKind;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use amp005_rfc003_tests::{Message, Relay, RelayError};
+use amp001_example::{
+    build_ping, build_pong, decode_relay_commit_report, demo_agents, hex_encode, peek_routing,
+    read_frame, receive_and_verify, spawn_keepalive, write_frame, AgentKeys, DidResolver, Keepalive,
+    KeepaliveConfig, PollResponse, RoutingEnvelope, TYPE_ACK, TYPE_HELLO, TYPE_PING, TYPE_PONG,
+};
+use amp005_rfc003_tests::{
+    add_relay_key, build_error_reply, build_hello_ack, build_hello_reject, build_relay_acks,
+    decode_relay_forward, encode_relay_commit_report, encode_relay_forward, handoff_retry_backoff_ms,
+    http_status_for,
+    is_retryable_handoff_error, message_from_wire, next_hop_forward, post_relay_commit,
+    post_relay_forward, read_http_request, relay_keys, sign_commit_receipt, sign_transfer_receipt,
+    validate_relay_commit_principal_binding, validate_relay_forward_principal_binding,
+    validate_session_principal_binding,
+    verify_commit_receipt, write_http_response, CommitReceipt, CommitResult, HandoffEvent,
+    HttpRequest, Message, RecipientState, Relay, RelayCommitResponse, RelayError, RelayForward,
+    RelayForwardResponse, TransferMode, TransferReceipt, TransferState, COMMIT_V1,
+    DEFAULT_HANDOFF_MAX_ATTEMPTS, DEFAULT_HOP_LIMIT, KEY_PURPOSE_ASSERTION_METHOD, MESSAGES_PATH,
+    RequestAuthenticator, RECEIPT_V1, RELAY_COMMIT_PATH, RELAY_FORWARD_PATH, DidDocumentServices,
+    FederationRequirements, RoutingTable, SessionChallenge, select_session_version,
+};
 
-#[derive(Debug)]
-struct Delivery {
-    from_did: String,
-    msg_id: String,
-    recipient_did: String,
-    text: String,
+const FEDERATION_TICK: Duration = Duration::from_millis(100);
+const DISPATCH_INTERVAL: Duration = Duration::from_millis(250);
+const RECEIPT_ALGS: &[i32] = &[-8];
+const DID_DOCUMENT_ROUTE_PRIORITY: u32 = 1_000;
+
+type EntryKey = (String, String, String);
+type Writer = Arc<Mutex<TcpStream>>;
+
+#[derive(Debug, Clone)]
+struct Config {
+    tcp_addr: String,
+    relay_id: String,
+    http_addr: Option<String>,
+    peers: HashMap<String, String>,
+    routes: Vec<(String, String)>,
+    did_documents: Vec<String>,
+    key_seed: Option<String>,
+    relay_keys: Vec<(String, String)>,
+    mode: TransferMode,
+    hop_limit: u64,
+    keepalive: KeepaliveConfig,
 }
 
+/// Handoff accepted from an upstream relay; dual-custody ones owe a commit report, retried
+/// with the handoff backoff until the upstream relay accepts it.
 #[derive(Debug)]
-struct SenderNotice {
-    sender_did: String,
-    msg_id: String,
-    recipient_did: String,
+struct InboundHandoff {
+    forward: RelayForward,
+    report_attempts: u8,
+    next_report_at: u64,
 }
 
 #[derive(Debug)]
 struct RelayState {
     relay: Relay,
-    payloads: HashMap<(String, String, String), String>,
-    writers: HashMap<String, Arc<Mutex<TcpStream>>>,
+    config: Config,
+    keys: AgentKeys,
+    resolver: DidResolver,
+    routing: RoutingTable,
+    payloads: HashMap<EntryKey, Vec<u8>>,
+    outbound: HashMap<EntryKey, RelayForward>,
+    inbound: HashMap<EntryKey, InboundHandoff>,
+    writers: HashMap<String, Writer>,
+    dispatch_wake: Sender<()>,
+    request_auth: RequestAuthenticator,
+}
+
+impl RelayState {
+    /// Best healthy next hop for `recipient_did` outside `avoid`; `None` when the
+    /// recipient is homed here or has no route.
+    fn downstream_for(&self, recipient_did: &str, avoid: &[String]) -> Option<String> {
+        self.routing
+            .select_next_hop(recipient_did, avoid, self.relay.now_ms)
+            .map(|c| c.relay_did.clone())
+            .filter(|relay| *relay != self.config.relay_id)
+    }
+
+    /// HTTP address of a peer relay: `--peer` first, then a DID Document forward endpoint.
+    fn peer_addr(&self, relay_did: &str) -> Option<String> {
+        self.config.peers.get(relay_did).cloned().or_else(|| {
+            self.routing
+                .endpoint_for(relay_did)
+                .and_then(|c| c.http_authority())
+                .map(str::to_string)
+        })
+    }
+}
+
+enum FederationJob {
+    Forward {
+        forward: RelayForward,
+        wrapper: Vec<u8>,
+        addr: Option<String>,
+    },
+    Commit {
+        key: EntryKey,
+        upstream_relay: String,
+        report: Vec<u8>,
+        addr: Option<String>,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:7103".to_string());
+    let config = parse_args()?;
+
+    let listener = TcpListener::bind(&config.tcp_addr)?;
+    let keys = relay_keys(&config.relay_id, config.key_seed.as_deref())
+        .map_err(|err| format!("--key-seed: {}", err.detail))?;
+    let routing = load_routing_table(&config)?;
+    let mut resolver = DidResolver::default();
+    resolver.add_agent(&keys);
+    resolver.add_trusted_relay(config.relay_id.clone());
+    for (relay_did, key) in &config.relay_keys {
+        add_relay_key(&mut resolver, relay_did, key)
+            .map_err(|err| format!("--relay-key {relay_did}: {}", err.detail))?;
+    }
+    let agents = demo_agents();
+    resolver.add_agent(&agents.alice);
+    resolver.add_agent(&agents.bob);
+    let http_listener = config.http_addr.as_ref().map(TcpListener::bind).transpose()?;
 
-    let listener = TcpListener::bind(&addr)?;
+    println!("AMP RFC003 relay server listening on {}", config.tcp_addr);
+    println!("relay id: {}", config.relay_id);
+    println!("relay key: {}", hex_encode(keys.signing_public_key.as_bytes()));
+    println!("protocol: AMPS framing (CHALLENGE, HELLO/HELLO_ACK, AMP messages)");
+
+    let (dispatch_wake, dispatch_rx) = mpsc::channel();
     let state = Arc::new(Mutex::new(RelayState {
-        relay: Relay::new("did:web:example.com:relay:store", now_ms()),
+        relay: Relay::new(config.relay_id.clone(), now_ms()),
+        config: config.clone(),
+        keys,
+        resolver,
+        routing,
         payloads: HashMap::new(),
+        outbound: HashMap::new(),
+        inbound: HashMap::new(),
         writers: HashMap::new(),
+        dispatch_wake,
+        request_auth: RequestAuthenticator::default(),
     }));
 
-    println!("AMP RFC003 relay server listening on {addr}");
-    println!("protocol: HELLO/SEND/POLL/ACK/QUIT");
+    let dispatch_state = Arc::clone(&state);
+    thread::spawn(move || push_dispatcher(dispatch_rx, dispatch_state));
+
+    if let Some(http_listener) = http_listener {
+        println!(
+            "AMP RFC003 federation listening on {} (mode={:?} hop_limit={})",
+            config.http_addr.as_deref().unwrap_or_default(),
+            config.mode,
+            config.hop_limit
+        );
+        let http_state = Arc::clone(&state);
+        thread::spawn(move || serve_http(http_listener, http_state));
+
+        let worker_state = Arc::clone(&state);
+        thread::spawn(move || federation_worker(worker_state));
+    }
 
     for stream in listener.incoming() {
         match stream {
@@ -67,345 +189,1169 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn parse_args() -> Result<Config, Box<dyn std::error::Error>> {
+    let usage = "usage: amp005-server [tcp_addr] [--relay-id <did>] [--http <addr>] \
+                 [--peer <relay_did>=<http_addr>]... [--route <recipient_did>=<relay_did>]... \
+                 [--did-doc <path.json>]... [--key-seed <hex>] [--relay-key <relay_did>=<hex>]... \
+                 [--mode single|dual] [--hop-limit <n>] \
+                 [--ping-interval <ms>] [--pong-timeout <ms>] [--max-missed-pongs <n>]";
+    let mut config = Config {
+        tcp_addr: "127.0.0.1:7103".to_string(),
+        relay_id: "did:web:example.com:relay:store".to_string(),
+        http_addr: None,
+        peers: HashMap::new(),
+        routes: Vec::new(),
+        did_documents: Vec::new(),
+        key_seed: None,
+        relay_keys: Vec::new(),
+        mode: TransferMode::Single,
+        hop_limit: DEFAULT_HOP_LIMIT,
+        keepalive: KeepaliveConfig::default(),
+    };
+
+    let mut args = std::env::args().skip(1).peekable();
+    if let Some(first) = args.peek() {
+        if !first.starts_with("--") {
+            config.tcp_addr = args.next().unwrap_or_default();
+        }
+    }
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{flag} requires a value; {usage}"))?;
+        match flag.as_str() {
+            "--relay-id" => config.relay_id = value,
+            "--http" => config.http_addr = Some(value),
+            "--peer" => {
+                let (did, addr) = split_pair(&value, usage)?;
+                config.peers.insert(did, addr);
+            }
+            "--route" => {
+                let (recipient, relay) = split_pair(&value, usage)?;
+                config.routes.push((recipient, relay));
+            }
+            "--did-doc" => config.did_documents.push(value),
+            "--key-seed" => config.key_seed = Some(value),
+            "--relay-key" => config.relay_keys.push(split_pair(&value, usage)?),
+            "--mode" => {
+                config.mode = match value.as_str() {
+                    "single" => TransferMode::Single,
+                    "dual" => TransferMode::Dual,
+                    other => return Err(format!("unknown transfer mode {other}; {usage}").into()),
+                }
+            }
+            "--hop-limit" => config.hop_limit = value.parse()?,
+            "--ping-interval" => config.keepalive.interval_ms = value.parse()?,
+            "--pong-timeout" => config.keepalive.pong_timeout_ms = value.parse()?,
+            "--max-missed-pongs" => config.keepalive.max_missed = value.parse()?,
+            other => return Err(format!("unknown option {other}; {usage}").into()),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Static `--route`s keep their command-line order as priority; DID Document relays
+/// (`--did-doc`) rank after them.
+fn load_routing_table(config: &Config) -> Result<RoutingTable, Box<dyn std::error::Error>> {
+    let mut routing = RoutingTable::new(config.relay_id.clone());
+    for (priority, (recipient, relay)) in config.routes.iter().enumerate() {
+        routing.add_static_route(recipient, relay, None, priority as u32);
+    }
+
+    let requirements = FederationRequirements {
+        transfer_mode: config.mode,
+        hop_limit: config.hop_limit,
+        ..FederationRequirements::default()
+    };
+    for path in &config.did_documents {
+        let document: DidDocumentServices = serde_json::from_slice(&std::fs::read(path)?)?;
+        let skipped = routing
+            .add_did_document_routes(&document, &requirements, DID_DOCUMENT_ROUTE_PRIORITY)
+            .map_err(|err| format!("{path}: {}", err.detail))?;
+        for reason in skipped {
+            println!("[routing] skipped relay service {reason}");
+        }
+    }
+    Ok(routing)
+}
+
+fn split_pair(value: &str, usage: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let (left, right) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <did>=<value>, got {value}; {usage}"))?;
+    Ok((left.to_string(), right.to_string()))
+}
+
 fn handle_connection(
     stream: TcpStream,
     state: Arc<Mutex<RelayState>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut reader = BufReader::new(stream.try_clone()?);
+    stream.set_nodelay(true)?;
+    let mut reader = stream.try_clone()?;
     let writer = Arc::new(Mutex::new(stream));
     let mut registered_did: Option<String> = None;
 
-    loop {
-        let mut line = String::new();
-        let n = reader.read_line(&mut line)?;
-        if n == 0 {
-            break;
-        }
+    let (relay_id, keepalive) = {
+        let guard = state.lock().expect("relay state poisoned");
+        (guard.config.relay_id.clone(), guard.config.keepalive)
+    };
+    let mut challenge = SessionChallenge::new(relay_id, now_ms());
+    send_frame(&writer, &challenge.to_frame())?;
 
-        let input = line.trim();
-        if input.is_empty() {
-            continue;
+    let mut liveness = Liveness {
+        keepalive: Arc::new(Mutex::new(Keepalive::new(keepalive, now_ms()))),
+        stop: Arc::new(AtomicBool::new(false)),
+    };
+    let result = serve_frames(
+        &mut reader,
+        &writer,
+        &state,
+        (&mut registered_did, &mut challenge),
+        &mut liveness,
+    );
+    liveness.stop.store(true, Ordering::SeqCst);
+
+    if let Some(did) = registered_did {
+        let mut guard = state.lock().expect("relay state poisoned");
+        if guard
+            .writers
+            .get(&did)
+            .is_some_and(|current| Arc::ptr_eq(current, &writer))
+        {
+            guard.writers.remove(&did);
         }
+        let released = guard.relay.release_inflight_for(&did);
+        if released > 0 {
+            // A newer connection for the same DID may already be registered.
+            let _ = guard.dispatch_wake.send(());
+        }
+        println!("[server] unregistered {did} released_inflight={released}");
+    }
 
-        match dispatch_command(input, &writer, &state, &mut registered_did) {
-            Ok(keep_running) => {
-                if !keep_running {
-                    break;
-                }
+    result
+}
+
+/// Keepalive for one connection; it starts pinging once the session is bound.
+struct Liveness {
+    keepalive: Arc<Mutex<Keepalive>>,
+    stop: Arc<AtomicBool>,
+}
+
+/// One AMP message per frame (RFC 002 §3.2). Only `HELLO` is accepted before the session
+/// is bound; afterwards every message must be from the bound DID.
+fn serve_frames(
+    reader: &mut TcpStream,
+    writer: &Writer,
+    state: &Arc<Mutex<RelayState>>,
+    (registered_did, challenge): (&mut Option<String>, &mut SessionChallenge),
+    liveness: &mut Liveness,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let frame = match read_frame(reader) {
+            Ok(frame) => frame,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                eprintln!("[server] read_frame ended: {err}");
+                break;
             }
+        };
+
+        liveness.keepalive.lock().expect("keepalive poisoned").observe_inbound(now_ms());
+
+        let routing = match peek_routing(&frame) {
+            Ok(routing) => routing,
             Err(err) => {
-                send_line(&writer, &format!("ERR 5001 INTERNAL {err}"))?;
+                println!("[server] drop malformed frame: {}", err.detail);
+                continue;
+            }
+        };
+
+        if routing.typ == TYPE_HELLO {
+            let was_bound = registered_did.is_some();
+            if !handle_hello(&frame, &routing, writer, state, registered_did, challenge)? {
+                break;
             }
+            if let (false, Some(did)) = (was_bound, registered_did.as_deref()) {
+                start_keepalive(did, writer, state, liveness);
+            }
+            continue;
         }
-    }
 
-    if let Some(did) = registered_did {
-        let mut guard = state.lock().expect("relay state poisoned");
-        guard.writers.remove(&did);
-        println!("[server] unregistered {did}");
+        let Some(me) = registered_did.as_deref() else {
+            reply_error(
+                writer,
+                state,
+                &routing,
+                &RelayError::unauthorized("send HELLO first"),
+            )?;
+            continue;
+        };
+        if let Err(err) = validate_session_principal_binding(me, &routing.from) {
+            reply_error(writer, state, &routing, &err)?;
+            continue;
+        }
+
+        if matches!(routing.typ, TYPE_PING | TYPE_PONG) && addressed_to_relay(state, &routing) {
+            handle_keepalive_frame(&frame, writer, state, &liveness.keepalive)?;
+            continue;
+        }
+
+        handle_submit(&frame, &routing, writer, state)?;
     }
 
     Ok(())
 }
 
-fn dispatch_command(
-    input: &str,
-    writer: &Arc<Mutex<TcpStream>>,
+/// Binds the connection to the DID whose AMP-signed `HELLO` echoes the connection
+/// challenge, then negotiates the version with `HELLO_ACK`. Each challenge is single-use;
+/// every rejected proof is answered with `ERROR` and a fresh challenge frame. Returns
+/// `false` when no version is compatible and the connection should close.
+fn handle_hello(
+    frame: &[u8],
+    routing: &RoutingEnvelope,
+    writer: &Writer,
     state: &Arc<Mutex<RelayState>>,
     registered_did: &mut Option<String>,
+    challenge: &mut SessionChallenge,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    if let Some(rest) = input.strip_prefix("HELLO ") {
-        let did = rest.trim();
-        if did.is_empty() {
-            send_line(writer, "ERR 1001 INVALID_MESSAGE missing did")?;
+    let proven = {
+        let guard = state.lock().expect("relay state poisoned");
+        challenge.verify_signed_hello(frame, &guard.keys, &guard.resolver, now_ms())
+    };
+    let relay_id = challenge.relay_did.clone();
+    *challenge = SessionChallenge::new(relay_id, now_ms());
+
+    let proven = proven.and_then(|(did, versions)| match registered_did {
+        Some(current) => validate_session_principal_binding(current, &did).map(|()| (did, versions)),
+        None => Ok((did, versions)),
+    });
+    let (did, versions) = match proven {
+        Ok(proven) => proven,
+        Err(err) => {
+            println!("[server] session proof rejected code={} detail={}", err.code, err.detail);
+            reply_error(writer, state, routing, &err)?;
+            send_frame(writer, &challenge.to_frame())?;
             return Ok(true);
         }
+    };
 
-        if let Some(current) = registered_did {
-            if current != did {
-                send_line(writer, "ERR 3001 UNAUTHORIZED sender DID switch denied")?;
-                return Ok(true);
+    let reply = {
+        let mut guard = state.lock().expect("relay state poisoned");
+        let now = now_ms();
+        match select_session_version(&versions) {
+            Some(selected) => {
+                if registered_did.is_none() {
+                    guard.writers.insert(did.clone(), Arc::clone(writer));
+                    *registered_did = Some(did.clone());
+                    println!("[server] registered {did} version={selected}");
+                }
+                build_hello_ack(&guard.keys, &did, routing.id, &selected, now).map(|f| (f, true))
+            }
+            None => {
+                println!("[server] HELLO_REJECT {did} versions={versions:?}");
+                build_hello_reject(&guard.keys, &did, routing.id, now).map(|f| (f, false))
             }
-        } else {
-            let mut guard = state.lock().expect("relay state poisoned");
-            guard.writers.insert(did.to_string(), Arc::clone(writer));
-            *registered_did = Some(did.to_string());
-            println!("[server] registered {did}");
         }
+    };
+    let (reply, keep_running) = match reply {
+        Ok(reply) => reply,
+        Err(err) => {
+            reply_error(writer, state, routing, &err)?;
+            return Ok(true);
+        }
+    };
 
-        send_line(writer, &format!("OK HELLO {did}"))?;
-        return Ok(true);
+    send_frame(writer, &reply)?;
+    if keep_running {
+        wake_dispatcher(state);
     }
+    Ok(keep_running)
+}
 
-    let Some(me) = registered_did.as_ref() else {
-        send_line(writer, "ERR 3001 UNAUTHORIZED send HELLO first")?;
-        return Ok(true);
+/// Stores a message from the bound agent and answers with relay `ACK`s; `ttl = 0`
+/// messages are written straight to the online recipients instead (RFC 003 §5).
+fn handle_submit(
+    frame: &[u8],
+    routing: &RoutingEnvelope,
+    writer: &Writer,
+    state: &Arc<Mutex<RelayState>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let accepted = {
+        let mut guard = state.lock().expect("relay state poisoned");
+        guard.relay.set_now(now_ms());
+        guard.relay.expire();
+        accept_wire(&mut guard, frame, routing).and_then(|immediate| {
+            let received_at = guard.relay.now_ms;
+            let relay_acks = if routing.typ == TYPE_ACK {
+                Vec::new()
+            } else {
+                build_relay_acks(&guard.keys, routing, received_at)?
+            };
+            Ok((immediate, relay_acks))
+        })
+    };
+    let (immediate, relay_acks) = match accepted {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            println!(
+                "[server] refused AMP from={} msg_id={} code={} detail={}",
+                routing.from,
+                hex_encode(&routing.id),
+                err.code,
+                err.detail
+            );
+            return Ok(reply_error(writer, state, routing, &err)?);
+        }
     };
 
-    if let Some(rest) = input.strip_prefix("SEND ") {
-        handle_send(me, rest, writer, state)?;
-        return Ok(true);
+    for (recipient_did, recipient_writer) in immediate {
+        if let Err(err) = send_frame(&recipient_writer, frame) {
+            eprintln!(
+                "[server] immediate delivery msg_id={} to={recipient_did} failed: {err}",
+                hex_encode(&routing.id)
+            );
+        }
+    }
+    for relay_ack in relay_acks {
+        send_frame(writer, &relay_ack)?;
     }
+    wake_dispatcher(state);
+    Ok(())
+}
 
-    if input == "POLL" {
-        handle_poll(me, writer, state)?;
-        return Ok(true);
+/// Queues raw AMP bytes for every recipient and returns the connections a `ttl = 0`
+/// message must be written to now. A recipient `ACK` (`typ = ACK` with `reply_to`) is
+/// also the delivery commit for the original message.
+fn accept_wire(
+    state: &mut RelayState,
+    wire: &[u8],
+    routing: &RoutingEnvelope,
+) -> Result<Vec<(String, Writer)>, RelayError> {
+    let message = message_from_wire(wire)?;
+    let recipient_online: HashMap<String, bool> = message
+        .recipients
+        .iter()
+        .map(|r| (r.clone(), state.writers.contains_key(r)))
+        .collect();
+    state.relay.ingress(&message, &recipient_online)?;
+    println!(
+        "[server] accepted AMP typ=0x{:02x} from={} msg_id={} to={} ttl_ms={}",
+        routing.typ,
+        message.from_did,
+        message.msg_id,
+        message.recipients.join(","),
+        message.ttl_ms
+    );
+
+    if message.ttl_ms == 0 {
+        return Ok(message
+            .recipients
+            .iter()
+            .filter_map(|r| state.writers.get(r).map(|w| (r.clone(), Arc::clone(w))))
+            .collect());
     }
 
-    if let Some(rest) = input.strip_prefix("ACK ") {
-        handle_ack(me, rest, writer, state)?;
-        return Ok(true);
+    for recipient in &message.recipients {
+        let key = (message.from_did.clone(), message.msg_id.clone(), recipient.clone());
+        state.payloads.entry(key).or_insert_with(|| wire.to_vec());
     }
 
-    if input == "QUIT" {
-        send_line(writer, "OK BYE")?;
-        return Ok(false);
+    if routing.typ == TYPE_ACK {
+        if let Some(reply_to) = routing.reply_to {
+            let acked_msg_id = hex_encode(&reply_to);
+            for original_sender in &message.recipients {
+                if state
+                    .relay
+                    .ack_recipient(original_sender, &acked_msg_id, &message.from_did)
+                    .is_ok()
+                {
+                    state.payloads.remove(&(
+                        original_sender.clone(),
+                        acked_msg_id.clone(),
+                        message.from_did.clone(),
+                    ));
+                    println!(
+                        "[server] recipient ACK committed msg_id={} recipient={}",
+                        acked_msg_id, message.from_did
+                    );
+                }
+            }
+        }
     }
+    Ok(Vec::new())
+}
 
-    send_line(writer, "ERR 1001 INVALID_MESSAGE unknown command")?;
-    Ok(true)
+/// `PING`s the bound agent while the connection is idle. After too many missed `PONG`s the
+/// socket is shut down, so the read loop ends, the DID leaves `writers` and its inflight
+/// entries go back to storage.
+fn start_keepalive(did: &str, writer: &Writer, state: &Arc<Mutex<RelayState>>, liveness: &Liveness) {
+    let keys = state.lock().expect("relay state poisoned").keys.clone();
+    let pong_timeout_ms = liveness.keepalive.lock().expect("keepalive poisoned").config().pong_timeout_ms;
+    let ping_to = did.to_string();
+    let lost_did = did.to_string();
+    let ping_writer = Arc::clone(writer);
+    let lost_writer = Arc::clone(writer);
+    spawn_keepalive(
+        Arc::clone(&liveness.keepalive),
+        Arc::clone(&liveness.stop),
+        move |now| {
+            let (id, ping) = build_ping(&keys, &ping_to, pong_timeout_ms, now)?;
+            send_frame(&ping_writer, &ping).map_err(|e| {
+                RelayError::endpoint_unavailable(format!("PING to {ping_to} failed: {e}"))
+            })?;
+            Ok(id)
+        },
+        move |health| {
+            println!(
+                "[server] keepalive lost {lost_did} missed={} last_rtt_ms={:?}; closing",
+                health.missed_pongs, health.last_rtt_ms
+            );
+            let guard = lost_writer.lock().expect("writer poisoned");
+            let _ = guard.shutdown(Shutdown::Both);
+        },
+    );
 }
 
-fn handle_send(
-    me: &str,
-    rest: &str,
-    writer: &Arc<Mutex<TcpStream>>,
+fn addressed_to_relay(state: &Arc<Mutex<RelayState>>, routing: &RoutingEnvelope) -> bool {
+    let guard = state.lock().expect("relay state poisoned");
+    routing.to.iter().any(|did| *did == guard.config.relay_id)
+}
+
+/// `PING`/`PONG` for the relay itself: a `PING` is answered with a relay-signed `PONG`, a
+/// `PONG` settles the outstanding keepalive `PING`. Neither is stored or ACKed.
+fn handle_keepalive_frame(
+    frame: &[u8],
+    writer: &Writer,
     state: &Arc<Mutex<RelayState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut parts = rest.splitn(4, ' ');
-    let msg_id = parts.next().unwrap_or_default().trim();
-    let ttl_ms = parts.next().unwrap_or_default().trim();
-    let recipient = parts.next().unwrap_or_default().trim();
-    let text = parts.next().unwrap_or_default().trim();
-
-    if msg_id.is_empty() || ttl_ms.is_empty() || recipient.is_empty() || text.is_empty() {
-        send_line(
-            writer,
-            "ERR 1001 INVALID_MESSAGE SEND format: SEND <msg_id> <ttl_ms> <recipient_did> <text>",
-        )?;
-        return Ok(());
+    keepalive: &Mutex<Keepalive>,
+) -> std::io::Result<()> {
+    let now = now_ms();
+    let (message, pong) = {
+        let guard = state.lock().expect("relay state poisoned");
+        let message = match receive_and_verify(&guard.keys, frame, &guard.resolver, now) {
+            Ok(message) => message,
+            Err(err) => {
+                println!("[server] drop keepalive frame: {}", err.detail);
+                return Ok(());
+            }
+        };
+        let pong = (message.meta.typ == TYPE_PING).then(|| build_pong(&guard.keys, &message.meta, now));
+        (message, pong)
+    };
+    match (pong, message.meta.reply_to) {
+        (Some(Ok(pong)), _) => send_frame(writer, &pong),
+        (Some(Err(err)), _) => {
+            eprintln!("[server] cannot answer PING from {}: {}", message.meta.from, err.detail);
+            Ok(())
+        }
+        (None, Some(ping_id)) => {
+            let rtt = keepalive.lock().expect("keepalive poisoned").pong_received(&ping_id, now);
+            if let Some(rtt) = rtt {
+                println!("[server] PONG from {} rtt_ms={rtt}", message.meta.from);
+            }
+            Ok(())
+        }
+        (None, None) => Ok(()),
     }
+}
 
-    let ttl_ms: u64 = match ttl_ms.parse() {
-        Ok(v) => v,
-        Err(_) => {
-            send_line(writer, "ERR 1001 INVALID_MESSAGE ttl_ms must be uint")?;
-            return Ok(());
-        }
+/// Answers a refused message with a relay-signed `ERROR` to its claimed sender.
+fn reply_error(
+    writer: &Writer,
+    state: &Arc<Mutex<RelayState>>,
+    routing: &RoutingEnvelope,
+    err: &RelayError,
+) -> std::io::Result<()> {
+    let reply = {
+        let guard = state.lock().expect("relay state poisoned");
+        build_error_reply(&guard.keys, &routing.from, Some(routing.id), err, now_ms())
     };
+    match reply {
+        Ok(frame) => send_frame(writer, &frame),
+        Err(build_err) => {
+            eprintln!(
+                "[server] cannot answer {} with ERROR {}: {}",
+                routing.from, err.code, build_err.detail
+            );
+            Ok(())
+        }
+    }
+}
+
+fn wake_dispatcher(state: &Arc<Mutex<RelayState>>) {
+    let guard = state.lock().expect("relay state poisoned");
+    let _ = guard.dispatch_wake.send(());
+}
 
-    let mut deliveries = Vec::new();
-    let mut immediate_delivery: Option<Delivery> = None;
+/// Pushes stored entries to recipients holding a live connection. Runs on every wake
+/// (new message, HELLO) and on a timer as a backstop; pushed entries stay `Inflight` until
+/// ACK, and go back to `Pending` when the write fails or the connection closes.
+fn push_dispatcher(wake: Receiver<()>, state: Arc<Mutex<RelayState>>) {
+    loop {
+        match wake.recv_timeout(DISPATCH_INTERVAL) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        while wake.try_recv().is_ok() {}
+        dispatch_pending(&state);
+    }
+}
+
+fn dispatch_pending(state: &Arc<Mutex<RelayState>>) {
+    let mut pushes = Vec::new();
     {
         let mut guard = state.lock().expect("relay state poisoned");
         guard.relay.set_now(now_ms());
         guard.relay.expire();
 
-        let mut recipient_online = HashMap::new();
-        recipient_online.insert(recipient.to_string(), guard.writers.contains_key(recipient));
+        let mut online: Vec<(String, Writer)> = guard
+            .writers
+            .iter()
+            .map(|(did, writer)| (did.clone(), Arc::clone(writer)))
+            .collect();
+        online.sort_by(|a, b| a.0.cmp(&b.0));
 
-        let message = Message {
-            from_did: me.to_string(),
-            msg_id: msg_id.to_string(),
-            recipients: vec![recipient.to_string()],
-            ts_ms: guard.relay.now_ms,
-            ttl_ms,
-        };
-
-        if let Err(err) = guard.relay.ingress(&message, &recipient_online) {
-            send_line(writer, &render_relay_error(&err))?;
-            return Ok(());
+        for (recipient_did, writer) in online {
+            for (from_did, msg_id) in guard.relay.pending_pushes(&recipient_did) {
+                let key = (from_did, msg_id, recipient_did.clone());
+                let Some(wire) = guard.payloads.get(&key).cloned() else {
+                    continue;
+                };
+                if guard.relay.mark_inflight(&key.0, &key.1, &key.2).is_ok() {
+                    pushes.push((Arc::clone(&writer), key, wire));
+                }
+            }
         }
-        println!(
-            "[server] accepted SEND from={} msg_id={} to={} ttl_ms={}",
-            me, msg_id, recipient, ttl_ms
-        );
+    }
 
-        if ttl_ms == 0 {
-            if recipient_online[recipient] {
-                immediate_delivery = Some(Delivery {
-                    from_did: me.to_string(),
-                    msg_id: msg_id.to_string(),
-                    recipient_did: recipient.to_string(),
-                    text: text.to_string(),
-                });
+    for (writer, key, wire) in pushes {
+        let (from_did, msg_id, recipient_did) = &key;
+        match send_frame(&writer, &wire) {
+            Ok(()) => {
+                println!("[dispatch] pushed msg_id={msg_id} from={from_did} to={recipient_did}");
+                // ACKs are never acknowledged themselves; a written ACK is delivered.
+                if peek_routing(&wire).is_ok_and(|r| r.typ == TYPE_ACK) {
+                    let mut guard = state.lock().expect("relay state poisoned");
+                    if guard.relay.ack_recipient(from_did, msg_id, recipient_did).is_ok() {
+                        guard.payloads.remove(&key);
+                    }
+                }
             }
-        } else {
-            let key = (me.to_string(), msg_id.to_string(), recipient.to_string());
-            guard.payloads.entry(key).or_insert_with(|| text.to_string());
-            if recipient_online[recipient] {
-                deliveries = collect_deliveries_for(&mut guard, recipient);
+            Err(err) => {
+                let mut guard = state.lock().expect("relay state poisoned");
+                let _ = guard.relay.release_inflight(from_did, msg_id, recipient_did);
+                if guard
+                    .writers
+                    .get(recipient_did)
+                    .is_some_and(|current| Arc::ptr_eq(current, &writer))
+                {
+                    guard.writers.remove(recipient_did);
+                }
+                println!(
+                    "[dispatch] push failed msg_id={msg_id} to={recipient_did} kept for redelivery: {err}"
+                );
             }
         }
     }
+}
 
-    if let Some(delivery) = immediate_delivery {
-        push_delivery(&delivery, state)?;
-    }
-    for delivery in deliveries {
-        push_delivery(&delivery, state)?;
+fn serve_http(listener: TcpListener, state: Arc<Mutex<RelayState>>) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let request = match read_http_request(&mut stream) {
+                Ok(request) => request,
+                Err(err) => {
+                    eprintln!("[http] read failed: {err}");
+                    return;
+                }
+            };
+            let (status, reason, body) = route_http(&request, &state);
+            if let Err(err) = write_http_response(
+                &mut stream,
+                status,
+                reason,
+                &body,
+                &[("Content-Type", "application/cbor")],
+            ) {
+                eprintln!("[http] write failed: {err}");
+            }
+        });
     }
+}
 
-    send_line(writer, &format!("OK SEND {msg_id}"))?;
-    Ok(())
+/// Every endpoint is answered for the DID whose signature the request carries; unsigned,
+/// stale or replayed requests get `3001`.
+fn route_http(request: &HttpRequest, state: &Arc<Mutex<RelayState>>) -> (u16, &'static str, Vec<u8>) {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let principal = {
+        let mut guard = state.lock().expect("relay state poisoned");
+        let guard = &mut *guard;
+        guard.request_auth.authenticate(request, &guard.resolver, now_ms())
+    };
+    let caller = principal.clone().unwrap_or_else(|_| "unauthenticated".to_string());
+
+    match (request.method.as_str(), path) {
+        ("POST", MESSAGES_PATH) => {
+            match principal.and_then(|principal| handle_http_submit(&principal, &request.body, state)) {
+                Ok(()) => (202, "Accepted", Vec::new()),
+                Err(err) => error_response(&err, cbor_error_body(&err)),
+            }
+        }
+        ("GET", MESSAGES_PATH) => match principal {
+            Ok(principal) => (200, "OK", handle_http_poll(&principal, state)),
+            Err(err) => error_response(&err, cbor_error_body(&err)),
+        },
+        ("POST", RELAY_FORWARD_PATH) => {
+            match principal.and_then(|principal| handle_relay_forward(&principal, &request.body, state)) {
+                Ok(receipt) => (200, "OK", to_cbor(&RelayForwardResponse::accepted(receipt))),
+                Err(err) => {
+                    println!(
+                        "[federation] refused forward from={} code={} detail={}",
+                        caller, err.code, err.detail
+                    );
+                    error_response(&err, to_cbor(&RelayForwardResponse::rejected(&err)))
+                }
+            }
+        }
+        ("POST", RELAY_COMMIT_PATH) => {
+            match principal.and_then(|principal| handle_relay_commit(&principal, &request.body, state)) {
+                Ok(()) => (200, "OK", to_cbor(&RelayCommitResponse::accepted())),
+                Err(err) => {
+                    println!(
+                        "[federation] refused commit from={} code={} detail={}",
+                        caller, err.code, err.detail
+                    );
+                    error_response(&err, to_cbor(&RelayCommitResponse::rejected(&err)))
+                }
+            }
+        }
+        _ => (404, "Not Found", Vec::new()),
+    }
 }
 
-fn handle_poll(
-    me: &str,
-    writer: &Arc<Mutex<TcpStream>>,
+/// `POST /amp/v1/messages`: strict principal binding, then the same acceptance as a
+/// framed submit. The `202` carries no body, so the relay `ACK`s are queued for the
+/// sender like any other message.
+fn handle_http_submit(
+    principal: &str,
+    wire: &[u8],
     state: &Arc<Mutex<RelayState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let deliveries = {
+) -> Result<(), RelayError> {
+    let routing = peek_routing(wire)?;
+    if principal != routing.from {
+        return Err(RelayError::unauthorized(format!(
+            "strict binding failed: principal={} from={}",
+            principal, routing.from
+        )));
+    }
+
+    let immediate = {
         let mut guard = state.lock().expect("relay state poisoned");
         guard.relay.set_now(now_ms());
         guard.relay.expire();
-        collect_deliveries_for(&mut guard, me)
+        let immediate = accept_wire(&mut guard, wire, &routing)?;
+        if routing.typ != TYPE_ACK {
+            let received_at = guard.relay.now_ms;
+            for relay_ack in build_relay_acks(&guard.keys, &routing, received_at)? {
+                let ack_routing = peek_routing(&relay_ack)?;
+                accept_wire(&mut guard, &relay_ack, &ack_routing)?;
+            }
+        }
+        immediate
     };
+    for (recipient_did, writer) in immediate {
+        if let Err(err) = send_frame(&writer, wire) {
+            eprintln!("[server] immediate delivery to={recipient_did} failed: {err}");
+        }
+    }
+    wake_dispatcher(state);
+    Ok(())
+}
 
-    for delivery in &deliveries {
-        send_line(
-            writer,
-            &format!(
-                "MSG {} {} {}",
-                delivery.from_did, delivery.msg_id, delivery.text
-            ),
-        )?;
+/// `GET /amp/v1/messages`: at-least-once redelivery of the principal's raw AMP bytes
+/// until ACK.
+fn handle_http_poll(principal: &str, state: &Arc<Mutex<RelayState>>) -> Vec<u8> {
+    let mut guard = state.lock().expect("relay state poisoned");
+    guard.relay.set_now(now_ms());
+    guard.relay.expire();
+
+    let mut messages = Vec::new();
+    for (from_did, msg_id) in guard.relay.poll(principal) {
+        if let Some(wire) = guard.payloads.get(&(from_did, msg_id, principal.to_string())) {
+            messages.push(serde_bytes::ByteBuf::from(wire.clone()));
+        }
     }
+    println!("[server] http poll recipient={} returned={}", principal, messages.len());
 
-    println!("[server] poll recipient={} returned={}", me, deliveries.len());
-    send_line(writer, &format!("OK POLL {}", deliveries.len()))?;
-    Ok(())
+    to_cbor(&PollResponse {
+        messages,
+        next_cursor: None,
+        has_more: false,
+    })
 }
 
-fn handle_ack(
-    me: &str,
-    rest: &str,
-    writer: &Arc<Mutex<TcpStream>>,
+/// `POST /amp/v1/relay/forward`: validate, take custody, answer with a signed receipt.
+fn handle_relay_forward(
+    principal: &str,
+    body: &[u8],
     state: &Arc<Mutex<RelayState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut parts = rest.splitn(2, ' ');
-    let from_did = parts.next().unwrap_or_default().trim();
-    let msg_id = parts.next().unwrap_or_default().trim();
-
-    if from_did.is_empty() || msg_id.is_empty() {
-        send_line(
-            writer,
-            "ERR 1001 INVALID_MESSAGE ACK format: ACK <from_did> <msg_id>",
+) -> Result<Vec<u8>, RelayError> {
+    let mut guard = state.lock().expect("relay state poisoned");
+    let local = guard.config.relay_id.clone();
+    let (forward, wire) = decode_relay_forward(body, &local)?;
+    validate_relay_forward_principal_binding(principal, &forward, &guard.resolver)?;
+
+    let message = Message {
+        recipients: vec![forward.recipient_did.clone()],
+        ..message_from_wire(&wire)?
+    };
+    guard.relay.set_now(now_ms());
+    if let Some(next) = guard.downstream_for(&forward.recipient_did, &forward.relay_path) {
+        next_hop_forward(
+            &local,
+            &message,
+            &forward.recipient_did,
+            &next,
+            &forward.relay_path,
+            forward.hop_limit,
+            guard.config.mode,
         )?;
-        return Ok(());
     }
 
-    let mut sender_notice: Option<SenderNotice> = None;
-    {
-        let mut guard = state.lock().expect("relay state poisoned");
-        guard.relay.set_now(now_ms());
-        guard.relay.expire();
+    guard.relay.expire();
+    guard.relay.ingress(&message, &HashMap::new())?;
+    let learned_at = guard.relay.now_ms;
+    guard
+        .routing
+        .learn_route(&forward.from_did, &forward.upstream_relay, learned_at);
 
-        if let Err(err) = guard.relay.ack_recipient(from_did, msg_id, me) {
-            send_line(writer, &render_relay_error(&err))?;
-            return Ok(());
-        }
-        println!(
-            "[server] ACK from recipient={} for from={} msg_id={}",
-            me, from_did, msg_id
-        );
+    let key = (
+        forward.from_did.clone(),
+        forward.msg_id.clone(),
+        forward.recipient_did.clone(),
+    );
+    guard.payloads.entry(key.clone()).or_insert(wire);
+    println!(
+        "[federation] accepted forward msg_id={} recipient={} upstream={} hop_limit={} mode={:?}",
+        forward.msg_id,
+        forward.recipient_did,
+        forward.upstream_relay,
+        forward.hop_limit,
+        forward.transfer_mode
+    );
 
-        guard
-            .payloads
-            .remove(&(from_did.to_string(), msg_id.to_string(), me.to_string()));
-        if guard.writers.contains_key(from_did) {
-            sender_notice = Some(SenderNotice {
-                sender_did: from_did.to_string(),
-                msg_id: msg_id.to_string(),
-                recipient_did: me.to_string(),
-            });
-        }
-    }
+    let receipt = TransferReceipt {
+        receipt_v: RECEIPT_V1,
+        msg_id: forward.msg_id.clone(),
+        from_did: forward.from_did.clone(),
+        recipient_did: forward.recipient_did.clone(),
+        upstream_relay: forward.upstream_relay.clone(),
+        downstream_relay: local,
+        accepted_at: guard.relay.now_ms,
+        hop_limit_remaining: forward.hop_limit,
+        accepted: true,
+        alg: -8,
+        kid: guard.keys.assertion_kid(),
+        key_purpose: KEY_PURPOSE_ASSERTION_METHOD.to_string(),
+    };
+    let receipt = sign_transfer_receipt(&receipt, &guard.keys)?;
+    guard.inbound.entry(key).or_insert(InboundHandoff {
+        forward,
+        report_attempts: 0,
+        next_report_at: 0,
+    });
+    Ok(receipt)
+}
+
+/// `POST /amp/v1/relay/commit`: only for dual-custody transfers this relay handed off.
+fn handle_relay_commit(
+    principal: &str,
+    body: &[u8],
+    state: &Arc<Mutex<RelayState>>,
+) -> Result<(), RelayError> {
+    let report = decode_relay_commit_report(body)?;
+
+    let mut guard = state.lock().expect("relay state poisoned");
+    let receipt = verify_commit_receipt(report.commit_receipt.as_ref(), &guard.resolver)?;
+    validate_relay_commit_principal_binding(principal, &receipt, &guard.resolver)?;
 
-    if let Some(notice) = sender_notice {
-        push_sender_notice(&notice, state)?;
+    let key = (
+        receipt.from_did.clone(),
+        receipt.msg_id.clone(),
+        receipt.recipient_did.clone(),
+    );
+    let forward = guard
+        .outbound
+        .get(&key)
+        .filter(|f| f.transfer_mode == TransferMode::Dual)
+        .cloned()
+        .ok_or_else(|| RelayError::unauthorized("unknown dual-custody transfer context"))?;
+    let accepted = guard.relay.transfer_state(&key.0, &key.1, &key.2) == Some(TransferState::Accepted);
+    if !accepted {
+        return Err(RelayError::unauthorized(
+            "commit report requires a previously accepted transfer",
+        ));
     }
 
-    send_line(writer, &format!("OK ACK {msg_id}"))?;
+    guard.relay.set_now(now_ms());
+    let resolver = guard.resolver.clone();
+    guard
+        .relay
+        .apply_commit_receipt(&forward, report.commit_receipt.as_ref(), &resolver, RECEIPT_ALGS)?;
+    if receipt.result == CommitResult::Delivered {
+        guard.payloads.remove(&key);
+    }
+    println!(
+        "[federation] commit applied msg_id={} recipient={} downstream={} result={:?}",
+        receipt.msg_id, receipt.recipient_did, receipt.downstream_relay, receipt.result
+    );
     Ok(())
 }
 
-fn collect_deliveries_for(state: &mut RelayState, recipient_did: &str) -> Vec<Delivery> {
-    let mut out = Vec::new();
-    for (from_did, msg_id) in state.relay.poll(recipient_did) {
-        if let Some(text) = state
-            .payloads
-            .get(&(from_did.clone(), msg_id.clone(), recipient_did.to_string()))
-            .cloned()
-        {
-            out.push(Delivery {
+fn federation_worker(state: Arc<Mutex<RelayState>>) {
+    loop {
+        thread::sleep(FEDERATION_TICK);
+        let jobs = plan_federation_jobs(&state);
+        for job in jobs {
+            run_federation_job(job, &state);
+        }
+    }
+}
+
+/// Under the state lock: drive timeouts/retries, start handoffs for routed recipients and
+/// collect commit reports owed to upstream relays.
+fn plan_federation_jobs(state: &Arc<Mutex<RelayState>>) -> Vec<FederationJob> {
+    let mut guard = state.lock().expect("relay state poisoned");
+    let guard = &mut *guard;
+    guard.relay.set_now(now_ms());
+
+    let (routing, local, now) = (&guard.routing, &guard.config.relay_id, guard.relay.now_ms);
+    let events = guard.relay.tick(|recipient, previous| {
+        routing
+            .select_next_hop(recipient, &[previous.to_string()], now)
+            .or_else(|| routing.select_next_hop(recipient, &[], now))
+            .map(|c| c.relay_did.clone())
+            .filter(|relay| relay != local)
+    });
+
+    let mut handoffs = Vec::new();
+    for event in events {
+        match event {
+            HandoffEvent::Retried {
                 from_did,
                 msg_id,
-                recipient_did: recipient_did.to_string(),
-                text,
-            });
+                recipient_did,
+                downstream_relay,
+                attempt,
+            } => {
+                println!(
+                    "[federation] retry msg_id={msg_id} recipient={recipient_did} downstream={downstream_relay} attempt={attempt}"
+                );
+                handoffs.push(((from_did, msg_id, recipient_did), downstream_relay, false));
+            }
+            HandoffEvent::RolledBack {
+                msg_id,
+                recipient_did,
+                downstream_relay,
+                ..
+            } => println!(
+                "[federation] rolled back msg_id={msg_id} recipient={recipient_did} downstream={downstream_relay}"
+            ),
+            HandoffEvent::Exhausted {
+                msg_id,
+                recipient_did,
+                attempts,
+                ..
+            } => println!(
+                "[federation] recipient failed msg_id={msg_id} recipient={recipient_did} attempts={attempts}"
+            ),
+        }
+    }
+
+    for key in guard.relay.handoff_candidates() {
+        if !guard.payloads.contains_key(&key) {
+            continue;
+        }
+        let avoid = guard
+            .inbound
+            .get(&key)
+            .map(|h| h.forward.relay_path.clone())
+            .unwrap_or_default();
+        if let Some(downstream) = guard.downstream_for(&key.2, &avoid) {
+            handoffs.push((key, downstream, true));
+        }
+    }
+
+    let mut jobs = Vec::new();
+    for (key, downstream, first_attempt) in handoffs {
+        match build_forward_job(guard, &key, &downstream, first_attempt) {
+            Ok(job) => jobs.push(job),
+            Err(err) => {
+                println!(
+                    "[federation] handoff rejected msg_id={} recipient={} downstream={} code={} detail={}",
+                    key.1, key.2, downstream, err.code, err.detail
+                );
+                if first_attempt {
+                    let mode = guard.config.mode;
+                    let _ = guard.relay.start_handoff(&key.0, &key.1, &key.2, &downstream, mode);
+                }
+                let _ = guard
+                    .relay
+                    .reject_handoff(&key.0, &key.1, &key.2, is_retryable_handoff_error(err.code));
+            }
         }
     }
-    out
+
+    jobs.extend(plan_commit_reports(guard));
+    jobs
 }
 
-fn push_delivery(
-    delivery: &Delivery,
-    state: &Arc<Mutex<RelayState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let maybe_writer = {
-        let guard = state.lock().expect("relay state poisoned");
-        guard.writers.get(&delivery.recipient_did).cloned()
+fn build_forward_job(
+    guard: &mut RelayState,
+    key: &EntryKey,
+    downstream: &str,
+    first_attempt: bool,
+) -> Result<FederationJob, RelayError> {
+    let Some(wire) = guard.payloads.get(key).cloned() else {
+        return Err(RelayError::recipient_not_found("no wire payload to forward"));
     };
+    let message = message_from_wire(&wire)?;
+    let (relay_path, hop_limit) = guard
+        .inbound
+        .get(key)
+        .map(|h| (h.forward.relay_path.clone(), h.forward.hop_limit))
+        .unwrap_or_else(|| (Vec::new(), guard.config.hop_limit));
+    let mode = guard.config.mode;
 
-    if let Some(writer) = maybe_writer {
-        println!(
-            "[server] deliver msg_id={} from={} to={}",
-            delivery.msg_id, delivery.from_did, delivery.recipient_did
-        );
-        send_line(
-            &writer,
-            &format!(
-                "MSG {} {} {}",
-                delivery.from_did, delivery.msg_id, delivery.text
-            ),
-        )?;
+    let forward = next_hop_forward(
+        &guard.config.relay_id,
+        &message,
+        &key.2,
+        downstream,
+        &relay_path,
+        hop_limit,
+        mode,
+    )?;
+    let wrapper = encode_relay_forward(&forward, &wire)?;
+    if first_attempt {
+        guard
+            .relay
+            .start_handoff(&key.0, &key.1, &key.2, downstream, mode)?;
     }
-    Ok(())
+    guard.outbound.insert(key.clone(), forward.clone());
+
+    Ok(FederationJob::Forward {
+        addr: guard.peer_addr(downstream),
+        forward,
+        wrapper,
+    })
 }
 
-fn push_sender_notice(
-    notice: &SenderNotice,
-    state: &Arc<Mutex<RelayState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let maybe_writer = {
+fn plan_commit_reports(guard: &mut RelayState) -> Vec<FederationJob> {
+    let now = guard.relay.now_ms;
+    let mut due: Vec<EntryKey> = guard
+        .inbound
+        .iter()
+        .filter(|(_, h)| h.forward.transfer_mode == TransferMode::Dual && h.next_report_at <= now)
+        .filter(|(key, _)| {
+            guard
+                .relay
+                .recipient_state(&key.0, &key.1, &key.2)
+                .is_some_and(RecipientState::is_terminal)
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    due.sort();
+
+    let mut jobs = Vec::new();
+    for key in due {
+        let Some(handoff) = guard.inbound.get_mut(&key) else {
+            continue;
+        };
+        if handoff.report_attempts >= DEFAULT_HANDOFF_MAX_ATTEMPTS {
+            println!(
+                "[federation] commit report abandoned msg_id={} recipient={} upstream={}",
+                key.1, key.2, handoff.forward.upstream_relay
+            );
+            guard.inbound.remove(&key);
+            continue;
+        }
+        handoff.report_attempts += 1;
+        handoff.next_report_at = now.saturating_add(handoff_retry_backoff_ms(handoff.report_attempts));
+
+        let result = match guard.relay.recipient_state(&key.0, &key.1, &key.2) {
+            Some(RecipientState::Delivered) => CommitResult::Delivered,
+            Some(RecipientState::Expired) => CommitResult::Expired,
+            _ => CommitResult::Failed,
+        };
+        let forward = handoff.forward.clone();
+        let receipt = CommitReceipt {
+            commit_v: COMMIT_V1,
+            msg_id: forward.msg_id.clone(),
+            from_did: forward.from_did.clone(),
+            recipient_did: forward.recipient_did.clone(),
+            upstream_relay: forward.upstream_relay.clone(),
+            downstream_relay: guard.config.relay_id.clone(),
+            result,
+            committed_at: guard.relay.now_ms,
+            alg: -8,
+            kid: guard.keys.assertion_kid(),
+            key_purpose: KEY_PURPOSE_ASSERTION_METHOD.to_string(),
+        };
+        let report = sign_commit_receipt(&receipt, &guard.keys)
+            .and_then(|signed| encode_relay_commit_report(&signed));
+        match report {
+            Ok(report) => jobs.push(FederationJob::Commit {
+                addr: guard.peer_addr(&forward.upstream_relay),
+                upstream_relay: forward.upstream_relay,
+                key,
+                report,
+            }),
+            Err(err) => eprintln!("[federation] commit receipt signing failed: {}", err.detail),
+        }
+    }
+    jobs
+}
+
+/// Outside the state lock: talk to the peer relay, then apply the outcome.
+fn run_federation_job(job: FederationJob, state: &Arc<Mutex<RelayState>>) {
+    let keys = {
         let guard = state.lock().expect("relay state poisoned");
-        guard.writers.get(&notice.sender_did).cloned()
+        guard.keys.clone()
     };
 
-    if let Some(writer) = maybe_writer {
-        println!(
-            "[server] delivery notice msg_id={} to sender={} recipient={}",
-            notice.msg_id, notice.sender_did, notice.recipient_did
-        );
-        send_line(
-            &writer,
-            &format!("DELIVERED {} {}", notice.msg_id, notice.recipient_did),
-        )?;
+    match job {
+        FederationJob::Forward {
+            forward,
+            wrapper,
+            addr,
+        } => {
+            let response = addr
+                .ok_or_else(|| {
+                    RelayError::endpoint_unavailable(format!(
+                        "no endpoint for relay {}",
+                        forward.downstream_relay
+                    ))
+                })
+                .and_then(|addr| post_relay_forward(&addr, &keys, &wrapper));
+
+            let mut guard = state.lock().expect("relay state poisoned");
+            guard.relay.set_now(now_ms());
+            let outcome = response.and_then(|response| {
+                if !response.accepted {
+                    let code = response.error_code.unwrap_or(2003) as u16;
+                    let detail = response.error_message.unwrap_or_default();
+                    return Err(RelayError {
+                        code,
+                        ..RelayError::relay_rejected(detail)
+                    });
+                }
+                let receipt = response
+                    .receipt
+                    .ok_or_else(|| RelayError::unauthorized("accepted forward without receipt"))?;
+                let resolver = guard.resolver.clone();
+                guard
+                    .relay
+                    .apply_transfer_receipt(&forward, receipt.as_ref(), &resolver, RECEIPT_ALGS)
+            });
+
+            match outcome {
+                Ok(()) => {
+                    let key = (
+                        forward.from_did.clone(),
+                        forward.msg_id.clone(),
+                        forward.recipient_did.clone(),
+                    );
+                    if forward.transfer_mode == TransferMode::Single {
+                        guard.payloads.remove(&key);
+                    }
+                    guard.routing.mark_success(&forward.downstream_relay);
+                    println!(
+                        "[federation] handoff accepted msg_id={} recipient={} downstream={} mode={:?}",
+                        forward.msg_id, forward.recipient_did, forward.downstream_relay, forward.transfer_mode
+                    );
+                }
+                Err(err) => {
+                    println!(
+                        "[federation] handoff rejected msg_id={} recipient={} downstream={} code={} detail={}",
+                        forward.msg_id, forward.recipient_did, forward.downstream_relay, err.code, err.detail
+                    );
+                    let retryable = is_retryable_handoff_error(err.code);
+                    if retryable {
+                        let now = guard.relay.now_ms;
+                        guard.routing.mark_failure(&forward.downstream_relay, now);
+                    }
+                    let _ = guard.relay.reject_handoff(
+                        &forward.from_did,
+                        &forward.msg_id,
+                        &forward.recipient_did,
+                        retryable,
+                    );
+                }
+            }
+        }
+        FederationJob::Commit {
+            key,
+            upstream_relay,
+            report,
+            addr,
+        } => {
+            let response = addr
+                .ok_or_else(|| {
+                    RelayError::endpoint_unavailable(format!("no endpoint for relay {upstream_relay}"))
+                })
+                .and_then(|addr| post_relay_commit(&addr, &keys, &report));
+
+            let mut guard = state.lock().expect("relay state poisoned");
+            match response {
+                Ok(response) if response.accepted => {
+                    guard.inbound.remove(&key);
+                    println!(
+                        "[federation] commit reported msg_id={} recipient={} upstream={}",
+                        key.1, key.2, upstream_relay
+                    );
+                }
+                Ok(response) => println!(
+                    "[federation] commit report refused msg_id={} upstream={} code={}",
+                    key.1,
+                    upstream_relay,
+                    response.error_code.unwrap_or_default()
+                ),
+                Err(err) => println!(
+                    "[federation] commit report failed msg_id={} upstream={} code={}",
+                    key.1, upstream_relay, err.code
+                ),
+            }
+        }
     }
-    Ok(())
 }
 
-fn send_line(writer: &Arc<Mutex<TcpStream>>, line: &str) -> std::io::Result<()> {
-    let mut guard = writer.lock().expect("writer poisoned");
-    guard.write_all(line.as_bytes())?;
-    guard.write_all(b"\n")?;
-    guard.flush()?;
-    Ok(())
+fn error_response(err: &RelayError, body: Vec<u8>) -> (u16, &'static str, Vec<u8>) {
+    let (status, reason) = http_status_for(err);
+    (status, reason, body)
+}
+
+fn cbor_error_body(err: &RelayError) -> Vec<u8> {
+    to_cbor(&amp001_example::cbor_map_string_pairs(&[
+        ("code", serde_cbor::Value::Integer(err.code.into())),
+        ("name", serde_cbor::Value::Text(err.name.to_string())),
+        ("message", serde_cbor::Value::Text(err.detail.clone())),
+    ]))
+}
+
+fn to_cbor<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    serde_cbor::to_vec(value).unwrap_or_default()
 }
 
-fn render_relay_error(err: &RelayError) -> String {
-    format!("ERR {} {} {}", err.code, err.name, err.detail)
+fn send_frame(writer: &Writer, frame: &[u8]) -> std::io::Result<()> {
+    let mut guard = writer.lock().expect("writer poisoned");
+    write_frame(&mut *guard, frame)
 }
 
 fn now_ms() -> u64 {
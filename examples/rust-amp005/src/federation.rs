@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use amp001_example::{
+    cose_sign1_sign, cose_sign1_verify, hex_decode, hex_encode, now_ms, peek_routing, AgentKeys,
+    DidResolver, RelayCommitReport,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::http::{send_http_request, HttpRequest, HttpResponse};
+use crate::{
+    compute_handoff_step, split_for_federation, CommitReceipt, Message, RelayError, RelayForward,
+    TransferMode, COMMIT_V1,
+};
+
+pub const MESSAGES_PATH: &str = "/amp/v1/messages";
+pub const RELAY_FORWARD_PATH: &str = "/amp/v1/relay/forward";
+pub const RELAY_COMMIT_PATH: &str = "/amp/v1/relay/commit";
+/// Authenticated transport principal (RFC 002 §7) for the HTTP binding: hex COSE_Sign1 over
+/// the request by the principal's `assertionMethod` key.
+pub const REQUEST_SIGNATURE_HEADER: &str = "X-AMP-Request-Signature";
+/// How far a signed request's `ts_ms` may be from the receiving relay's clock.
+pub const REQUEST_SIGNATURE_WINDOW_MS: u64 = 60_000;
+pub const REQUEST_NONCE_LEN: usize = 16;
+pub const DEFAULT_HOP_LIMIT: u64 = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RelayForwardResponse {
+    pub accepted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RelayCommitResponse {
+    pub accepted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+}
+
+/// Payload of a request signature: everything the principal vouches for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SignedRequest {
+    method: String,
+    path: String,
+    ts_ms: u64,
+    nonce: ByteBuf,
+    body: ByteBuf,
+}
+
+/// Verifies [`REQUEST_SIGNATURE_HEADER`] on inbound HTTP requests and remembers the nonces it
+/// accepted until they fall out of the signature window, so a captured request cannot be
+/// replayed.
+#[derive(Debug, Default)]
+pub struct RequestAuthenticator {
+    seen: HashMap<(String, [u8; REQUEST_NONCE_LEN]), u64>,
+}
+
+impl RequestAuthenticator {
+    /// Returns the principal DID that signed `request`; `3001` when the signature is
+    /// missing, does not verify against `resolver`, covers a different request, is outside
+    /// the window or was already used.
+    pub fn authenticate(
+        &mut self,
+        request: &HttpRequest,
+        resolver: &DidResolver,
+        now_ms: u64,
+    ) -> Result<String, RelayError> {
+        let header = request
+            .header(REQUEST_SIGNATURE_HEADER)
+            .ok_or_else(|| RelayError::unauthorized("request signature is required"))?;
+        let bytes = hex_decode(header)
+            .ok_or_else(|| RelayError::invalid_message("request signature must be hex"))?;
+        let signed = cose_sign1_verify(&bytes, resolver)
+            .map_err(|e| RelayError::unauthorized(format!("request signature rejected: {}", e.detail)))?;
+        let payload: SignedRequest = serde_cbor::from_slice(&signed.payload)
+            .map_err(|e| RelayError::invalid_message(format!("invalid request signature payload: {e}")))?;
+        if payload.method != request.method
+            || payload.path != request.path
+            || payload.body.as_ref() != request.body.as_slice()
+        {
+            return Err(RelayError::unauthorized("request signature covers a different request"));
+        }
+        if payload.ts_ms.abs_diff(now_ms) > REQUEST_SIGNATURE_WINDOW_MS {
+            return Err(RelayError::unauthorized("request signature outside the time window"));
+        }
+        let nonce = <[u8; REQUEST_NONCE_LEN]>::try_from(payload.nonce.as_ref())
+            .map_err(|_| RelayError::invalid_message("request nonce must be 16 bytes"))?;
+
+        self.seen
+            .retain(|_, ts_ms| ts_ms.abs_diff(now_ms) <= REQUEST_SIGNATURE_WINDOW_MS);
+        let principal = signed.kid_did().to_string();
+        if self.seen.insert((principal.clone(), nonce), payload.ts_ms).is_some() {
+            return Err(RelayError::unauthorized("request signature replayed"));
+        }
+        Ok(principal)
+    }
+}
+
+/// Signs an HTTP request as `signer` for [`REQUEST_SIGNATURE_HEADER`].
+pub fn sign_http_request(
+    signer: &AgentKeys,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    now_ms: u64,
+) -> Result<String, RelayError> {
+    let mut nonce = [0_u8; REQUEST_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let payload = to_cbor(&SignedRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        ts_ms: now_ms,
+        nonce: ByteBuf::from(nonce.to_vec()),
+        body: ByteBuf::from(body.to_vec()),
+    })?;
+    Ok(hex_encode(&cose_sign1_sign(signer, &signer.assertion_kid(), &payload)?))
+}
+
+/// Sends a request signed by `signer`. Signing failures and transport failures map to
+/// `2002`, like any unreachable endpoint.
+pub fn send_signed_request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    signer: &AgentKeys,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<HttpResponse, RelayError> {
+    let signature = sign_http_request(signer, method, path, body, now_ms())?;
+    let mut headers = headers.to_vec();
+    headers.push((REQUEST_SIGNATURE_HEADER, signature));
+    send_http_request(addr, method, path, &headers, body)
+        .map_err(|e| RelayError::endpoint_unavailable(format!("{addr}{path}: {e}")))
+}
+
+impl RelayForwardResponse {
+    pub fn accepted(receipt: Vec<u8>) -> Self {
+        Self {
+            accepted: true,
+            receipt: Some(ByteBuf::from(receipt)),
+            error_code: None,
+            error_message: None,
+        }
+    }
+
+    pub fn rejected(err: &RelayError) -> Self {
+        Self {
+            accepted: false,
+            receipt: None,
+            error_code: Some(u64::from(err.code)),
+            error_message: Some(err.detail.clone()),
+        }
+    }
+}
+
+impl RelayCommitResponse {
+    pub fn accepted() -> Self {
+        Self {
+            accepted: true,
+            error_code: None,
+            error_message: None,
+        }
+    }
+
+    pub fn rejected(err: &RelayError) -> Self {
+        Self {
+            accepted: false,
+            error_code: Some(u64::from(err.code)),
+            error_message: Some(err.detail.clone()),
+        }
+    }
+}
+
+/// Derives the queueing view of a raw AMP message from its envelope.
+pub fn message_from_wire(wire: &[u8]) -> Result<Message, RelayError> {
+    let routing = peek_routing(wire)?;
+    Ok(Message {
+        from_did: routing.from,
+        msg_id: hex_encode(&routing.id),
+        recipients: routing.to,
+        ts_ms: routing.ts_ms,
+        ttl_ms: routing.ttl_ms,
+    })
+}
+
+/// Builds the forward for handing `recipient_did` of `message` to `downstream_relay`,
+/// applying the loop check and hop decrement for this relay. The next hop must still
+/// have at least one hop left, otherwise the handoff is refused with `2003`.
+pub fn next_hop_forward(
+    local_relay_id: &str,
+    message: &Message,
+    recipient_did: &str,
+    downstream_relay: &str,
+    relay_path: &[String],
+    hop_limit: u64,
+    mode: TransferMode,
+) -> Result<RelayForward, RelayError> {
+    let (path_next, hop_next) = compute_handoff_step(local_relay_id, relay_path, hop_limit)?;
+    if hop_next == 0 {
+        return Err(RelayError::relay_rejected("hop limit exhausted"));
+    }
+
+    let single = Message {
+        recipients: vec![recipient_did.to_string()],
+        ..message.clone()
+    };
+    let mut forwards = split_for_federation(
+        &single,
+        local_relay_id,
+        downstream_relay,
+        &path_next,
+        hop_next,
+        mode,
+    )?;
+    forwards
+        .pop()
+        .ok_or_else(|| RelayError::recipient_not_found("message has no recipient"))
+}
+
+pub fn encode_relay_forward(forward: &RelayForward, message: &[u8]) -> Result<Vec<u8>, RelayError> {
+    to_cbor(&amp001_example::RelayForward {
+        fwd_v: forward.fwd_v,
+        message: ByteBuf::from(message.to_vec()),
+        from_did: forward.from_did.clone(),
+        recipient_did: forward.recipient_did.clone(),
+        relay_path: forward.relay_path.clone(),
+        hop_limit: forward.hop_limit,
+        upstream_relay: forward.upstream_relay.clone(),
+        transfer_mode: forward.transfer_mode.into(),
+    })
+}
+
+/// Decodes an inbound `relay-forward` (RFC 002 §6.5) and applies the checks that need
+/// `local_relay_id` (RFC 003 §5.6.3). Returns the handoff context and the untouched AMP
+/// message bytes.
+pub fn decode_relay_forward(
+    bytes: &[u8],
+    local_relay_id: &str,
+) -> Result<(RelayForward, Vec<u8>), RelayError> {
+    let wrapper = amp001_example::decode_relay_forward(bytes)?;
+    if wrapper.upstream_relay == local_relay_id {
+        return Err(RelayError::unauthorized("relay-forward upstream_relay is this relay"));
+    }
+    if wrapper.relay_path.iter().any(|r| r == local_relay_id) {
+        return Err(RelayError::relay_rejected("relay loop detected"));
+    }
+
+    let forward = RelayForward {
+        fwd_v: wrapper.fwd_v,
+        msg_id: message_from_wire(wrapper.message.as_ref())?.msg_id,
+        from_did: wrapper.from_did,
+        recipient_did: wrapper.recipient_did,
+        relay_path: wrapper.relay_path,
+        hop_limit: wrapper.hop_limit,
+        upstream_relay: wrapper.upstream_relay,
+        downstream_relay: local_relay_id.to_string(),
+        transfer_mode: wrapper.transfer_mode.into(),
+    };
+    Ok((forward, wrapper.message.into_vec()))
+}
+
+pub fn encode_relay_commit_report(commit_receipt: &[u8]) -> Result<Vec<u8>, RelayError> {
+    to_cbor(&RelayCommitReport {
+        commit_v: COMMIT_V1,
+        commit_receipt: ByteBuf::from(commit_receipt.to_vec()),
+    })
+}
+
+/// RFC 002 §7.3: the forward principal must be the declared upstream relay and a
+/// trusted relay identity.
+pub fn validate_relay_forward_principal_binding(
+    principal_did: &str,
+    forward: &RelayForward,
+    resolver: &DidResolver,
+) -> Result<(), RelayError> {
+    if principal_did != forward.upstream_relay {
+        return Err(RelayError::unauthorized(format!(
+            "relay-forward binding failed: principal={} upstream_relay={}",
+            principal_did, forward.upstream_relay
+        )));
+    }
+    if !resolver.is_trusted_relay(principal_did) {
+        return Err(RelayError::unauthorized(format!(
+            "relay {principal_did} is not trusted for federation"
+        )));
+    }
+    Ok(())
+}
+
+/// RFC 002 §7.3: the commit principal must be the receipt's downstream relay.
+pub fn validate_relay_commit_principal_binding(
+    principal_did: &str,
+    receipt: &CommitReceipt,
+    resolver: &DidResolver,
+) -> Result<(), RelayError> {
+    if principal_did != receipt.downstream_relay {
+        return Err(RelayError::unauthorized(format!(
+            "relay-commit binding failed: principal={} downstream_relay={}",
+            principal_did, receipt.downstream_relay
+        )));
+    }
+    if !resolver.is_trusted_relay(principal_did) {
+        return Err(RelayError::unauthorized(format!(
+            "relay {principal_did} is not trusted for federation"
+        )));
+    }
+    Ok(())
+}
+
+/// Sends a `relay-forward` to the downstream relay at `addr`. Transport failures map to
+/// `2002`; a refusal comes back as `Ok` with `accepted = false`.
+pub fn post_relay_forward(
+    addr: &str,
+    signer: &AgentKeys,
+    wrapper: &[u8],
+) -> Result<RelayForwardResponse, RelayError> {
+    let body = post_cbor(addr, RELAY_FORWARD_PATH, signer, wrapper)?;
+    serde_cbor::from_slice(&body)
+        .map_err(|e| RelayError::invalid_message(format!("invalid relay-forward response: {e}")))
+}
+
+/// Sends a `relay-commit-report` to the upstream relay at `addr`.
+pub fn post_relay_commit(
+    addr: &str,
+    signer: &AgentKeys,
+    report: &[u8],
+) -> Result<RelayCommitResponse, RelayError> {
+    let body = post_cbor(addr, RELAY_COMMIT_PATH, signer, report)?;
+    serde_cbor::from_slice(&body)
+        .map_err(|e| RelayError::invalid_message(format!("invalid relay-commit response: {e}")))
+}
+
+/// RFC 002 §6.4 status mapping for an error hint.
+pub fn http_status_for(err: &RelayError) -> (u16, &'static str) {
+    match err.code {
+        3001 => (403, "Forbidden"),
+        2001 | 2002 => (404, "Not Found"),
+        2003 => (429, "Too Many Requests"),
+        5001 => (500, "Internal Server Error"),
+        _ => (400, "Bad Request"),
+    }
+}
+
+/// Handoff failures worth another attempt after backoff; everything else is final
+/// (RFC 003 §6.2 `FAILED` on non-retryable reject/policy/auth failure).
+pub fn is_retryable_handoff_error(code: u16) -> bool {
+    matches!(code, 2002 | 5001 | 5002 | 5003 | 5004)
+}
+
+/// This relay's identity from a hex Ed25519 seed (`--key-seed`), or a fresh random seed
+/// when none is given.
+pub fn relay_keys(relay_did: &str, seed_hex: Option<&str>) -> Result<AgentKeys, RelayError> {
+    let seed = match seed_hex {
+        Some(hex) => decode_key32(hex, "key seed")?,
+        None => {
+            let mut seed = [0_u8; 32];
+            OsRng.fill_bytes(&mut seed);
+            seed
+        }
+    };
+    Ok(AgentKeys::from_sign_seed(relay_did, seed))
+}
+
+/// Publishes a peer relay's hex Ed25519 verifying key in `resolver` and trusts it for
+/// federation.
+pub fn add_relay_key(resolver: &mut DidResolver, relay_did: &str, key_hex: &str) -> Result<(), RelayError> {
+    resolver.add_signing_key(relay_did, &decode_key32(key_hex, "relay key")?)?;
+    resolver.add_trusted_relay(relay_did);
+    Ok(())
+}
+
+fn decode_key32(hex: &str, what: &str) -> Result<[u8; 32], RelayError> {
+    hex_decode(hex)
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| RelayError::invalid_message(format!("{what} must be 32 bytes of hex")))
+}
+
+fn post_cbor(addr: &str, path: &str, signer: &AgentKeys, body: &[u8]) -> Result<Vec<u8>, RelayError> {
+    let response = send_signed_request(
+        addr,
+        "POST",
+        path,
+        signer,
+        &[
+            ("Content-Type", "application/cbor".to_string()),
+            ("Accept", "application/cbor".to_string()),
+            ("X-AMP-Transport-Version", "1".to_string()),
+        ],
+        body,
+    )?;
+    if response.body.is_empty() {
+        return Err(RelayError::endpoint_unavailable(format!(
+            "{addr}{path}: HTTP {} without body",
+            response.status
+        )));
+    }
+    Ok(response.body)
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, RelayError> {
+    serde_cbor::to_vec(value)
+        .map_err(|e| RelayError::invalid_message(format!("cbor encode failed: {e}")))
+}
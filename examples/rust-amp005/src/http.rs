@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Header lookup by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub fn read_http_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+
+    let mut buf = Vec::new();
+    let mut temp = [0_u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut temp)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before header",
+            ));
+        }
+        buf.extend_from_slice(&temp[..n]);
+        if let Some(pos) = find_subslice(&buf, b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let header_text = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = header_text.split("\r\n").filter(|v| !v.is_empty());
+    let request_line = lines
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing request line"))?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let headers = parse_headers(lines);
+
+    let content_len = content_length(&headers);
+    let mut body = buf[header_end..].to_vec();
+    while body.len() < content_len {
+        let n = stream.read(&mut temp)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&temp[..n]);
+    }
+    body.truncate(content_len);
+
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub fn write_http_response(
+    stream: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    body: &[u8],
+    extra_headers: &[(&str, &str)],
+) -> io::Result<()> {
+    let mut resp = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    for (k, v) in extra_headers {
+        resp.push_str(&format!("{k}: {v}\r\n"));
+    }
+    resp.push_str("\r\n");
+
+    stream.write_all(resp.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+pub fn send_http_request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<HttpResponse> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for (k, v) in headers {
+        req.push_str(&format!("{k}: {v}\r\n"));
+    }
+    req.push_str("\r\n");
+
+    stream.write_all(req.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    let header_end = find_subslice(&buf, b"\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid http response"))?
+        + 4;
+
+    let header_text = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = header_text.split("\r\n").filter(|v| !v.is_empty());
+    let status = lines
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .nth(1)
+        .and_then(|v| v.parse::<u16>().ok())
+        .unwrap_or(0);
+    let headers = parse_headers(lines);
+
+    let mut body = buf[header_end..].to_vec();
+    body.truncate(content_length(&headers));
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((k, v)) = line.split_once(':') {
+            headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+        }
+    }
+    headers
+}
+
+fn content_length(headers: &HashMap<String, String>) -> usize {
+    headers
+        .get("content-length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -1,10 +1,34 @@
 use std::collections::{HashMap, HashSet};
 
+use amp001_example::{
+    cose_sign1_sign, cose_sign1_verify, hex_decode, hex_encode, AgentKeys, AmpError, DidResolver,
+    COSE_ALG_EDDSA,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+mod amps;
+mod federation;
+mod http;
+mod outbox;
+mod routing;
+mod session;
+
+pub use amps::*;
+pub use federation::*;
+pub use http::{read_http_request, send_http_request, write_http_response, HttpRequest, HttpResponse};
+pub use outbox::*;
+pub use routing::*;
+pub use session::*;
+
 pub const FWD_V1: u64 = 1;
 pub const RECEIPT_V1: u64 = 1;
 pub const COMMIT_V1: u64 = 1;
 pub const DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS: u64 = 5_000;
 pub const DEFAULT_HANDOFF_MAX_ATTEMPTS: u8 = 3;
+pub const DEFAULT_HANDOFF_RETRY_BACKOFF_MS: u64 = 1_000;
+pub const MAX_HANDOFF_RETRY_BACKOFF_MS: u64 = 30_000;
+pub const KEY_PURPOSE_ASSERTION_METHOD: &str = "assertionMethod";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RelayError {
@@ -71,6 +95,26 @@ impl RelayError {
     }
 }
 
+impl From<AmpError> for RelayError {
+    fn from(err: AmpError) -> Self {
+        match err.code {
+            1001 => Self::invalid_message(err.detail),
+            1004 => Self::unsupported_version(err.detail),
+            _ => Self::unauthorized(err.detail),
+        }
+    }
+}
+
+impl From<RelayError> for AmpError {
+    fn from(err: RelayError) -> Self {
+        AmpError {
+            code: err.code,
+            name: err.name,
+            detail: err.detail,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub from_did: String,
@@ -113,12 +157,31 @@ pub enum QueueStatus {
     Rejected,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransferMode {
     Single,
     Dual,
 }
 
+impl From<TransferMode> for amp001_example::TransferMode {
+    fn from(mode: TransferMode) -> Self {
+        match mode {
+            TransferMode::Single => Self::Single,
+            TransferMode::Dual => Self::Dual,
+        }
+    }
+}
+
+impl From<amp001_example::TransferMode> for TransferMode {
+    fn from(mode: amp001_example::TransferMode) -> Self {
+        match mode {
+            amp001_example::TransferMode::Single => Self::Single,
+            amp001_example::TransferMode::Dual => Self::Dual,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransferState {
     None,
@@ -135,6 +198,31 @@ pub enum CommitResult {
     Expired,
 }
 
+/// Outcome of one handoff transition taken by [`Relay::tick`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandoffEvent {
+    RolledBack {
+        from_did: String,
+        msg_id: String,
+        recipient_did: String,
+        downstream_relay: String,
+        attempts: u8,
+    },
+    Retried {
+        from_did: String,
+        msg_id: String,
+        recipient_did: String,
+        downstream_relay: String,
+        attempt: u8,
+    },
+    Exhausted {
+        from_did: String,
+        msg_id: String,
+        recipient_did: String,
+        attempts: u8,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct RelayForward {
     pub fwd_v: u64,
@@ -179,6 +267,35 @@ pub struct CommitReceipt {
     pub key_purpose: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct TransferReceiptPayload {
+    receipt_v: u64,
+    msg_id: ByteBuf,
+    from_did: String,
+    recipient_did: String,
+    upstream_relay: String,
+    downstream_relay: String,
+    accepted_at: u64,
+    hop_limit_remaining: u64,
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason_code: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitReceiptPayload {
+    commit_v: u64,
+    msg_id: ByteBuf,
+    from_did: String,
+    recipient_did: String,
+    upstream_relay: String,
+    downstream_relay: String,
+    result: String,
+    committed_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason_code: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct RecipientEntry {
     pub state: RecipientState,
@@ -232,7 +349,8 @@ impl Relay {
                 "message recipients must not be empty",
             ));
         }
-        if self.now_ms > message.expires_at() {
+        // TTL=0 has no storage lifetime; only the immediate-delivery rule applies.
+        if message.ttl_ms > 0 && self.now_ms > message.expires_at() {
             return Err(RelayError::message_expired("ingress message already expired"));
         }
 
@@ -294,7 +412,13 @@ impl Relay {
         let mut out = Vec::new();
         for ((from, msg), record) in &mut self.records {
             if let Some(entry) = record.recipients.get_mut(recipient_did) {
-                if matches!(entry.state, RecipientState::Pending | RecipientState::Inflight) {
+                let handed_off = matches!(
+                    entry.transfer_state,
+                    TransferState::Pending | TransferState::Accepted | TransferState::CommitReported
+                );
+                if !handed_off
+                    && matches!(entry.state, RecipientState::Pending | RecipientState::Inflight)
+                {
                     entry.state = RecipientState::Inflight;
                     out.push((from.clone(), msg.clone()));
                 }
@@ -303,6 +427,70 @@ impl Relay {
         out
     }
 
+    /// `Pending` entries for `recipient_did` that a push dispatcher may deliver now, oldest
+    /// first. Entries already pushed (`Inflight`) or handed off are left alone.
+    pub fn pending_pushes(&self, recipient_did: &str) -> Vec<(String, String)> {
+        let mut out: Vec<(u64, String, String)> = self
+            .records
+            .iter()
+            .filter_map(|((from, msg), record)| {
+                let entry = record.recipients.get(recipient_did)?;
+                let handed_off = matches!(
+                    entry.transfer_state,
+                    TransferState::Pending | TransferState::Accepted | TransferState::CommitReported
+                );
+                (entry.state == RecipientState::Pending && !handed_off)
+                    .then(|| (record.accepted_at, from.clone(), msg.clone()))
+            })
+            .collect();
+        out.sort();
+        out.into_iter().map(|(_, from, msg)| (from, msg)).collect()
+    }
+
+    /// `Pending -> Inflight` once a push has been handed to the recipient connection.
+    pub fn mark_inflight(
+        &mut self,
+        from_did: &str,
+        msg_id: &str,
+        recipient_did: &str,
+    ) -> Result<(), RelayError> {
+        let entry = self.entry_mut(from_did, msg_id, recipient_did)?;
+        if entry.state == RecipientState::Pending {
+            entry.state = RecipientState::Inflight;
+        }
+        Ok(())
+    }
+
+    /// `Inflight -> Pending` after a failed push, so the entry stays stored for the next
+    /// push or poll.
+    pub fn release_inflight(
+        &mut self,
+        from_did: &str,
+        msg_id: &str,
+        recipient_did: &str,
+    ) -> Result<(), RelayError> {
+        let entry = self.entry_mut(from_did, msg_id, recipient_did)?;
+        if entry.state == RecipientState::Inflight {
+            entry.state = RecipientState::Pending;
+        }
+        Ok(())
+    }
+
+    /// Returns every unacknowledged `Inflight` entry of `recipient_did` to `Pending` (the
+    /// recipient connection went away); yields how many were released.
+    pub fn release_inflight_for(&mut self, recipient_did: &str) -> usize {
+        let mut released = 0;
+        for record in self.records.values_mut() {
+            if let Some(entry) = record.recipients.get_mut(recipient_did) {
+                if entry.state == RecipientState::Inflight {
+                    entry.state = RecipientState::Pending;
+                    released += 1;
+                }
+            }
+        }
+        released
+    }
+
     pub fn ack_recipient(
         &mut self,
         from_did: &str,
@@ -362,13 +550,62 @@ impl Relay {
         Ok(())
     }
 
+    /// Rolls back a pending handoff the downstream relay refused. Retryable refusals are
+    /// left to [`Relay::tick`]; anything else fails the recipient (RFC 003 §6.2).
+    pub fn reject_handoff(
+        &mut self,
+        from_did: &str,
+        msg_id: &str,
+        recipient_did: &str,
+        retryable: bool,
+    ) -> Result<(), RelayError> {
+        let record = self
+            .records
+            .get_mut(&(from_did.to_string(), msg_id.to_string()))
+            .ok_or_else(|| RelayError::recipient_not_found("queue record not found"))?;
+        let entry = record
+            .recipients
+            .get_mut(recipient_did)
+            .ok_or_else(|| RelayError::recipient_not_found("recipient state not found"))?;
+
+        if entry.transfer_state != TransferState::Pending {
+            return Ok(());
+        }
+        entry.transfer_state = TransferState::RolledBack;
+        entry.last_transfer_change_ms = self.now_ms;
+        if !retryable && !entry.state.is_terminal() {
+            entry.state = RecipientState::Failed;
+        }
+        Self::refresh_record_status(record);
+        Ok(())
+    }
+
+    /// Non-terminal `(from_did, msg_id, recipient_did)` entries that have never been handed
+    /// off, in key order.
+    pub fn handoff_candidates(&self) -> Vec<(String, String, String)> {
+        let mut out = Vec::new();
+        for ((from, msg), record) in &self.records {
+            for (recipient, entry) in &record.recipients {
+                if !entry.state.is_terminal() && entry.transfer_state == TransferState::None {
+                    out.push((from.clone(), msg.clone(), recipient.clone()));
+                }
+            }
+        }
+        out.sort();
+        out
+    }
+
+    /// Verifies a COSE_Sign1 transfer receipt and, if it matches `forward`, records custody
+    /// acceptance (RFC 003 §4.1-§4.2).
     pub fn apply_transfer_receipt(
         &mut self,
         forward: &RelayForward,
-        receipt: &TransferReceipt,
+        receipt_bytes: &[u8],
+        resolver: &DidResolver,
         supported_algs: &[i32],
     ) -> Result<(), RelayError> {
-        validate_transfer_receipt(forward, receipt, supported_algs)?;
+        let receipt = verify_transfer_receipt(receipt_bytes, resolver)?;
+        validate_transfer_receipt(forward, &receipt, supported_algs)?;
 
         let record = self
             .records
@@ -387,13 +624,16 @@ impl Relay {
         Ok(())
     }
 
+    /// Verifies a COSE_Sign1 commit receipt and applies its terminal result (RFC 003 §4.3-§4.4).
     pub fn apply_commit_receipt(
         &mut self,
         forward: &RelayForward,
-        receipt: &CommitReceipt,
+        receipt_bytes: &[u8],
+        resolver: &DidResolver,
         supported_algs: &[i32],
     ) -> Result<(), RelayError> {
-        validate_commit_receipt(forward, receipt, supported_algs)?;
+        let receipt = verify_commit_receipt(receipt_bytes, resolver)?;
+        validate_commit_receipt(forward, &receipt, supported_algs)?;
 
         let record = self
             .records
@@ -438,16 +678,92 @@ impl Relay {
             .get_mut(recipient_did)
             .ok_or_else(|| RelayError::recipient_not_found("recipient state not found"))?;
 
-        if entry.transfer_state != TransferState::Pending {
-            return Ok(());
-        }
+        Self::roll_back_timed_out(entry, self.now_ms);
+        Ok(())
+    }
+
+    /// Drives federation handoffs forward at the current `now_ms` (RFC 003 §5.6.7).
+    ///
+    /// Pending transfers past the accept timeout are rolled back first. A rolled-back
+    /// recipient is retried once its backoff has elapsed, towards the relay returned by
+    /// `select_downstream(recipient_did, previous_downstream)`; `None` keeps the copy
+    /// local. After `DEFAULT_HANDOFF_MAX_ATTEMPTS` the recipient becomes `Failed`.
+    /// Records are visited in key order so repeated runs yield the same events.
+    pub fn tick<F>(&mut self, mut select_downstream: F) -> Vec<HandoffEvent>
+    where
+        F: FnMut(&str, &str) -> Option<String>,
+    {
+        self.expire();
+
+        let mut keys: Vec<(String, String)> = self.records.keys().cloned().collect();
+        keys.sort();
 
-        let elapsed = self.now_ms.saturating_sub(entry.last_transfer_change_ms);
-        if elapsed >= DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS {
-            entry.transfer_state = TransferState::RolledBack;
-            entry.last_transfer_change_ms = self.now_ms;
+        let mut events = Vec::new();
+        for key in keys {
+            let Some(record) = self.records.get_mut(&key) else {
+                continue;
+            };
+            let mut recipients: Vec<String> = record.recipients.keys().cloned().collect();
+            recipients.sort();
+
+            for recipient_did in recipients {
+                let Some(entry) = record.recipients.get_mut(&recipient_did) else {
+                    continue;
+                };
+                if entry.state.is_terminal() {
+                    continue;
+                }
+                let previous = entry.downstream_relay.clone().unwrap_or_default();
+
+                if Self::roll_back_timed_out(entry, self.now_ms) {
+                    events.push(HandoffEvent::RolledBack {
+                        from_did: key.0.clone(),
+                        msg_id: key.1.clone(),
+                        recipient_did: recipient_did.clone(),
+                        downstream_relay: previous.clone(),
+                        attempts: entry.handoff_attempts,
+                    });
+                }
+
+                if entry.transfer_state != TransferState::RolledBack {
+                    continue;
+                }
+                if entry.handoff_attempts >= DEFAULT_HANDOFF_MAX_ATTEMPTS {
+                    entry.state = RecipientState::Failed;
+                    events.push(HandoffEvent::Exhausted {
+                        from_did: key.0.clone(),
+                        msg_id: key.1.clone(),
+                        recipient_did: recipient_did.clone(),
+                        attempts: entry.handoff_attempts,
+                    });
+                    continue;
+                }
+
+                let due = entry
+                    .last_transfer_change_ms
+                    .saturating_add(handoff_retry_backoff_ms(entry.handoff_attempts));
+                if self.now_ms < due {
+                    continue;
+                }
+                let Some(downstream_relay) = select_downstream(&recipient_did, &previous) else {
+                    continue;
+                };
+
+                entry.transfer_state = TransferState::Pending;
+                entry.downstream_relay = Some(downstream_relay.clone());
+                entry.last_transfer_change_ms = self.now_ms;
+                entry.handoff_attempts = entry.handoff_attempts.saturating_add(1);
+                events.push(HandoffEvent::Retried {
+                    from_did: key.0.clone(),
+                    msg_id: key.1.clone(),
+                    recipient_did: recipient_did.clone(),
+                    downstream_relay,
+                    attempt: entry.handoff_attempts,
+                });
+            }
+            Self::refresh_record_status(record);
         }
-        Ok(())
+        events
     }
 
     pub fn recipient_state(
@@ -498,6 +814,31 @@ impl Relay {
             .map(|r| r.recipients.len())
     }
 
+    fn entry_mut(
+        &mut self,
+        from_did: &str,
+        msg_id: &str,
+        recipient_did: &str,
+    ) -> Result<&mut RecipientEntry, RelayError> {
+        self.records
+            .get_mut(&(from_did.to_string(), msg_id.to_string()))
+            .ok_or_else(|| RelayError::recipient_not_found("queue record not found"))?
+            .recipients
+            .get_mut(recipient_did)
+            .ok_or_else(|| RelayError::recipient_not_found("recipient state not found"))
+    }
+
+    /// Rolls a `Pending` transfer back once the accept timeout has passed; true when it did.
+    fn roll_back_timed_out(entry: &mut RecipientEntry, now_ms: u64) -> bool {
+        let elapsed = now_ms.saturating_sub(entry.last_transfer_change_ms);
+        if entry.transfer_state != TransferState::Pending || elapsed < DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS {
+            return false;
+        }
+        entry.transfer_state = TransferState::RolledBack;
+        entry.last_transfer_change_ms = now_ms;
+        true
+    }
+
     fn refresh_record_status(record: &mut QueueRecord) {
         if !record.recipients.values().all(|e| e.state.is_terminal()) {
             return;
@@ -543,6 +884,15 @@ pub fn split_for_federation(
     Ok(out)
 }
 
+/// Backoff before the next handoff attempt after `attempts` failed ones:
+/// `1000, 2000, 4000, ...` capped at `30000` (RFC 003 §5.6.7).
+pub fn handoff_retry_backoff_ms(attempts: u8) -> u64 {
+    let exponent = u32::from(attempts.saturating_sub(1)).min(16);
+    DEFAULT_HANDOFF_RETRY_BACKOFF_MS
+        .saturating_mul(1u64 << exponent)
+        .min(MAX_HANDOFF_RETRY_BACKOFF_MS)
+}
+
 pub fn compute_handoff_step(
     local_relay_id: &str,
     relay_path: &[String],
@@ -572,7 +922,7 @@ pub fn validate_transfer_receipt(
     if receipt.receipt_v != RECEIPT_V1 {
         return Err(RelayError::unsupported_version("unsupported transfer receipt version"));
     }
-    if !supported_algs.iter().any(|a| *a == receipt.alg) {
+    if !supported_algs.contains(&receipt.alg) {
         return Err(RelayError::unauthorized("unsupported transfer receipt algorithm"));
     }
     if receipt.key_purpose != "assertionMethod" {
@@ -611,7 +961,7 @@ pub fn validate_commit_receipt(
     if receipt.commit_v != COMMIT_V1 {
         return Err(RelayError::unsupported_version("unsupported commit receipt version"));
     }
-    if !supported_algs.iter().any(|a| *a == receipt.alg) {
+    if !supported_algs.contains(&receipt.alg) {
         return Err(RelayError::unauthorized("unsupported commit receipt algorithm"));
     }
     if receipt.key_purpose != "assertionMethod" {
@@ -635,3 +985,169 @@ pub fn validate_commit_receipt(
 
     Ok(())
 }
+
+/// Signs `receipt` as a COSE_Sign1 transfer receipt with the downstream relay's
+/// `assertionMethod` key. `receipt.kid` must name a key under `signer.did`.
+pub fn sign_transfer_receipt(
+    receipt: &TransferReceipt,
+    signer: &AgentKeys,
+) -> Result<Vec<u8>, RelayError> {
+    check_receipt_signer(&receipt.downstream_relay, receipt.alg, &receipt.kid, signer)?;
+    let payload = TransferReceiptPayload {
+        receipt_v: receipt.receipt_v,
+        msg_id: ByteBuf::from(msg_id_to_bytes(&receipt.msg_id)?),
+        from_did: receipt.from_did.clone(),
+        recipient_did: receipt.recipient_did.clone(),
+        upstream_relay: receipt.upstream_relay.clone(),
+        downstream_relay: receipt.downstream_relay.clone(),
+        accepted_at: receipt.accepted_at,
+        hop_limit_remaining: receipt.hop_limit_remaining,
+        status: if receipt.accepted { "accepted" } else { "rejected" }.to_string(),
+        reason_code: None,
+    };
+    let payload = serde_cbor::to_vec(&payload)
+        .map_err(|e| RelayError::invalid_message(format!("transfer receipt encode failed: {e}")))?;
+    Ok(cose_sign1_sign(signer, &receipt.kid, &payload)?)
+}
+
+/// Verifies a COSE_Sign1 transfer receipt against the `assertionMethod` key its `kid`
+/// resolves to and decodes the payload. Tuple checks are left to
+/// [`validate_transfer_receipt`].
+pub fn verify_transfer_receipt(
+    receipt_bytes: &[u8],
+    resolver: &DidResolver,
+) -> Result<TransferReceipt, RelayError> {
+    let signed = cose_sign1_verify(receipt_bytes, resolver)?;
+    let payload: TransferReceiptPayload = serde_cbor::from_slice(&signed.payload)
+        .map_err(|e| RelayError::invalid_message(format!("invalid transfer receipt payload: {e}")))?;
+    if signed.kid_did() != payload.downstream_relay {
+        return Err(RelayError::unauthorized(
+            "transfer receipt kid must resolve under downstream_relay",
+        ));
+    }
+    let accepted = match payload.status.as_str() {
+        "accepted" => true,
+        "rejected" => false,
+        other => {
+            return Err(RelayError::invalid_message(format!(
+                "unknown transfer receipt status {other}"
+            )))
+        }
+    };
+
+    Ok(TransferReceipt {
+        receipt_v: payload.receipt_v,
+        msg_id: msg_id_from_bytes(payload.msg_id.as_ref())?,
+        from_did: payload.from_did,
+        recipient_did: payload.recipient_did,
+        upstream_relay: payload.upstream_relay,
+        downstream_relay: payload.downstream_relay,
+        accepted_at: payload.accepted_at,
+        hop_limit_remaining: payload.hop_limit_remaining,
+        accepted,
+        alg: cose_alg_to_i32(signed.alg)?,
+        kid: signed.kid,
+        key_purpose: KEY_PURPOSE_ASSERTION_METHOD.to_string(),
+    })
+}
+
+/// Signs `receipt` as a COSE_Sign1 commit receipt with the downstream relay's
+/// `assertionMethod` key.
+pub fn sign_commit_receipt(receipt: &CommitReceipt, signer: &AgentKeys) -> Result<Vec<u8>, RelayError> {
+    check_receipt_signer(&receipt.downstream_relay, receipt.alg, &receipt.kid, signer)?;
+    let result = match receipt.result {
+        CommitResult::Delivered => "delivered",
+        CommitResult::Failed => "failed",
+        CommitResult::Expired => "expired",
+    };
+    let payload = CommitReceiptPayload {
+        commit_v: receipt.commit_v,
+        msg_id: ByteBuf::from(msg_id_to_bytes(&receipt.msg_id)?),
+        from_did: receipt.from_did.clone(),
+        recipient_did: receipt.recipient_did.clone(),
+        upstream_relay: receipt.upstream_relay.clone(),
+        downstream_relay: receipt.downstream_relay.clone(),
+        result: result.to_string(),
+        committed_at: receipt.committed_at,
+        reason_code: None,
+    };
+    let payload = serde_cbor::to_vec(&payload)
+        .map_err(|e| RelayError::invalid_message(format!("commit receipt encode failed: {e}")))?;
+    Ok(cose_sign1_sign(signer, &receipt.kid, &payload)?)
+}
+
+/// Verifies a COSE_Sign1 commit receipt and decodes the payload. Tuple checks are left to
+/// [`validate_commit_receipt`].
+pub fn verify_commit_receipt(
+    receipt_bytes: &[u8],
+    resolver: &DidResolver,
+) -> Result<CommitReceipt, RelayError> {
+    let signed = cose_sign1_verify(receipt_bytes, resolver)?;
+    let payload: CommitReceiptPayload = serde_cbor::from_slice(&signed.payload)
+        .map_err(|e| RelayError::invalid_message(format!("invalid commit receipt payload: {e}")))?;
+    if signed.kid_did() != payload.downstream_relay {
+        return Err(RelayError::unauthorized(
+            "commit receipt kid must resolve under downstream_relay",
+        ));
+    }
+    let result = match payload.result.as_str() {
+        "delivered" => CommitResult::Delivered,
+        "failed" => CommitResult::Failed,
+        "expired" => CommitResult::Expired,
+        other => {
+            return Err(RelayError::invalid_message(format!(
+                "unknown commit receipt result {other}"
+            )))
+        }
+    };
+
+    Ok(CommitReceipt {
+        commit_v: payload.commit_v,
+        msg_id: msg_id_from_bytes(payload.msg_id.as_ref())?,
+        from_did: payload.from_did,
+        recipient_did: payload.recipient_did,
+        upstream_relay: payload.upstream_relay,
+        downstream_relay: payload.downstream_relay,
+        result,
+        committed_at: payload.committed_at,
+        alg: cose_alg_to_i32(signed.alg)?,
+        kid: signed.kid,
+        key_purpose: KEY_PURPOSE_ASSERTION_METHOD.to_string(),
+    })
+}
+
+fn check_receipt_signer(
+    downstream_relay: &str,
+    alg: i32,
+    kid: &str,
+    signer: &AgentKeys,
+) -> Result<(), RelayError> {
+    if i64::from(alg) != COSE_ALG_EDDSA {
+        return Err(RelayError::unauthorized(format!(
+            "receipt signing supports alg {COSE_ALG_EDDSA} only, got {alg}"
+        )));
+    }
+    if signer.did != downstream_relay || kid.split('#').next() != Some(downstream_relay) {
+        return Err(RelayError::unauthorized(
+            "receipt must be signed by a downstream_relay assertionMethod key",
+        ));
+    }
+    Ok(())
+}
+
+fn msg_id_to_bytes(msg_id: &str) -> Result<Vec<u8>, RelayError> {
+    hex_decode(msg_id)
+        .filter(|bytes| bytes.len() == 16)
+        .ok_or_else(|| RelayError::invalid_message("msg_id must be 16 bytes (32 hex chars)"))
+}
+
+fn msg_id_from_bytes(bytes: &[u8]) -> Result<String, RelayError> {
+    if bytes.len() != 16 {
+        return Err(RelayError::invalid_message("receipt msg_id must be 16 bytes"));
+    }
+    Ok(hex_encode(bytes))
+}
+
+fn cose_alg_to_i32(alg: i64) -> Result<i32, RelayError> {
+    i32::try_from(alg).map_err(|_| RelayError::unauthorized("receipt alg out of range"))
+}
@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+
+use amp001_example::{
+    peek_routing, validate_ack_semantics, validate_proc_semantics, AckBody, AckSource,
+    DidResolver, ErrorBody, ProcOutcome, ReceivedMessage, TYPE_ACK, TYPE_ERROR, TYPE_PROC_FAIL,
+    TYPE_PROC_OK,
+};
+
+use crate::RelayError;
+
+pub const DEFAULT_RELAY_ACK_TIMEOUT_MS: u64 = 5_000;
+pub const DEFAULT_OUTBOX_MAX_ATTEMPTS: u32 = 3;
+
+/// Where one recipient's copy of an outgoing message stands, as far as the sender can
+/// tell from the replies it has seen (RFC 001 §16.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryState {
+    Submitted,
+    /// A trusted relay stored the message; not yet delivered.
+    RelayAcked,
+    /// The recipient's agent received the message.
+    RecipientAcked,
+    ProcOk,
+    ProcFail,
+    Expired,
+    /// An `ERROR` answered the message.
+    Rejected,
+}
+
+impl DeliveryState {
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            DeliveryState::ProcOk
+                | DeliveryState::ProcFail
+                | DeliveryState::Expired
+                | DeliveryState::Rejected
+        )
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            DeliveryState::Submitted => 0,
+            DeliveryState::RelayAcked => 1,
+            DeliveryState::RecipientAcked => 2,
+            _ => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStatus {
+    pub state: DeliveryState,
+    pub updated_at: u64,
+    /// `(code, message)` of the `ERROR` or `PROC_FAIL` reason for this copy.
+    pub error: Option<(u16, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub msg_id: [u8; 16],
+    pub wire: Vec<u8>,
+    pub recipients: BTreeMap<String, RecipientStatus>,
+    pub submitted_at: u64,
+    pub expires_at: u64,
+    pub attempts: u32,
+    pub last_attempt_at: u64,
+}
+
+impl OutboxEntry {
+    pub fn is_settled(&self) -> bool {
+        self.recipients
+            .values()
+            .all(|s| s.state.is_terminal() || s.state == DeliveryState::RecipientAcked)
+    }
+}
+
+/// One state transition, handed to every callback registered with [`Outbox::on_change`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryUpdate {
+    pub msg_id: [u8; 16],
+    pub recipient: String,
+    pub previous: DeliveryState,
+    pub state: DeliveryState,
+}
+
+type UpdateCallback = Box<dyn FnMut(&DeliveryUpdate) + Send>;
+
+/// Sender-side delivery tracking: every submitted message id per recipient through relay
+/// ACK, recipient ACK and `PROC_OK`/`PROC_FAIL`, correlated by `reply_to` and
+/// `ack_target`. States only move forward; a late relay ACK never undoes a delivery.
+pub struct Outbox {
+    entries: HashMap<[u8; 16], OutboxEntry>,
+    callbacks: Vec<UpdateCallback>,
+    relay_ack_timeout_ms: u64,
+    max_attempts: u32,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            callbacks: Vec::new(),
+            relay_ack_timeout_ms: DEFAULT_RELAY_ACK_TIMEOUT_MS,
+            max_attempts: DEFAULT_OUTBOX_MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn with_relay_ack_timeout(mut self, timeout_ms: u64) -> Self {
+        self.relay_ack_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Total submissions per message, the first one included.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn on_change(&mut self, callback: impl FnMut(&DeliveryUpdate) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Starts tracking `wire` as submitted at `now_ms`. Submitting the same id again
+    /// keeps the existing entry.
+    pub fn submit(&mut self, wire: &[u8], now_ms: u64) -> Result<[u8; 16], RelayError> {
+        let routing = peek_routing(wire)?;
+        if routing.to.is_empty() {
+            return Err(RelayError::recipient_not_found(
+                "message recipients must not be empty",
+            ));
+        }
+        self.entries.entry(routing.id).or_insert_with(|| OutboxEntry {
+            msg_id: routing.id,
+            wire: wire.to_vec(),
+            recipients: routing
+                .to
+                .iter()
+                .map(|did| {
+                    let status = RecipientStatus {
+                        state: DeliveryState::Submitted,
+                        updated_at: now_ms,
+                        error: None,
+                    };
+                    (did.clone(), status)
+                })
+                .collect(),
+            submitted_at: now_ms,
+            expires_at: routing.ts_ms.saturating_add(routing.ttl_ms),
+            attempts: 1,
+            last_attempt_at: now_ms,
+        });
+        Ok(routing.id)
+    }
+
+    /// Applies a verified `ACK`, `PROC_OK`, `PROC_FAIL` or `ERROR` to the entry named by its
+    /// `reply_to`. Messages that answer nothing in the outbox return no updates; replies
+    /// that break RFC 001 §16.1 source rules are refused.
+    pub fn observe(
+        &mut self,
+        reply: &ReceivedMessage,
+        resolver: &DidResolver,
+        now_ms: u64,
+    ) -> Result<Vec<DeliveryUpdate>, RelayError> {
+        let Some(reply_to) = reply.meta.reply_to else {
+            return Ok(Vec::new());
+        };
+        let Some(entry) = self.entries.get_mut(&reply_to) else {
+            return Ok(Vec::new());
+        };
+        let original_to: Vec<String> = entry.recipients.keys().cloned().collect();
+        let from = &reply.meta.from;
+
+        let (targets, state, error) = match reply.meta.typ {
+            TYPE_ACK => {
+                validate_ack_semantics(reply, &original_to, resolver)?;
+                let body: AckBody = reply.decode_body()?;
+                match body.ack_source {
+                    AckSource::Relay => {
+                        let targets = match body.ack_target {
+                            Some(target) => vec![target],
+                            None => original_to.clone(),
+                        };
+                        (targets, DeliveryState::RelayAcked, None)
+                    }
+                    AckSource::Recipient => {
+                        (vec![from.clone()], DeliveryState::RecipientAcked, None)
+                    }
+                }
+            }
+            TYPE_PROC_OK | TYPE_PROC_FAIL => {
+                match validate_proc_semantics(reply, &reply_to, &original_to)? {
+                    ProcOutcome::Ok(_) => (vec![from.clone()], DeliveryState::ProcOk, None),
+                    ProcOutcome::Fail(body) => {
+                        let error = body.reason().map(|e| (e.code, e.message));
+                        (vec![from.clone()], DeliveryState::ProcFail, error)
+                    }
+                }
+            }
+            TYPE_ERROR => {
+                let body: ErrorBody = reply.decode_body()?;
+                // A recipient only rejects its own copy; a trusted relay rejects every copy
+                // it has not seen delivered.
+                let targets = if entry.recipients.contains_key(from) {
+                    vec![from.clone()]
+                } else if resolver.is_trusted_relay(from) {
+                    original_to.clone()
+                } else {
+                    return Err(RelayError::unauthorized(format!(
+                        "ERROR from {from}, which is neither a recipient nor a trusted relay"
+                    )));
+                };
+                (targets, DeliveryState::Rejected, Some((body.code, body.message)))
+            }
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut updates = Vec::new();
+        for target in targets {
+            let Some(status) = entry.recipients.get_mut(&target) else {
+                return Err(RelayError::invalid_message(format!(
+                    "ack_target {target} is not a recipient of the original message"
+                )));
+            };
+            let previous = status.state;
+            if advance(status, state, now_ms) {
+                status.error = error.clone();
+                updates.push(DeliveryUpdate {
+                    msg_id: reply_to,
+                    recipient: target,
+                    previous,
+                    state,
+                });
+            }
+        }
+        self.notify(&updates);
+        Ok(updates)
+    }
+
+    /// Wire messages to submit again because some recipient's copy got no relay ACK within
+    /// the timeout. Each call counts as one more attempt; entries that used every attempt
+    /// wait for expiry.
+    pub fn retries_due(&mut self, now_ms: u64) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        for entry in self.entries.values_mut() {
+            let waiting = entry
+                .recipients
+                .values()
+                .any(|s| s.state == DeliveryState::Submitted);
+            if !waiting
+                || entry.attempts >= self.max_attempts
+                || now_ms >= entry.expires_at
+                || now_ms < entry.last_attempt_at.saturating_add(self.relay_ack_timeout_ms)
+            {
+                continue;
+            }
+            entry.attempts += 1;
+            entry.last_attempt_at = now_ms;
+            due.push(entry.wire.clone());
+        }
+        due
+    }
+
+    /// Marks copies that were never delivered before the message TTL ran out as
+    /// `Expired`.
+    pub fn expire(&mut self, now_ms: u64) -> Vec<DeliveryUpdate> {
+        let mut updates = Vec::new();
+        for entry in self.entries.values_mut() {
+            if now_ms <= entry.expires_at {
+                continue;
+            }
+            for (recipient, status) in &mut entry.recipients {
+                if status.state.rank() < DeliveryState::RecipientAcked.rank() {
+                    let previous = status.state;
+                    status.state = DeliveryState::Expired;
+                    status.updated_at = now_ms;
+                    updates.push(DeliveryUpdate {
+                        msg_id: entry.msg_id,
+                        recipient: recipient.clone(),
+                        previous,
+                        state: DeliveryState::Expired,
+                    });
+                }
+            }
+        }
+        self.notify(&updates);
+        updates
+    }
+
+    pub fn status(&self, msg_id: &[u8; 16], recipient: &str) -> Option<DeliveryState> {
+        self.entries
+            .get(msg_id)?
+            .recipients
+            .get(recipient)
+            .map(|s| s.state)
+    }
+
+    pub fn entry(&self, msg_id: &[u8; 16]) -> Option<&OutboxEntry> {
+        self.entries.get(msg_id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &OutboxEntry> {
+        self.entries.values()
+    }
+
+    /// Drops entries whose every copy is delivered or terminal.
+    pub fn prune_settled(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_settled());
+        before - self.entries.len()
+    }
+
+    fn notify(&mut self, updates: &[DeliveryUpdate]) {
+        for update in updates {
+            for callback in &mut self.callbacks {
+                callback(update);
+            }
+        }
+    }
+}
+
+/// Moves `status` to `next` unless that would go backwards or leave a terminal state. An
+/// `ERROR` never overrides a recipient ACK.
+fn advance(status: &mut RecipientStatus, next: DeliveryState, now_ms: u64) -> bool {
+    if status.state.is_terminal() || next.rank() <= status.state.rank() {
+        return false;
+    }
+    if next == DeliveryState::Rejected && status.state == DeliveryState::RecipientAcked {
+        return false;
+    }
+    status.state = next;
+    status.updated_at = now_ms;
+    true
+}
@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::{split_for_federation, Message, RelayError, RelayForward, TransferMode, DEFAULT_HOP_LIMIT};
+
+pub const SERVICE_TYPE_AGENT_MESSAGING_RELAY: &str = "AgentMessagingRelay";
+pub const DEFAULT_ROUTE_FAILURE_THRESHOLD: u32 = 3;
+pub const DEFAULT_ROUTE_COOLDOWN_MS: u64 = 30_000;
+pub const DEFAULT_LEARNED_ROUTE_TTL_MS: u64 = 3_600_000;
+
+/// `relay-capabilities` from an `AgentMessagingRelay` service entry (RFC 008 §4.1).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayCapabilities {
+    pub federation: bool,
+    #[serde(default)]
+    pub relay_forward_endpoint: Option<String>,
+    #[serde(default)]
+    pub transfer_modes: Vec<TransferMode>,
+    #[serde(default)]
+    pub max_hop_limit: Option<u64>,
+    #[serde(default)]
+    pub default_hop_limit: Option<u64>,
+    #[serde(default)]
+    pub receipt_algs: Vec<i32>,
+}
+
+impl RelayCapabilities {
+    /// RFC 008 §4.1 rules for a descriptor that advertises `federation = true`.
+    pub fn validate(&self) -> Result<(), RelayError> {
+        if !self.federation {
+            return Ok(());
+        }
+        if self.relay_forward_endpoint.as_deref().unwrap_or_default().is_empty() {
+            return Err(RelayError::invalid_message(
+                "federation=true requires relayForwardEndpoint",
+            ));
+        }
+        if self.transfer_modes.is_empty() {
+            return Err(RelayError::invalid_message("federation=true requires transferModes"));
+        }
+        let Some(max_hop_limit) = self.max_hop_limit else {
+            return Err(RelayError::invalid_message("federation=true requires maxHopLimit"));
+        };
+        if self.default_hop_limit() > max_hop_limit {
+            return Err(RelayError::invalid_message(
+                "defaultHopLimit must be <= maxHopLimit",
+            ));
+        }
+        if !self.receipt_algs.contains(&-8) {
+            return Err(RelayError::invalid_message("receiptAlgs must include -8"));
+        }
+        Ok(())
+    }
+
+    pub fn default_hop_limit(&self) -> u64 {
+        self.default_hop_limit.unwrap_or(DEFAULT_HOP_LIMIT)
+    }
+}
+
+/// One `service` entry of a DID Document (RFC 008 §4).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidService {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub service_type: String,
+    pub service_endpoint: String,
+    #[serde(default)]
+    pub relay_capabilities: Option<RelayCapabilities>,
+}
+
+/// The parts of a DID Document the routing table reads.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DidDocumentServices {
+    pub id: String,
+    #[serde(default)]
+    pub service: Vec<DidService>,
+}
+
+/// What this relay needs from a downstream relay before forwarding to it
+/// (RFC 008 §5 candidate filter).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationRequirements {
+    pub transfer_mode: TransferMode,
+    pub receipt_algs: Vec<i32>,
+    pub hop_limit: u64,
+}
+
+impl Default for FederationRequirements {
+    fn default() -> Self {
+        Self {
+            transfer_mode: TransferMode::Single,
+            receipt_algs: vec![-8],
+            hop_limit: DEFAULT_HOP_LIMIT,
+        }
+    }
+}
+
+impl FederationRequirements {
+    fn check(&self, caps: &RelayCapabilities) -> Result<(), String> {
+        if !caps.federation {
+            return Err("federation=false".to_string());
+        }
+        caps.validate().map_err(|err| err.detail)?;
+        if !caps.transfer_modes.contains(&self.transfer_mode) {
+            return Err(format!("transfer mode {:?} not offered", self.transfer_mode));
+        }
+        if !caps.receipt_algs.iter().any(|alg| self.receipt_algs.contains(alg)) {
+            return Err("no common receipt alg".to_string());
+        }
+        if caps.max_hop_limit.unwrap_or_default() < self.hop_limit {
+            return Err(format!(
+                "maxHopLimit {} below required {}",
+                caps.max_hop_limit.unwrap_or_default(),
+                self.hop_limit
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RouteSource {
+    Static,
+    DidDocument,
+    Learned,
+}
+
+/// One next-hop candidate for a recipient. Lower `priority` wins; ties prefer
+/// static config over DID Document entries over learned routes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteCandidate {
+    pub relay_did: String,
+    pub endpoint: Option<String>,
+    pub priority: u32,
+    pub source: RouteSource,
+    pub expires_at: Option<u64>,
+}
+
+impl RouteCandidate {
+    /// `host:port` of an `http://` forward endpoint, for plain-TCP HTTP clients.
+    pub fn http_authority(&self) -> Option<&str> {
+        let rest = self.endpoint.as_deref()?.strip_prefix("http://")?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        (!authority.is_empty()).then_some(authority)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct RelayHealth {
+    consecutive_failures: u32,
+    down_until: u64,
+}
+
+/// Recipients of one message grouped by where they go next.
+#[derive(Debug, Clone, Default)]
+pub struct RoutePlan {
+    /// Recipients homed on this relay.
+    pub local: Vec<String>,
+    /// Per-recipient forwards keyed by downstream relay DID.
+    pub forwards: BTreeMap<String, Vec<RelayForward>>,
+    /// Recipients without a usable route.
+    pub unroutable: Vec<String>,
+}
+
+/// Maps recipient DIDs to next-hop relays. Candidates come from static config, DID
+/// Document `AgentMessagingRelay` services (RFC 008 §4.1) and routes learned from
+/// accepted handoffs; relays that keep failing are skipped until their cooldown ends.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    pub local_relay_id: String,
+    pub failure_threshold: u32,
+    pub cooldown_ms: u64,
+    pub learned_ttl_ms: u64,
+    routes: HashMap<String, Vec<RouteCandidate>>,
+    health: HashMap<String, RelayHealth>,
+}
+
+impl RoutingTable {
+    pub fn new(local_relay_id: impl Into<String>) -> Self {
+        Self {
+            local_relay_id: local_relay_id.into(),
+            failure_threshold: DEFAULT_ROUTE_FAILURE_THRESHOLD,
+            cooldown_ms: DEFAULT_ROUTE_COOLDOWN_MS,
+            learned_ttl_ms: DEFAULT_LEARNED_ROUTE_TTL_MS,
+            routes: HashMap::new(),
+            health: HashMap::new(),
+        }
+    }
+
+    pub fn add_static_route(
+        &mut self,
+        recipient_did: &str,
+        relay_did: &str,
+        endpoint: Option<String>,
+        priority: u32,
+    ) {
+        self.upsert(
+            recipient_did,
+            RouteCandidate {
+                relay_did: relay_did.to_string(),
+                endpoint,
+                priority,
+                source: RouteSource::Static,
+                expires_at: None,
+            },
+        );
+    }
+
+    /// Adds one candidate per federation-capable `AgentMessagingRelay` service of the
+    /// recipient's DID Document, in document order starting at `priority`. Services that
+    /// fail `requirements` are skipped; the skip reasons are returned.
+    pub fn add_did_document_routes(
+        &mut self,
+        document: &DidDocumentServices,
+        requirements: &FederationRequirements,
+        priority: u32,
+    ) -> Result<Vec<String>, RelayError> {
+        let mut skipped = Vec::new();
+        let mut added = 0_u32;
+        for service in &document.service {
+            if service.service_type != SERVICE_TYPE_AGENT_MESSAGING_RELAY {
+                continue;
+            }
+            let Some(caps) = &service.relay_capabilities else {
+                skipped.push(format!("{}: relayCapabilities absent", service.id));
+                continue;
+            };
+            if let Err(reason) = requirements.check(caps) {
+                skipped.push(format!("{}: {reason}", service.id));
+                continue;
+            }
+            let relay_did = did_web_for_endpoint(&service.service_endpoint)?;
+            self.upsert(
+                &document.id,
+                RouteCandidate {
+                    relay_did,
+                    endpoint: caps.relay_forward_endpoint.clone(),
+                    priority: priority.saturating_add(added),
+                    source: RouteSource::DidDocument,
+                    expires_at: None,
+                },
+            );
+            added += 1;
+        }
+        Ok(skipped)
+    }
+
+    /// Remembers that `recipient_did` was reachable through `relay_did` (e.g. the
+    /// upstream relay of an accepted forward from that DID). Learned routes never
+    /// replace configured ones and expire after `learned_ttl_ms`.
+    pub fn learn_route(&mut self, recipient_did: &str, relay_did: &str, now_ms: u64) {
+        if relay_did == self.local_relay_id {
+            return;
+        }
+        let candidates = self.routes.entry(recipient_did.to_string()).or_default();
+        if let Some(existing) = candidates.iter_mut().find(|c| c.relay_did == relay_did) {
+            if existing.source == RouteSource::Learned {
+                existing.expires_at = Some(now_ms.saturating_add(self.learned_ttl_ms));
+            }
+            return;
+        }
+        candidates.push(RouteCandidate {
+            relay_did: relay_did.to_string(),
+            endpoint: None,
+            priority: u32::MAX,
+            source: RouteSource::Learned,
+            expires_at: Some(now_ms.saturating_add(self.learned_ttl_ms)),
+        });
+    }
+
+    pub fn mark_success(&mut self, relay_did: &str) {
+        self.health.remove(relay_did);
+    }
+
+    /// Counts a failed handoff; after `failure_threshold` in a row the relay is down for
+    /// `cooldown_ms`.
+    pub fn mark_failure(&mut self, relay_did: &str, now_ms: u64) {
+        let health = self.health.entry(relay_did.to_string()).or_default();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.failure_threshold {
+            health.down_until = now_ms.saturating_add(self.cooldown_ms);
+        }
+    }
+
+    pub fn is_healthy(&self, relay_did: &str, now_ms: u64) -> bool {
+        self.health
+            .get(relay_did)
+            .is_none_or(|health| now_ms >= health.down_until)
+    }
+
+    /// Live candidates for `recipient_did`, best first. Unhealthy relays are dropped.
+    pub fn next_hops(&self, recipient_did: &str, now_ms: u64) -> Vec<&RouteCandidate> {
+        let mut candidates: Vec<&RouteCandidate> = self
+            .routes
+            .get(recipient_did)
+            .into_iter()
+            .flatten()
+            .filter(|c| c.expires_at.is_none_or(|at| now_ms < at))
+            .filter(|c| self.is_healthy(&c.relay_did, now_ms))
+            .collect();
+        candidates.sort_by(|a, b| {
+            (a.priority, a.source, &a.relay_did).cmp(&(b.priority, b.source, &b.relay_did))
+        });
+        candidates
+    }
+
+    /// Best live candidate that is not in `avoid` (previous downstream, relays already
+    /// on the path).
+    pub fn select_next_hop(
+        &self,
+        recipient_did: &str,
+        avoid: &[String],
+        now_ms: u64,
+    ) -> Option<&RouteCandidate> {
+        self.next_hops(recipient_did, now_ms)
+            .into_iter()
+            .find(|c| !avoid.contains(&c.relay_did))
+    }
+
+    /// Groups the recipients of `message` by next hop and builds the per-recipient
+    /// forwards for each downstream relay. `relay_path`/`hop_limit` are the values to put
+    /// on the wire (see `compute_handoff_step`); relays already on the path are skipped.
+    pub fn plan_forwards(
+        &self,
+        message: &Message,
+        relay_path: &[String],
+        hop_limit: u64,
+        mode: TransferMode,
+        now_ms: u64,
+    ) -> Result<RoutePlan, RelayError> {
+        let mut plan = RoutePlan::default();
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for recipient in &message.recipients {
+            if self
+                .select_next_hop(recipient, &[], now_ms)
+                .is_some_and(|c| c.relay_did == self.local_relay_id)
+            {
+                plan.local.push(recipient.clone());
+                continue;
+            }
+            match self.select_next_hop(recipient, relay_path, now_ms) {
+                Some(c) => groups
+                    .entry(c.relay_did.clone())
+                    .or_default()
+                    .push(recipient.clone()),
+                None => plan.unroutable.push(recipient.clone()),
+            }
+        }
+
+        for (downstream, recipients) in groups {
+            let group = Message {
+                recipients,
+                ..message.clone()
+            };
+            let forwards = split_for_federation(
+                &group,
+                &self.local_relay_id,
+                &downstream,
+                relay_path,
+                hop_limit,
+                mode,
+            )?;
+            plan.forwards.insert(downstream, forwards);
+        }
+        Ok(plan)
+    }
+
+    /// Configured (non-learned) relay DIDs, sorted and deduplicated.
+    pub fn known_relays(&self) -> Vec<String> {
+        let mut relays: Vec<String> = self
+            .routes
+            .values()
+            .flatten()
+            .filter(|c| c.source != RouteSource::Learned)
+            .map(|c| c.relay_did.clone())
+            .collect();
+        relays.sort();
+        relays.dedup();
+        relays
+    }
+
+    /// Any candidate for `relay_did` that carries a forward endpoint.
+    pub fn endpoint_for(&self, relay_did: &str) -> Option<&RouteCandidate> {
+        self.routes
+            .values()
+            .flatten()
+            .find(|c| c.relay_did == relay_did && c.endpoint.is_some())
+    }
+
+    fn upsert(&mut self, recipient_did: &str, candidate: RouteCandidate) {
+        let candidates = self.routes.entry(recipient_did.to_string()).or_default();
+        match candidates
+            .iter_mut()
+            .find(|c| c.relay_did == candidate.relay_did)
+        {
+            Some(existing) if existing.source >= candidate.source => *existing = candidate,
+            Some(_) => {}
+            None => candidates.push(candidate),
+        }
+    }
+}
+
+/// `did:web` identifier for a relay `serviceEndpoint` URL (`https://relay.example.com`
+/// -> `did:web:relay.example.com`, port percent-encoded, path segments colon-joined).
+pub fn did_web_for_endpoint(endpoint: &str) -> Result<String, RelayError> {
+    let rest = endpoint
+        .strip_prefix("https://")
+        .or_else(|| endpoint.strip_prefix("http://"))
+        .ok_or_else(|| RelayError::invalid_message(format!("unsupported relay endpoint {endpoint}")))?;
+    let mut segments = rest.split('/').filter(|s| !s.is_empty());
+    let host = segments
+        .next()
+        .ok_or_else(|| RelayError::invalid_message(format!("relay endpoint {endpoint} has no host")))?;
+
+    let mut did = format!("did:web:{}", host.replace(':', "%3A"));
+    for segment in segments {
+        did.push(':');
+        did.push_str(segment);
+    }
+    Ok(did)
+}
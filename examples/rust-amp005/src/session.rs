@@ -0,0 +1,208 @@
+use amp001_example::{
+    build_plain_signed, make_message_id, now_ms, read_frame, receive_and_verify, write_frame,
+    AgentKeys, AmpError, Connection, DidResolver, ErrorBody, Handshake, HandshakeOutcome,
+    HelloAckBody, MessageMeta, Recipients, TYPE_ERROR, TYPE_HELLO, TYPE_HELLO_ACK,
+    TYPE_HELLO_REJECT,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::RelayError;
+
+pub const SESSION_NONCE_LEN: usize = 32;
+pub const DEFAULT_SESSION_CHALLENGE_TTL_MS: u64 = 30_000;
+
+/// Body of an AMP `HELLO` used as a session proof: the usual version list plus the
+/// relay-issued nonce it answers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionHelloBody {
+    pub versions: Vec<String>,
+    pub challenge: ByteBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChallengeFrame {
+    relay_did: String,
+    nonce: ByteBuf,
+}
+
+/// One-shot nonce a relay hands to a fresh connection; the connection is bound to a DID
+/// only after that DID's authentication key answers it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionChallenge {
+    pub relay_did: String,
+    pub nonce: [u8; SESSION_NONCE_LEN],
+    pub issued_at: u64,
+    pub ttl_ms: u64,
+}
+
+impl SessionChallenge {
+    pub fn new(relay_did: impl Into<String>, now_ms: u64) -> Self {
+        let mut nonce = [0_u8; SESSION_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        Self {
+            relay_did: relay_did.into(),
+            nonce,
+            issued_at: now_ms,
+            ttl_ms: DEFAULT_SESSION_CHALLENGE_TTL_MS,
+        }
+    }
+
+    /// Transport handshake frame sent before any AMP message (RFC 002 §3.1):
+    /// CBOR `{"relay_did": tstr, "nonce": bstr}`.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let frame = ChallengeFrame {
+            relay_did: self.relay_did.clone(),
+            nonce: ByteBuf::from(self.nonce.to_vec()),
+        };
+        serde_cbor::to_vec(&frame).unwrap_or_default()
+    }
+
+    pub fn from_frame(bytes: &[u8]) -> Result<Self, RelayError> {
+        let frame: ChallengeFrame = serde_cbor::from_slice(bytes)
+            .map_err(|e| RelayError::invalid_message(format!("invalid challenge frame: {e}")))?;
+        let nonce = <[u8; SESSION_NONCE_LEN]>::try_from(frame.nonce.as_ref())
+            .map_err(|_| RelayError::invalid_message("challenge nonce must be 32 bytes"))?;
+        Ok(Self {
+            relay_did: frame.relay_did,
+            nonce,
+            issued_at: 0,
+            ttl_ms: DEFAULT_SESSION_CHALLENGE_TTL_MS,
+        })
+    }
+
+    /// Verifies an AMP-signed `HELLO` addressed to this relay that echoes the nonce, and
+    /// returns the sender DID it proves with the versions it offers.
+    pub fn verify_signed_hello(
+        &self,
+        wire: &[u8],
+        relay_keys: &AgentKeys,
+        resolver: &DidResolver,
+        now_ms: u64,
+    ) -> Result<(String, Vec<String>), RelayError> {
+        self.check_fresh(now_ms)?;
+        let received = receive_and_verify(relay_keys, wire, resolver, now_ms)
+            .map_err(|e| RelayError::unauthorized(format!("session proof rejected: {}", e.detail)))?;
+        if received.meta.typ != TYPE_HELLO {
+            return Err(RelayError::invalid_message("session proof must be a HELLO"));
+        }
+        let body: SessionHelloBody = received.decode_body()?;
+        if body.challenge.as_ref() != self.nonce {
+            return Err(RelayError::unauthorized("HELLO answers a different challenge"));
+        }
+        Ok((received.meta.from, body.versions))
+    }
+
+    fn check_fresh(&self, now_ms: u64) -> Result<(), RelayError> {
+        if now_ms > self.issued_at.saturating_add(self.ttl_ms) {
+            return Err(RelayError::unauthorized("session challenge expired"));
+        }
+        Ok(())
+    }
+}
+
+/// Client side of the AMP proof: a signed `HELLO` to the relay carrying the nonce.
+pub fn build_session_hello(
+    agent: &AgentKeys,
+    challenge: &SessionChallenge,
+    versions: &[String],
+    now_ms: u64,
+) -> Result<Vec<u8>, RelayError> {
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(now_ms, u64::from_be_bytes(challenge.nonce[..8].try_into().unwrap_or_default())),
+        typ: TYPE_HELLO,
+        ts_ms: now_ms,
+        ttl_ms: challenge.ttl_ms,
+        from: String::new(),
+        to: Recipients::One(challenge.relay_did.clone()),
+        reply_to: None,
+        thread_id: None,
+    };
+    let body = SessionHelloBody {
+        versions: versions.to_vec(),
+        challenge: ByteBuf::from(challenge.nonce.to_vec()),
+    };
+    Ok(build_plain_signed(agent, meta, &body)?)
+}
+
+/// [`Handshake`] for an AMPS relay session: answers the connection challenge with a signed
+/// `HELLO` and waits for `HELLO_ACK`. The challenge frame is unauthenticated, so the relay
+/// is the configured `relay_did`, never the DID the frame names; its key must already be in
+/// the resolver (see [`add_relay_key`](crate::add_relay_key)) so its ACKs verify.
+#[derive(Debug, Clone)]
+pub struct SessionHandshake {
+    pub relay_did: String,
+    pub versions: Vec<String>,
+}
+
+impl Handshake for SessionHandshake {
+    fn open(
+        &self,
+        keys: &AgentKeys,
+        resolver: &mut DidResolver,
+        connection: &mut Connection,
+    ) -> Result<HandshakeOutcome, AmpError> {
+        let unreachable =
+            |e: std::io::Error| AmpError::endpoint_unreachable(format!("session handshake: {e}"));
+        let frame = read_frame(&mut connection.reader).map_err(unreachable)?;
+        let challenge = SessionChallenge::from_frame(&frame)?;
+        if challenge.relay_did != self.relay_did {
+            return Err(AmpError::unauthorized(format!(
+                "challenge names relay {}, expected {}",
+                challenge.relay_did, self.relay_did
+            )));
+        }
+        if resolver.signing_key_for(&self.relay_did).is_none() {
+            return Err(AmpError::unauthorized(format!(
+                "relay {} has no key in the resolver",
+                self.relay_did
+            )));
+        }
+
+        let hello = build_session_hello(keys, &challenge, &self.versions, now_ms())?;
+        write_frame(&mut connection.writer, &hello).map_err(unreachable)?;
+        loop {
+            let frame = read_frame(&mut connection.reader).map_err(unreachable)?;
+            let reply = receive_and_verify(keys, &frame, resolver, now_ms())?;
+            match reply.meta.typ {
+                TYPE_HELLO_ACK => {
+                    let body: HelloAckBody = reply.decode_body()?;
+                    return Ok(HandshakeOutcome {
+                        version: Some(body.selected),
+                        peer_did: Some(self.relay_did.clone()),
+                    });
+                }
+                TYPE_HELLO_REJECT => {
+                    return Err(AmpError::unsupported_version(format!(
+                        "relay supports none of {:?}",
+                        self.versions
+                    )));
+                }
+                TYPE_ERROR => {
+                    let body: ErrorBody = reply.decode_body()?;
+                    return Err(AmpError::unauthorized(format!(
+                        "session proof refused: {} {}",
+                        body.code, body.message
+                    )));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Strict binding for an authenticated session: everything the connection submits must
+/// be from the DID it proved (RFC 002 §7.3, as for HTTP principals).
+pub fn validate_session_principal_binding(
+    session_did: &str,
+    amp_from_did: &str,
+) -> Result<(), RelayError> {
+    if session_did != amp_from_did {
+        return Err(RelayError::unauthorized(format!(
+            "strict binding failed: principal={session_did} from={amp_from_did}"
+        )));
+    }
+    Ok(())
+}
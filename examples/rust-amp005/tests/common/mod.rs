@@ -0,0 +1,51 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+use amp001_example::{hex_encode, AgentKeys, DidResolver};
+use serde_cbor::Value;
+
+/// Fixed identity for a relay a test talks to; spawned relays get it through
+/// [`relay_key_args`].
+pub fn relay_keys(relay_did: &str) -> AgentKeys {
+    let mut seed = [0x5a_u8; 32];
+    for (idx, byte) in relay_did.bytes().enumerate() {
+        seed[idx % 32] = seed[idx % 32].rotate_left(3) ^ byte;
+    }
+    AgentKeys::from_sign_seed(relay_did, seed)
+}
+
+/// `--key-seed` for an `amp005-server` that should sign as [`relay_keys`].
+pub fn relay_key_args(relay_did: &str) -> [String; 2] {
+    ["--key-seed".to_string(), hex_encode(relay_keys(relay_did).signing_key.as_bytes())]
+}
+
+/// `--relay-key` publishing the [`relay_keys`] of `relay_did` to a spawned relay.
+pub fn peer_key_args(relay_did: &str) -> [String; 2] {
+    let public = hex_encode(relay_keys(relay_did).signing_public_key.as_bytes());
+    ["--relay-key".to_string(), format!("{relay_did}={public}")]
+}
+
+/// Demo agents plus the trusted relays in `relay_dids`.
+pub fn resolver_trusting<'a>(relay_dids: impl IntoIterator<Item = &'a str>) -> DidResolver {
+    let mut resolver = amp001_example::demo_agents().resolver();
+    for did in relay_dids {
+        resolver.add_agent(&relay_keys(did));
+        resolver.add_trusted_relay(did);
+    }
+    resolver
+}
+
+/// `cose` with its protected `alg` header replaced, leaving the signature as it was.
+pub fn with_protected_alg(cose: &[u8], alg: i64) -> Vec<u8> {
+    let Value::Array(mut items) = serde_cbor::from_slice(cose).expect("COSE array") else {
+        panic!("COSE_Sign1 must be an array");
+    };
+    let Value::Bytes(protected) = &items[0] else {
+        panic!("protected header must be bstr");
+    };
+    let mut headers: BTreeMap<i64, Value> = serde_cbor::from_slice(protected).expect("headers");
+    headers.insert(1, Value::Integer(alg.into()));
+    items[0] = Value::Bytes(serde_cbor::to_vec(&headers).expect("encode headers"));
+    serde_cbor::to_vec(&Value::Array(items)).expect("encode COSE")
+}
@@ -0,0 +1,147 @@
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    demo_agents, AckBody, AckSource, Agent, AgentHandle, AmpError, ProcOkBody, TYPE_ACK,
+    TYPE_PROC_OK,
+};
+use amp005_rfc003_tests::SessionHandshake;
+use common::{relay_key_args, resolver_trusting};
+
+const RELAY: &str = "did:web:example.com:relay:store";
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_server() -> Server {
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+        listener.local_addr().expect("local addr").to_string()
+    };
+    let child = Command::new(env!("CARGO_BIN_EXE_amp005-server"))
+        .arg(&addr)
+        .args(relay_key_args(RELAY))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp005-server");
+
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Server { child, addr }
+}
+
+fn handshake() -> SessionHandshake {
+    SessionHandshake {
+        relay_did: RELAY.to_string(),
+        versions: vec!["1.0".to_string()],
+    }
+}
+
+#[test]
+fn rfc003_agents_exchange_messages_through_a_session_relay() {
+    let server = spawn_server();
+    let agents = demo_agents();
+
+    let (texts_tx, texts) = mpsc::channel();
+    let bob = Agent::builder(agents.bob.clone())
+        .resolver(resolver_trusting([RELAY]))
+        .connect_tcp(server.addr.clone())
+        .handshake(handshake())
+        .report_processing(true)
+        .on_message(move |_, message, body| {
+            texts_tx.send((message.meta.from.clone(), body.msg)).expect("text");
+            Ok(())
+        })
+        .build()
+        .expect("bob");
+    let (bob, bob_run) = bob.spawn();
+
+    let (replies_tx, replies) = mpsc::channel();
+    let proc_tx = replies_tx.clone();
+    let alice = Agent::builder(agents.alice.clone())
+        .resolver(resolver_trusting([RELAY]))
+        .connect_tcp(server.addr.clone())
+        .handshake(handshake())
+        .on(TYPE_ACK, move |_, message, ack: AckBody| {
+            replies_tx.send((TYPE_ACK, Some(ack.ack_source), message.meta.reply_to)).expect("ack");
+            Ok(())
+        })
+        .on(TYPE_PROC_OK, move |_, message, _: ProcOkBody| {
+            proc_tx.send((TYPE_PROC_OK, None, message.meta.reply_to)).expect("proc");
+            Ok(())
+        })
+        .build()
+        .expect("alice");
+    let (alice, alice_run) = alice.spawn();
+
+    for agent in [&alice, &bob] {
+        assert!(agent.wait_connected(WAIT), "{} has a session", agent.did());
+        assert_eq!(agent.version().as_deref(), Some("1.0"));
+    }
+
+    let id = alice.send_text(&agents.bob.did, "hello via agent", 60_000).expect("send");
+    let (from, text) = texts.recv_timeout(WAIT).expect("bob receives");
+    assert_eq!((from.as_str(), text.as_str()), (agents.alice.did.as_str(), "hello via agent"));
+
+    let mut seen = Vec::new();
+    while seen.len() < 3 {
+        let (typ, source, reply_to) = replies.recv_timeout(WAIT).expect("reply for alice");
+        assert_eq!(reply_to, Some(id));
+        seen.push((typ, source));
+    }
+    seen.sort_by_key(|(typ, source)| (*typ, *source == Some(AckSource::Recipient)));
+    assert_eq!(
+        seen,
+        vec![
+            (TYPE_ACK, Some(AckSource::Relay)),
+            (TYPE_ACK, Some(AckSource::Recipient)),
+            (TYPE_PROC_OK, None),
+        ]
+    );
+
+    stop(alice, alice_run);
+    stop(bob, bob_run);
+}
+
+#[test]
+fn rfc003_agent_without_a_common_version_stops_with_1004() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let agent = Agent::builder(agents.alice.clone())
+        .resolver(resolver_trusting([RELAY]))
+        .connect_tcp(server.addr.clone())
+        .handshake(SessionHandshake {
+            relay_did: RELAY.to_string(),
+            versions: vec!["9.0".to_string()],
+        })
+        .build()
+        .expect("agent");
+    let handle = agent.handle();
+    let err = agent.run().expect_err("no compatible version");
+    assert_eq!(err.code, 1004);
+    assert!(!handle.is_connected());
+}
+
+fn stop(agent: AgentHandle, run: thread::JoinHandle<Result<(), AmpError>>) {
+    agent.shutdown();
+    run.join().expect("agent thread").expect("clean stop");
+}
@@ -1,16 +1,22 @@
+mod common;
+
 use std::collections::HashMap;
 
+use amp001_example::AgentKeys;
 use amp005_rfc003_tests::{
-    compute_handoff_step, split_for_federation, CommitReceipt, CommitResult, Message, QueueStatus,
-    RecipientState, Relay, TransferMode, TransferReceipt, TransferState, COMMIT_V1,
-    DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS, FWD_V1, RECEIPT_V1,
+    compute_handoff_step, sign_commit_receipt, sign_transfer_receipt, split_for_federation,
+    CommitReceipt, CommitResult, HandoffEvent, Message, QueueStatus, RecipientState, Relay,
+    TransferMode, TransferReceipt, TransferState, COMMIT_V1, DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS,
+    DEFAULT_HANDOFF_MAX_ATTEMPTS, FWD_V1, RECEIPT_V1,
 };
+use common::{relay_keys, resolver_trusting, with_protected_alg};
 
 const ALICE: &str = "did:web:example.com:agent:alice";
 const BOB: &str = "did:web:example.com:agent:bob";
 const CAROL: &str = "did:web:example.com:agent:carol";
 const RELAY_A: &str = "did:web:example.com:relay:a";
 const RELAY_B: &str = "did:web:example.com:relay:b";
+const RELAY_C: &str = "did:web:example.com:relay:c";
 
 fn message(msg_id: &str, ttl_ms: u64, recipients: &[&str]) -> Message {
     Message {
@@ -22,6 +28,19 @@ fn message(msg_id: &str, ttl_ms: u64, recipients: &[&str]) -> Message {
     }
 }
 
+/// Receipts carry the message id as bytes, so signed-receipt tests need a 32-hex id.
+fn receipt_msg_id(tail: u64) -> String {
+    format!("0000019c3520e44c{tail:016x}")
+}
+
+fn signed_transfer(receipt: &TransferReceipt) -> Vec<u8> {
+    sign_transfer_receipt(receipt, &relay_keys(&receipt.downstream_relay)).expect("sign transfer receipt")
+}
+
+fn signed_commit(receipt: &CommitReceipt) -> Vec<u8> {
+    sign_commit_receipt(receipt, &relay_keys(&receipt.downstream_relay)).expect("sign commit receipt")
+}
+
 fn transfer_receipt(forward: &amp005_rfc003_tests::RelayForward) -> TransferReceipt {
     TransferReceipt {
         receipt_v: RECEIPT_V1,
@@ -75,6 +94,42 @@ fn rfc003_e2e_store_forward_poll_and_ack() {
     assert_eq!(relay.record_status(ALICE, "m-001"), Some(QueueStatus::Done));
 }
 
+#[test]
+fn rfc003_e2e_push_inflight_release_and_backlog_order() {
+    let mut relay = Relay::new(RELAY_A, 1_707_055_200_100);
+    let older = message("m-013", 60_000, &[BOB]);
+    relay.ingress(&older, &HashMap::new()).expect("older queued");
+    relay.set_now(1_707_055_200_200);
+    let newer = message("m-014", 60_000, &[BOB]);
+    relay.ingress(&newer, &HashMap::new()).expect("newer queued");
+
+    let backlog = relay.pending_pushes(BOB);
+    assert_eq!(
+        backlog.iter().map(|(_, msg)| msg.as_str()).collect::<Vec<_>>(),
+        vec![older.msg_id.as_str(), newer.msg_id.as_str()],
+        "backlog is pushed oldest first"
+    );
+
+    relay.mark_inflight(ALICE, &older.msg_id, BOB).expect("pushed");
+    relay.mark_inflight(ALICE, &newer.msg_id, BOB).expect("pushed");
+    assert_eq!(relay.recipient_state(ALICE, &older.msg_id, BOB), Some(RecipientState::Inflight));
+    assert!(relay.pending_pushes(BOB).is_empty(), "inflight entries are not pushed twice");
+    assert_eq!(relay.poll(BOB).len(), 2, "poll still redelivers inflight entries");
+
+    relay.release_inflight(ALICE, &newer.msg_id, BOB).expect("write failed");
+    assert_eq!(relay.recipient_state(ALICE, &newer.msg_id, BOB), Some(RecipientState::Pending));
+    assert_eq!(relay.pending_pushes(BOB).len(), 1);
+
+    relay.ack_recipient(ALICE, &older.msg_id, BOB).expect("ack");
+    relay.mark_inflight(ALICE, &newer.msg_id, BOB).expect("pushed again");
+    assert_eq!(relay.release_inflight_for(BOB), 1, "connection closed before ACK");
+    assert_eq!(
+        relay.pending_pushes(BOB),
+        vec![(ALICE.to_string(), newer.msg_id.clone())]
+    );
+    assert_eq!(relay.recipient_state(ALICE, &older.msg_id, BOB), Some(RecipientState::Delivered));
+}
+
 #[test]
 fn rfc003_e2e_multi_recipient_independent_commit() {
     let mut relay = Relay::new(RELAY_A, 1_707_055_200_100);
@@ -129,7 +184,8 @@ fn rfc003_e2e_duplicate_suppression_and_expiry() {
 fn rfc003_e2e_federation_single_custody_transfer() {
     let mut upstream = Relay::new(RELAY_A, 1_707_055_200_100);
     let mut downstream = Relay::new(RELAY_B, 1_707_055_200_200);
-    let msg = message("m-005", 60_000, &[BOB]);
+    let msg_id = receipt_msg_id(5);
+    let msg = message(&msg_id, 60_000, &[BOB]);
 
     upstream.ingress(&msg, &HashMap::new()).expect("upstream ingress");
 
@@ -144,30 +200,31 @@ fn rfc003_e2e_federation_single_custody_transfer() {
     assert_eq!(hop_next, 7);
 
     upstream
-        .start_handoff(ALICE, "m-005", BOB, RELAY_B, TransferMode::Single)
+        .start_handoff(ALICE, &msg_id, BOB, RELAY_B, TransferMode::Single)
         .expect("start handoff");
 
     downstream
-        .ingress(&message("m-005", 60_000, &[BOB]), &HashMap::new())
+        .ingress(&message(&msg_id, 60_000, &[BOB]), &HashMap::new())
         .expect("downstream ingress");
 
     let receipt = transfer_receipt(&forward);
     upstream
-        .apply_transfer_receipt(&forward, &receipt, &[-8, -7])
+        .apply_transfer_receipt(&forward, &signed_transfer(&receipt), &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]), &[-8, -7])
         .expect("transfer receipt accepted");
 
     assert_eq!(
-        upstream.transfer_state(ALICE, "m-005", BOB),
+        upstream.transfer_state(ALICE, &msg_id, BOB),
         Some(TransferState::Accepted)
     );
-    assert_eq!(upstream.retained_local_copy(ALICE, "m-005", BOB), Some(false));
+    assert_eq!(upstream.retained_local_copy(ALICE, &msg_id, BOB), Some(false));
 }
 
 #[test]
 fn rfc003_e2e_federation_dual_custody_commit_feedback() {
     let mut upstream = Relay::new(RELAY_A, 1_707_055_200_100);
     let mut downstream = Relay::new(RELAY_B, 1_707_055_200_200);
-    let msg = message("m-006", 60_000, &[BOB]);
+    let msg_id = receipt_msg_id(6);
+    let msg = message(&msg_id, 60_000, &[BOB]);
 
     upstream.ingress(&msg, &HashMap::new()).expect("upstream ingress");
     downstream.ingress(&msg, &HashMap::new()).expect("downstream ingress");
@@ -177,37 +234,38 @@ fn rfc003_e2e_federation_dual_custody_commit_feedback() {
     let forward = forwards.pop().expect("single recipient forward");
 
     upstream
-        .start_handoff(ALICE, "m-006", BOB, RELAY_B, TransferMode::Dual)
+        .start_handoff(ALICE, &msg_id, BOB, RELAY_B, TransferMode::Dual)
         .expect("start handoff");
 
     let receipt = transfer_receipt(&forward);
     upstream
-        .apply_transfer_receipt(&forward, &receipt, &[-8, -7])
+        .apply_transfer_receipt(&forward, &signed_transfer(&receipt), &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]), &[-8, -7])
         .expect("transfer receipt accepted");
 
-    assert_eq!(upstream.retained_local_copy(ALICE, "m-006", BOB), Some(true));
+    assert_eq!(upstream.retained_local_copy(ALICE, &msg_id, BOB), Some(true));
 
     downstream
-        .ack_recipient(ALICE, "m-006", BOB)
+        .ack_recipient(ALICE, &msg_id, BOB)
         .expect("downstream recipient ack");
 
     let commit = commit_receipt(&forward, CommitResult::Delivered);
     upstream
-        .apply_commit_receipt(&forward, &commit, &[-8, -7])
+        .apply_commit_receipt(&forward, &signed_commit(&commit), &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]), &[-8, -7])
         .expect("commit receipt accepted");
 
     assert_eq!(
-        upstream.transfer_state(ALICE, "m-006", BOB),
+        upstream.transfer_state(ALICE, &msg_id, BOB),
         Some(TransferState::CommitReported)
     );
-    assert_eq!(upstream.retained_local_copy(ALICE, "m-006", BOB), Some(false));
-    assert_eq!(upstream.record_status(ALICE, "m-006"), Some(QueueStatus::Done));
+    assert_eq!(upstream.retained_local_copy(ALICE, &msg_id, BOB), Some(false));
+    assert_eq!(upstream.record_status(ALICE, &msg_id), Some(QueueStatus::Done));
 }
 
 #[test]
 fn rfc003_e2e_federation_rollback_and_negative_paths() {
     let mut upstream = Relay::new(RELAY_A, 1_707_055_200_100);
-    let msg = message("m-007", 60_000, &[BOB]);
+    let msg_id = receipt_msg_id(7);
+    let msg = message(&msg_id, 60_000, &[BOB]);
     upstream.ingress(&msg, &HashMap::new()).expect("ingress");
 
     let forward = split_for_federation(&msg, RELAY_A, RELAY_B, &[], 8, TransferMode::Single)
@@ -216,15 +274,15 @@ fn rfc003_e2e_federation_rollback_and_negative_paths() {
         .expect("forward");
 
     upstream
-        .start_handoff(ALICE, "m-007", BOB, RELAY_B, TransferMode::Single)
+        .start_handoff(ALICE, &msg_id, BOB, RELAY_B, TransferMode::Single)
         .expect("start handoff");
 
     upstream.set_now(1_707_055_200_100 + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS + 1);
     upstream
-        .handoff_timeout_rollback(ALICE, "m-007", BOB)
+        .handoff_timeout_rollback(ALICE, &msg_id, BOB)
         .expect("rollback");
     assert_eq!(
-        upstream.transfer_state(ALICE, "m-007", BOB),
+        upstream.transfer_state(ALICE, &msg_id, BOB),
         Some(TransferState::RolledBack)
     );
 
@@ -236,23 +294,27 @@ fn rfc003_e2e_federation_rollback_and_negative_paths() {
     assert_eq!(hop_err.code, 2003);
 
     let mut invalid = transfer_receipt(&forward);
-    invalid.msg_id = "wrong-msg-id".to_string();
+    invalid.msg_id = receipt_msg_id(0xff);
     let tuple_err = upstream
-        .apply_transfer_receipt(&forward, &invalid, &[-8])
+        .apply_transfer_receipt(&forward, &signed_transfer(&invalid), &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]), &[-8])
         .expect_err("tuple mismatch must reject");
     assert_eq!(tuple_err.code, 3001);
 
-    let mut unsupported_alg = transfer_receipt(&forward);
-    unsupported_alg.alg = -35;
+    let unsupported_alg = with_protected_alg(&signed_transfer(&transfer_receipt(&forward)), -35);
     let alg_err = upstream
-        .apply_transfer_receipt(&forward, &unsupported_alg, &[-8])
+        .apply_transfer_receipt(&forward, &unsupported_alg, &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]), &[-8])
         .expect_err("unsupported alg must reject");
     assert_eq!(alg_err.code, 3001);
 
     let mut unsupported_version = transfer_receipt(&forward);
     unsupported_version.receipt_v = 9;
     let ver_err = upstream
-        .apply_transfer_receipt(&forward, &unsupported_version, &[-8, -7])
+        .apply_transfer_receipt(
+            &forward,
+            &signed_transfer(&unsupported_version),
+            &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]),
+            &[-8, -7],
+        )
         .expect_err("unsupported version must reject");
     assert_eq!(ver_err.code, 1004);
 }
@@ -268,3 +330,210 @@ fn rfc003_e2e_multi_recipient_federation_split() {
     assert!(forwards.iter().any(|f| f.recipient_did == BOB));
     assert!(forwards.iter().any(|f| f.recipient_did == CAROL));
 }
+
+#[test]
+fn rfc003_e2e_tick_rolls_back_and_retries_alternate_route() {
+    let start = 1_707_055_200_100;
+    let mut upstream = Relay::new(RELAY_A, start);
+    let msg_id = receipt_msg_id(9);
+    let msg = message(&msg_id, 60_000, &[BOB]);
+    upstream.ingress(&msg, &HashMap::new()).expect("ingress");
+    upstream
+        .start_handoff(ALICE, &msg_id, BOB, RELAY_B, TransferMode::Single)
+        .expect("start handoff");
+
+    let alternate = |_: &str, previous: &str| {
+        Some(if previous == RELAY_B { RELAY_C } else { RELAY_B }.to_string())
+    };
+
+    upstream.set_now(start + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS - 1);
+    assert!(upstream.tick(alternate).is_empty(), "accept timeout not reached");
+
+    upstream.set_now(start + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS);
+    let events = upstream.tick(alternate);
+    assert_eq!(
+        events,
+        vec![HandoffEvent::RolledBack {
+            from_did: ALICE.to_string(),
+            msg_id: msg_id.clone(),
+            recipient_did: BOB.to_string(),
+            downstream_relay: RELAY_B.to_string(),
+            attempts: 1,
+        }]
+    );
+    assert_eq!(
+        upstream.transfer_state(ALICE, &msg_id, BOB),
+        Some(TransferState::RolledBack)
+    );
+
+    let rolled_back_at = start + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS;
+    upstream.set_now(rolled_back_at + 999);
+    assert!(upstream.tick(alternate).is_empty(), "first backoff is 1000ms");
+
+    upstream.set_now(rolled_back_at + 1_000);
+    let events = upstream.tick(alternate);
+    assert_eq!(
+        events,
+        vec![HandoffEvent::Retried {
+            from_did: ALICE.to_string(),
+            msg_id: msg_id.clone(),
+            recipient_did: BOB.to_string(),
+            downstream_relay: RELAY_C.to_string(),
+            attempt: 2,
+        }]
+    );
+
+    let forward = split_for_federation(&msg, RELAY_A, RELAY_C, &[], 8, TransferMode::Single)
+        .expect("split")
+        .pop()
+        .expect("forward");
+    upstream
+        .apply_transfer_receipt(
+            &forward,
+            &signed_transfer(&transfer_receipt(&forward)),
+            &resolver_trusting([RELAY_A, RELAY_B, RELAY_C]),
+            &[-8],
+        )
+        .expect("alternate relay accepts");
+    assert_eq!(upstream.retained_local_copy(ALICE, &msg_id, BOB), Some(false));
+
+    upstream.set_now(rolled_back_at + 60_000);
+    assert!(upstream.tick(alternate).is_empty(), "accepted handoff stops retries");
+}
+
+#[test]
+fn rfc003_e2e_tick_marks_failed_after_max_attempts() {
+    let start = 1_707_055_200_100;
+    let mut upstream = Relay::new(RELAY_A, start);
+    let msg = message("m-010", 120_000, &[BOB]);
+    upstream.ingress(&msg, &HashMap::new()).expect("ingress");
+    upstream
+        .start_handoff(ALICE, "m-010", BOB, RELAY_B, TransferMode::Dual)
+        .expect("start handoff");
+
+    let mut now = start;
+    let mut retried = Vec::new();
+    let mut exhausted = None;
+    for _ in 0..20 {
+        now += 1_000;
+        upstream.set_now(now);
+        for event in upstream.tick(|_, _| Some(RELAY_B.to_string())) {
+            match event {
+                HandoffEvent::Retried { attempt, .. } => retried.push(attempt),
+                HandoffEvent::Exhausted { attempts, .. } => exhausted = Some(attempts),
+                HandoffEvent::RolledBack { .. } => {}
+            }
+        }
+    }
+
+    assert_eq!(retried, vec![2, 3]);
+    assert_eq!(exhausted, Some(DEFAULT_HANDOFF_MAX_ATTEMPTS));
+    assert_eq!(
+        upstream.recipient_state(ALICE, "m-010", BOB),
+        Some(RecipientState::Failed)
+    );
+    assert_eq!(upstream.record_status(ALICE, "m-010"), Some(QueueStatus::Done));
+}
+
+#[test]
+fn rfc003_e2e_tick_stops_retry_at_local_expiry() {
+    let start = 1_707_055_200_100;
+    let mut upstream = Relay::new(RELAY_A, start);
+    let msg = message("m-011", 4_000, &[BOB]);
+    upstream.ingress(&msg, &HashMap::new()).expect("ingress");
+    upstream
+        .start_handoff(ALICE, "m-011", BOB, RELAY_B, TransferMode::Single)
+        .expect("start handoff");
+
+    upstream.set_now(start + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS);
+    assert!(upstream.tick(|_, _| Some(RELAY_B.to_string())).is_empty());
+    assert_eq!(
+        upstream.recipient_state(ALICE, "m-011", BOB),
+        Some(RecipientState::Expired)
+    );
+    assert_eq!(upstream.record_status(ALICE, "m-011"), Some(QueueStatus::Expired));
+}
+
+#[test]
+fn rfc003_e2e_tick_keeps_local_copy_without_route() {
+    let start = 1_707_055_200_100;
+    let mut upstream = Relay::new(RELAY_A, start);
+    let msg = message("m-012", 60_000, &[BOB]);
+    upstream.ingress(&msg, &HashMap::new()).expect("ingress");
+    upstream
+        .start_handoff(ALICE, "m-012", BOB, RELAY_B, TransferMode::Single)
+        .expect("start handoff");
+
+    upstream.set_now(start + DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS + 30_000);
+    let events = upstream.tick(|_, _| None);
+    assert_eq!(events.len(), 1, "rollback only, no retry target");
+    assert_eq!(
+        upstream.transfer_state(ALICE, "m-012", BOB),
+        Some(TransferState::RolledBack)
+    );
+    assert_eq!(upstream.poll(BOB).len(), 1, "local routing resumes");
+}
+
+#[test]
+fn rfc003_e2e_forged_receipt_signatures_rejected() {
+    let mut upstream = Relay::new(RELAY_A, 1_707_055_200_100);
+    let msg_id = receipt_msg_id(15);
+    let msg = message(&msg_id, 60_000, &[BOB]);
+    upstream.ingress(&msg, &HashMap::new()).expect("ingress");
+    let forward = split_for_federation(&msg, RELAY_A, RELAY_B, &[], 8, TransferMode::Dual)
+        .expect("split")
+        .pop()
+        .expect("forward");
+    upstream
+        .start_handoff(ALICE, &msg.msg_id, BOB, RELAY_B, TransferMode::Dual)
+        .expect("start handoff");
+    let resolver = resolver_trusting([RELAY_A, RELAY_B, RELAY_C]);
+
+    let mut flipped = signed_transfer(&transfer_receipt(&forward));
+    let last = flipped.len() - 1;
+    flipped[last] ^= 0x01;
+    let err = upstream
+        .apply_transfer_receipt(&forward, &flipped, &resolver, &[-8])
+        .expect_err("flipped signature bit must reject");
+    assert_eq!(err.code, 3001);
+
+    let impostor = AgentKeys::from_sign_seed(RELAY_B, [66_u8; 32]);
+    let forged = sign_transfer_receipt(&transfer_receipt(&forward), &impostor).expect("sign");
+    let err = upstream
+        .apply_transfer_receipt(&forward, &forged, &resolver, &[-8])
+        .expect_err("key not published under downstream assertionMethod must reject");
+    assert_eq!(err.code, 3001);
+
+    let mut foreign = transfer_receipt(&forward);
+    foreign.downstream_relay = RELAY_C.to_string();
+    foreign.kid = format!("{RELAY_C}#k1");
+    let err = upstream
+        .apply_transfer_receipt(&forward, &signed_transfer(&foreign), &resolver, &[-8])
+        .expect_err("receipt signed by another relay must reject");
+    assert_eq!(err.code, 3001);
+    assert_eq!(
+        upstream.transfer_state(ALICE, &msg.msg_id, BOB),
+        Some(TransferState::Pending)
+    );
+
+    upstream
+        .apply_transfer_receipt(&forward, &signed_transfer(&transfer_receipt(&forward)), &resolver, &[-8])
+        .expect("genuine receipt accepted");
+
+    let mut forged_commit = signed_commit(&commit_receipt(&forward, CommitResult::Delivered));
+    let last = forged_commit.len() - 1;
+    forged_commit[last] ^= 0x80;
+    let err = upstream
+        .apply_commit_receipt(&forward, &forged_commit, &resolver, &[-8])
+        .expect_err("forged commit receipt must reject");
+    assert_eq!(err.code, 3001);
+    assert_eq!(
+        upstream.recipient_state(ALICE, &msg.msg_id, BOB),
+        Some(RecipientState::Pending)
+    );
+
+    let err = upstream
+        .apply_transfer_receipt(&forward, b"not-a-cose-receipt", &resolver, &[-8])
+        .expect_err("malformed receipt must reject");
+    assert_eq!(err.code, 1001);
+}
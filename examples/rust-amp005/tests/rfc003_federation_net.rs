@@ -0,0 +1,469 @@
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    build_plain_signed, decode_poll_response, demo_agents, hex_encode, make_message_id, now_ms,
+    peek_routing, AckBody, AckSource, AgentKeys, MessageMeta, Recipients, TextMessageBody, TYPE_ACK,
+    TYPE_MESSAGE,
+};
+use amp005_rfc003_tests::{
+    encode_relay_forward, message_from_wire, next_hop_forward, post_relay_forward,
+    send_http_request, send_signed_request, sign_http_request, verify_transfer_receipt,
+    TransferMode, MESSAGES_PATH, REQUEST_SIGNATURE_HEADER,
+};
+use common::{peer_key_args, relay_key_args, relay_keys, resolver_trusting};
+
+const RELAY_A: &str = "did:web:example.com:relay:a";
+const RELAY_B: &str = "did:web:example.com:relay:b";
+const RELAY_C: &str = "did:web:example.com:relay:c";
+const WAIT: Duration = Duration::from_secs(10);
+
+struct RelayProcess {
+    child: Child,
+    http_addr: String,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl RelayProcess {
+    fn wait_for_log(&self, needle: &str) -> String {
+        let deadline = Instant::now() + WAIT;
+        while Instant::now() < deadline {
+            if let Some(line) = self
+                .log
+                .lock()
+                .expect("log poisoned")
+                .iter()
+                .find(|line| line.contains(needle))
+            {
+                return line.clone();
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!(
+            "timed out waiting for {needle:?}; log:\n{}",
+            self.log.lock().expect("log poisoned").join("\n")
+        );
+    }
+}
+
+impl Drop for RelayProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+    listener.local_addr().expect("local addr").to_string()
+}
+
+fn spawn_relay(relay_id: &str, http_addr: &str, extra_args: &[String]) -> RelayProcess {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amp005-server"))
+        .arg(free_addr())
+        .args(["--relay-id", relay_id, "--http", http_addr])
+        .args(relay_key_args(relay_id))
+        .args(
+            [RELAY_A, RELAY_B, RELAY_C]
+                .into_iter()
+                .filter(|peer| *peer != relay_id)
+                .flat_map(peer_key_args),
+        )
+        .args(extra_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp005-server");
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let stdout = child.stdout.take().expect("stdout piped");
+    let sink = Arc::clone(&log);
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            sink.lock().expect("log poisoned").push(line);
+        }
+    });
+
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(http_addr).is_err() {
+        assert!(Instant::now() < deadline, "relay {relay_id} did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    RelayProcess {
+        child,
+        http_addr: http_addr.to_string(),
+        log,
+    }
+}
+
+fn args(pairs: &[(&str, String)]) -> Vec<String> {
+    pairs
+        .iter()
+        .flat_map(|(flag, value)| [(*flag).to_string(), value.clone()])
+        .collect()
+}
+
+fn text_message(from: &AgentKeys, to: &str, counter: u64, text: &str) -> Vec<u8> {
+    let ts_ms = now_ms();
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(ts_ms, counter),
+        typ: TYPE_MESSAGE,
+        ts_ms,
+        ttl_ms: 60_000,
+        from: String::new(),
+        to: Recipients::One(to.to_string()),
+        reply_to: None,
+        thread_id: None,
+    };
+    build_plain_signed(from, meta, &TextMessageBody { msg: text.to_string() }).expect("build message")
+}
+
+fn recipient_ack(from: &AgentKeys, original: &[u8], counter: u64) -> Vec<u8> {
+    let routing = peek_routing(original).expect("routing");
+    let ts_ms = now_ms();
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(ts_ms, counter),
+        typ: TYPE_ACK,
+        ts_ms,
+        ttl_ms: 60_000,
+        from: String::new(),
+        to: Recipients::One(routing.from),
+        reply_to: Some(routing.id),
+        thread_id: None,
+    };
+    let body = AckBody {
+        ack_source: AckSource::Recipient,
+        received_at: ts_ms,
+        ack_target: None,
+    };
+    build_plain_signed(from, meta, &body).expect("build ack")
+}
+
+fn submit(relay: &RelayProcess, principal: &AgentKeys, wire: &[u8]) -> u16 {
+    send_signed_request(&relay.http_addr, "POST", MESSAGES_PATH, principal, &[], wire)
+        .expect("submit")
+        .status
+}
+
+fn poll_until(relay: &RelayProcess, principal: &AgentKeys, count: usize) -> Vec<Vec<u8>> {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let response = send_signed_request(&relay.http_addr, "GET", MESSAGES_PATH, principal, &[], &[])
+            .expect("poll");
+        assert_eq!(response.status, 200);
+        let messages: Vec<Vec<u8>> = decode_poll_response(&response.body)
+            .expect("poll response")
+            .messages
+            .into_iter()
+            .map(|m| m.into_vec())
+            .collect();
+        if messages.len() >= count || Instant::now() > deadline {
+            return messages;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn rfc003_net_single_custody_handoff_between_two_relays() {
+    let agents = demo_agents();
+    let (addr_a, addr_b) = (free_addr(), free_addr());
+    let relay_b = spawn_relay(
+        RELAY_B,
+        &addr_b,
+        &args(&[("--peer", format!("{RELAY_A}={addr_a}"))]),
+    );
+    let relay_a = spawn_relay(
+        RELAY_A,
+        &addr_a,
+        &args(&[
+            ("--peer", format!("{RELAY_B}={addr_b}")),
+            ("--route", format!("{}={RELAY_B}", agents.bob.did)),
+        ]),
+    );
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 1, "hello across relays");
+    let msg_id = hex_encode(&peek_routing(&wire).expect("routing").id);
+    assert_eq!(submit(&relay_a, &agents.alice, &wire), 202);
+
+    relay_a.wait_for_log(&format!("[federation] handoff accepted msg_id={msg_id}"));
+    relay_b.wait_for_log(&format!("[federation] accepted forward msg_id={msg_id}"));
+
+    let delivered = poll_until(&relay_b, &agents.bob, 1);
+    assert_eq!(delivered, vec![wire.clone()], "downstream relay delivers untouched bytes");
+    assert!(
+        poll_until(&relay_a, &agents.bob, 0).is_empty(),
+        "single custody releases the upstream copy"
+    );
+}
+
+#[test]
+fn rfc003_net_dual_custody_commit_flows_back_upstream() {
+    let agents = demo_agents();
+    let (addr_a, addr_b) = (free_addr(), free_addr());
+    let relay_b = spawn_relay(
+        RELAY_B,
+        &addr_b,
+        &args(&[
+            ("--peer", format!("{RELAY_A}={addr_a}")),
+            ("--mode", "dual".to_string()),
+        ]),
+    );
+    let relay_a = spawn_relay(
+        RELAY_A,
+        &addr_a,
+        &args(&[
+            ("--peer", format!("{RELAY_B}={addr_b}")),
+            ("--route", format!("{}={RELAY_B}", agents.bob.did)),
+            ("--mode", "dual".to_string()),
+        ]),
+    );
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 2, "dual custody");
+    let msg_id = hex_encode(&peek_routing(&wire).expect("routing").id);
+    assert_eq!(submit(&relay_a, &agents.alice, &wire), 202);
+    relay_a.wait_for_log(&format!("[federation] handoff accepted msg_id={msg_id}"));
+
+    let delivered = poll_until(&relay_b, &agents.bob, 1);
+    assert_eq!(delivered, vec![wire.clone()]);
+
+    let ack = recipient_ack(&agents.bob, &wire, 3);
+    assert_eq!(submit(&relay_b, &agents.bob, &ack), 202);
+
+    relay_b.wait_for_log(&format!("[federation] commit reported msg_id={msg_id}"));
+    let applied = relay_a.wait_for_log(&format!("[federation] commit applied msg_id={msg_id}"));
+    assert!(applied.contains("result=Delivered"), "{applied}");
+
+    let acks = poll_until(&relay_a, &agents.alice, 2);
+    assert_eq!(acks.len(), 2, "relay ACK and recipient ACK");
+    assert!(
+        acks.contains(&ack),
+        "recipient ACK follows the route learned from the forward back to the sender's relay"
+    );
+    let stored = acks.iter().find(|a| **a != ack).expect("relay ACK");
+    let routing = peek_routing(stored).expect("routing");
+    assert_eq!((routing.from.as_str(), routing.typ), (RELAY_A, TYPE_ACK));
+    assert_eq!(hex_encode(&routing.reply_to.expect("reply_to")), msg_id);
+}
+
+#[test]
+fn rfc003_net_hop_limit_exhaustion_fails_the_chain() {
+    let agents = demo_agents();
+    let (addr_a, addr_b, addr_c) = (free_addr(), free_addr(), free_addr());
+    let _relay_c = spawn_relay(
+        RELAY_C,
+        &addr_c,
+        &args(&[("--peer", format!("{RELAY_B}={addr_b}"))]),
+    );
+    let relay_b = spawn_relay(
+        RELAY_B,
+        &addr_b,
+        &args(&[
+            ("--peer", format!("{RELAY_A}={addr_a}")),
+            ("--peer", format!("{RELAY_C}={addr_c}")),
+            ("--route", format!("{}={RELAY_C}", agents.bob.did)),
+        ]),
+    );
+    let relay_a = spawn_relay(
+        RELAY_A,
+        &addr_a,
+        &args(&[
+            ("--peer", format!("{RELAY_B}={addr_b}")),
+            ("--route", format!("{}={RELAY_B}", agents.bob.did)),
+            ("--hop-limit", "2".to_string()),
+        ]),
+    );
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 4, "too far");
+    let msg_id = hex_encode(&peek_routing(&wire).expect("routing").id);
+    assert_eq!(submit(&relay_a, &agents.alice, &wire), 202);
+
+    let refused = relay_b.wait_for_log("[federation] refused forward");
+    assert!(refused.contains("code=2003"), "{refused}");
+    let rejected = relay_a.wait_for_log(&format!("[federation] handoff rejected msg_id={msg_id}"));
+    assert!(rejected.contains("code=2003"), "{rejected}");
+}
+
+#[test]
+fn rfc003_net_three_relay_chain_reaches_final_hop() {
+    let agents = demo_agents();
+    let (addr_a, addr_b, addr_c) = (free_addr(), free_addr(), free_addr());
+    let relay_c = spawn_relay(
+        RELAY_C,
+        &addr_c,
+        &args(&[("--peer", format!("{RELAY_B}={addr_b}"))]),
+    );
+    let relay_b = spawn_relay(
+        RELAY_B,
+        &addr_b,
+        &args(&[
+            ("--peer", format!("{RELAY_A}={addr_a}")),
+            ("--peer", format!("{RELAY_C}={addr_c}")),
+            ("--route", format!("{}={RELAY_C}", agents.bob.did)),
+        ]),
+    );
+    let relay_a = spawn_relay(
+        RELAY_A,
+        &addr_a,
+        &args(&[
+            ("--peer", format!("{RELAY_B}={addr_b}")),
+            ("--route", format!("{}={RELAY_B}", agents.bob.did)),
+        ]),
+    );
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 5, "two hops");
+    let msg_id = hex_encode(&peek_routing(&wire).expect("routing").id);
+    assert_eq!(submit(&relay_a, &agents.alice, &wire), 202);
+
+    relay_a.wait_for_log(&format!("[federation] handoff accepted msg_id={msg_id}"));
+    relay_b.wait_for_log(&format!("[federation] handoff accepted msg_id={msg_id}"));
+    let accepted = relay_c.wait_for_log(&format!("[federation] accepted forward msg_id={msg_id}"));
+    assert!(accepted.contains(&format!("upstream={RELAY_B}")), "{accepted}");
+    assert!(accepted.contains("hop_limit=6"), "{accepted}");
+
+    assert_eq!(poll_until(&relay_c, &agents.bob, 1), vec![wire]);
+}
+
+#[test]
+fn rfc008_net_did_document_relay_service_routes_handoff() {
+    let agents = demo_agents();
+    let (addr_a, addr_b) = (free_addr(), free_addr());
+    let relay_b_did = format!("did:web:{}", addr_b.replace(':', "%3A"));
+    let document = serde_json::json!({
+        "id": agents.bob.did,
+        "service": [{
+            "id": format!("{}#amp-relay", agents.bob.did),
+            "type": "AgentMessagingRelay",
+            "serviceEndpoint": format!("http://{addr_b}"),
+            "relayCapabilities": {
+                "federation": true,
+                "relayForwardEndpoint": format!("http://{addr_b}/amp/v1/relay/forward"),
+                "transferModes": ["single"],
+                "maxHopLimit": 16,
+                "receiptAlgs": [-8],
+            },
+        }],
+    });
+    let doc_path = std::env::temp_dir().join(format!("amp005-bob-{}.json", addr_b.replace(':', "-")));
+    std::fs::write(&doc_path, serde_json::to_vec(&document).expect("json")).expect("write did doc");
+
+    let relay_b = spawn_relay(
+        &relay_b_did,
+        &addr_b,
+        &args(&[("--peer", format!("{RELAY_A}={addr_a}"))]),
+    );
+    let mut relay_a_args = args(&[("--did-doc", doc_path.display().to_string())]);
+    relay_a_args.extend(peer_key_args(&relay_b_did));
+    let relay_a = spawn_relay(RELAY_A, &addr_a, &relay_a_args);
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 7, "found via DID document");
+    let msg_id = hex_encode(&peek_routing(&wire).expect("routing").id);
+    assert_eq!(submit(&relay_a, &agents.alice, &wire), 202);
+
+    let accepted = relay_a.wait_for_log(&format!("[federation] handoff accepted msg_id={msg_id}"));
+    assert!(accepted.contains(&format!("downstream={relay_b_did}")), "{accepted}");
+    assert_eq!(poll_until(&relay_b, &agents.bob, 1), vec![wire]);
+    let _ = std::fs::remove_file(doc_path);
+}
+
+#[test]
+fn rfc003_net_forward_rejects_loops_and_principal_mismatch() {
+    let agents = demo_agents();
+    let addr_b = free_addr();
+    let relay_b = spawn_relay(RELAY_B, &addr_b, &args(&[("--peer", format!("{RELAY_A}=127.0.0.1:9"))]));
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 6, "crafted");
+    let message = message_from_wire(&wire).expect("message");
+
+    let looped = next_hop_forward(
+        RELAY_A,
+        &message,
+        &agents.bob.did,
+        RELAY_B,
+        &[RELAY_B.to_string()],
+        4,
+        TransferMode::Single,
+    )
+    .expect("forward");
+    let wrapper = encode_relay_forward(&looped, &wire).expect("wrapper");
+    let response = post_relay_forward(&relay_b.http_addr, &relay_keys(RELAY_A), &wrapper).expect("response");
+    assert!(!response.accepted);
+    assert_eq!(response.error_code, Some(2003));
+
+    let honest = next_hop_forward(
+        RELAY_A,
+        &message,
+        &agents.bob.did,
+        RELAY_B,
+        &[],
+        4,
+        TransferMode::Single,
+    )
+    .expect("forward");
+    let wrapper = encode_relay_forward(&honest, &wire).expect("wrapper");
+    let response = post_relay_forward(&relay_b.http_addr, &relay_keys(RELAY_C), &wrapper).expect("response");
+    assert!(!response.accepted);
+    assert_eq!(response.error_code, Some(3001));
+
+    let response = post_relay_forward(&relay_b.http_addr, &relay_keys(RELAY_A), &wrapper).expect("response");
+    assert!(response.accepted);
+    let receipt = verify_transfer_receipt(
+        response.receipt.expect("receipt").as_ref(),
+        &resolver_trusting([RELAY_B]),
+    )
+    .expect("relay-signed receipt");
+    assert_eq!(receipt.downstream_relay, RELAY_B);
+    assert_eq!(receipt.hop_limit_remaining, 3);
+}
+
+#[test]
+fn rfc003_net_http_principal_is_the_request_signer() {
+    let agents = demo_agents();
+    let addr_b = free_addr();
+    let relay_b = spawn_relay(RELAY_B, &addr_b, &[]);
+
+    let wire = text_message(&agents.alice, &agents.bob.did, 7, "for bob only");
+    assert_eq!(submit(&relay_b, &agents.alice, &wire), 202);
+
+    let claimed = send_http_request(
+        &relay_b.http_addr,
+        "GET",
+        MESSAGES_PATH,
+        &[("X-Principal-Did", agents.bob.did.clone())],
+        &[],
+    )
+    .expect("unsigned poll");
+    assert_eq!(claimed.status, 403, "a claimed principal is not an authenticated one");
+    assert!(
+        !poll_until(&relay_b, &agents.alice, 1).contains(&wire),
+        "alice's signature only opens alice's mailbox"
+    );
+
+    let signature = sign_http_request(&agents.bob, "GET", MESSAGES_PATH, &[], now_ms()).expect("sign");
+    let signed = [(REQUEST_SIGNATURE_HEADER, signature)];
+    let first = send_http_request(&relay_b.http_addr, "GET", MESSAGES_PATH, &signed, &[]).expect("poll");
+    assert_eq!(first.status, 200);
+    let replayed = send_http_request(&relay_b.http_addr, "GET", MESSAGES_PATH, &signed, &[]).expect("poll");
+    assert_eq!(replayed.status, 403, "a request signature is single-use");
+
+    let other = text_message(&agents.alice, &agents.bob.did, 8, "swapped body");
+    let signature = sign_http_request(&agents.alice, "POST", MESSAGES_PATH, &wire, now_ms()).expect("sign");
+    let swapped = send_http_request(
+        &relay_b.http_addr,
+        "POST",
+        MESSAGES_PATH,
+        &[(REQUEST_SIGNATURE_HEADER, signature)],
+        &other,
+    )
+    .expect("submit");
+    assert_eq!(swapped.status, 403, "the signature covers the body");
+}
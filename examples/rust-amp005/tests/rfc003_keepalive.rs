@@ -0,0 +1,119 @@
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    demo_agents, peek_routing, read_frame, Agent, Connection, Handshake, KeepaliveConfig, TYPE_PING,
+};
+use amp005_rfc003_tests::SessionHandshake;
+use common::{relay_key_args, resolver_trusting};
+
+const RELAY: &str = "did:web:example.com:relay:store";
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Relay that PINGs after 100ms of silence and closes after two missed PONGs.
+fn spawn_server() -> Server {
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+        listener.local_addr().expect("local addr").to_string()
+    };
+    let child = Command::new(env!("CARGO_BIN_EXE_amp005-server"))
+        .arg(&addr)
+        .args(relay_key_args(RELAY))
+        .args(["--ping-interval", "100", "--pong-timeout", "100", "--max-missed-pongs", "2"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp005-server");
+
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Server { child, addr }
+}
+
+fn handshake() -> SessionHandshake {
+    SessionHandshake {
+        relay_did: RELAY.to_string(),
+        versions: vec!["1.0".to_string()],
+    }
+}
+
+#[test]
+fn rfc003_relay_closes_a_session_that_ignores_ping() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let mut resolver = resolver_trusting([RELAY]);
+    let mut connection = Connection::tcp(&server.addr).expect("connect");
+    handshake()
+        .open(&agents.alice, &mut resolver, &mut connection)
+        .expect("session");
+
+    let (frames_tx, frames) = mpsc::channel();
+    thread::spawn(move || loop {
+        match read_frame(&mut connection.reader) {
+            Ok(frame) => frames_tx.send(Some(frame)).expect("frame"),
+            Err(_) => {
+                let _ = frames_tx.send(None);
+                return;
+            }
+        }
+    });
+
+    let mut pings = 0;
+    while let Some(frame) = frames.recv_timeout(WAIT).expect("relay keeps talking until it closes") {
+        let routing = peek_routing(&frame).expect("routing");
+        assert_eq!(routing.typ, TYPE_PING, "only PINGs reach a silent agent");
+        assert_eq!(routing.to, vec![agents.alice.did.clone()]);
+        pings += 1;
+    }
+    assert_eq!(pings, 2, "closed after max-missed-pongs unanswered PINGs");
+}
+
+#[test]
+fn rfc003_agent_answers_relay_ping_and_measures_rtt() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let agent = Agent::builder(agents.bob.clone())
+        .resolver(resolver_trusting([RELAY]))
+        .connect_tcp(server.addr.clone())
+        .handshake(handshake())
+        .keepalive(KeepaliveConfig {
+            interval_ms: 50,
+            pong_timeout_ms: 500,
+            max_missed: 2,
+        })
+        .build()
+        .expect("agent");
+    let (handle, run) = agent.spawn();
+    assert!(handle.wait_connected(WAIT), "bob has a session");
+
+    // Several keepalive rounds pass while the agent sends nothing else.
+    thread::sleep(Duration::from_millis(800));
+    assert!(handle.is_connected(), "answered PINGs keep the session open");
+    let health = handle.health().expect("keepalive running");
+    assert!(health.pongs_received > 0, "relay answers agent PINGs: {health:?}");
+    assert!(health.last_rtt_ms.is_some());
+    assert_eq!(health.missed_pongs, 0);
+
+    handle.shutdown();
+    run.join().expect("agent thread").expect("clean stop");
+}
@@ -0,0 +1,282 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use amp001_example::{
+    build_plain_signed, demo_agents, make_message_id, peek_routing, receive_and_verify, AckBody,
+    AckSource, AgentKeys, DidResolver, ErrorBody, MessageMeta, ProcFailBody, ProcOkBody,
+    ReceivedMessage, Recipients, TextMessageBody, TYPE_ACK, TYPE_ERROR, TYPE_MESSAGE,
+    TYPE_PROC_FAIL, TYPE_PROC_OK,
+};
+use amp005_rfc003_tests::{
+    build_error_reply, build_relay_acks, DeliveryState, DeliveryUpdate, Outbox, RelayError,
+};
+use common::{relay_keys, resolver_trusting};
+
+const RELAY: &str = "did:web:example.com:relay:store";
+const CAROL: &str = "did:web:example.com:agent:carol";
+const T0: u64 = 1_707_055_200_000;
+
+fn resolver() -> DidResolver {
+    resolver_trusting([RELAY])
+}
+
+fn outgoing(sender: &AgentKeys, to: Recipients, ttl_ms: u64) -> Vec<u8> {
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(T0, 1),
+        typ: TYPE_MESSAGE,
+        ts_ms: T0,
+        ttl_ms,
+        from: String::new(),
+        to,
+        reply_to: None,
+        thread_id: None,
+    };
+    let body = TextMessageBody {
+        msg: "track me".to_string(),
+    };
+    build_plain_signed(sender, meta, &body).expect("message")
+}
+
+/// A reply from `signer` to `original`, verified as the sender would see it.
+fn reply(
+    signer: &AgentKeys,
+    typ: u8,
+    original: &[u8],
+    body: &impl serde::Serialize,
+    tail: u64,
+) -> Vec<u8> {
+    let routing = peek_routing(original).expect("routing");
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(T0 + 10, tail),
+        typ,
+        ts_ms: T0 + 10,
+        ttl_ms: 60_000,
+        from: String::new(),
+        to: Recipients::One(routing.from.clone()),
+        reply_to: Some(routing.id),
+        thread_id: None,
+    };
+    build_plain_signed(signer, meta, body).expect("reply")
+}
+
+fn recipient_ack(signer: &AgentKeys, original: &[u8], tail: u64) -> Vec<u8> {
+    let multi = peek_routing(original).expect("routing").to.len() > 1;
+    let body = AckBody {
+        ack_source: AckSource::Recipient,
+        received_at: T0 + 10,
+        ack_target: multi.then(|| signer.did.clone()),
+    };
+    reply(signer, TYPE_ACK, original, &body, tail)
+}
+
+fn open(wire: &[u8]) -> ReceivedMessage {
+    let alice = demo_agents().alice;
+    receive_and_verify(&alice, wire, &resolver(), T0 + 20).expect("verify reply")
+}
+
+fn observe(outbox: &mut Outbox, wire: &[u8]) -> Result<Vec<DeliveryUpdate>, RelayError> {
+    outbox.observe(&open(wire), &resolver(), T0 + 20)
+}
+
+#[test]
+fn rfc003_outbox_tracks_stored_delivered_and_processed() {
+    let agents = demo_agents();
+    let relay = relay_keys(RELAY);
+    let wire = outgoing(&agents.alice, Recipients::One(agents.bob.did.clone()), 60_000);
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut outbox = Outbox::new();
+    let sink = Arc::clone(&seen);
+    outbox.on_change(move |update| sink.lock().unwrap().push(update.state));
+
+    let id = outbox.submit(&wire, T0).expect("submit");
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::Submitted));
+
+    let routing = peek_routing(&wire).expect("routing");
+    let stored = build_relay_acks(&relay, &routing, T0 + 5).expect("relay ack");
+    observe(&mut outbox, &stored[0]).expect("relay ack");
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::RelayAcked));
+
+    observe(&mut outbox, &recipient_ack(&agents.bob, &wire, 2)).expect("recipient ack");
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::RecipientAcked));
+
+    let late = build_relay_acks(&relay, &routing, T0 + 15).expect("relay ack");
+    assert!(
+        observe(&mut outbox, &late[0]).expect("late relay ack").is_empty(),
+        "a late relay ACK never moves a delivered copy backwards"
+    );
+
+    let proc_ok = reply(&agents.bob, TYPE_PROC_OK, &wire, &ProcOkBody::default(), 3);
+    observe(&mut outbox, &proc_ok).expect("proc ok");
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::ProcOk));
+
+    let failure = ProcFailBody::new(5001, "late failure");
+    let proc_fail = reply(&agents.bob, TYPE_PROC_FAIL, &wire, &failure, 4);
+    assert!(observe(&mut outbox, &proc_fail).expect("proc fail").is_empty(), "terminal");
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            DeliveryState::RelayAcked,
+            DeliveryState::RecipientAcked,
+            DeliveryState::ProcOk
+        ]
+    );
+    assert_eq!(outbox.prune_settled(), 1);
+    assert!(outbox.entry(&id).is_none());
+}
+
+#[test]
+fn rfc003_outbox_correlates_multi_recipient_acks_by_target() {
+    let agents = demo_agents();
+    let relay = relay_keys(RELAY);
+    let to = Recipients::Many(vec![agents.bob.did.clone(), CAROL.to_string()]);
+    let wire = outgoing(&agents.alice, to, 60_000);
+    let mut outbox = Outbox::new();
+    let id = outbox.submit(&wire, T0).expect("submit");
+
+    let routing = peek_routing(&wire).expect("routing");
+    let stored = build_relay_acks(&relay, &routing, T0 + 5).expect("relay acks");
+    let updates = observe(&mut outbox, &stored[1]).expect("relay ack for one target");
+    assert_eq!(updates.len(), 1);
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::Submitted));
+    assert_eq!(outbox.status(&id, CAROL), Some(DeliveryState::RelayAcked));
+
+    observe(&mut outbox, &recipient_ack(&agents.bob, &wire, 2)).expect("bob ack");
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::RecipientAcked));
+    assert_eq!(outbox.status(&id, CAROL), Some(DeliveryState::RelayAcked));
+
+    let failure = ProcFailBody::new(4004, "missing field: msg");
+    let proc_fail = reply(&agents.bob, TYPE_PROC_FAIL, &wire, &failure, 3);
+    observe(&mut outbox, &proc_fail).expect("proc fail");
+    let status = &outbox.entry(&id).expect("entry").recipients[&agents.bob.did];
+    assert_eq!(status.state, DeliveryState::ProcFail);
+    assert_eq!(status.error, Some((4004, "missing field: msg".to_string())));
+
+    let mut untrusted = demo_agents().resolver();
+    untrusted.add_agent(&relay);
+    let err = outbox
+        .observe(&open(&stored[0]), &untrusted, T0 + 20)
+        .expect_err("relay ACK from an untrusted DID");
+    assert_eq!(err.code, 3001);
+
+    let stranger = reply(&agents.alice, TYPE_PROC_OK, &wire, &ProcOkBody::default(), 5);
+    let err = outbox
+        .observe(&open(&stranger), &resolver(), T0 + 20)
+        .expect_err("PROC_OK is recipient-only");
+    assert_eq!(err.code, 1001);
+}
+
+#[test]
+fn rfc003_outbox_retries_until_relay_ack_then_expires() {
+    let agents = demo_agents();
+    let relay = relay_keys(RELAY);
+    let wire = outgoing(&agents.alice, Recipients::One(agents.bob.did.clone()), 60_000);
+    let mut outbox = Outbox::new().with_relay_ack_timeout(1_000).with_max_attempts(3);
+    let id = outbox.submit(&wire, T0).expect("submit");
+
+    assert!(outbox.retries_due(T0 + 999).is_empty());
+    assert_eq!(outbox.retries_due(T0 + 1_000), vec![wire.clone()]);
+    assert!(outbox.retries_due(T0 + 1_500).is_empty(), "timeout restarts per attempt");
+    assert_eq!(outbox.retries_due(T0 + 2_000), vec![wire.clone()]);
+    assert!(outbox.retries_due(T0 + 9_000).is_empty(), "max attempts reached");
+    assert_eq!(outbox.entry(&id).expect("entry").attempts, 3);
+
+    let other = {
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(T0, 9),
+            typ: TYPE_MESSAGE,
+            ts_ms: T0,
+            ttl_ms: 60_000,
+            from: String::new(),
+            to: Recipients::One(agents.bob.did.clone()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = TextMessageBody {
+            msg: "acked".to_string(),
+        };
+        build_plain_signed(&agents.alice, meta, &body).expect("message")
+    };
+    let other_id = outbox.submit(&other, T0).expect("submit");
+    let routing = peek_routing(&other).expect("routing");
+    let stored = build_relay_acks(&relay, &routing, T0 + 5).expect("relay ack");
+    observe(&mut outbox, &stored[0]).expect("relay ack");
+    assert!(outbox.retries_due(T0 + 5_000).is_empty(), "relay ACK stops retries");
+
+    let expired = outbox.expire(T0 + 60_001);
+    assert_eq!(expired.len(), 2);
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::Expired));
+    assert_eq!(outbox.status(&other_id, &agents.bob.did), Some(DeliveryState::Expired));
+}
+
+#[test]
+fn rfc003_outbox_error_reply_rejects_the_message() {
+    let agents = demo_agents();
+    let relay = relay_keys(RELAY);
+    let wire = outgoing(&agents.alice, Recipients::One(agents.bob.did.clone()), 0);
+    let mut outbox = Outbox::new().with_relay_ack_timeout(0);
+    let id = outbox.submit(&wire, T0).expect("submit");
+
+    let routing = peek_routing(&wire).expect("routing");
+    let refused = build_error_reply(
+        &relay,
+        &agents.alice.did,
+        Some(routing.id),
+        &RelayError::relay_rejected("ttl=0 requires immediate next-hop availability"),
+        T0 + 5,
+    )
+    .expect("error reply");
+    let updates = observe(&mut outbox, &refused).expect("error");
+    assert_eq!(updates[0].state, DeliveryState::Rejected);
+
+    let entry = outbox.entry(&id).expect("entry");
+    let status = &entry.recipients[&agents.bob.did];
+    assert_eq!(status.error.as_ref().map(|(code, _)| *code), Some(2003));
+    assert!(outbox.retries_due(T0 + 10).is_empty(), "rejected messages are not resubmitted");
+}
+
+#[test]
+fn rfc003_outbox_error_only_rejects_undelivered_copies() {
+    let agents = demo_agents();
+    let relay = relay_keys(RELAY);
+    let to = Recipients::Many(vec![agents.bob.did.clone(), CAROL.to_string()]);
+    let wire = outgoing(&agents.alice, to, 60_000);
+    let mut outbox = Outbox::new().with_relay_ack_timeout(1_000);
+    let id = outbox.submit(&wire, T0).expect("submit");
+
+    // A relay ACK for carol's copy says nothing about bob's.
+    let routing = peek_routing(&wire).expect("routing");
+    let stored = build_relay_acks(&relay, &routing, T0 + 5).expect("relay acks");
+    observe(&mut outbox, &stored[1]).expect("relay ack for carol");
+    assert_eq!(outbox.retries_due(T0 + 1_000), vec![wire.clone()], "bob's copy still waits");
+
+    observe(&mut outbox, &recipient_ack(&agents.bob, &wire, 2)).expect("bob ack");
+    let late = reply(&agents.bob, TYPE_ERROR, &wire, &ErrorBody::new(5001, "too late"), 3);
+    assert!(
+        observe(&mut outbox, &late).expect("bob error").is_empty(),
+        "an ERROR never overrides a recipient ACK"
+    );
+
+    let forged = reply(&agents.alice, TYPE_ERROR, &wire, &ErrorBody::new(2003, "forged"), 4);
+    let err = observe(&mut outbox, &forged).expect_err("ERROR from a non-recipient");
+    assert_eq!(err.code, 3001);
+
+    let refused = build_error_reply(
+        &relay,
+        &agents.alice.did,
+        Some(routing.id),
+        &RelayError::relay_rejected("next hop refused the message"),
+        T0 + 5,
+    )
+    .expect("error reply");
+    let updates = observe(&mut outbox, &refused).expect("relay error");
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].recipient, CAROL);
+    assert_eq!(outbox.status(&id, &agents.bob.did), Some(DeliveryState::RecipientAcked));
+    assert_eq!(outbox.status(&id, CAROL), Some(DeliveryState::Rejected));
+}
@@ -0,0 +1,363 @@
+mod common;
+
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    build_authcrypt_signed, build_plain_signed, demo_agents, hex_encode, make_message_id, now_ms,
+    peek_routing, read_frame, receive_and_verify, validate_ack_semantics, write_frame, AckBody,
+    AckSource, AgentKeys, DidResolver, MessageMeta, ReceivedMessage, Recipients, TextMessageBody,
+    TYPE_ACK, TYPE_HELLO_ACK, TYPE_MESSAGE,
+};
+use amp005_rfc003_tests::{build_session_hello, SessionChallenge};
+use common::{relay_key_args, relay_keys, resolver_trusting};
+
+const ALICE: &str = "did:web:example.com:agent:alice";
+const BOB: &str = "did:web:example.com:agent:bob";
+const CAROL: &str = "did:web:example.com:agent:carol";
+const RELAY: &str = "did:web:example.com:relay:store";
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_server() -> Server {
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+        listener.local_addr().expect("local addr").to_string()
+    };
+    let child = Command::new(env!("CARGO_BIN_EXE_amp005-server"))
+        .arg(&addr)
+        .args(relay_key_args(RELAY))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp005-server");
+
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Server { child, addr }
+}
+
+fn keys_for(did: &str) -> AgentKeys {
+    let agents = demo_agents();
+    [agents.alice, agents.bob]
+        .into_iter()
+        .find(|agent| agent.did == did)
+        .expect("demo agent keys")
+}
+
+fn resolver() -> DidResolver {
+    resolver_trusting([RELAY])
+}
+
+/// Framed AMP connection; a reader thread turns frames into a channel so waits never
+/// split a frame.
+struct FrameClient {
+    stream: TcpStream,
+    frames: Receiver<Vec<u8>>,
+    resolver: DidResolver,
+    counter: u64,
+}
+
+impl FrameClient {
+    fn connect(addr: &str) -> (Self, SessionChallenge) {
+        let stream = TcpStream::connect(addr).expect("connect");
+        let mut reader = stream.try_clone().expect("clone");
+        let (tx, frames) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(frame) = read_frame(&mut reader) {
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut client = Self {
+            stream,
+            frames,
+            resolver: resolver(),
+            counter: 0,
+        };
+        let challenge = client.expect_challenge();
+        (client, challenge)
+    }
+
+    fn hello(addr: &str, did: &str) -> Self {
+        let (mut client, challenge) = Self::connect(addr);
+        let hello = build_session_hello(&keys_for(did), &challenge, &["1.0".to_string()], now_ms())
+            .expect("hello");
+        client.send(&hello);
+        let (_, ack) = client.expect(TYPE_HELLO_ACK);
+        assert_eq!(ack.meta.to.as_vec(), vec![did.to_string()]);
+        client
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        write_frame(&mut self.stream, frame).expect("write frame");
+    }
+
+    fn next_frame(&mut self, wait: Duration) -> Option<Vec<u8>> {
+        self.frames.recv_timeout(wait).ok()
+    }
+
+    fn expect_challenge(&mut self) -> SessionChallenge {
+        let frame = self.next_frame(WAIT).expect("challenge frame");
+        SessionChallenge::from_frame(&frame).expect("challenge")
+    }
+
+    /// Verifies a relay frame as whichever demo agent it is addressed to.
+    fn open(&self, frame: &[u8]) -> ReceivedMessage {
+        let to = peek_routing(frame).expect("amp frame").to;
+        receive_and_verify(&keys_for(&to[0]), frame, &self.resolver, now_ms()).expect("verify")
+    }
+
+    /// Next AMP message of `typ`, skipping anything else.
+    fn expect(&mut self, typ: u8) -> (Vec<u8>, ReceivedMessage) {
+        let deadline = Instant::now() + WAIT;
+        while let Some(frame) = self.next_frame(deadline.saturating_duration_since(Instant::now())) {
+            if peek_routing(&frame).is_ok_and(|r| r.typ == typ) {
+                let message = self.open(&frame);
+                return (frame, message);
+            }
+        }
+        panic!("timed out waiting for typ=0x{typ:02x}");
+    }
+
+    /// Every AMP message that arrives within `window`.
+    fn drain(&mut self, window: Duration) -> Vec<ReceivedMessage> {
+        let deadline = Instant::now() + window;
+        let mut out = Vec::new();
+        while let Some(frame) = self.next_frame(deadline.saturating_duration_since(Instant::now())) {
+            if peek_routing(&frame).is_ok() {
+                out.push(self.open(&frame));
+            }
+        }
+        out
+    }
+
+    fn message(&mut self, from: &str, to: &str, text: &str, ttl_ms: u64) -> Vec<u8> {
+        self.counter += 1;
+        let ts = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, self.counter),
+            typ: TYPE_MESSAGE,
+            ts_ms: ts,
+            ttl_ms,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = TextMessageBody {
+            msg: text.to_string(),
+        };
+        build_authcrypt_signed(&keys_for(from), to, meta, &body, &self.resolver).expect("message")
+    }
+
+    fn recipient_ack(&mut self, original: &ReceivedMessage) -> Vec<u8> {
+        self.counter += 1;
+        let ts = now_ms();
+        let recipient = original.meta.to.as_vec()[0].clone();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, self.counter),
+            typ: TYPE_ACK,
+            ts_ms: ts,
+            ttl_ms: 60_000,
+            from: String::new(),
+            to: Recipients::One(original.meta.from.clone()),
+            reply_to: Some(original.meta.id),
+            thread_id: None,
+        };
+        let body = AckBody {
+            ack_source: AckSource::Recipient,
+            received_at: ts,
+            ack_target: None,
+        };
+        build_plain_signed(&keys_for(&recipient), meta, &body).expect("ack")
+    }
+
+    fn close(self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn text_of(message: &ReceivedMessage) -> String {
+    message.decode_body::<TextMessageBody>().expect("text body").msg
+}
+
+#[test]
+fn rfc003_push_delivers_untouched_wire_bytes_without_poll() {
+    let server = spawn_server();
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+
+    let wire = alice.message(ALICE, BOB, "hello bob", 60_000);
+    let msg_id = peek_routing(&wire).expect("routing").id;
+    alice.send(&wire);
+
+    let (_, stored) = alice.expect(TYPE_ACK);
+    assert_eq!(stored.meta.from, RELAY);
+    assert_eq!(stored.meta.reply_to, Some(msg_id));
+    let stored_body: AckBody = stored.decode_body().expect("ack body");
+    assert_eq!(stored_body.ack_source, AckSource::Relay);
+
+    let (delivered, received) = bob.expect(TYPE_MESSAGE);
+    assert_eq!(delivered, wire, "the relay forwards the signed, encrypted bytes unchanged");
+    assert_eq!(text_of(&received), "hello bob");
+
+    let ack = bob.recipient_ack(&received);
+    bob.send(&ack);
+    let (forwarded_ack, confirmation) = alice.expect(TYPE_ACK);
+    assert_eq!(forwarded_ack, ack);
+    assert_eq!(confirmation.meta.from, BOB);
+    assert_eq!(
+        confirmation.decode_body::<AckBody>().expect("ack body").ack_source,
+        AckSource::Recipient
+    );
+
+    let extra = bob.drain(Duration::from_millis(600));
+    assert!(
+        !extra.iter().any(|m| m.meta.typ == TYPE_MESSAGE),
+        "an inflight push is not repeated while connected: {} extra",
+        extra.len()
+    );
+}
+
+#[test]
+fn rfc003_push_flushes_backlog_on_hello() {
+    let server = spawn_server();
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+    for text in ["first", "second"] {
+        let wire = alice.message(ALICE, BOB, text, 60_000);
+        alice.send(&wire);
+        alice.expect(TYPE_ACK);
+    }
+
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+    assert_eq!(text_of(&bob.expect(TYPE_MESSAGE).1), "first");
+    assert_eq!(text_of(&bob.expect(TYPE_MESSAGE).1), "second");
+}
+
+#[test]
+fn rfc003_push_unacked_inflight_returns_to_backlog_after_disconnect() {
+    let server = spawn_server();
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+
+    let acked = alice.message(ALICE, BOB, "acked", 60_000);
+    alice.send(&acked);
+    let (_, received) = bob.expect(TYPE_MESSAGE);
+    let ack = bob.recipient_ack(&received);
+    bob.send(&ack);
+    alice.expect(TYPE_ACK);
+
+    let unacked = alice.message(ALICE, BOB, "unacked", 60_000);
+    alice.send(&unacked);
+    let (_, received) = bob.expect(TYPE_MESSAGE);
+    assert_eq!(text_of(&received), "unacked");
+    bob.close();
+
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+    let (redelivered, _) = bob.expect(TYPE_MESSAGE);
+    assert_eq!(redelivered, unacked);
+    let acked_id = hex_encode(&peek_routing(&acked).expect("routing").id);
+    let extra = bob.drain(Duration::from_millis(600));
+    assert!(
+        !extra.iter().any(|m| hex_encode(&m.meta.id) == acked_id),
+        "acknowledged entries are never redelivered"
+    );
+}
+
+#[test]
+fn rfc003_push_ttl_zero_requires_online_recipient() {
+    let server = spawn_server();
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+
+    let offline = alice.message(ALICE, BOB, "now or never", 0);
+    alice.send(&offline);
+    let (_, refused) = alice.expect(amp001_example::TYPE_ERROR);
+    let error: amp001_example::ErrorBody = refused.decode_body().expect("error body");
+    assert_eq!(error.code, 2003);
+    assert_eq!(refused.meta.reply_to, peek_routing(&offline).ok().map(|r| r.id));
+
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+    let online = alice.message(ALICE, BOB, "now", 0);
+    alice.send(&online);
+    let (delivered, _) = bob.expect(TYPE_MESSAGE);
+    assert_eq!(delivered, online);
+}
+
+#[test]
+fn rfc003_relay_acks_each_recipient_of_a_multi_recipient_message() {
+    let server = spawn_server();
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+    let mut bob = FrameClient::hello(&server.addr, BOB);
+
+    let ts = now_ms();
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(ts, 7),
+        typ: TYPE_MESSAGE,
+        ts_ms: ts,
+        ttl_ms: 60_000,
+        from: String::new(),
+        to: Recipients::Many(vec![BOB.to_string(), CAROL.to_string()]),
+        reply_to: None,
+        thread_id: None,
+    };
+    let body = TextMessageBody {
+        msg: "to both".to_string(),
+    };
+    let wire = build_plain_signed(&keys_for(ALICE), meta.clone(), &body).expect("message");
+    alice.send(&wire);
+
+    let original_to = meta.to.as_vec();
+    let mut targets = Vec::new();
+    for _ in 0..2 {
+        let (_, ack) = alice.expect(TYPE_ACK);
+        assert_eq!(ack.meta.from, RELAY);
+        assert_eq!(ack.meta.reply_to, Some(meta.id));
+        validate_ack_semantics(&ack, &original_to, &alice.resolver).expect("trusted relay ACK");
+        let body: AckBody = ack.decode_body().expect("ack body");
+        assert_eq!(body.ack_source, AckSource::Relay, "stored by relay, not delivered");
+        assert!(body.received_at >= ts);
+        targets.push(body.ack_target.expect("multi-recipient ACK names its target"));
+    }
+    targets.sort();
+    assert_eq!(targets, original_to);
+
+    let (delivered, _) = bob.expect(TYPE_MESSAGE);
+    assert_eq!(delivered, wire);
+}
+
+#[test]
+fn rfc003_relay_ack_requires_a_trusted_relay() {
+    let server = spawn_server();
+    let mut alice = FrameClient::hello(&server.addr, ALICE);
+    let wire = alice.message(ALICE, BOB, "stored?", 60_000);
+    alice.send(&wire);
+    let (_, ack) = alice.expect(TYPE_ACK);
+
+    let mut untrusted = demo_agents().resolver();
+    untrusted.add_agent(&relay_keys(RELAY));
+    let err = validate_ack_semantics(&ack, &[BOB.to_string()], &untrusted)
+        .expect_err("relay DID is not trusted");
+    assert_eq!(err.code, 3001);
+}
@@ -0,0 +1,277 @@
+use amp005_rfc003_tests::{
+    did_web_for_endpoint, DidDocumentServices, FederationRequirements, Message, RouteSource,
+    RoutingTable, TransferMode, DEFAULT_ROUTE_COOLDOWN_MS, DEFAULT_ROUTE_FAILURE_THRESHOLD,
+};
+
+const ALICE: &str = "did:web:example.com:agent:alice";
+const BOB: &str = "did:web:example.com:agent:bob";
+const CAROL: &str = "did:web:example.com:agent:carol";
+const DAVE: &str = "did:web:example.com:agent:dave";
+const RELAY_A: &str = "did:web:example.com:relay:a";
+const RELAY_B: &str = "did:web:example.com:relay:b";
+const RELAY_C: &str = "did:web:example.com:relay:c";
+const NOW: u64 = 1_707_055_200_000;
+
+fn bob_document(services: serde_json::Value) -> DidDocumentServices {
+    serde_json::from_value(serde_json::json!({
+        "@context": ["https://www.w3.org/ns/did/v1"],
+        "id": BOB,
+        "service": services,
+    }))
+    .expect("did document")
+}
+
+fn federation_service(fragment: &str, endpoint: &str, caps: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "id": format!("{BOB}#{fragment}"),
+        "type": "AgentMessagingRelay",
+        "serviceEndpoint": endpoint,
+        "relayCapabilities": caps,
+    })
+}
+
+fn full_caps(endpoint: &str) -> serde_json::Value {
+    serde_json::json!({
+        "federation": true,
+        "relayForwardEndpoint": format!("{endpoint}/amp/v1/relay/forward"),
+        "transferModes": ["single", "dual"],
+        "maxHopLimit": 16,
+        "defaultHopLimit": 8,
+        "receiptAlgs": [-8, -7],
+    })
+}
+
+#[test]
+fn rfc008_did_document_relay_services_become_ranked_candidates() {
+    let document = bob_document(serde_json::json!([
+        {
+            "id": format!("{BOB}#amp"),
+            "type": "AgentMessaging",
+            "serviceEndpoint": "https://amp.example.com/agent/bob",
+        },
+        federation_service("amp-relay", "https://relay.example.com", full_caps("https://relay.example.com")),
+        federation_service("amp-relay-2", "https://backup.example.com:8443/amp", full_caps("https://backup.example.com:8443")),
+    ]));
+
+    let mut table = RoutingTable::new(RELAY_A);
+    let skipped = table
+        .add_did_document_routes(&document, &FederationRequirements::default(), 10)
+        .expect("routes");
+    assert!(skipped.is_empty(), "{skipped:?}");
+
+    let hops = table.next_hops(BOB, NOW);
+    assert_eq!(hops.len(), 2);
+    assert_eq!(hops[0].relay_did, "did:web:relay.example.com");
+    assert_eq!(hops[0].priority, 10);
+    assert_eq!(hops[0].source, RouteSource::DidDocument);
+    assert_eq!(
+        hops[0].endpoint.as_deref(),
+        Some("https://relay.example.com/amp/v1/relay/forward")
+    );
+    assert_eq!(hops[1].relay_did, "did:web:backup.example.com%3A8443:amp");
+    assert_eq!(hops[1].priority, 11);
+}
+
+#[test]
+fn rfc008_relay_candidates_filtered_by_federation_requirements() {
+    let endpoint = "https://relay.example.com";
+    let document = bob_document(serde_json::json!([
+        {
+            "id": format!("{BOB}#basic"),
+            "type": "AgentMessagingRelay",
+            "serviceEndpoint": "https://basic.example.com",
+        },
+        federation_service("off", "https://off.example.com", serde_json::json!({ "federation": false })),
+        federation_service("incomplete", "https://partial.example.com", serde_json::json!({
+            "federation": true,
+            "transferModes": ["single"],
+            "maxHopLimit": 16,
+            "receiptAlgs": [-8],
+        })),
+        federation_service("no-eddsa", "https://es256.example.com", serde_json::json!({
+            "federation": true,
+            "relayForwardEndpoint": "https://es256.example.com/amp/v1/relay/forward",
+            "transferModes": ["single", "dual"],
+            "maxHopLimit": 16,
+            "receiptAlgs": [-7],
+        })),
+        federation_service("single-only", "https://single.example.com", serde_json::json!({
+            "federation": true,
+            "relayForwardEndpoint": "https://single.example.com/amp/v1/relay/forward",
+            "transferModes": ["single"],
+            "maxHopLimit": 16,
+            "receiptAlgs": [-8],
+        })),
+        federation_service("short", "https://short.example.com", serde_json::json!({
+            "federation": true,
+            "relayForwardEndpoint": "https://short.example.com/amp/v1/relay/forward",
+            "transferModes": ["single", "dual"],
+            "maxHopLimit": 4,
+            "defaultHopLimit": 4,
+            "receiptAlgs": [-8],
+        })),
+        federation_service("good", endpoint, full_caps(endpoint)),
+    ]));
+
+    let requirements = FederationRequirements {
+        transfer_mode: TransferMode::Dual,
+        ..FederationRequirements::default()
+    };
+    let mut table = RoutingTable::new(RELAY_A);
+    let skipped = table
+        .add_did_document_routes(&document, &requirements, 0)
+        .expect("routes");
+    assert_eq!(skipped.len(), 6, "{skipped:?}");
+    assert!(skipped[0].contains("relayCapabilities absent"));
+    assert!(skipped[1].contains("federation=false"));
+    assert!(skipped[2].contains("relayForwardEndpoint"));
+    assert!(skipped[3].contains("-8"));
+    assert!(skipped[4].contains("Dual"));
+    assert!(skipped[5].contains("maxHopLimit"));
+
+    let hops = table.next_hops(BOB, NOW);
+    assert_eq!(hops.len(), 1);
+    assert_eq!(hops[0].relay_did, "did:web:relay.example.com");
+}
+
+#[test]
+fn routing_priority_and_source_precedence() {
+    let mut table = RoutingTable::new(RELAY_A);
+    table.add_static_route(BOB, RELAY_C, None, 5);
+    table.add_static_route(BOB, RELAY_B, None, 1);
+    table.learn_route(BOB, "did:web:example.com:relay:d", NOW);
+
+    let order: Vec<&str> = table
+        .next_hops(BOB, NOW)
+        .iter()
+        .map(|c| c.relay_did.as_str())
+        .collect();
+    assert_eq!(order, vec![RELAY_B, RELAY_C, "did:web:example.com:relay:d"]);
+
+    // Learning a configured relay does not demote it; configuring a learned one promotes it.
+    table.learn_route(BOB, RELAY_B, NOW);
+    assert_eq!(table.next_hops(BOB, NOW)[0].source, RouteSource::Static);
+    table.add_static_route(BOB, "did:web:example.com:relay:d", None, 0);
+    let best = &table.next_hops(BOB, NOW)[0];
+    assert_eq!(best.relay_did, "did:web:example.com:relay:d");
+    assert_eq!(best.source, RouteSource::Static);
+
+    assert_eq!(
+        table
+            .select_next_hop(BOB, &["did:web:example.com:relay:d".to_string()], NOW)
+            .map(|c| c.relay_did.as_str()),
+        Some(RELAY_B)
+    );
+}
+
+#[test]
+fn routing_learned_routes_expire() {
+    let mut table = RoutingTable::new(RELAY_B);
+    table.learned_ttl_ms = 1_000;
+    table.learn_route(ALICE, RELAY_A, NOW);
+    table.learn_route(ALICE, RELAY_B, NOW);
+
+    assert_eq!(table.next_hops(ALICE, NOW).len(), 1, "own relay is never learned");
+    assert_eq!(table.next_hops(ALICE, NOW + 999)[0].relay_did, RELAY_A);
+    assert!(table.next_hops(ALICE, NOW + 1_000).is_empty());
+
+    table.learn_route(ALICE, RELAY_A, NOW + 500);
+    assert_eq!(table.next_hops(ALICE, NOW + 1_000).len(), 1, "refresh extends expiry");
+}
+
+#[test]
+fn routing_unhealthy_relay_skipped_until_cooldown() {
+    let mut table = RoutingTable::new(RELAY_A);
+    table.add_static_route(BOB, RELAY_B, None, 0);
+    table.add_static_route(BOB, RELAY_C, None, 1);
+
+    for _ in 1..DEFAULT_ROUTE_FAILURE_THRESHOLD {
+        table.mark_failure(RELAY_B, NOW);
+    }
+    assert!(table.is_healthy(RELAY_B, NOW), "below threshold");
+    table.mark_failure(RELAY_B, NOW);
+    assert!(!table.is_healthy(RELAY_B, NOW));
+    assert_eq!(table.select_next_hop(BOB, &[], NOW).map(|c| c.relay_did.as_str()), Some(RELAY_C));
+
+    let later = NOW + DEFAULT_ROUTE_COOLDOWN_MS;
+    assert_eq!(table.select_next_hop(BOB, &[], later).map(|c| c.relay_did.as_str()), Some(RELAY_B));
+
+    table.mark_failure(RELAY_B, later);
+    table.mark_success(RELAY_B);
+    table.mark_failure(RELAY_B, later);
+    assert!(table.is_healthy(RELAY_B, later), "success resets the failure streak");
+}
+
+#[test]
+fn routing_groups_recipients_into_per_downstream_forwards() {
+    let mut table = RoutingTable::new(RELAY_A);
+    table.add_static_route(BOB, RELAY_B, None, 0);
+    table.add_static_route(CAROL, RELAY_B, None, 0);
+    table.add_static_route(DAVE, RELAY_C, None, 0);
+    table.add_static_route(ALICE, RELAY_A, None, 0);
+
+    let message = Message {
+        from_did: ALICE.to_string(),
+        msg_id: "0000019c3520e44c0000000000000001".to_string(),
+        recipients: vec![
+            BOB.to_string(),
+            DAVE.to_string(),
+            CAROL.to_string(),
+            ALICE.to_string(),
+            "did:web:example.com:agent:erin".to_string(),
+        ],
+        ts_ms: NOW,
+        ttl_ms: 60_000,
+    };
+    let plan = table
+        .plan_forwards(&message, &[RELAY_A.to_string()], 7, TransferMode::Dual, NOW)
+        .expect("plan");
+
+    assert_eq!(plan.local, vec![ALICE.to_string()]);
+    assert_eq!(plan.unroutable, vec!["did:web:example.com:agent:erin".to_string()]);
+    assert_eq!(plan.forwards.len(), 2);
+
+    let to_b = &plan.forwards[RELAY_B];
+    assert_eq!(
+        to_b.iter().map(|f| f.recipient_did.as_str()).collect::<Vec<_>>(),
+        vec![BOB, CAROL]
+    );
+    assert!(to_b.iter().all(|f| f.downstream_relay == RELAY_B
+        && f.upstream_relay == RELAY_A
+        && f.hop_limit == 7
+        && f.transfer_mode == TransferMode::Dual));
+    assert_eq!(plan.forwards[RELAY_C][0].recipient_did, DAVE);
+}
+
+#[test]
+fn routing_plan_skips_relays_already_on_path() {
+    let mut table = RoutingTable::new(RELAY_B);
+    table.add_static_route(BOB, RELAY_A, None, 0);
+    table.add_static_route(BOB, RELAY_C, None, 1);
+
+    let message = Message {
+        from_did: ALICE.to_string(),
+        msg_id: "0000019c3520e44c0000000000000002".to_string(),
+        recipients: vec![BOB.to_string()],
+        ts_ms: NOW,
+        ttl_ms: 60_000,
+    };
+    let path = vec![RELAY_A.to_string(), RELAY_B.to_string()];
+    let plan = table
+        .plan_forwards(&message, &path, 6, TransferMode::Single, NOW)
+        .expect("plan");
+    assert_eq!(plan.forwards.keys().collect::<Vec<_>>(), vec![RELAY_C]);
+}
+
+#[test]
+fn did_web_identifier_for_relay_endpoint() {
+    assert_eq!(
+        did_web_for_endpoint("https://relay.agentries.xyz").expect("did"),
+        "did:web:relay.agentries.xyz"
+    );
+    assert_eq!(
+        did_web_for_endpoint("http://127.0.0.1:8104/").expect("did"),
+        "did:web:127.0.0.1%3A8104"
+    );
+    assert_eq!(did_web_for_endpoint("ftp://relay").expect_err("scheme").code, 1001);
+}
@@ -1,10 +1,14 @@
+mod common;
+
 use std::collections::HashMap;
 
 use amp005_rfc003_tests::{
-    compute_handoff_step, split_for_federation, CommitReceipt, CommitResult, QueueStatus, Relay,
-    TransferMode, TransferReceipt, TransferState, COMMIT_V1, FWD_V1, RECEIPT_V1,
-    DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS, DEFAULT_HANDOFF_MAX_ATTEMPTS,
+    compute_handoff_step, handoff_retry_backoff_ms, sign_commit_receipt, sign_transfer_receipt,
+    split_for_federation, CommitReceipt, CommitResult, QueueStatus, Relay, TransferMode,
+    TransferReceipt, TransferState, COMMIT_V1, FWD_V1, RECEIPT_V1,
+    DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS, DEFAULT_HANDOFF_MAX_ATTEMPTS, MAX_HANDOFF_RETRY_BACKOFF_MS,
 };
+use common::{relay_keys, resolver_trusting, with_protected_alg};
 
 fn sample_message(ttl_ms: u64, recipients: Vec<&str>) -> amp005_rfc003_tests::Message {
     amp005_rfc003_tests::Message {
@@ -182,8 +186,9 @@ fn rfc003_a9_single_custody_acceptance_removes_local_copy() {
         kid: "did:web:relay:b#k1".to_string(),
         key_purpose: "assertionMethod".to_string(),
     };
+    let receipt = sign_transfer_receipt(&receipt, &relay_keys("did:web:relay:b")).expect("sign receipt");
     relay
-        .apply_transfer_receipt(&forward, &receipt, &[-8, -7])
+        .apply_transfer_receipt(&forward, &receipt, &resolver_trusting(["did:web:relay:b"]), &[-8, -7])
         .expect("receipt accepted");
 
     assert_eq!(
@@ -212,7 +217,7 @@ fn rfc003_a10_and_a11_invalid_or_unsupported_receipt_rejected() {
 
     let invalid_tuple = TransferReceipt {
         receipt_v: RECEIPT_V1,
-        msg_id: "wrong".to_string(),
+        msg_id: "0000019c3520e44c00000000000000ff".to_string(),
         from_did: msg.from_did.clone(),
         recipient_did: forward.recipient_did.clone(),
         upstream_relay: forward.upstream_relay.clone(),
@@ -224,8 +229,9 @@ fn rfc003_a10_and_a11_invalid_or_unsupported_receipt_rejected() {
         kid: "did:web:relay:b#k1".to_string(),
         key_purpose: "assertionMethod".to_string(),
     };
+    let invalid_tuple = sign_transfer_receipt(&invalid_tuple, &relay_keys("did:web:relay:b")).expect("sign");
     let err = relay
-        .apply_transfer_receipt(&forward, &invalid_tuple, &[-8])
+        .apply_transfer_receipt(&forward, &invalid_tuple, &resolver_trusting(["did:web:relay:b"]), &[-8])
         .expect_err("tuple mismatch must reject");
     assert_eq!(err.code, 3001);
 
@@ -239,14 +245,20 @@ fn rfc003_a10_and_a11_invalid_or_unsupported_receipt_rejected() {
         accepted_at: relay.now_ms,
         hop_limit_remaining: 7,
         accepted: true,
-        alg: -35,
+        alg: -8,
         kid: "did:web:relay:b#k1".to_string(),
         key_purpose: "assertionMethod".to_string(),
     };
+    let signed = sign_transfer_receipt(&unsupported_alg, &relay_keys("did:web:relay:b")).expect("sign");
     let err = relay
-        .apply_transfer_receipt(&forward, &unsupported_alg, &[-8])
+        .apply_transfer_receipt(&forward, &with_protected_alg(&signed, -35), &resolver_trusting(["did:web:relay:b"]), &[-8])
         .expect_err("unsupported alg must reject");
     assert_eq!(err.code, 3001);
+
+    let err = relay
+        .apply_transfer_receipt(&forward, &signed, &resolver_trusting(["did:web:relay:b"]), &[-7])
+        .expect_err("alg outside local policy must reject");
+    assert_eq!(err.code, 3001);
 }
 
 #[test]
@@ -290,8 +302,9 @@ fn rfc003_a12_dual_custody_commit_receipt_positive() {
         kid: "did:web:relay:b#k1".to_string(),
         key_purpose: "assertionMethod".to_string(),
     };
+    let receipt = sign_transfer_receipt(&receipt, &relay_keys("did:web:relay:b")).expect("sign receipt");
     relay
-        .apply_transfer_receipt(&forward, &receipt, &[-8, -7])
+        .apply_transfer_receipt(&forward, &receipt, &resolver_trusting(["did:web:relay:b"]), &[-8, -7])
         .expect("receipt accepted");
 
     let commit = CommitReceipt {
@@ -307,8 +320,9 @@ fn rfc003_a12_dual_custody_commit_receipt_positive() {
         kid: "did:web:relay:b#k1".to_string(),
         key_purpose: "assertionMethod".to_string(),
     };
+    let commit = sign_commit_receipt(&commit, &relay_keys("did:web:relay:b")).expect("sign commit");
     relay
-        .apply_commit_receipt(&forward, &commit, &[-8, -7])
+        .apply_commit_receipt(&forward, &commit, &resolver_trusting(["did:web:relay:b"]), &[-8, -7])
         .expect("commit receipt");
 
     assert_eq!(
@@ -350,3 +364,10 @@ fn rfc003_mti_defaults_present() {
     assert_eq!(DEFAULT_HANDOFF_MAX_ATTEMPTS, 3);
     assert_eq!(DEFAULT_HANDOFF_ACCEPT_TIMEOUT_MS, 5_000);
 }
+
+#[test]
+fn rfc003_handoff_retry_backoff_sequence() {
+    let sequence: Vec<u64> = (1..=7).map(handoff_retry_backoff_ms).collect();
+    assert_eq!(sequence, vec![1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000]);
+    assert_eq!(handoff_retry_backoff_ms(u8::MAX), MAX_HANDOFF_RETRY_BACKOFF_MS);
+}
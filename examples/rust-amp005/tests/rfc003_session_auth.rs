@@ -0,0 +1,409 @@
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use amp001_example::{
+    build_authcrypt_signed, build_plain_signed, demo_agents, hex_encode, make_message_id, now_ms,
+    peek_routing, read_frame, receive_and_verify, write_frame, AckBody, AckSource, AgentKeys,
+    Connection, DidResolver, ErrorBody, Handshake, MessageMeta, ReceivedMessage, Recipients,
+    TextMessageBody, TYPE_ACK, TYPE_ERROR, TYPE_HELLO, TYPE_HELLO_ACK, TYPE_HELLO_REJECT,
+    TYPE_MESSAGE,
+};
+use amp005_rfc003_tests::{
+    add_relay_key, build_session_hello, validate_session_principal_binding, SessionChallenge,
+    SessionHandshake, SessionHelloBody, DEFAULT_SESSION_CHALLENGE_TTL_MS,
+};
+use serde_bytes::ByteBuf;
+use common::{relay_key_args, relay_keys, resolver_trusting};
+
+const ALICE: &str = "did:web:example.com:agent:alice";
+const BOB: &str = "did:web:example.com:agent:bob";
+const RELAY: &str = "did:web:example.com:relay:store";
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_server() -> Server {
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+        listener.local_addr().expect("local addr").to_string()
+    };
+    let child = Command::new(env!("CARGO_BIN_EXE_amp005-server"))
+        .arg(&addr)
+        .args(relay_key_args(RELAY))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn amp005-server");
+
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Server { child, addr }
+}
+
+fn keys_for(did: &str) -> AgentKeys {
+    let agents = demo_agents();
+    [agents.alice, agents.bob]
+        .into_iter()
+        .find(|agent| agent.did == did)
+        .expect("demo agent keys")
+}
+
+fn resolver() -> DidResolver {
+    resolver_trusting([RELAY])
+}
+
+/// Framed AMP connection; a reader thread turns frames into a channel so waits never
+/// split a frame.
+struct FrameClient {
+    stream: TcpStream,
+    frames: Receiver<Vec<u8>>,
+    resolver: DidResolver,
+    counter: u64,
+}
+
+impl FrameClient {
+    fn connect(addr: &str) -> (Self, SessionChallenge) {
+        let stream = TcpStream::connect(addr).expect("connect");
+        let mut reader = stream.try_clone().expect("clone");
+        let (tx, frames) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(frame) = read_frame(&mut reader) {
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut client = Self {
+            stream,
+            frames,
+            resolver: resolver(),
+            counter: 0,
+        };
+        let challenge = client.expect_challenge();
+        (client, challenge)
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        write_frame(&mut self.stream, frame).expect("write frame");
+    }
+
+    fn next_frame(&mut self, wait: Duration) -> Option<Vec<u8>> {
+        self.frames.recv_timeout(wait).ok()
+    }
+
+    fn expect_challenge(&mut self) -> SessionChallenge {
+        let frame = self.next_frame(WAIT).expect("challenge frame");
+        SessionChallenge::from_frame(&frame).expect("challenge")
+    }
+
+    /// Verifies a relay frame as whichever demo agent it is addressed to.
+    fn open(&self, frame: &[u8]) -> ReceivedMessage {
+        let to = peek_routing(frame).expect("amp frame").to;
+        receive_and_verify(&keys_for(&to[0]), frame, &self.resolver, now_ms()).expect("verify")
+    }
+
+    /// Next AMP message of `typ`, skipping anything else.
+    fn expect(&mut self, typ: u8) -> (Vec<u8>, ReceivedMessage) {
+        let deadline = Instant::now() + WAIT;
+        while let Some(frame) = self.next_frame(deadline.saturating_duration_since(Instant::now())) {
+            if peek_routing(&frame).is_ok_and(|r| r.typ == typ) {
+                let message = self.open(&frame);
+                return (frame, message);
+            }
+        }
+        panic!("timed out waiting for typ=0x{typ:02x}");
+    }
+
+    fn message(&mut self, from: &str, to: &str, text: &str, ttl_ms: u64) -> Vec<u8> {
+        self.counter += 1;
+        let ts = now_ms();
+        let meta = MessageMeta {
+            v: 1,
+            id: make_message_id(ts, self.counter),
+            typ: TYPE_MESSAGE,
+            ts_ms: ts,
+            ttl_ms,
+            from: String::new(),
+            to: Recipients::One(to.to_string()),
+            reply_to: None,
+            thread_id: None,
+        };
+        let body = TextMessageBody {
+            msg: text.to_string(),
+        };
+        build_authcrypt_signed(&keys_for(from), to, meta, &body, &self.resolver).expect("message")
+    }
+
+}
+
+const NOW: u64 = 1_707_055_200_000;
+
+fn versions() -> Vec<String> {
+    vec!["1.0".to_string()]
+}
+
+fn expect_error(client: &mut FrameClient, code: u16) -> ErrorBody {
+    let (_, refused) = client.expect(TYPE_ERROR);
+    let body: ErrorBody = refused.decode_body().expect("error body");
+    assert_eq!(body.code, code, "{}", body.message);
+    body
+}
+
+#[test]
+fn session_signed_hello_binds_did_relay_and_nonce() {
+    let agents = demo_agents();
+    let resolver = resolver();
+    let relay_keys = relay_keys(RELAY);
+    let challenge = SessionChallenge::new(RELAY, NOW);
+
+    let wire = build_session_hello(&agents.bob, &challenge, &versions(), NOW).expect("hello");
+    let (did, offered) = challenge
+        .verify_signed_hello(&wire, &relay_keys, &resolver, NOW)
+        .expect("valid hello");
+    assert_eq!((did.as_str(), offered), (BOB, versions()));
+
+    // Another nonce, another relay, a forged key or a stale challenge prove nothing.
+    let fresh = SessionChallenge::new(RELAY, NOW);
+    assert_ne!(fresh.nonce, challenge.nonce);
+    let replay = fresh
+        .verify_signed_hello(&wire, &relay_keys, &resolver, NOW)
+        .expect_err("replayed hello");
+    assert_eq!(replay.code, 3001);
+
+    let other_relay = SessionChallenge::new("did:web:example.com:relay:other", NOW);
+    let misdirected =
+        build_session_hello(&agents.bob, &other_relay, &versions(), NOW).expect("hello");
+    assert_eq!(
+        challenge
+            .verify_signed_hello(&misdirected, &relay_keys, &resolver, NOW)
+            .expect_err("addressed to another relay")
+            .code,
+        3001
+    );
+
+    let forged_alice = AgentKeys::from_seeds(ALICE, [2_u8; 32], [12_u8; 32]);
+    let forged = build_session_hello(&forged_alice, &challenge, &versions(), NOW).expect("hello");
+    assert_eq!(
+        challenge
+            .verify_signed_hello(&forged, &relay_keys, &resolver, NOW)
+            .expect_err("forged key")
+            .code,
+        3001
+    );
+
+    let mallory = AgentKeys::from_sign_seed("did:web:example.com:agent:mallory", [9_u8; 32]);
+    let unknown = build_session_hello(&mallory, &challenge, &versions(), NOW).expect("hello");
+    assert_eq!(
+        challenge
+            .verify_signed_hello(&unknown, &relay_keys, &resolver, NOW)
+            .expect_err("unresolvable")
+            .code,
+        3001
+    );
+
+    let late = NOW + DEFAULT_SESSION_CHALLENGE_TTL_MS + 1;
+    let expired = challenge
+        .verify_signed_hello(&wire, &relay_keys, &resolver, late)
+        .expect_err("expired");
+    assert!(expired.detail.contains("expired"));
+}
+
+#[test]
+fn session_challenge_frame_roundtrip() {
+    let challenge = SessionChallenge::new(RELAY, NOW);
+    let parsed = SessionChallenge::from_frame(&challenge.to_frame()).expect("frame roundtrip");
+    assert_eq!((parsed.relay_did.as_str(), parsed.nonce), (RELAY, challenge.nonce));
+
+    let short = serde_cbor::to_vec(&serde_cbor::Value::Map(
+        [
+            (
+                serde_cbor::Value::Text("relay_did".to_string()),
+                serde_cbor::Value::Text(RELAY.to_string()),
+            ),
+            (
+                serde_cbor::Value::Text("nonce".to_string()),
+                serde_cbor::Value::Bytes(vec![0, 255]),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    ))
+    .expect("cbor");
+    assert_eq!(SessionChallenge::from_frame(&short).expect_err("short nonce").code, 1001);
+}
+
+#[test]
+fn session_principal_binding_is_strict() {
+    validate_session_principal_binding(ALICE, ALICE).expect("same did");
+    let err = validate_session_principal_binding(ALICE, BOB).expect_err("switch");
+    assert_eq!(err.code, 3001);
+    assert!(err.detail.contains("strict binding failed"));
+}
+
+#[test]
+fn rfc003_session_rejects_unproven_hello_and_messages() {
+    let server = spawn_server();
+    let (mut client, first) = FrameClient::connect(&server.addr);
+    assert_eq!(first.relay_did, RELAY);
+
+    let early = client.message(ALICE, BOB, "before hello", 60_000);
+    client.send(&early);
+    expect_error(&mut client, 3001);
+
+    // A HELLO that answers no challenge of this connection.
+    let unrelated = SessionChallenge::new(RELAY, now_ms());
+    let agents = demo_agents();
+    client.send(&build_session_hello(&agents.alice, &unrelated, &versions(), now_ms()).expect("hello"));
+    expect_error(&mut client, 3001);
+    let second = client.expect_challenge();
+    assert_ne!(second.nonce, first.nonce, "a failed proof rotates the challenge");
+
+    // Bob's key cannot answer for Alice.
+    let forged_alice = AgentKeys::from_seeds(ALICE, [2_u8; 32], [12_u8; 32]);
+    client.send(&build_session_hello(&forged_alice, &second, &versions(), now_ms()).expect("hello"));
+    expect_error(&mut client, 3001);
+    let third = client.expect_challenge();
+
+    // A valid answer to an earlier challenge is a replay.
+    client.send(&build_session_hello(&agents.alice, &second, &versions(), now_ms()).expect("hello"));
+    expect_error(&mut client, 3001);
+    let fourth = client.expect_challenge();
+    assert_ne!(third.nonce, fourth.nonce);
+
+    client.send(&build_session_hello(&agents.alice, &fourth, &versions(), now_ms()).expect("hello"));
+    let (_, ack) = client.expect(TYPE_HELLO_ACK);
+    assert_eq!(ack.meta.from, RELAY);
+
+    let accepted = client.message(ALICE, BOB, "after hello", 60_000);
+    client.send(&accepted);
+    let (_, stored) = client.expect(TYPE_ACK);
+    assert_eq!(
+        stored.decode_body::<AckBody>().expect("ack body").ack_source,
+        AckSource::Relay
+    );
+}
+
+#[test]
+fn rfc003_session_hello_is_single_use_and_binding_is_strict() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let (mut client, challenge) = FrameClient::connect(&server.addr);
+
+    let hello = build_session_hello(&agents.bob, &challenge, &versions(), now_ms()).expect("hello");
+    client.send(&hello);
+    client.expect(TYPE_HELLO_ACK);
+
+    // The proof was consumed; re-sending it is rejected and a fresh challenge follows.
+    client.send(&hello);
+    expect_error(&mut client, 3001);
+    let next = client.expect_challenge();
+
+    // Proving a second DID on a bound connection does not switch the session.
+    client.send(&build_session_hello(&agents.alice, &next, &versions(), now_ms()).expect("hello"));
+    let err = expect_error(&mut client, 3001);
+    assert!(err.message.contains("strict binding failed"), "{}", err.message);
+    let next = client.expect_challenge();
+
+    // Nor may the bound connection submit for someone else.
+    let spoofed = client.message(ALICE, BOB, "not mine", 60_000);
+    client.send(&spoofed);
+    let err = expect_error(&mut client, 3001);
+    assert!(err.message.contains("strict binding failed"), "{}", err.message);
+
+    client.send(&build_session_hello(&agents.bob, &next, &versions(), now_ms()).expect("hello"));
+    client.expect(TYPE_HELLO_ACK);
+}
+
+#[test]
+fn rfc003_session_without_compatible_version_is_rejected_and_closed() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let (mut client, challenge) = FrameClient::connect(&server.addr);
+
+    let ts = now_ms();
+    let meta = MessageMeta {
+        v: 1,
+        id: make_message_id(ts, 1),
+        typ: TYPE_HELLO,
+        ts_ms: ts,
+        ttl_ms: 60_000,
+        from: String::new(),
+        to: Recipients::One(RELAY.to_string()),
+        reply_to: None,
+        thread_id: None,
+    };
+    let body = SessionHelloBody {
+        versions: vec!["2.0".to_string()],
+        challenge: ByteBuf::from(challenge.nonce.to_vec()),
+    };
+    client.send(&build_plain_signed(&agents.alice, meta, &body).expect("hello"));
+
+    let (_, reject) = client.expect(TYPE_HELLO_REJECT);
+    assert_eq!(reject.meta.to.as_vec(), vec![ALICE.to_string()]);
+    assert!(client.next_frame(WAIT).is_none(), "the relay closes the connection");
+}
+
+#[test]
+fn rfc003_session_requires_a_published_relay_key() {
+    let server = spawn_server();
+    let agents = demo_agents();
+    let handshake = SessionHandshake {
+        relay_did: RELAY.to_string(),
+        versions: versions(),
+    };
+
+    // The relay's key is not derivable from its DID; without it the session is refused.
+    let mut unknown = demo_agents().resolver();
+    let mut connection = Connection::tcp(&server.addr).expect("connect");
+    let err = handshake
+        .open(&agents.alice, &mut unknown, &mut connection)
+        .expect_err("unknown relay key");
+    assert_eq!(err.code, 3001);
+
+    let public = hex_encode(relay_keys(RELAY).signing_public_key.as_bytes());
+    add_relay_key(&mut unknown, RELAY, &public).expect("publish relay key");
+    let mut connection = Connection::tcp(&server.addr).expect("connect");
+    let outcome = handshake
+        .open(&agents.alice, &mut unknown, &mut connection)
+        .expect("session");
+    assert_eq!(outcome.peer_did.as_deref(), Some(RELAY));
+
+    // The challenge frame is unsigned: a relay that names itself differently from the one
+    // configured is refused even when its key is known.
+    let other = "did:web:example.com:relay:other";
+    let mut trusting_both = resolver_trusting([RELAY, other]);
+    let expecting_other = SessionHandshake {
+        relay_did: other.to_string(),
+        ..handshake.clone()
+    };
+    let mut connection = Connection::tcp(&server.addr).expect("connect");
+    let err = expecting_other
+        .open(&agents.alice, &mut trusting_both, &mut connection)
+        .expect_err("challenge from another relay");
+    assert_eq!(err.code, 3001);
+
+    let err = add_relay_key(&mut unknown, RELAY, "abcd").expect_err("short key");
+    assert_eq!(err.code, 1001);
+    let seeded = amp005_rfc003_tests::relay_keys(RELAY, Some(&"07".repeat(32))).expect("seed");
+    assert_eq!(seeded.signing_key.as_bytes(), &[7_u8; 32]);
+    let fresh = amp005_rfc003_tests::relay_keys(RELAY, None).expect("random");
+    assert_ne!(fresh.signing_public_key, seeded.signing_public_key);
+}